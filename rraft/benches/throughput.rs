@@ -0,0 +1,5 @@
+//! Committed entries per second of logical time; see `rraft::bench`.
+
+fn main() {
+    rraft::bench::run();
+}
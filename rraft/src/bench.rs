@@ -0,0 +1,76 @@
+//! Throughput benchmark: a cluster under sustained writes for a fixed span of
+//! logical time, reporting committed entries per second. `cargo bench --bench
+//! throughput` runs it through `benches/throughput.rs`.
+
+use std::time::{Duration, Instant};
+
+use super::Simulation;
+
+/// Logical time each model runs for once a leader is elected.
+const RUN: Duration = Duration::from_secs(10);
+
+/// The leader is kept this many entries ahead of its commit index, which is
+/// as fast as it can usefully take them: more only grows its backlog.
+const WINDOW: usize = 64;
+
+struct Model {
+    name: &'static str,
+    latency: Duration,
+    jitter: Duration,
+}
+
+const MODELS: [Model; 2] = [
+    Model {
+        name: "zero-loss",
+        latency: Duration::from_millis(1),
+        jitter: Duration::ZERO,
+    },
+    Model {
+        name: "moderate-latency",
+        latency: Duration::from_millis(20),
+        jitter: Duration::from_millis(10),
+    },
+];
+
+pub fn run() {
+    for model in &MODELS {
+        let started = Instant::now();
+        let committed = measure(model);
+        let wall = started.elapsed();
+        println!(
+            "throughput/{}: {:.0} commits/s ({} in {:?} logical, {:.2?} wall)",
+            model.name,
+            committed as f64 / RUN.as_secs_f64(),
+            committed,
+            RUN,
+            wall
+        );
+    }
+}
+
+/// Entries committed during `RUN` under `model`, on a five-node cluster.
+fn measure(model: &Model) -> usize {
+    let mut sim = Simulation::new(5, 1);
+    sim.network_latency = model.latency;
+    sim.network_jitter = model.jitter;
+    sim.run_for(Duration::from_secs(1));
+    let committed = |sim: &Simulation| {
+        sim.machines
+            .values()
+            .map(|m| m.state.commit_index)
+            .max()
+            .unwrap_or(0)
+    };
+    let before = committed(&sim);
+    let until = sim.elapsed() + RUN;
+    while sim.elapsed() < until {
+        if let Some(leader) = sim.leader() {
+            let s = &sim.machines[&leader].state;
+            for _ in s.last_log_index() - s.commit_index..WINDOW {
+                sim.propose(b"set bench value").unwrap();
+            }
+        }
+        sim.step();
+    }
+    committed(&sim) - before
+}
@@ -0,0 +1,939 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use super::driver::RaftError;
+use super::json::{Json, JsonError};
+use super::wire::decode_results;
+use super::{Message, Outbox};
+
+#[derive(Debug, Clone)]
+pub enum Request {
+    /// A command to commit through the log, answered with its result.
+    Write(Vec<u8>),
+    /// A command answered as soon as it commits, before it is applied.
+    WriteCommitted(Vec<u8>),
+    /// A query answered by the leader without going through the log.
+    Read(Vec<u8>),
+    /// Queries the leader answers together, from one state.
+    ReadTxn(Vec<Vec<u8>>),
+    /// A query every node answers from its own state, settled by the most
+    /// up-to-date answer among a majority.
+    QuorumRead(Vec<u8>),
+}
+
+impl Request {
+    fn to_json(&self) -> Json {
+        let (kind, bytes) = match self {
+            Request::Write(b) => ("write", b),
+            Request::WriteCommitted(b) => ("write_committed", b),
+            Request::Read(b) => ("read", b),
+            Request::QuorumRead(b) => ("quorum_read", b),
+            Request::ReadTxn(queries) => {
+                return Json::obj(vec![
+                    ("kind", "read_txn".into()),
+                    (
+                        "queries",
+                        Json::Arr(queries.iter().map(|q| q.as_slice().into()).collect()),
+                    ),
+                ]);
+            }
+        };
+        Json::obj(vec![
+            ("kind", kind.into()),
+            ("bytes", bytes.as_slice().into()),
+        ])
+    }
+
+    fn from_json(json: &Json) -> Result<Self, JsonError> {
+        if json.str("kind")? == "read_txn" {
+            let queries = json.arr("queries")?.iter().map(Json::as_bytes);
+            return Ok(Request::ReadTxn(queries.collect::<Result<_, _>>()?));
+        }
+        let bytes = json.bytes("bytes")?;
+        match json.str("kind")? {
+            "write" => Ok(Request::Write(bytes)),
+            "write_committed" => Ok(Request::WriteCommitted(bytes)),
+            "read" => Ok(Request::Read(bytes)),
+            "quorum_read" => Ok(Request::QuorumRead(bytes)),
+            other => Err(JsonError(format!("unknown request kind `{}`", other))),
+        }
+    }
+}
+
+/// A way for `Client::read` to get its answer, cheapest first.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReadPath {
+    /// A node other than the leader answers by itself, under the read
+    /// lease the leader granted it.
+    Lease,
+    /// The leader answers once a round of heartbeats confirms it still
+    /// leads.
+    ReadIndex,
+    /// Every node answers; the most up-to-date of a majority counts, so no
+    /// leader is needed.
+    Quorum,
+}
+
+impl ReadPath {
+    fn name(self) -> &'static str {
+        match self {
+            ReadPath::Lease => "lease",
+            ReadPath::ReadIndex => "read_index",
+            ReadPath::Quorum => "quorum",
+        }
+    }
+
+    fn from_name(name: &str) -> Result<Self, JsonError> {
+        match name {
+            "lease" => Ok(ReadPath::Lease),
+            "read_index" => Ok(ReadPath::ReadIndex),
+            "quorum" => Ok(ReadPath::Quorum),
+            other => Err(JsonError(format!("unknown read path `{}`", other))),
+        }
+    }
+}
+
+/// A request the client has submitted but not yet seen answered.
+struct InFlight {
+    request_id: u64,
+    request: Request,
+    attempts: u32,
+    /// When to (re)send; also acts as the reply timeout once sent.
+    retry_at: Instant,
+    /// When to give up on the request altogether, if the client has a
+    /// request timeout.
+    deadline: Option<Instant>,
+    /// A quorum read's answers so far, by node, as `(last_applied, result)`.
+    replies: BTreeMap<u32, (usize, Vec<u8>)>,
+    /// When a majority had answered a quorum read.
+    quorum_at: Option<Instant>,
+    /// For a read, the position in the client's read chain of the path it
+    /// is trying.
+    path: Option<usize>,
+    /// Times sent since the last answer.
+    unanswered: u32,
+}
+
+impl InFlight {
+    /// Has a read try path `path` of `chain`; a quorum read goes to every
+    /// node from then on.
+    fn take_path(&mut self, path: usize, chain: &[ReadPath]) {
+        self.path = Some(path);
+        self.unanswered = 0;
+        if chain[path] == ReadPath::Quorum {
+            if let Request::Read(query) = &self.request {
+                self.request = Request::QuorumRead(query.clone());
+            }
+        }
+    }
+
+    /// Moves a read on to the next path of `chain`, if there is one.
+    fn fall_back(&mut self, chain: &[ReadPath]) -> bool {
+        match self.path.filter(|&path| path + 1 < chain.len()) {
+            Some(path) => {
+                self.take_path(path + 1, chain);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// A read-only transaction being put together by `Client::read_txn`.
+pub struct ReadTxn<'a> {
+    client: &'a mut Client,
+    queries: Vec<Vec<u8>>,
+}
+
+impl ReadTxn<'_> {
+    /// Adds a read of `key`; returns where its value will be among the
+    /// transaction's results.
+    pub fn get(&mut self, key: &str) -> usize {
+        self.queries.push(format!("get {}", key).into_bytes());
+        self.queries.len() - 1
+    }
+
+    /// Queues the transaction's reads as one request, sent in order with
+    /// the client's other requests.
+    pub fn commit(self) {
+        self.client.queued.push_back(Request::ReadTxn(self.queries));
+    }
+}
+
+/// Submits commands one at a time, following leader hints and backing off
+/// exponentially while the cluster has no leader.
+pub struct Client {
+    pub id: u32,
+    cluster: Vec<u32>,
+    pub leader_hint: Option<u32>,
+    /// Node to try next when there is no hint, so retries rotate through the cluster.
+    next_target: usize,
+    next_request_id: u64,
+    queued: VecDeque<Request>,
+    in_flight: Option<InFlight>,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    backoff: Duration,
+    /// How long a request may go unanswered before the client gives up on it.
+    request_timeout: Option<Duration>,
+    /// How long a quorum read, once a majority has answered, waits for the
+    /// other nodes before settling on what it has.
+    straggler_wait: Duration,
+    /// `(request_id, result)` of every answered request, in answer order.
+    pub completed: Vec<(u64, Vec<u8>)>,
+    /// Requests given up on after `request_timeout`, in the order they timed
+    /// out. A write among them may still commit later.
+    pub timed_out: Vec<u64>,
+    /// `(request_id, max)` of writes the leader refused as longer than its
+    /// `max` entry size. They are given up on rather than retried.
+    pub too_large: Vec<(u64, usize)>,
+    /// Paths a read tries in turn, moving on when one is refused or goes
+    /// unanswered twice.
+    read_chain: Vec<ReadPath>,
+    /// `(request_id, path)` of every answered read, in answer order.
+    read_paths: Vec<(u64, ReadPath)>,
+}
+
+impl Client {
+    pub fn new(id: u32, cluster: Vec<u32>) -> Self {
+        Self::with_backoff(
+            id,
+            cluster,
+            Duration::from_millis(10),
+            Duration::from_millis(500),
+        )
+    }
+
+    pub fn with_backoff(
+        id: u32,
+        cluster: Vec<u32>,
+        initial_backoff: Duration,
+        max_backoff: Duration,
+    ) -> Self {
+        Client {
+            id,
+            cluster,
+            leader_hint: None,
+            next_target: 0,
+            next_request_id: 1,
+            queued: VecDeque::new(),
+            in_flight: None,
+            initial_backoff,
+            max_backoff,
+            backoff: initial_backoff,
+            request_timeout: None,
+            straggler_wait: Duration::ZERO,
+            completed: vec![],
+            timed_out: vec![],
+            too_large: vec![],
+            read_chain: vec![ReadPath::ReadIndex],
+            read_paths: vec![],
+        }
+    }
+
+    /// Gives up on each request not answered within `timeout` of being sent
+    /// first, e.g. a write the cluster cannot commit for lack of a quorum,
+    /// and moves on to the next one.
+    pub fn set_request_timeout(&mut self, timeout: Option<Duration>) {
+        self.request_timeout = timeout;
+    }
+
+    /// Lets quorum reads wait up to `wait` past a majority for slower nodes,
+    /// which may have applied more.
+    pub fn set_straggler_wait(&mut self, wait: Duration) {
+        self.straggler_wait = wait;
+    }
+
+    /// Has reads try each path of `chain` in turn, falling back to the next
+    /// when one is refused, e.g. by a node without a lease, or goes
+    /// unanswered twice, e.g. because there is no leader. Only ReadIndex by
+    /// default.
+    pub fn set_read_chain(&mut self, chain: Vec<ReadPath>) {
+        assert!(!chain.is_empty(), "a read needs a path to take");
+        assert!(
+            !chain[..chain.len() - 1].contains(&ReadPath::Quorum),
+            "a quorum read never needs a fallback"
+        );
+        self.read_chain = chain;
+    }
+
+    /// The path that answered read `request_id`, once it was answered.
+    pub fn read_path(&self, request_id: u64) -> Option<ReadPath> {
+        self.read_paths
+            .iter()
+            .find(|(id, _)| *id == request_id)
+            .map(|&(_, path)| path)
+    }
+
+    /// The outcome of request `request_id`, or `None` while it is queued or
+    /// in flight.
+    pub fn result(&self, request_id: u64) -> Option<Result<&[u8], RaftError>> {
+        if self.timed_out.contains(&request_id) {
+            return Some(Err(RaftError::Timeout));
+        }
+        if let Some(&(_, max)) = self.too_large.iter().find(|(id, _)| *id == request_id) {
+            return Some(Err(RaftError::EntryTooLarge { max }));
+        }
+        self.completed
+            .iter()
+            .find(|(id, _)| *id == request_id)
+            .map(|(_, result)| Ok(result.as_slice()))
+    }
+
+    /// Queues `command`; it is sent on the next tick once earlier ones commit.
+    pub fn submit(&mut self, command: &[u8]) {
+        self.queued.push_back(Request::Write(command.to_vec()));
+    }
+
+    /// Like `submit`, but the answer comes once `command` commits, with no
+    /// result, instead of waiting for it to be applied.
+    pub fn submit_committed(&mut self, command: &[u8]) {
+        self.queued
+            .push_back(Request::WriteCommitted(command.to_vec()));
+    }
+
+    /// Queues a linearizable read, sent in order with the writes and down
+    /// the read chain; see `set_read_chain`.
+    pub fn read(&mut self, query: &[u8]) {
+        self.queued.push_back(Request::Read(query.to_vec()));
+    }
+
+    /// Starts a read-only transaction: its reads are queued together, once
+    /// it is committed, and the leader answers them all at one ReadIndex
+    /// from the same state, so they see a single snapshot of the store.
+    pub fn read_txn(&mut self) -> ReadTxn<'_> {
+        ReadTxn {
+            client: self,
+            queries: vec![],
+        }
+    }
+
+    /// The results of read transaction `request_id`, in the order of its
+    /// reads, or `None` while it is queued or in flight.
+    pub fn txn_results(&self, request_id: u64) -> Option<Result<Vec<Vec<u8>>, RaftError>> {
+        Some(
+            self.result(request_id)?.map(|packed| {
+                decode_results(packed).expect("the leader packs transaction results")
+            }),
+        )
+    }
+
+    /// Queues a read that asks every node instead of the leader and
+    /// answers with the result of whichever node in a majority has applied
+    /// the most, sparing the leader. It sees every write applied on at least
+    /// one node of that majority, which a write only is once a follower
+    /// hears of its commit or the leader itself answers.
+    pub fn quorum_read(&mut self, query: &[u8]) {
+        self.queued.push_back(Request::QuorumRead(query.to_vec()));
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.in_flight.is_none() && self.queued.is_empty()
+    }
+
+    fn target(&mut self) -> u32 {
+        if let Some(leader) = self.leader_hint {
+            return leader;
+        }
+        let target = self.cluster[self.next_target % self.cluster.len()];
+        self.next_target += 1;
+        target
+    }
+
+    /// A node to ask for a read under its lease: any but the leader, which
+    /// holds no lease of its own, in turn.
+    fn lease_target(&mut self) -> u32 {
+        let others: Vec<u32> = self
+            .cluster
+            .iter()
+            .copied()
+            .filter(|&node| Some(node) != self.leader_hint)
+            .collect();
+        if others.is_empty() {
+            return self.target();
+        }
+        let target = others[self.next_target % others.len()];
+        self.next_target += 1;
+        target
+    }
+
+    pub fn tick(&mut self, at: Instant) -> Outbox {
+        if self.in_flight.is_none() {
+            let Some(request) = self.queued.pop_front() else {
+                return vec![];
+            };
+            let is_read = matches!(request, Request::Read(_));
+            let mut request = InFlight {
+                request_id: self.next_request_id,
+                request,
+                attempts: 0,
+                retry_at: at,
+                deadline: self.request_timeout.map(|timeout| at + timeout),
+                replies: BTreeMap::new(),
+                quorum_at: None,
+                path: None,
+                unanswered: 0,
+            };
+            if is_read {
+                request.take_path(0, &self.read_chain);
+            }
+            self.in_flight = Some(request);
+            self.next_request_id += 1;
+        }
+        if self
+            .in_flight
+            .as_ref()
+            .is_some_and(|r| r.deadline.is_some_and(|deadline| at >= deadline))
+        {
+            let request = self.in_flight.take().unwrap();
+            self.timed_out.push(request.request_id);
+            // Whoever we were talking to could not get it committed in time,
+            // so start the next request without trusting the old hint.
+            self.leader_hint = None;
+            self.backoff = self.initial_backoff;
+            return self.tick(at);
+        }
+        if self.in_flight.as_ref().is_some_and(|r| {
+            r.quorum_at
+                .is_some_and(|quorum_at| at >= quorum_at + self.straggler_wait)
+        }) {
+            self.settle_quorum_read();
+            return self.tick(at);
+        }
+        if self.in_flight.as_ref().is_some_and(|r| at < r.retry_at) {
+            return vec![];
+        }
+        let chain = &self.read_chain;
+        if let Some(request) = self.in_flight.as_mut().filter(|r| r.unanswered >= 2) {
+            request.fall_back(chain);
+        }
+        let timeout = self.backoff.max(self.initial_backoff * 4);
+        if let Some(InFlight {
+            request_id,
+            request: Request::QuorumRead(query),
+            attempts,
+            retry_at,
+            replies,
+            ..
+        }) = self.in_flight.as_mut()
+        {
+            *attempts += 1;
+            *retry_at = at + timeout;
+            return self
+                .cluster
+                .iter()
+                .filter(|node| !replies.contains_key(node))
+                .map(|&node| {
+                    let msg = Message::LocalRead {
+                        client_id: self.id,
+                        request_id: *request_id,
+                        query: query.clone(),
+                    };
+                    (node, msg)
+                })
+                .collect();
+        }
+        let path = self.in_flight.as_ref().unwrap().path;
+        let local_only = path.is_some_and(|path| self.read_chain[path] == ReadPath::Lease);
+        let to = if local_only {
+            self.lease_target()
+        } else {
+            self.target()
+        };
+        let request = self.in_flight.as_mut().unwrap();
+        request.attempts += 1;
+        request.unanswered += 1;
+        request.retry_at = at + timeout;
+        let msg = match &request.request {
+            Request::Write(command) => Message::ClientRequest {
+                client_id: self.id,
+                request_id: request.request_id,
+                command: command.clone(),
+                wait_for_apply: true,
+            },
+            Request::WriteCommitted(command) => Message::ClientRequest {
+                client_id: self.id,
+                request_id: request.request_id,
+                command: command.clone(),
+                wait_for_apply: false,
+            },
+            Request::Read(query) => Message::ClientRead {
+                client_id: self.id,
+                request_id: request.request_id,
+                query: query.clone(),
+                local_only,
+            },
+            Request::ReadTxn(queries) => Message::ClientReadTxn {
+                client_id: self.id,
+                request_id: request.request_id,
+                queries: queries.clone(),
+            },
+            Request::QuorumRead(_) => unreachable!("quorum reads go to every node"),
+        };
+        vec![(to, msg)]
+    }
+
+    pub fn to_json(&self, base: Instant) -> Json {
+        Json::obj(vec![
+            ("id", self.id.into()),
+            ("cluster", self.cluster.clone().into()),
+            ("leader_hint", self.leader_hint.into()),
+            ("next_target", self.next_target.into()),
+            ("next_request_id", self.next_request_id.into()),
+            (
+                "queued",
+                Json::Arr(self.queued.iter().map(Request::to_json).collect()),
+            ),
+            (
+                "in_flight",
+                self.in_flight.as_ref().map_or(Json::Null, |r| {
+                    Json::obj(vec![
+                        ("request_id", r.request_id.into()),
+                        ("request", r.request.to_json()),
+                        ("attempts", r.attempts.into()),
+                        ("retry_at", (r.retry_at - base).into()),
+                        (
+                            "deadline",
+                            r.deadline.map(|deadline| deadline - base).into(),
+                        ),
+                        (
+                            "replies",
+                            Json::Arr(
+                                r.replies
+                                    .iter()
+                                    .map(|(&node, (last_applied, result))| {
+                                        Json::obj(vec![
+                                            ("node", node.into()),
+                                            ("last_applied", (*last_applied).into()),
+                                            ("result", result.as_slice().into()),
+                                        ])
+                                    })
+                                    .collect(),
+                            ),
+                        ),
+                        (
+                            "quorum_at",
+                            r.quorum_at.map(|quorum_at| quorum_at - base).into(),
+                        ),
+                        ("path", r.path.into()),
+                        ("unanswered", r.unanswered.into()),
+                    ])
+                }),
+            ),
+            ("initial_backoff", self.initial_backoff.into()),
+            ("max_backoff", self.max_backoff.into()),
+            ("backoff", self.backoff.into()),
+            ("request_timeout", self.request_timeout.into()),
+            ("straggler_wait", self.straggler_wait.into()),
+            (
+                "completed",
+                Json::Arr(
+                    self.completed
+                        .iter()
+                        .map(|(id, result)| {
+                            Json::obj(vec![
+                                ("request_id", (*id).into()),
+                                ("result", result.as_slice().into()),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+            ("timed_out", self.timed_out.clone().into()),
+            (
+                "read_chain",
+                Json::Arr(self.read_chain.iter().map(|p| p.name().into()).collect()),
+            ),
+            (
+                "read_paths",
+                Json::Arr(
+                    self.read_paths
+                        .iter()
+                        .map(|(id, path)| {
+                            Json::obj(vec![
+                                ("request_id", (*id).into()),
+                                ("path", path.name().into()),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+            (
+                "too_large",
+                Json::Arr(
+                    self.too_large
+                        .iter()
+                        .map(|(id, max)| {
+                            Json::obj(vec![("request_id", (*id).into()), ("max", (*max).into())])
+                        })
+                        .collect(),
+                ),
+            ),
+        ])
+    }
+
+    pub fn from_json(json: &Json, base: Instant) -> Result<Self, JsonError> {
+        let in_flight = match json.field("in_flight")? {
+            Json::Null => None,
+            r => Some(InFlight {
+                request_id: r.u64("request_id")?,
+                request: Request::from_json(r.field("request")?)?,
+                attempts: r.u32("attempts")?,
+                retry_at: r.instant("retry_at", base)?,
+                deadline: match r.field("deadline")? {
+                    Json::Null => None,
+                    _ => Some(r.instant("deadline", base)?),
+                },
+                replies: r
+                    .arr("replies")?
+                    .iter()
+                    .map(|reply| {
+                        Ok((
+                            reply.u32("node")?,
+                            (reply.usize("last_applied")?, reply.bytes("result")?),
+                        ))
+                    })
+                    .collect::<Result<_, JsonError>>()?,
+                quorum_at: match r.field("quorum_at")? {
+                    Json::Null => None,
+                    _ => Some(r.instant("quorum_at", base)?),
+                },
+                path: match r.field("path")? {
+                    Json::Null => None,
+                    _ => Some(r.usize("path")?),
+                },
+                unanswered: r.u32("unanswered")?,
+            }),
+        };
+        Ok(Client {
+            id: json.u32("id")?,
+            cluster: json
+                .arr("cluster")?
+                .iter()
+                .map(|id| Ok(id.as_u64()? as u32))
+                .collect::<Result<_, JsonError>>()?,
+            leader_hint: json.opt_u32("leader_hint")?,
+            next_target: json.usize("next_target")?,
+            next_request_id: json.u64("next_request_id")?,
+            queued: json
+                .arr("queued")?
+                .iter()
+                .map(Request::from_json)
+                .collect::<Result<_, _>>()?,
+            in_flight,
+            initial_backoff: json.duration("initial_backoff")?,
+            max_backoff: json.duration("max_backoff")?,
+            backoff: json.duration("backoff")?,
+            request_timeout: match json.field("request_timeout")? {
+                Json::Null => None,
+                _ => Some(json.duration("request_timeout")?),
+            },
+            straggler_wait: json.duration("straggler_wait")?,
+            completed: json
+                .arr("completed")?
+                .iter()
+                .map(|c| Ok((c.u64("request_id")?, c.bytes("result")?)))
+                .collect::<Result<_, JsonError>>()?,
+            timed_out: json
+                .arr("timed_out")?
+                .iter()
+                .map(Json::as_u64)
+                .collect::<Result<_, _>>()?,
+            too_large: json
+                .arr("too_large")?
+                .iter()
+                .map(|t| Ok((t.u64("request_id")?, t.usize("max")?)))
+                .collect::<Result<_, JsonError>>()?,
+            read_chain: json
+                .arr("read_chain")?
+                .iter()
+                .map(|p| ReadPath::from_name(p.as_str()?))
+                .collect::<Result<_, _>>()?,
+            read_paths: json
+                .arr("read_paths")?
+                .iter()
+                .map(|r| Ok((r.u64("request_id")?, ReadPath::from_name(r.str("path")?)?)))
+                .collect::<Result<_, JsonError>>()?,
+        })
+    }
+
+    pub fn handle(&mut self, msg: Message, at: Instant) {
+        if let Message::LocalReadResponse {
+            from,
+            request_id,
+            last_applied,
+            result,
+        } = msg
+        {
+            self.handle_local_read(from, request_id, last_applied, result, at);
+            return;
+        }
+        let Message::ClientResponse {
+            request_id,
+            success,
+            leader_hint,
+            result,
+            busy,
+            reconfiguring,
+            too_large,
+            ..
+        } = msg
+        else {
+            return;
+        };
+        let Some(request) = self.in_flight.as_mut() else {
+            return;
+        };
+        if request.request_id != request_id {
+            return;
+        }
+        request.unanswered = 0;
+        if success {
+            if let Some(path) = request.path {
+                self.read_paths.push((request_id, self.read_chain[path]));
+            }
+            self.completed.push((request_id, result));
+            self.in_flight = None;
+            self.backoff = self.initial_backoff;
+            return;
+        }
+        if let Some(max) = too_large {
+            // Every leader would refuse it again; move on to the next one.
+            self.too_large.push((request_id, max));
+            self.in_flight = None;
+            self.backoff = self.initial_backoff;
+            return;
+        }
+        if busy || reconfiguring {
+            // Right node, just overloaded or mid-reconfiguration: retry it
+            // after backing off.
+            request.retry_at = at + self.backoff;
+            self.backoff = (self.backoff * 2).min(self.max_backoff);
+            return;
+        }
+        let leased = request
+            .path
+            .is_some_and(|path| self.read_chain[path] == ReadPath::Lease);
+        if leased {
+            // That node holds no lease; the leader it knows of may still
+            // serve the next path.
+            self.leader_hint = leader_hint.or(self.leader_hint);
+        }
+        match leader_hint {
+            // A fresh redirect is worth following right away.
+            Some(leader) if !leased && self.leader_hint != Some(leader) => {
+                self.leader_hint = Some(leader);
+                request.retry_at = at;
+            }
+            // No one to ask on this path; the next one may not need a leader.
+            _ if request.fall_back(&self.read_chain) => request.retry_at = at,
+            _ => {
+                if !leased {
+                    self.leader_hint = None;
+                }
+                request.retry_at = at + self.backoff;
+                self.backoff = (self.backoff * 2).min(self.max_backoff);
+            }
+        }
+    }
+
+    /// Records one node's answer to the quorum read in flight, settling it
+    /// once every node has answered.
+    fn handle_local_read(
+        &mut self,
+        from: u32,
+        request_id: u64,
+        last_applied: usize,
+        result: Vec<u8>,
+        at: Instant,
+    ) {
+        let Some(request) = self.in_flight.as_mut() else {
+            return;
+        };
+        if request.request_id != request_id || !matches!(request.request, Request::QuorumRead(_)) {
+            return;
+        }
+        request.replies.insert(from, (last_applied, result));
+        if request.quorum_at.is_none() && request.replies.len() > self.cluster.len() / 2 {
+            request.quorum_at = Some(at);
+        }
+        if request.replies.len() == self.cluster.len() {
+            self.settle_quorum_read();
+        }
+    }
+
+    /// Answers the quorum read in flight with the result of the node that
+    /// had applied the most.
+    fn settle_quorum_read(&mut self) {
+        let request = self.in_flight.take().unwrap();
+        if let Some(path) = request.path {
+            self.read_paths
+                .push((request.request_id, self.read_chain[path]));
+        }
+        let (_, result) = request
+            .replies
+            .into_values()
+            .max_by_key(|&(last_applied, _)| last_applied)
+            .unwrap();
+        self.completed.push((request.request_id, result));
+        self.backoff = self.initial_backoff;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{wire, Options, Simulation};
+    use super::*;
+
+    #[test]
+    fn client_retries_until_a_leader_is_elected() {
+        let mut sim = Simulation::new(5, 42);
+        // Submitted before any leader exists, so the client has to back off and retry.
+        let client = sim.add_client();
+        sim.clients
+            .get_mut(&client)
+            .unwrap()
+            .submit(b"set greeting hello");
+        sim.run_for(Duration::from_secs(2));
+        assert_eq!(sim.clients[&client].result(1), Some(Ok(&b"hello"[..])));
+    }
+
+    #[test]
+    fn client_write_times_out_without_majority() {
+        // A write sent while no majority can talk gives up at the client's
+        // deadline instead of hanging; once the partition heals a retry commits.
+        let mut sim = Simulation::new(5, 19);
+        sim.run_for(Duration::from_secs(1));
+        let client = sim.add_client();
+        let c = sim.clients.get_mut(&client).unwrap();
+        c.set_request_timeout(Some(Duration::from_millis(500)));
+        c.submit(b"set k v");
+        sim.partition(&[&[0], &[1], &[2], &[3], &[4]]);
+        sim.run_for(Duration::from_secs(1));
+        assert_eq!(
+            sim.clients[&client].result(1),
+            Some(Err(RaftError::Timeout))
+        );
+        assert!(sim.clients[&client].is_idle());
+        sim.heal();
+        sim.clients.get_mut(&client).unwrap().submit(b"set k v");
+        sim.run_for(Duration::from_secs(2));
+        assert_eq!(sim.clients[&client].result(2), Some(Ok(&b"v"[..])));
+    }
+
+    #[test]
+    fn quorum_read_takes_most_applied_answer() {
+        // A quorum read asks every node and takes the answer of whoever has
+        // applied most, so a stale node cannot hide the latest write, and a
+        // silent one only costs the straggler wait.
+        let mut sim = Simulation::new(3, 1);
+        let leader = sim.assert_converges(200);
+        let (stale, other) = ((leader + 1) % 3, (leader + 2) % 3);
+        let client = sim.add_client();
+        sim.clients.get_mut(&client).unwrap().submit(b"set x 1");
+        sim.run_for(Duration::from_millis(500));
+        sim.partition(&[&[leader, other], &[stale]]);
+        let c = sim.clients.get_mut(&client).unwrap();
+        c.submit(b"set x 2");
+        c.set_straggler_wait(Duration::from_millis(30));
+        sim.run_for(Duration::from_millis(500));
+        assert_eq!(sim.machines[&stale].state_machine.query(b"get x"), b"1");
+        let quorum_read = |sim: &mut Simulation| {
+            sim.clients.get_mut(&client).unwrap().quorum_read(b"get x");
+            let started = sim.elapsed();
+            while !sim.clients[&client].is_idle() {
+                sim.step();
+            }
+            let (_, result) = sim.clients[&client].completed.last().unwrap().clone();
+            (result, sim.elapsed() - started)
+        };
+        let (all, all_took) = quorum_read(&mut sim);
+        assert_eq!(all, b"2");
+        sim.pause(other);
+        let (majority, majority_took) = quorum_read(&mut sim);
+        assert_eq!(majority, b"2");
+        assert!(majority_took > all_took);
+    }
+
+    #[test]
+    fn read_txn_answers_from_one_state() {
+        // A read transaction takes one ReadIndex for all its reads and answers
+        // them from the same state: a write to both keys landing while it waits
+        // on that ReadIndex shows up in neither read, and a later transaction
+        // sees it in both.
+        let mut sim = Simulation::new(3, 21);
+        sim.assert_converges(200);
+        let leader = sim.leader().unwrap();
+        sim.propose(b"set a 1").unwrap();
+        sim.propose(b"set b 1").unwrap();
+        sim.run_for(Duration::from_millis(200));
+        let client = sim.add_client();
+        let mut txn = sim.clients.get_mut(&client).unwrap().read_txn();
+        let (a, b) = (txn.get("a"), txn.get("b"));
+        txn.commit();
+        while sim.machines[&leader].pending_reads.is_empty() {
+            sim.step();
+        }
+        sim.propose(b"set a 2").unwrap();
+        sim.propose(b"set b 2").unwrap();
+        sim.run_for(Duration::from_millis(500));
+        let c = sim.clients.get_mut(&client).unwrap();
+        let before = c.txn_results(1).unwrap().unwrap();
+        assert_eq!((&before[a][..], &before[b][..]), (&b"1"[..], &b"1"[..]));
+        let mut txn = c.read_txn();
+        txn.get("a");
+        txn.get("b");
+        txn.commit();
+        sim.run_for(Duration::from_millis(200));
+        let after = sim.clients[&client].txn_results(2).unwrap().unwrap();
+        assert_eq!(after, [b"2".to_vec(), b"2".to_vec()]);
+        let request = Message::ClientReadTxn {
+            client_id: client,
+            request_id: 2,
+            queries: vec![b"get a".to_vec(), b"get b".to_vec()],
+        };
+        let Message::ClientReadTxn { queries, .. } = wire::decode(&wire::encode(&request)).unwrap()
+        else {
+            panic!("read transaction decoded as another message");
+        };
+        assert_eq!(queries, [b"get a".to_vec(), b"get b".to_vec()]);
+    }
+
+    #[test]
+    fn read_chain_takes_cheapest_available_path() {
+        // A client reading down the chain lease, ReadIndex, quorum takes the
+        // cheapest path the cluster offers: a follower's lease while leaders
+        // grant them, the leader without, and every node once there is none.
+        for (follower_read_lease, leader_down, expected) in [
+            (Some(Duration::from_millis(200)), false, ReadPath::Lease),
+            (None, false, ReadPath::ReadIndex),
+            (None, true, ReadPath::Quorum),
+        ] {
+            let options = Options {
+                follower_read_lease,
+                ..Options::default()
+            };
+            let mut sim = Simulation::with_options(3, 7, options);
+            let leader = sim.assert_converges(200);
+            let client = sim.add_client();
+            let c = sim.clients.get_mut(&client).unwrap();
+            c.submit(b"set x 1");
+            sim.run_for(Duration::from_millis(500));
+            if leader_down {
+                sim.pause(leader);
+            }
+            let c = sim.clients.get_mut(&client).unwrap();
+            c.set_read_chain(vec![ReadPath::Lease, ReadPath::ReadIndex, ReadPath::Quorum]);
+            c.read(b"get x");
+            let deadline = sim.current_time + Duration::from_secs(2);
+            while !sim.clients[&client].is_idle() {
+                assert!(sim.current_time < deadline, "read never answered");
+                sim.step();
+            }
+            let c = &sim.clients[&client];
+            let (request_id, result) = c.completed.last().unwrap();
+            assert_eq!(result, b"1");
+            assert_eq!(c.read_path(*request_id), Some(expected));
+        }
+    }
+}
@@ -0,0 +1,312 @@
+//! Awaiting commands as futures, with the simulation as the only executor.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::future::Future;
+use std::pin::{pin, Pin};
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use super::{Message, Simulation};
+
+/// Sender of submitted commands, outside the ids of nodes and clients.
+pub const DRIVER_ID: u32 = u32::MAX;
+
+/// Why a submitted command produced no result.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RaftError {
+    /// No node was leading when the command was submitted, or the node it
+    /// went to knows of no leader.
+    NoLeader,
+    /// The leader refused the command because it is overloaded.
+    Busy,
+    /// The leader refused the command until its pending membership change
+    /// commits.
+    Reconfiguring,
+    /// The leader refused the command as longer than its `max_entry_size`
+    /// of `max` bytes. Retrying it cannot help.
+    EntryTooLarge { max: usize },
+    /// The node given the command was not leading, or stopped leading or
+    /// crashed before answering. The command may still commit under the
+    /// next leader.
+    LeadershipLost { leader_hint: Option<u32> },
+    /// The command went unanswered for longer than the client's request
+    /// timeout, e.g. because the leader it reached could not get a quorum.
+    /// It may still commit.
+    Timeout,
+}
+
+impl fmt::Display for RaftError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RaftError::NoLeader => write!(f, "no leader to submit to"),
+            RaftError::Busy => write!(f, "the leader is overloaded"),
+            RaftError::Reconfiguring => write!(f, "the cluster is changing membership"),
+            RaftError::EntryTooLarge { max } => {
+                write!(f, "the command exceeds the {}-byte entry size limit", max)
+            }
+            RaftError::Timeout => write!(f, "no answer before the request timeout"),
+            RaftError::LeadershipLost { leader_hint } => {
+                write!(
+                    f,
+                    "leadership lost before commit (leader hint {:?})",
+                    leader_hint
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for RaftError {}
+
+type CommitResult = Result<Vec<u8>, RaftError>;
+
+#[derive(Default)]
+struct Slot {
+    result: Option<CommitResult>,
+    waker: Option<Waker>,
+}
+
+/// Resolves once a submitted command is applied, with the state machine's
+/// result for it.
+pub struct Commit {
+    slot: Rc<RefCell<Slot>>,
+}
+
+impl Commit {
+    fn ready(result: CommitResult) -> Self {
+        Commit {
+            slot: Rc::new(RefCell::new(Slot {
+                result: Some(result),
+                waker: None,
+            })),
+        }
+    }
+}
+
+impl Future for Commit {
+    type Output = CommitResult;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<CommitResult> {
+        let mut slot = self.slot.borrow_mut();
+        match slot.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// The sending half of a `Commit`.
+pub struct Resolver {
+    slot: Rc<RefCell<Slot>>,
+}
+
+impl Resolver {
+    fn resolve(self, result: CommitResult) {
+        let mut slot = self.slot.borrow_mut();
+        slot.result = Some(result);
+        if let Some(waker) = slot.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+fn oneshot() -> (Resolver, Commit) {
+    let slot = Rc::new(RefCell::new(Slot::default()));
+    (Resolver { slot: slot.clone() }, Commit { slot })
+}
+
+/// A submitted command, with the leader and term it was given to.
+pub struct Submitted {
+    leader: u32,
+    term: usize,
+    resolver: Resolver,
+}
+
+impl Simulation {
+    /// Sends `command` to the current leader as a client request; the
+    /// returned future resolves when the leader answers it, or fails once
+    /// that leader is gone.
+    pub fn submit(&mut self, command: &[u8]) -> Commit {
+        let Some(leader) = self.leader() else {
+            return Commit::ready(Err(RaftError::NoLeader));
+        };
+        let request_id = self.next_submit_id;
+        self.next_submit_id += 1;
+        let (resolver, commit) = oneshot();
+        let term = self.machines[&leader].state.current_term;
+        self.submitted.insert(
+            request_id,
+            Submitted {
+                leader,
+                term,
+                resolver,
+            },
+        );
+        let request = Message::ClientRequest {
+            client_id: DRIVER_ID,
+            request_id,
+            command: command.to_vec(),
+            wait_for_apply: true,
+        };
+        self.send(DRIVER_ID, vec![(leader, request)]);
+        commit
+    }
+
+    /// Resolves the submitted command a leader's response is for.
+    pub fn resolve_submitted(&mut self, msg: Message) {
+        let Message::ClientResponse {
+            request_id,
+            success,
+            leader_hint,
+            result,
+            busy,
+            reconfiguring,
+            too_large,
+            ..
+        } = msg
+        else {
+            return;
+        };
+        let Some(submitted) = self.submitted.remove(&request_id) else {
+            return;
+        };
+        submitted.resolver.resolve(if success {
+            Ok(result)
+        } else if busy {
+            Err(RaftError::Busy)
+        } else if reconfiguring {
+            Err(RaftError::Reconfiguring)
+        } else if let Some(max) = too_large {
+            Err(RaftError::EntryTooLarge { max })
+        } else if leader_hint.is_none() {
+            Err(RaftError::NoLeader)
+        } else {
+            Err(RaftError::LeadershipLost { leader_hint })
+        });
+    }
+
+    /// Fails the submitted commands whose leader crashed or no longer leads
+    /// the term it took them in, unless its answer is already on the way.
+    pub fn fail_deposed_submits(&mut self) {
+        if self.submitted.is_empty() {
+            return;
+        }
+        let answered: Vec<u64> = self
+            .queue
+            .iter()
+            .filter_map(|m| match m.message {
+                Message::ClientResponse { request_id, .. } if m.to == DRIVER_ID => Some(request_id),
+                _ => None,
+            })
+            .collect();
+        let deposed: Vec<u64> = self
+            .submitted
+            .iter()
+            .filter(|(id, s)| {
+                let leading = self.machines.get(&s.leader).is_some_and(|m| {
+                    m.state.current_term == s.term && m.role.leader_hint(&m.state) == Some(s.leader)
+                });
+                !leading && !answered.contains(id)
+            })
+            .map(|(&id, _)| id)
+            .collect();
+        for id in deposed {
+            let submitted = self.submitted.remove(&id).unwrap();
+            submitted.resolver.resolve(Err(match self.leader() {
+                None => RaftError::NoLeader,
+                leader_hint => RaftError::LeadershipLost { leader_hint },
+            }));
+        }
+    }
+
+    /// Steps the simulation until `future` completes, for at most `max` of
+    /// simulated time; `None` if it is still pending by then.
+    pub fn block_on<F: Future>(&mut self, max: Duration, future: F) -> Option<F::Output> {
+        let mut future = pin!(future);
+        let mut cx = Context::from_waker(Waker::noop());
+        let until = self.current_time + max;
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return Some(output);
+            }
+            if self.current_time >= until {
+                return None;
+            }
+            self.step();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn awaited_command_gives_result_or_error() {
+        // Awaiting a command gives the state machine's result, or an error once
+        // the leader it went to is deposed and its entry replaced.
+        let mut sim = Simulation::new(3, 8);
+        sim.run_for(Duration::from_secs(1));
+        let set = sim.submit(b"set answer 42");
+        let get = sim.submit(b"get answer");
+        let max = Duration::from_secs(1);
+        assert_eq!(sim.block_on(max, set), Some(Ok(b"42".to_vec())));
+        assert_eq!(sim.block_on(max, get), Some(Ok(b"42".to_vec())));
+        let leader = sim.leader().unwrap();
+        let rest: Vec<u32> = (0..3).filter(|&id| id != leader).collect();
+        sim.partition(&[&[leader], &rest]);
+        let lost = sim.submit(b"set answer 43");
+        sim.run_for(Duration::from_secs(1));
+        sim.heal();
+        assert!(matches!(
+            sim.block_on(max, lost),
+            Some(Err(RaftError::LeadershipLost { leader_hint: Some(hint) })) if hint != leader
+        ));
+    }
+
+    #[test]
+    fn command_refused_by_leaderless_node_fails_with_no_leader() {
+        // A command that reaches a node knowing of no leader fails with
+        // NoLeader, whether that node answers it or the driver notices first
+        // that the leader it went to has stepped down.
+        let mut sim = Simulation::new(3, 8);
+        sim.run_for(Duration::from_secs(1));
+        let leader = sim.leader().unwrap();
+        let set = sim.submit(b"set answer 42");
+        let term = sim.machines[&leader].state.current_term;
+        sim.step_down(leader, term);
+        assert_eq!(
+            sim.block_on(Duration::from_secs(1), set),
+            Some(Err(RaftError::NoLeader))
+        );
+
+        let (resolver, commit) = oneshot();
+        sim.submitted.insert(
+            u64::MAX,
+            Submitted {
+                leader,
+                term,
+                resolver,
+            },
+        );
+        sim.resolve_submitted(Message::ClientResponse {
+            request_id: u64::MAX,
+            success: false,
+            leader_hint: None,
+            result: vec![],
+            busy: false,
+            reconfiguring: false,
+            too_large: None,
+            staleness: None,
+        });
+        assert_eq!(
+            sim.block_on(Duration::ZERO, commit),
+            Some(Err(RaftError::NoLeader))
+        );
+    }
+}
@@ -0,0 +1,79 @@
+//! How long a fresh cluster takes to elect a leader, over many seeds: the
+//! spread matters more than any one run when tuning election timeouts.
+
+use std::time::Duration;
+
+use super::Simulation;
+
+/// Time from start until the cluster settled under one leader, over a
+/// number of runs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ElectionTimingReport {
+    pub runs: usize,
+    pub min: Duration,
+    pub median: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+    /// Runs that had not settled after `MAX_WAIT`; not in the figures above.
+    pub unsettled: usize,
+}
+
+/// How long one run may take to settle, in election timeouts.
+const MAX_WAIT: u32 = 100;
+
+impl Simulation {
+    /// Starts `runs` clusters like this one, with seeds `0..runs`, and times
+    /// how long each takes to become quiescent under its first leader. Only
+    /// the node count, node 0's options and the network model are copied.
+    pub fn measure_elections(&self, runs: usize) -> ElectionTimingReport {
+        let options = self.machines.values().next().unwrap().state.options.clone();
+        let max_wait = options.base_election_timeout() * MAX_WAIT;
+        let mut times = vec![];
+        for seed in 0..runs as u64 {
+            let mut sim =
+                Simulation::with_options(self.machines.len() as u32, seed, options.clone());
+            sim.tick_interval = self.tick_interval;
+            sim.network_latency = self.network_latency;
+            sim.network_jitter = self.network_jitter;
+            sim.loss_rate = self.loss_rate;
+            if sim.run_until_quiescent(max_wait) {
+                times.push(sim.elapsed());
+            }
+        }
+        times.sort_unstable();
+        let at = |q: f64| {
+            times
+                .get(((times.len() as f64 * q).ceil() as usize).saturating_sub(1))
+                .copied()
+                .unwrap_or_default()
+        };
+        ElectionTimingReport {
+            runs,
+            min: times.first().copied().unwrap_or_default(),
+            median: at(0.5),
+            p99: at(0.99),
+            max: times.last().copied().unwrap_or_default(),
+            unsettled: runs - times.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Options;
+    use super::*;
+
+    #[test]
+    fn elections_settle_within_few_timeouts() {
+        // Over many seeds, elections settle within a few election timeouts.
+        let report = Simulation::new(5, 0).measure_elections(200);
+        let timeout = Options::default().election_timeout;
+        assert_eq!(report.unsettled, 0, "some elections never settled");
+        assert!(
+            report.min >= timeout,
+            "elected before any timeout: {:?}",
+            report
+        );
+        assert!(report.median < timeout * 4, "slow elections: {:?}", report);
+    }
+}
@@ -0,0 +1,80 @@
+//! How long writes take to commit, over every entry the cluster's leaders
+//! saw commit: under load the tail matters more than the typical write.
+
+use std::time::Duration;
+
+use super::Simulation;
+
+/// Time from a leader appending an entry until it saw it committed, over
+/// every such entry so far.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatencyReport {
+    pub samples: usize,
+    pub p50: Duration,
+    pub p99: Duration,
+    pub p999: Duration,
+    pub max: Duration,
+}
+
+impl Simulation {
+    /// Commit latency percentiles over what every running node measured
+    /// while it led; a crashed node's measurements are lost with it.
+    pub fn latency_report(&self) -> LatencyReport {
+        let mut latencies: Vec<Duration> = self
+            .machines
+            .values()
+            .flat_map(|m| m.commit_latencies().iter().copied())
+            .collect();
+        latencies.sort_unstable();
+        let at = |q: f64| {
+            latencies
+                .get(((latencies.len() as f64 * q).ceil() as usize).saturating_sub(1))
+                .copied()
+                .unwrap_or_default()
+        };
+        LatencyReport {
+            samples: latencies.len(),
+            p50: at(0.5),
+            p99: at(0.99),
+            p999: at(0.999),
+            max: latencies.last().copied().unwrap_or_default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commit_latency_tail_grows_with_jitter() {
+        // Under a steady write load on a healthy cluster, almost every write
+        // commits within a round trip plus a step or two; a network whose delays
+        // swing by up to 60ms stretches the tail well past that bound.
+        let bound = Duration::from_millis(70);
+        let mut reports = vec![];
+        for jitter in [5, 60] {
+            let mut sim = Simulation::new(5, 23);
+            sim.network_jitter = Duration::from_millis(jitter);
+            sim.assert_converges(500);
+            let until = sim.current_time + Duration::from_secs(2);
+            let mut i = 0;
+            while sim.current_time < until {
+                sim.propose(format!("set key{} value{}", i % 10, i).as_bytes())
+                    .unwrap();
+                i += 1;
+                sim.step();
+            }
+            let report = sim.latency_report();
+            assert!(report.samples > 150, "too few commits: {:?}", report);
+            assert!(report.p50 <= report.p99 && report.p99 <= report.p999);
+            reports.push(report);
+        }
+        assert!(reports[0].p99 <= bound, "slow commits: {:?}", reports[0]);
+        assert!(
+            reports[1].p99 > bound,
+            "jitter did not show: {:?}",
+            reports[1]
+        );
+    }
+}
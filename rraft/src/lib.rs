@@ -0,0 +1,5990 @@
+//! Raft nodes on a simulated network, with the tools to drive a cluster and
+//! check it. The `raft` binary runs a short scenario against it.
+
+pub mod bench;
+pub mod client;
+pub mod compress;
+pub mod driver;
+pub mod elections;
+pub mod json;
+pub mod latency;
+pub mod membership;
+pub mod metrics;
+pub mod multiraft;
+pub mod scenario;
+pub mod shutdown;
+pub mod snapshot;
+pub mod storage;
+#[cfg(test)]
+mod tests;
+pub mod typed;
+pub mod wire;
+
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use client::Client;
+use driver::{RaftError, Submitted, DRIVER_ID};
+use json::Json;
+use membership::{validate_config_change, Config, ConfigChange, ConfigError};
+use scenario::Scenario;
+use storage::{FileStorage, MemStorage, SlowStorage, Storage, WalRecord};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct LogEntry {
+    pub index: usize,
+    pub term: usize,
+    pub command: Vec<u8>,
+    /// Set on membership entries, which carry no command.
+    pub config: Option<Config>,
+    /// Client and request id of a write proposed with `dedup_writes`;
+    /// applying it records the result in the node's session table.
+    pub session: Option<(u32, u64)>,
+}
+
+/// Request id and result of the latest write applied for each client.
+pub type Sessions = BTreeMap<u32, (u64, Vec<u8>)>;
+
+/// Lays out a log from the term of each entry, for setting up edge cases:
+/// `LogBuilder::new().terms(&[1, 1, 2]).build()` is three entries at
+/// indexes 1 to 3. Each command names its index and term, so entries that
+/// differ only in term still differ.
+#[derive(Debug, Default)]
+pub struct LogBuilder {
+    pub entries: Vec<LogEntry>,
+}
+
+impl LogBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends one entry per term in `terms`, at the next indexes.
+    pub fn terms(mut self, terms: &[usize]) -> Self {
+        for &term in terms {
+            let index = self.entries.len() + 1;
+            self.entries.push(LogEntry {
+                index,
+                term,
+                command: format!("set e{} t{}", index, term).into_bytes(),
+                config: None,
+                session: None,
+            });
+        }
+        self
+    }
+
+    pub fn build(self) -> Vec<LogEntry> {
+        self.entries
+    }
+}
+
+/// What replaces the compacted prefix of the log: the state machine as of
+/// `applied`, and enough about entry `last_index` to check the entries after it.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct LogSnapshot {
+    pub last_index: usize,
+    pub last_term: usize,
+    /// Configuration in effect at `last_index`.
+    pub config: Config,
+    /// `StateMachine::snapshot` output after applying `applied`; the log may
+    /// still hold entries between `last_index` and `applied`.
+    pub applied: usize,
+    pub data: Vec<u8>,
+    /// The session table as of `applied`, which goes with `data`.
+    pub sessions: Sessions,
+}
+
+/// A log entry annotated with how far it has got on the node it is read from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LogView<'a> {
+    pub entry: &'a LogEntry,
+    pub committed: bool,
+    pub applied: bool,
+}
+
+/// What a node can say about one index of its log.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EntryLookup<'a> {
+    Present(&'a LogEntry),
+    /// Folded into the snapshot; only its effect on the state machine is left.
+    Compacted,
+    /// Past the end of the log, as far as this node knows.
+    NotYetExists,
+}
+
+/// Deterministic application of committed commands.
+pub trait StateMachine {
+    fn apply(&mut self, command: &[u8]) -> Vec<u8>;
+
+    /// Answers a read without changing anything.
+    fn query(&self, query: &[u8]) -> Vec<u8>;
+
+    /// Serializes the whole state, for compaction and for catching up a
+    /// follower without replaying the log.
+    fn snapshot(&self) -> Vec<u8>;
+
+    /// Replaces the whole state with one produced by `snapshot`.
+    fn restore(&mut self, data: &[u8]);
+
+    /// Most entries this state machine keeps up with per tick; the rest of
+    /// what has committed waits for later ticks. Unlimited by default.
+    fn apply_budget(&self) -> Option<usize> {
+        None
+    }
+
+    /// A new instance in the state before any command, to replay the log
+    /// against; `None` by default, for state machines that cannot make one.
+    fn fresh(&self) -> Option<Box<dyn StateMachine>> {
+        None
+    }
+}
+
+/// Applies with `inner` but keeps up with only `per_tick` entries a tick,
+/// like a state machine backed by a slow database.
+pub struct Throttled<S> {
+    pub inner: S,
+    pub per_tick: usize,
+}
+
+impl<S: StateMachine> StateMachine for Throttled<S> {
+    fn apply(&mut self, command: &[u8]) -> Vec<u8> {
+        self.inner.apply(command)
+    }
+
+    fn query(&self, query: &[u8]) -> Vec<u8> {
+        self.inner.query(query)
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        self.inner.snapshot()
+    }
+
+    fn restore(&mut self, data: &[u8]) {
+        self.inner.restore(data)
+    }
+
+    fn apply_budget(&self) -> Option<usize> {
+        Some(self.per_tick)
+    }
+
+    fn fresh(&self) -> Option<Box<dyn StateMachine>> {
+        self.inner.fresh()
+    }
+}
+
+/// Applies with `inner` and counts every command applied, in a counter
+/// that outlives the machine.
+pub struct Counted<S> {
+    pub inner: S,
+    pub applies: Rc<Cell<usize>>,
+}
+
+impl<S: StateMachine> StateMachine for Counted<S> {
+    fn apply(&mut self, command: &[u8]) -> Vec<u8> {
+        self.applies.set(self.applies.get() + 1);
+        self.inner.apply(command)
+    }
+
+    fn query(&self, query: &[u8]) -> Vec<u8> {
+        self.inner.query(query)
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        self.inner.snapshot()
+    }
+
+    fn restore(&mut self, data: &[u8]) {
+        self.inner.restore(data)
+    }
+
+    fn fresh(&self) -> Option<Box<dyn StateMachine>> {
+        self.inner.fresh()
+    }
+}
+
+/// A wait on the simulation ran out of time; `lagging` are the nodes it was
+/// still waiting for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeoutError {
+    pub waited: Duration,
+    pub lagging: Vec<u32>,
+}
+
+impl fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "nodes {:?} still behind after {:?}",
+            self.lagging, self.waited
+        )
+    }
+}
+
+impl std::error::Error for TimeoutError {}
+
+/// A committed entry the state machine panicked on. Applying halts there:
+/// skipping it would leave this replica silently diverged from the others.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApplyError {
+    pub index: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ApplyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "applying entry {} panicked: {}",
+            self.index, self.message
+        )
+    }
+}
+
+impl std::error::Error for ApplyError {}
+
+/// A tiny key-value store driven by textual commands:
+/// `set <key> <value>`, `get <key>` and `del <key>`.
+#[derive(Debug, Default)]
+pub struct KvStateMachine {
+    pub data: BTreeMap<String, String>,
+}
+
+impl StateMachine for KvStateMachine {
+    fn apply(&mut self, command: &[u8]) -> Vec<u8> {
+        let command = String::from_utf8_lossy(command);
+        let mut parts = command.splitn(3, ' ');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some("set"), Some(key), Some(value)) => {
+                self.data.insert(key.to_string(), value.to_string());
+                value.as_bytes().to_vec()
+            }
+            (Some("get"), Some(key), None) => self
+                .data
+                .get(key)
+                .map(|v| v.as_bytes().to_vec())
+                .unwrap_or_default(),
+            (Some("del"), Some(key), None) => self
+                .data
+                .remove(key)
+                .map(|v| v.into_bytes())
+                .unwrap_or_default(),
+            _ => vec![],
+        }
+    }
+
+    fn query(&self, query: &[u8]) -> Vec<u8> {
+        let query = String::from_utf8_lossy(query);
+        match query.split_once(' ') {
+            Some(("get", key)) => self
+                .data
+                .get(key)
+                .map(|v| v.as_bytes().to_vec())
+                .unwrap_or_default(),
+            _ => vec![],
+        }
+    }
+
+    /// The data as a JSON object of string values.
+    fn snapshot(&self) -> Vec<u8> {
+        let fields = self
+            .data
+            .iter()
+            .map(|(k, v)| (k.clone(), Json::Str(v.clone())))
+            .collect();
+        Json::Obj(fields).to_string().into_bytes()
+    }
+
+    fn restore(&mut self, data: &[u8]) {
+        let json = Json::parse(&String::from_utf8_lossy(data)).expect("malformed kv snapshot");
+        let Json::Obj(fields) = json else {
+            panic!("kv snapshot is not an object");
+        };
+        self.data = fields
+            .into_iter()
+            .map(|(k, v)| Ok((k, v.as_str()?.to_string())))
+            .collect::<Result<_, json::JsonError>>()
+            .expect("kv snapshot value is not a string");
+    }
+
+    fn fresh(&self) -> Option<Box<dyn StateMachine>> {
+        Some(Box::new(KvStateMachine::default()))
+    }
+}
+
+/// Tunables shared by every role of a node.
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub heartbeat_interval: Duration,
+    /// Once every peer holds the whole log and it is all committed, the
+    /// leader only sends heartbeats this often, until there is something new
+    /// to replicate. Must stay below the election timeout, or followers will
+    /// start elections between keepalives.
+    pub idle_heartbeat_interval: Option<Duration>,
+    /// Base election timeout; the actual timeout is drawn from `[base, 2 * base)`.
+    pub election_timeout: Duration,
+    /// The base election timeout never goes below this, whatever
+    /// `election_timeout` says, so a mistuned or computed timeout cannot
+    /// make nodes campaign against each other nonstop. Must be above
+    /// `heartbeat_interval`.
+    pub min_election_timeout: Option<Duration>,
+    pub timeout_distribution: TimeoutDistribution,
+    /// Tell followers about a new commit index right away instead of
+    /// waiting for the next heartbeat.
+    pub commit_notify: bool,
+    /// Group commit: buffer records until the next tick and write them all at
+    /// once, instead of one write per record as they are produced.
+    pub batch_writes: bool,
+    /// When term and vote changes are written; see `VotePersistence`.
+    pub vote_persistence: VotePersistence,
+    /// Also write the commit index to storage as it advances, so a restarted
+    /// node applies everything it knew was committed before hearing from a
+    /// leader again, instead of only what it had already applied.
+    pub persist_commit_index: bool,
+    /// Only count followers that answered within the last election timeout
+    /// towards a commit, not just any that once stored the entry.
+    pub require_fresh_acks: bool,
+    /// Break split votes by id: a candidate that hears from a lower-id
+    /// candidate in its own term withdraws and votes for it instead.
+    pub concede_to_lower_id: bool,
+    /// A candidate asked for its vote by a candidate of the same term with a
+    /// strictly more up-to-date log withdraws and votes for it: its own log
+    /// could never win that candidate's vote anyway.
+    pub concede_to_better_log: bool,
+    /// A leader deposed by a candidate with a shorter log, while it still
+    /// held a lease, campaigns again at once instead of waiting out a
+    /// failed election.
+    pub leader_affinity: bool,
+    /// Ignore vote requests while a leader is still heard from, without
+    /// adopting their term, so a node rejoining after a partition cannot
+    /// depose it; only elections a leader asked for with TimeoutNow get
+    /// through.
+    pub leader_stickiness: bool,
+    /// Drop messages whose term is more than this far ahead of ours, so one
+    /// faulty node cannot push the whole cluster to an absurd term.
+    pub max_term_jump: Option<usize>,
+    /// Nodes that follow the log to serve reads but stay outside the
+    /// configuration: unlike learners they are never counted in a quorum,
+    /// never asked for a vote and never promoted.
+    pub observers: Vec<u32>,
+    /// Append proposals to the log at once but replicate them only with the
+    /// next heartbeat, in one AppendEntries per follower for everything
+    /// proposed in between instead of one per proposal.
+    pub batch_appends: bool,
+    /// A new leader appends an empty entry of its term right away and,
+    /// once that is applied, compacts its log up to it, bounding how much a
+    /// node recovering later has to replay.
+    pub snapshot_on_election: bool,
+    /// Refuse client writes as busy while the leader has this many entries
+    /// not yet committed.
+    pub max_uncommitted: Option<usize>,
+    /// Refuse client writes whose command is longer than this many bytes,
+    /// before they reach the log.
+    pub max_entry_size: Option<usize>,
+    /// Refuse client writes while a configuration change is uncommitted,
+    /// trading write availability during reconfiguration for simplicity.
+    pub reject_writes_during_reconfig: bool,
+    /// Only tell a client its write succeeded once this many nodes, the
+    /// leader included, store it; commit still needs just a majority.
+    pub min_ack_replicas: Option<usize>,
+    /// Remember the answer to each client's latest write and give it again
+    /// when the client retries, instead of committing the command twice.
+    /// Every node learns the answers as it applies the writes, so a retry
+    /// sent to a new leader is answered too.
+    pub dedup_writes: bool,
+    /// Let followers answer reads themselves if they were caught up with the
+    /// leader's commit index at most this long ago.
+    pub max_staleness: Option<Duration>,
+    /// Have the leader grant followers a read lease this long with every
+    /// AppendEntries; see `Message::AppendEntryRequest::read_lease`.
+    pub follower_read_lease: Option<Duration>,
+    /// A follower whose last AppendEntries was refused, or left it behind
+    /// the leader's commit index, waits `[2 * base, 3 * base)` before
+    /// campaigning, so followers holding the leader's log time out first:
+    /// when the leader fails, one of them usually wins in the next term
+    /// instead of a lagging follower wasting that term on an election it
+    /// cannot win.
+    pub fast_election: bool,
+    /// While a leader is known, a follower waits this many extra heartbeat
+    /// intervals past its election deadline, absorbing delayed heartbeats.
+    pub election_grace: u32,
+    /// Raise `Alert::StuckElecting` once this many elections in a row have
+    /// failed to produce a leader this node heard from.
+    pub max_election_rounds: Option<u32>,
+    /// A leader that has had committed entries waiting this long without
+    /// applying any hands leadership to a caught-up peer, as when drained:
+    /// its state machine looks stuck, and its clients would wait forever.
+    pub max_apply_stall: Option<Duration>,
+    /// A candidate asks voters that have not answered again after this long,
+    /// in the same term, instead of waiting for the election to time out.
+    pub vote_retry_interval: Option<Duration>,
+    /// A candidate asks at most this many voters for their vote per tick,
+    /// in waves, instead of all of them at once; it wins as soon as enough
+    /// have granted, whether or not the rest were asked.
+    pub vote_fan_out: Option<usize>,
+    /// Before campaigning, ask whether peers would vote without bumping the
+    /// term, so a node that cannot win never disrupts a working leader.
+    pub pre_vote_enabled: bool,
+    /// Vote weight, counting the node's own, that pre-votes must reach
+    /// before a real election starts, in place of a majority. Set above a
+    /// majority it takes more agreement to disrupt a leader; set above the
+    /// total weight, no node ever campaigns.
+    pub pre_vote_quorum: Option<usize>,
+    /// On starting up, each voter waits this much longer for every voter
+    /// with a lower id before its first election timeout, so a cluster
+    /// booted all at once does not split its first vote.
+    pub startup_stagger: Option<Duration>,
+    /// Nodes added to the configuration join as learners and only become
+    /// voters once they hold everything committed, so an empty log can
+    /// neither win an election nor slow down commits.
+    pub learner_catch_up: bool,
+    /// With `learner_catch_up`, give a learner this many rounds to catch up
+    /// and remove it from the configuration if it has not by then. A round
+    /// ends once the learner holds the log as it was when the round began,
+    /// or after an election timeout; the learner is caught up if it got
+    /// there in time.
+    pub max_catch_up_rounds: Option<u32>,
+    /// Call `on_commit` observers once with every entry applied together,
+    /// instead of once per entry.
+    pub batch_commit_notifications: bool,
+    /// Largest piece of snapshot data one InstallSnapshot message carries.
+    pub snapshot_chunk_size: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            heartbeat_interval: Duration::from_millis(50),
+            idle_heartbeat_interval: None,
+            election_timeout: Duration::from_millis(150),
+            min_election_timeout: None,
+            timeout_distribution: TimeoutDistribution::Uniform,
+            commit_notify: false,
+            batch_writes: false,
+            vote_persistence: VotePersistence::Immediate,
+            persist_commit_index: false,
+            require_fresh_acks: false,
+            concede_to_lower_id: false,
+            concede_to_better_log: false,
+            leader_affinity: false,
+            leader_stickiness: false,
+            max_term_jump: None,
+            observers: vec![],
+            batch_appends: false,
+            snapshot_on_election: false,
+            max_uncommitted: None,
+            max_entry_size: None,
+            reject_writes_during_reconfig: false,
+            min_ack_replicas: None,
+            dedup_writes: false,
+            max_staleness: None,
+            follower_read_lease: None,
+            fast_election: false,
+            election_grace: 0,
+            max_election_rounds: None,
+            max_apply_stall: None,
+            vote_retry_interval: None,
+            vote_fan_out: None,
+            pre_vote_enabled: false,
+            pre_vote_quorum: None,
+            startup_stagger: None,
+            learner_catch_up: false,
+            max_catch_up_rounds: None,
+            batch_commit_notifications: false,
+            snapshot_chunk_size: 64 * 1024,
+        }
+    }
+}
+
+impl Options {
+    /// `election_timeout`, raised to `min_election_timeout` if below it.
+    pub fn base_election_timeout(&self) -> Duration {
+        self.election_timeout
+            .max(self.min_election_timeout.unwrap_or_default())
+    }
+
+    /// Rejects settings that cannot work together.
+    pub fn validate(&self) -> Result<(), OptionsError> {
+        match self.min_election_timeout {
+            Some(floor) if floor <= self.heartbeat_interval => {
+                Err(OptionsError::TimeoutFloorTooLow {
+                    floor,
+                    heartbeat_interval: self.heartbeat_interval,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// An election timeout for `n_nodes` nodes a round trip of `rtt` apart.
+    /// A candidate needs about one round trip to collect its votes, and the
+    /// chance that another node times out within that window grows with the
+    /// number of nodes, so the base timeout grows with both.
+    pub fn suggested_election_timeout(n_nodes: u32, rtt: Duration) -> Duration {
+        rtt * n_nodes.max(3)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptionsError {
+    /// Followers would time out between heartbeats of a healthy leader.
+    TimeoutFloorTooLow {
+        floor: Duration,
+        heartbeat_interval: Duration,
+    },
+}
+
+impl fmt::Display for OptionsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OptionsError::TimeoutFloorTooLow {
+                floor,
+                heartbeat_interval,
+            } => write!(
+                f,
+                "election timeout floor {:?} is not above the heartbeat interval {:?}",
+                floor, heartbeat_interval
+            ),
+        }
+    }
+}
+
+impl std::error::Error for OptionsError {}
+
+/// How election timeouts are spread over `[base, 2 * base)`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeoutDistribution {
+    Uniform,
+    /// Normal around `1.5 * base` with standard deviation `base / 4`,
+    /// redrawn until it lands in range.
+    Normal,
+}
+
+/// When a node writes a changed term or vote to storage.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VotePersistence {
+    /// On its own, as soon as it changes.
+    Immediate,
+    /// With the next group commit, sharing its fsync. Whatever the node
+    /// would send meanwhile, a vote included, waits until that write is
+    /// durable, so it never grants a vote it could forget in a crash.
+    Batched,
+}
+
+/// 64-bit FNV-1a, for digests that must not change with the Rust release
+/// the way `DefaultHasher` may.
+#[derive(Debug, Clone)]
+pub struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// SplitMix64, seeded per node so simulations are reproducible.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    pub state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[0, n)`; `n` must be non-zero.
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+
+    /// Uniform in `[lo, hi)`, with nanosecond resolution.
+    pub fn duration_between(&mut self, lo: Duration, hi: Duration) -> Duration {
+        let span = hi.saturating_sub(lo).as_nanos() as u64;
+        if span == 0 {
+            return lo;
+        }
+        lo + Duration::from_nanos(self.below(span))
+    }
+
+    /// Uniform in `[0, 1)`.
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Standard normal, by Box-Muller.
+    pub fn standard_normal(&mut self) -> f64 {
+        let u1 = 1.0 - self.next_f64();
+        let u2 = self.next_f64();
+        (-2.0 * u1.ln()).sqrt() * (std::f64::consts::TAU * u2).cos()
+    }
+
+    /// Normal with the given mean and deviation, clamped to be non-negative.
+    pub fn normal_duration(&mut self, mean: Duration, std_dev: Duration) -> Duration {
+        let x = mean.as_secs_f64() + std_dev.as_secs_f64() * self.standard_normal();
+        Duration::from_secs_f64(x.max(0.0))
+    }
+
+    /// Normal with the given mean and deviation, truncated to `[lo, hi)`.
+    pub fn normal_duration_between(
+        &mut self,
+        lo: Duration,
+        hi: Duration,
+        mean: Duration,
+        std_dev: Duration,
+    ) -> Duration {
+        if hi <= lo {
+            return lo;
+        }
+        loop {
+            let x = mean.as_secs_f64() + std_dev.as_secs_f64() * self.standard_normal();
+            if x >= lo.as_secs_f64() && x < hi.as_secs_f64() {
+                return Duration::from_secs_f64(x);
+            }
+        }
+    }
+}
+
+pub struct State {
+    pub id: u32,
+    /// Voters and learners other than this node, derived from `config`.
+    pub peers: Vec<u32>,
+    pub current_term: usize,
+    pub voted_for: Option<u32>,
+    /// Entries after `snapshot.last_index`; `log[i]` has index
+    /// `snapshot.last_index + i + 1`.
+    pub log: Vec<LogEntry>,
+    pub snapshot: LogSnapshot,
+    pub commit_index: usize,
+    pub last_applied: usize,
+    pub n_nodes: usize,
+    pub options: Options,
+    pub rng: Rng,
+    /// Every change of `voted_for`, as `(term, voted_for)`, oldest first.
+    /// Only used to make "at most one vote per term" checkable.
+    pub vote_history: Vec<(usize, Option<u32>)>,
+    /// Log changes not yet handed to storage.
+    pub unsaved: Vec<WalRecord>,
+    /// Latest configuration in the log, committed or not, as Raft requires.
+    pub config: Config,
+    /// Configuration in effect before the log holds any membership entry.
+    pub initial_config: Config,
+    /// Elections started since this node last led or heard from a leader.
+    pub elections_without_leader: u32,
+    /// The last vote request this node refused and why, for debugging.
+    pub last_vote_denial: Option<VoteDenial>,
+    /// The last AppendEntries this node refused and why, for debugging.
+    pub last_append_rejection: Option<AppendRejection>,
+}
+
+/// Why a node refused to vote for a candidate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VoteDenialReason {
+    /// It already voted for someone else in the term.
+    AlreadyVoted,
+    /// The request is from an older term.
+    StaleTerm,
+    /// Its own log is more up to date than the candidate's.
+    LogNotUpToDate,
+    /// It still hears from a leader and has `leader_stickiness` on.
+    LeaderContact,
+}
+
+/// Why a node refused an AppendEntries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AppendRejectionReason {
+    /// The request is from an older term.
+    StaleTerm,
+    /// Its log ends before `prev_log_index`.
+    LogGap,
+    /// It holds an entry of another term at `prev_log_index`.
+    TermConflict,
+}
+
+/// A refused AppendEntries from `leader` in `term`, whose entries were to
+/// follow `prev_log_index`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AppendRejection {
+    pub term: usize,
+    pub leader: u32,
+    pub prev_log_index: usize,
+    pub reason: AppendRejectionReason,
+}
+
+/// A refused vote request: `candidate` asking for a vote in `term`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VoteDenial {
+    pub term: usize,
+    pub candidate: u32,
+    pub reason: VoteDenialReason,
+}
+
+impl State {
+    pub fn new(id: u32, peers: Vec<u32>, options: Options, seed: u64) -> Self {
+        let config = Config::new(
+            peers
+                .iter()
+                .copied()
+                .chain([id])
+                .filter(|p| !options.observers.contains(p)),
+        );
+        let n_nodes = config.voters.len();
+        let mut peers = peers;
+        for &observer in &options.observers {
+            if observer != id && !peers.contains(&observer) {
+                peers.push(observer);
+            }
+        }
+        State {
+            id,
+            peers,
+            current_term: 0,
+            voted_for: None,
+            log: vec![],
+            snapshot: LogSnapshot::default(),
+            commit_index: 0,
+            last_applied: 0,
+            n_nodes,
+            options,
+            rng: Rng::new(seed),
+            vote_history: vec![],
+            unsaved: vec![],
+            initial_config: config.clone(),
+            config,
+            elections_without_leader: 0,
+            last_vote_denial: None,
+            last_append_rejection: None,
+        }
+    }
+
+    pub fn set_config(&mut self, config: Config) {
+        self.peers = config
+            .voters
+            .union(&config.learners)
+            .copied()
+            .chain(self.options.observers.iter().copied())
+            .filter(|&p| p != self.id)
+            .collect::<BTreeSet<u32>>()
+            .into_iter()
+            .collect();
+        self.n_nodes = config.voters.len();
+        self.config = config;
+    }
+
+    /// Re-derives the configuration from the log, e.g. after a truncation.
+    pub fn refresh_config(&mut self) {
+        self.set_config(self.config_at(self.last_log_index()));
+    }
+
+    /// Configuration in effect at `index`, which must not be compacted away.
+    pub fn config_at(&self, index: usize) -> Config {
+        self.log[..index - self.snapshot.last_index]
+            .iter()
+            .rev()
+            .find_map(|e| e.config.clone())
+            .unwrap_or_else(|| self.base_config())
+    }
+
+    /// Configuration in effect before the first entry still in the log.
+    pub fn base_config(&self) -> Config {
+        if self.snapshot.last_index > 0 {
+            self.snapshot.config.clone()
+        } else {
+            self.initial_config.clone()
+        }
+    }
+
+    /// Index of the entry that set the current configuration, 0 for the initial one.
+    pub fn config_index(&self) -> usize {
+        self.log
+            .iter()
+            .rev()
+            .find(|e| e.config.is_some())
+            .map_or(0, |e| e.index)
+    }
+
+    /// Whether `votes` are enough pre-votes to start a real election.
+    pub fn pre_vote_won<'a>(&self, votes: impl IntoIterator<Item = &'a u32>) -> bool {
+        match self.options.pre_vote_quorum {
+            Some(weight) => self.config.carries(votes, weight),
+            None => self.config.is_quorum(votes),
+        }
+    }
+
+    pub fn is_voter(&self) -> bool {
+        self.config.voters.contains(&self.id)
+    }
+
+    pub fn is_observer(&self) -> bool {
+        self.options.observers.contains(&self.id)
+    }
+
+    /// The role a node starts in, or comes back in after a restart.
+    pub fn initial_role(&mut self, at: Instant) -> Box<dyn Role> {
+        let mut follower = Follower::new(at, self);
+        if let Some(stagger) = self.options.startup_stagger {
+            let rank = self.config.voters.range(..self.id).count() as u32;
+            follower.election_deadline += stagger * rank;
+        }
+        if self.is_observer() {
+            Box::new(Observer { follower })
+        } else {
+            Box::new(follower)
+        }
+    }
+
+    /// Peers whose votes count; learners are replicated to but never asked.
+    pub fn voting_peers(&self) -> impl Iterator<Item = u32> + '_ {
+        self.peers
+            .iter()
+            .copied()
+            .filter(|p| self.config.voters.contains(p))
+    }
+
+    pub fn last_log_index(&self) -> usize {
+        self.log
+            .last()
+            .map_or(self.snapshot.last_index, |e| e.index)
+    }
+
+    pub fn last_log_term(&self) -> usize {
+        self.log.last().map_or(self.snapshot.last_term, |e| e.term)
+    }
+
+    /// Term of the entry at `index`; index 0 is the empty prefix with term 0.
+    /// `None` past the end of the log and before the snapshot.
+    pub fn term_at(&self, index: usize) -> Option<usize> {
+        let base = self.snapshot.last_index;
+        match index {
+            i if i == base => Some(self.snapshot.last_term),
+            i if i < base => None,
+            i => self.log.get(i - base - 1).map(|e| e.term),
+        }
+    }
+
+    /// The entry at `index`, unless it is past the end or compacted away.
+    pub fn entry(&self, index: usize) -> Option<&LogEntry> {
+        let base = self.snapshot.last_index;
+        if index <= base {
+            return None;
+        }
+        self.log.get(index - base - 1)
+    }
+
+    /// `(term, first index, last index)` of each run of same-term entries
+    /// before `index`, oldest first, the snapshot's last entry counting as a
+    /// run of its own: enough for a leader to find where its log and this
+    /// one part ways without walking back one entry at a time.
+    pub fn term_bounds(&self, index: usize) -> Vec<(usize, usize, usize)> {
+        let base = self.snapshot.last_index;
+        let mut bounds = vec![];
+        if base > 0 && base < index {
+            bounds.push((self.snapshot.last_term, base, base));
+        }
+        let log = &self.log[..index.saturating_sub(base + 1).min(self.log.len())];
+        let mut start = 0;
+        while start < log.len() {
+            let term = log[start].term;
+            // Terms never decrease along the log, so each run ends where they grow.
+            let end = log.partition_point(|e| e.term <= term);
+            bounds.push((term, base + start + 1, base + end));
+            start = end;
+        }
+        bounds
+    }
+
+    /// Highest index at which this log agrees with the one `term_bounds`
+    /// describes; by the Log Matching property both agree on everything
+    /// before it too. Compacted entries are not compared, bar the last.
+    pub fn last_agreeing(&self, term_bounds: &[(usize, usize, usize)]) -> usize {
+        let base = self.snapshot.last_index;
+        let mut agreed = 0;
+        for &(term, first, last) in term_bounds {
+            let mut ours_first = base + self.log.partition_point(|e| e.term < term) + 1;
+            let ours_last = base + self.log.partition_point(|e| e.term <= term);
+            if term == self.snapshot.last_term && ours_first == base + 1 {
+                ours_first = base;
+            }
+            if first.max(ours_first) <= last.min(ours_last) {
+                agreed = last.min(ours_last);
+            }
+        }
+        agreed
+    }
+
+    /// Entries from `index` on; from the first one after the snapshot if
+    /// `index` is compacted away.
+    pub fn entries_from(&self, index: usize) -> Vec<LogEntry> {
+        let base = self.snapshot.last_index;
+        self.log
+            .get(index.max(base + 1) - base - 1..)
+            .unwrap_or_default()
+            .to_vec()
+    }
+
+    /// Replaces the log up to `up_to` with a snapshot holding `data`, the
+    /// state machine as of `last_applied`.
+    pub fn compact(&mut self, up_to: usize, data: Vec<u8>, sessions: Sessions) {
+        let snapshot = LogSnapshot {
+            last_index: up_to,
+            last_term: self.term_at(up_to).expect("compacting past the log"),
+            config: self.config_at(up_to),
+            applied: self.last_applied,
+            data,
+            sessions,
+        };
+        self.log.drain(..up_to - self.snapshot.last_index);
+        self.snapshot = snapshot;
+        self.unsaved
+            .push(WalRecord::Snapshot(self.snapshot.clone()));
+    }
+
+    /// Replaces the log with a leader's snapshot. Entries after it stay if
+    /// the log agrees with the snapshot's last entry; otherwise all go.
+    pub fn install_snapshot(&mut self, snapshot: LogSnapshot) {
+        let base = self.snapshot.last_index;
+        if self.term_at(snapshot.last_index) == Some(snapshot.last_term) {
+            self.log.drain(..snapshot.last_index - base);
+        } else {
+            self.log.clear();
+            self.unsaved.push(WalRecord::TruncateFrom(base + 1));
+        }
+        self.commit_index = self.commit_index.max(snapshot.last_index);
+        self.snapshot = snapshot;
+        self.unsaved
+            .push(WalRecord::Snapshot(self.snapshot.clone()));
+        self.refresh_config();
+    }
+
+    /// Panics in debug builds if the log and snapshot disagree on where
+    /// entries sit.
+    pub fn debug_check_offsets(&self) {
+        let base = self.snapshot.last_index;
+        debug_assert!(base <= self.last_applied && self.snapshot.applied <= self.last_applied);
+        debug_assert_eq!(self.last_log_index(), base + self.log.len());
+        debug_assert!(self
+            .log
+            .iter()
+            .enumerate()
+            .all(|(i, e)| e.index == base + i + 1));
+        debug_assert_eq!(self.term_at(base), Some(self.snapshot.last_term));
+        debug_assert_eq!(self.entries_from(base + 1).len(), self.log.len());
+        debug_assert!(self
+            .entries_from(0)
+            .first()
+            .is_none_or(|e| e.index == base + 1));
+    }
+
+    pub fn append(&mut self, command: Vec<u8>) -> usize {
+        self.push_entry(command, None, None)
+    }
+
+    /// Appends a membership entry; it takes effect immediately. Refused
+    /// while the last one is uncommitted, whoever asks.
+    pub fn append_config(&mut self, config: Config) -> Result<usize, ConfigError> {
+        let pending = self.config_index();
+        if pending > self.commit_index {
+            return Err(ConfigError::ChangeInProgress(pending));
+        }
+        self.set_config(config.clone());
+        Ok(self.push_entry(vec![], Some(config), None))
+    }
+
+    /// Starts an empty log with `config` as an entry committed by fiat, so
+    /// the founding members need no leader to agree on who they are.
+    pub fn bootstrap(&mut self, config: Config) {
+        assert!(
+            self.last_log_index() == 0,
+            "only an empty log can be bootstrapped"
+        );
+        self.commit_index = self
+            .append_config(config)
+            .expect("an empty log has no change in progress");
+    }
+
+    /// Forgets the configuration implied by the peer list: until a leader
+    /// replicates a membership entry here, this node neither votes in its
+    /// own right nor campaigns.
+    pub fn clear_initial_config(&mut self) {
+        self.initial_config = Config::default();
+        self.refresh_config();
+    }
+
+    pub fn push_entry(
+        &mut self,
+        command: Vec<u8>,
+        config: Option<Config>,
+        session: Option<(u32, u64)>,
+    ) -> usize {
+        let index = self.last_log_index() + 1;
+        let entry = LogEntry {
+            index,
+            term: self.current_term,
+            command,
+            config,
+            session,
+        };
+        self.unsaved.push(WalRecord::Append(entry.clone()));
+        self.log.push(entry);
+        index
+    }
+
+    /// Reconciles the log with a leader's AppendEntries: drops any conflicting
+    /// suffix and appends what is missing. Returns the last index known to
+    /// match the leader, or `None` if the consistency check fails.
+    pub fn merge_entries(
+        &mut self,
+        prev_log_index: usize,
+        prev_log_term: usize,
+        entries: Vec<LogEntry>,
+    ) -> Option<usize> {
+        let base = self.snapshot.last_index;
+        // Everything up to the snapshot is committed, so matches the leader.
+        if prev_log_index >= base && self.term_at(prev_log_index) != Some(prev_log_term) {
+            return None;
+        }
+        let last_new = prev_log_index + entries.len();
+        let mut config_changed = false;
+        for entry in entries.into_iter().filter(|e| e.index > base) {
+            match self.term_at(entry.index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => {
+                    config_changed |= self.log[entry.index - base - 1..]
+                        .iter()
+                        .any(|e| e.config.is_some());
+                    self.log.truncate(entry.index - base - 1);
+                    self.unsaved.push(WalRecord::TruncateFrom(entry.index));
+                }
+                None => {}
+            }
+            config_changed |= entry.config.is_some();
+            self.unsaved.push(WalRecord::Append(entry.clone()));
+            self.log.push(entry);
+        }
+        if config_changed {
+            self.refresh_config();
+        }
+        Some(last_new)
+    }
+
+    pub fn random_election_timeout(&mut self) -> Duration {
+        let base = self.options.base_election_timeout();
+        match self.options.timeout_distribution {
+            TimeoutDistribution::Uniform => self.rng.duration_between(base, base * 2),
+            TimeoutDistribution::Normal => {
+                self.rng
+                    .normal_duration_between(base, base * 2, base * 3 / 2, base / 4)
+            }
+        }
+    }
+
+    /// The usual election timeout for a follower `up_to_date` with the
+    /// leader; otherwise one from `[2 * base, 3 * base)`, longer than any
+    /// up-to-date follower's. See `Options::fast_election`.
+    pub fn biased_election_timeout(&mut self, up_to_date: bool) -> Duration {
+        let base = self.options.base_election_timeout();
+        if up_to_date {
+            self.random_election_timeout()
+        } else {
+            self.rng.duration_between(base * 2, base * 3)
+        }
+    }
+
+    pub fn set_voted_for(&mut self, voted_for: Option<u32>) {
+        // Every term is opened by `advance_term` with no vote, so a vote must
+        // follow a record of the current term.
+        debug_assert!(
+            voted_for.is_none()
+                || self
+                    .vote_history
+                    .last()
+                    .is_some_and(|&(term, _)| term == self.current_term),
+            "node {} voting in term {} before it was entered",
+            self.id,
+            self.current_term
+        );
+        self.voted_for = voted_for;
+        self.vote_history.push((self.current_term, voted_for));
+    }
+
+    /// The one place `current_term` changes: it only moves forward, and the
+    /// vote is cleared before anything can be cast in the new term.
+    pub fn advance_term(&mut self, new_term: usize) {
+        assert!(
+            new_term > self.current_term,
+            "node {}: term cannot go from {} to {}",
+            self.id,
+            self.current_term,
+            new_term
+        );
+        self.current_term = new_term;
+        self.set_voted_for(None);
+    }
+
+    /// Moves to a newer term seen on the wire; the vote is fresh in it.
+    pub fn adopt_term(&mut self, term: usize) -> bool {
+        if term <= self.current_term {
+            return false;
+        }
+        self.advance_term(term);
+        true
+    }
+
+    /// Decides a vote request in the current term, recording the vote if
+    /// granted and the reason if not.
+    pub fn decide_vote(
+        &mut self,
+        term: usize,
+        candidate_id: u32,
+        last_log_index: usize,
+        last_log_term: usize,
+    ) -> bool {
+        let up_to_date = log_is_up_to_date(
+            last_log_term,
+            last_log_index,
+            self.last_log_term(),
+            self.last_log_index(),
+        );
+        let reason = if term != self.current_term {
+            Some(VoteDenialReason::StaleTerm)
+        } else if self.voted_for.is_some_and(|v| v != candidate_id) {
+            Some(VoteDenialReason::AlreadyVoted)
+        } else if !up_to_date {
+            Some(VoteDenialReason::LogNotUpToDate)
+        } else {
+            None
+        };
+        match reason {
+            Some(reason) => self.deny_vote(term, candidate_id, reason),
+            None if self.voted_for.is_none() => self.set_voted_for(Some(candidate_id)),
+            None => {}
+        }
+        reason.is_none()
+    }
+
+    pub fn deny_vote(&mut self, term: usize, candidate: u32, reason: VoteDenialReason) {
+        self.last_vote_denial = Some(VoteDenial {
+            term,
+            candidate,
+            reason,
+        });
+    }
+
+    pub fn reject_append(
+        &mut self,
+        term: usize,
+        leader: u32,
+        prev_log_index: usize,
+        reason: AppendRejectionReason,
+    ) {
+        self.last_append_rejection = Some(AppendRejection {
+            term,
+            leader,
+            prev_log_index,
+            reason,
+        });
+    }
+
+    /// Returns the first term in which two different candidates were voted for.
+    /// When candidates may concede, a withdrawn self-vote followed by another
+    /// vote does not count: the node stopped campaigning, so its own vote can
+    /// no longer elect anyone.
+    pub fn conflicting_vote(&self) -> Option<(usize, u32, u32)> {
+        let may_concede = self.options.concede_to_lower_id || self.options.concede_to_better_log;
+        let mut votes: BTreeMap<usize, u32> = BTreeMap::new();
+        for &(term, voted_for) in &self.vote_history {
+            let Some(candidate) = voted_for else {
+                continue;
+            };
+            match votes.get(&term) {
+                Some(&first) if may_concede && first == self.id && candidate != self.id => {
+                    votes.insert(term, candidate);
+                }
+                Some(&first) if first != candidate => return Some((term, first, candidate)),
+                Some(_) => {}
+                None => {
+                    votes.insert(term, candidate);
+                }
+            }
+        }
+        None
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Message {
+    AppendEntryRequest {
+        term: usize,
+        leader_id: u32,
+        prev_log_index: usize,
+        prev_log_term: usize,
+        entries: Vec<LogEntry>,
+        leader_commit: usize,
+        /// Latest read confirmation round, echoed back by the follower.
+        read_round: usize,
+        /// How long a caught-up follower may serve reads on its own from
+        /// when this arrives; `None` takes any lease it had away.
+        read_lease: Option<Duration>,
+    },
+
+    /// `match_index` echoes which request this answers: on success the last
+    /// index now known to match the leader, on failure the rejected
+    /// `prev_log_index`.
+    AppendEntryResponse {
+        term: usize,
+        success: bool,
+        from: u32,
+        match_index: usize,
+        read_round: usize,
+        /// On failure, the follower's `term_bounds` below `match_index`, so
+        /// the leader can jump straight to where the logs diverge.
+        term_bounds: Vec<(usize, usize, usize)>,
+    },
+
+    RequestVoteRequest {
+        term: usize,
+        candidate_id: u32,
+        last_log_index: usize,
+        last_log_term: usize,
+        /// The election was started by TimeoutNow, so leader stickiness
+        /// does not apply.
+        forced: bool,
+    },
+
+    RequestVoteResponse {
+        term: usize,
+        vote_granted: bool,
+        from: u32,
+    },
+
+    /// Would the receiver vote for this candidate in `term`? Changes nothing
+    /// on either side; `term` is the one the candidate would campaign in.
+    PreVoteRequest {
+        term: usize,
+        candidate_id: u32,
+        last_log_index: usize,
+        last_log_term: usize,
+    },
+
+    /// Echoes the proposed `term` of the request it answers.
+    PreVoteResponse {
+        term: usize,
+        vote_granted: bool,
+        from: u32,
+    },
+
+    /// One piece of the leader's snapshot, for a follower that needs
+    /// entries the leader has compacted away; `data` starts at byte `offset`
+    /// and `done` marks the last piece.
+    InstallSnapshotRequest {
+        term: usize,
+        leader_id: u32,
+        last_index: usize,
+        last_term: usize,
+        config: Config,
+        applied: usize,
+        sessions: Sessions,
+        offset: usize,
+        data: Vec<u8>,
+        done: bool,
+    },
+
+    /// `offset` is how much of the snapshot ending at `last_index` the
+    /// follower holds, so the leader knows where to go on; `done` once it is
+    /// installed or no longer needed.
+    InstallSnapshotResponse {
+        term: usize,
+        from: u32,
+        last_index: usize,
+        offset: usize,
+        done: bool,
+    },
+
+    /// Sent by the leader as soon as its commit index advances.
+    CommitNotify {
+        term: usize,
+        leader_id: u32,
+        commit_index: usize,
+    },
+
+    /// Asks a caught-up follower to start an election right away, handing
+    /// over leadership.
+    TimeoutNow { term: usize, leader_id: u32 },
+
+    /// A command submitted by a client; answered with its result once it is
+    /// applied, or without one as soon as it commits unless `wait_for_apply`.
+    ClientRequest {
+        client_id: u32,
+        request_id: u64,
+        command: Vec<u8>,
+        wait_for_apply: bool,
+    },
+
+    /// A linearizable read, answered by the leader without touching the log.
+    ClientRead {
+        client_id: u32,
+        request_id: u64,
+        query: Vec<u8>,
+        /// Only answer from this node's own state, under a read lease or
+        /// within `max_staleness`; refuse instead of asking the leader.
+        local_only: bool,
+    },
+
+    /// Several reads served together at one ReadIndex, from the same state,
+    /// so that none sees a write the others miss. Answered with the results
+    /// in order, packed by `wire::encode_results`.
+    ClientReadTxn {
+        client_id: u32,
+        request_id: u64,
+        queries: Vec<Vec<u8>>,
+    },
+
+    /// Asks a node how it sees the cluster; answered at once, outside Raft.
+    StatusRequest { from: u32 },
+
+    /// Asks any node, leader or not, to answer `query` from its own state
+    /// machine right away; one part of a client's quorum read.
+    LocalRead {
+        client_id: u32,
+        request_id: u64,
+        query: Vec<u8>,
+    },
+
+    /// The answer to a `LocalRead`, as of the `last_applied` entry of the
+    /// node it comes `from`.
+    LocalReadResponse {
+        from: u32,
+        request_id: u64,
+        last_applied: usize,
+        result: Vec<u8>,
+    },
+
+    /// Asks for the cluster membership; only the leader knows it for sure.
+    MembershipRequest { from: u32 },
+
+    /// The leader's committed configuration as of `term`. A node that is
+    /// not leading sends empty sets and where it thinks the leader is.
+    MembershipResponse {
+        from: u32,
+        term: usize,
+        voters: Vec<u32>,
+        learners: Vec<u32>,
+        leader_hint: Option<u32>,
+    },
+
+    /// Diagnostics straight from the answering node's state.
+    StatusResponse {
+        from: u32,
+        role: String,
+        term: usize,
+        commit_index: usize,
+        leader_id: Option<u32>,
+        /// Index of the last entry, counting those compacted into a
+        /// snapshot, so that nodes compare whatever each has compacted.
+        log_len: usize,
+    },
+
+    /// `leader_hint` points a rejected client at the node believed to lead;
+    /// `busy` means the leader refused the write because it is overloaded;
+    /// `reconfiguring` that it refused it until a membership change commits;
+    /// `too_large` is the leader's entry size limit, set when the command
+    /// exceeded it and will be refused however often it is retried;
+    /// `staleness` is set on reads a follower answered from its own state.
+    ClientResponse {
+        request_id: u64,
+        success: bool,
+        leader_hint: Option<u32>,
+        result: Vec<u8>,
+        busy: bool,
+        reconfiguring: bool,
+        too_large: Option<usize>,
+        staleness: Option<Duration>,
+    },
+}
+
+pub trait HasTerm {
+    fn term(&self) -> usize;
+}
+
+impl HasTerm for Message {
+    fn term(&self) -> usize {
+        match self {
+            Message::AppendEntryRequest { term, .. } => *term,
+            Message::AppendEntryResponse { term, .. } => *term,
+            Message::RequestVoteRequest { term, .. } => *term,
+            Message::RequestVoteResponse { term, .. } => *term,
+            Message::InstallSnapshotRequest { term, .. } => *term,
+            Message::InstallSnapshotResponse { term, .. } => *term,
+            Message::CommitNotify { term, .. } => *term,
+            Message::TimeoutNow { term, .. } => *term,
+            // Nobody may adopt a term that was only proposed; clients live
+            // outside the term protocol altogether.
+            Message::PreVoteRequest { .. }
+            | Message::PreVoteResponse { .. }
+            | Message::StatusRequest { .. }
+            | Message::StatusResponse { .. }
+            | Message::LocalRead { .. }
+            | Message::LocalReadResponse { .. }
+            | Message::MembershipRequest { .. }
+            | Message::MembershipResponse { .. }
+            | Message::ClientRequest { .. }
+            | Message::ClientRead { .. }
+            | Message::ClientReadTxn { .. }
+            | Message::ClientResponse { .. } => 0,
+        }
+    }
+}
+
+impl Message {
+    pub fn kind(&self) -> MessageKind {
+        match self {
+            Message::AppendEntryRequest { .. } => MessageKind::AppendEntryRequest,
+            Message::AppendEntryResponse { .. } => MessageKind::AppendEntryResponse,
+            Message::RequestVoteRequest { .. } => MessageKind::RequestVoteRequest,
+            Message::RequestVoteResponse { .. } => MessageKind::RequestVoteResponse,
+            Message::PreVoteRequest { .. } => MessageKind::PreVoteRequest,
+            Message::PreVoteResponse { .. } => MessageKind::PreVoteResponse,
+            Message::InstallSnapshotRequest { .. } => MessageKind::InstallSnapshotRequest,
+            Message::InstallSnapshotResponse { .. } => MessageKind::InstallSnapshotResponse,
+            Message::CommitNotify { .. } => MessageKind::CommitNotify,
+            Message::TimeoutNow { .. } => MessageKind::TimeoutNow,
+            Message::ClientRequest { .. } => MessageKind::ClientRequest,
+            Message::ClientRead { .. } => MessageKind::ClientRead,
+            Message::ClientReadTxn { .. } => MessageKind::ClientReadTxn,
+            Message::StatusRequest { .. } => MessageKind::StatusRequest,
+            Message::LocalRead { .. } => MessageKind::LocalRead,
+            Message::LocalReadResponse { .. } => MessageKind::LocalReadResponse,
+            Message::MembershipRequest { .. } => MessageKind::MembershipRequest,
+            Message::MembershipResponse { .. } => MessageKind::MembershipResponse,
+            Message::StatusResponse { .. } => MessageKind::StatusResponse,
+            Message::ClientResponse { .. } => MessageKind::ClientResponse,
+        }
+    }
+
+    /// An AppendEntries with nothing to append: it only asserts leadership,
+    /// advertises the commit index and confirms reads.
+    pub fn is_heartbeat(&self) -> bool {
+        matches!(self, Message::AppendEntryRequest { entries, .. } if entries.is_empty())
+    }
+
+    /// The node or client the message says it comes from, if it says.
+    pub fn sender(&self) -> Option<u32> {
+        match self {
+            Message::AppendEntryRequest { leader_id, .. }
+            | Message::InstallSnapshotRequest { leader_id, .. }
+            | Message::CommitNotify { leader_id, .. }
+            | Message::TimeoutNow { leader_id, .. } => Some(*leader_id),
+            Message::RequestVoteRequest { candidate_id, .. }
+            | Message::PreVoteRequest { candidate_id, .. } => Some(*candidate_id),
+            Message::AppendEntryResponse { from, .. }
+            | Message::RequestVoteResponse { from, .. }
+            | Message::PreVoteResponse { from, .. }
+            | Message::InstallSnapshotResponse { from, .. }
+            | Message::StatusRequest { from }
+            | Message::StatusResponse { from, .. }
+            | Message::LocalReadResponse { from, .. }
+            | Message::MembershipRequest { from }
+            | Message::MembershipResponse { from, .. } => Some(*from),
+            Message::ClientRequest { client_id, .. }
+            | Message::ClientRead { client_id, .. }
+            | Message::ClientReadTxn { client_id, .. }
+            | Message::LocalRead { client_id, .. } => Some(*client_id),
+            Message::ClientResponse { .. } => None,
+        }
+    }
+}
+
+/// Which variant a `Message` is, for counting messages by type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum MessageKind {
+    AppendEntryRequest,
+    AppendEntryResponse,
+    RequestVoteRequest,
+    RequestVoteResponse,
+    PreVoteRequest,
+    PreVoteResponse,
+    InstallSnapshotRequest,
+    InstallSnapshotResponse,
+    CommitNotify,
+    TimeoutNow,
+    ClientRequest,
+    ClientRead,
+    ClientReadTxn,
+    StatusRequest,
+    LocalRead,
+    LocalReadResponse,
+    MembershipRequest,
+    MembershipResponse,
+    StatusResponse,
+    ClientResponse,
+}
+
+impl MessageKind {
+    pub const ALL: [MessageKind; 20] = [
+        MessageKind::AppendEntryRequest,
+        MessageKind::AppendEntryResponse,
+        MessageKind::RequestVoteRequest,
+        MessageKind::RequestVoteResponse,
+        MessageKind::PreVoteRequest,
+        MessageKind::PreVoteResponse,
+        MessageKind::InstallSnapshotRequest,
+        MessageKind::InstallSnapshotResponse,
+        MessageKind::CommitNotify,
+        MessageKind::TimeoutNow,
+        MessageKind::ClientRequest,
+        MessageKind::ClientRead,
+        MessageKind::ClientReadTxn,
+        MessageKind::StatusRequest,
+        MessageKind::LocalRead,
+        MessageKind::LocalReadResponse,
+        MessageKind::MembershipRequest,
+        MessageKind::MembershipResponse,
+        MessageKind::StatusResponse,
+        MessageKind::ClientResponse,
+    ];
+}
+
+/// Messages a role wants sent, each paired with its destination.
+pub type Outbox = Vec<(u32, Message)>;
+
+/// A role's own fields, for tests and debugging to look at without knowing
+/// the concrete role type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RoleDebug {
+    Follower {
+        election_deadline: Instant,
+        leader_id: Option<u32>,
+        leader_match: usize,
+    },
+    PreCandidate {
+        votes_received: BTreeSet<u32>,
+        election_deadline: Instant,
+    },
+    Candidate {
+        votes_received: BTreeSet<u32>,
+        votes_denied: BTreeSet<u32>,
+        election_deadline: Instant,
+    },
+    Leader {
+        next_index: BTreeMap<u32, usize>,
+        match_index: BTreeMap<u32, usize>,
+    },
+    Observer {
+        leader_id: Option<u32>,
+        leader_match: usize,
+    },
+}
+
+pub trait Role {
+    fn transition(&mut self, msg: &Message, at: Instant, s: &mut State) -> Option<Box<dyn Role>>;
+    fn handle(&mut self, msg: Message, at: Instant, s: &mut State) -> Outbox;
+    fn tick(&mut self, at: Instant, s: &mut State) -> Option<Box<dyn Role>>;
+    fn tick_msg(&mut self, at: Instant, s: &mut State) -> Outbox;
+    fn name(&self) -> &'static str;
+
+    /// When this role next needs a tick, as of `now`; `now` if it may need
+    /// one at any step. Ticking earlier is harmless, only wasted work.
+    fn next_tick(&self, now: Instant) -> Instant {
+        now
+    }
+
+    /// Role-specific fields, with instants as offsets from `base`.
+    fn to_json(&self, base: Instant) -> Json;
+
+    /// Role-specific fields, for inspection.
+    fn debug_state(&self) -> RoleDebug;
+
+    /// The node this role believes is the leader, for redirecting clients.
+    fn leader_hint(&self, _s: &State) -> Option<u32> {
+        None
+    }
+
+    /// Appends a client command, tagged with `session` if set; only a
+    /// Leader accepts it, returning the entry's index and the replication
+    /// messages to send.
+    fn propose(
+        &mut self,
+        _command: Vec<u8>,
+        _session: Option<(u32, u64)>,
+        _at: Instant,
+        _s: &mut State,
+    ) -> Option<(usize, Outbox)> {
+        None
+    }
+
+    /// Where a read may be served once the returned confirmation round is
+    /// acknowledged by a majority; only a Leader can serve reads.
+    fn read_index(&mut self, _at: Instant, _s: &mut State) -> Option<(usize, usize)> {
+        None
+    }
+
+    fn confirmed_round(&self, _s: &State) -> usize {
+        0
+    }
+
+    /// How many nodes, this one included, are known to store `index`; only
+    /// a Leader keeps track.
+    fn replicas(&self, _index: usize, _s: &State) -> Option<usize> {
+        None
+    }
+
+    /// The highest index stored on a quorum of voters, whatever its term;
+    /// only a Leader keeps track.
+    fn agreed_index(&self, _s: &State) -> Option<usize> {
+        None
+    }
+
+    /// `(follower, next_index, match_index)` for every peer replicated to,
+    /// by id; only a Leader keeps track.
+    fn replication_progress(&self) -> Option<Vec<(u32, usize, usize)>> {
+        None
+    }
+
+    /// When each node stored entry `index`; only a Leader keeps track.
+    fn commit_timeline(&self, _index: usize) -> Option<Vec<(u32, Instant)>> {
+        None
+    }
+
+    /// How far behind the leader's commit index this node may be, if known.
+    fn staleness(&self, _at: Instant) -> Option<Duration> {
+        None
+    }
+
+    /// The leader lets this node serve reads by itself until then.
+    fn read_lease(&self) -> Option<Instant> {
+        None
+    }
+
+    /// Appends a membership change; only a Leader accepts it.
+    fn change_config(
+        &mut self,
+        _change: &ConfigChange,
+        _at: Instant,
+        _s: &mut State,
+    ) -> Result<(usize, Outbox), ConfigError> {
+        Err(ConfigError::NotLeader)
+    }
+
+    /// True while this node knows of a leader it heard from within an
+    /// election timeout; such a node refuses pre-votes.
+    fn leader_alive(&self, _at: Instant) -> bool {
+        false
+    }
+
+    /// Stops taking writes and hands leadership to a peer; only a Leader
+    /// with a peer to hand over to can drain.
+    fn drain(&mut self, _at: Instant, _s: &mut State) -> Option<DrainHandle> {
+        None
+    }
+}
+
+/// True if a log ending at `(cand_last_term, cand_last_index)` is at least as
+/// up-to-date as one ending at `(my_last_term, my_last_index)`: the later last
+/// term wins, and with equal terms the longer log does.
+pub fn log_is_up_to_date(
+    cand_last_term: usize,
+    cand_last_index: usize,
+    my_last_term: usize,
+    my_last_index: usize,
+) -> bool {
+    (cand_last_term, cand_last_index) >= (my_last_term, my_last_index)
+}
+
+/// Answers a vote request from any role; only a Follower can actually grant it,
+/// every other role has already voted for itself in the current term.
+pub fn answer_vote(msg: &Message, s: &mut State) -> Option<(u32, Message)> {
+    let Message::RequestVoteRequest {
+        term,
+        candidate_id,
+        last_log_index,
+        last_log_term,
+        ..
+    } = *msg
+    else {
+        return None;
+    };
+    let vote_granted = s.decide_vote(term, candidate_id, last_log_index, last_log_term);
+    Some((
+        candidate_id,
+        Message::RequestVoteResponse {
+            term: s.current_term,
+            vote_granted,
+            from: s.id,
+        },
+    ))
+}
+
+pub struct Follower {
+    pub election_deadline: Instant,
+    pub leader_id: Option<u32>,
+    /// Last index known to match the current leader's log.
+    pub leader_match: usize,
+    /// When this node last knew it had everything the leader had committed.
+    pub caught_up_at: Option<Instant>,
+    /// End of the read lease the leader last granted.
+    pub lease_until: Option<Instant>,
+    /// The leader's snapshot as far as it has arrived, while it is sent.
+    pub incoming_snapshot: Option<LogSnapshot>,
+}
+
+impl Follower {
+    pub fn new(at: Instant, s: &mut State) -> Self {
+        Follower {
+            election_deadline: at + s.random_election_timeout(),
+            leader_id: None,
+            leader_match: 0,
+            caught_up_at: None,
+            lease_until: None,
+            incoming_snapshot: None,
+        }
+    }
+
+    /// Takes the leader's commit index, but never past what is known to
+    /// match its log, nor past the end of our own, whatever `leader_commit`
+    /// claims.
+    pub fn advance_commit(&self, leader_commit: usize, s: &mut State) {
+        let commit = leader_commit.min(self.leader_match).min(s.last_log_index());
+        if commit > s.commit_index {
+            s.commit_index = commit;
+        }
+    }
+
+    /// Adds a chunk to the snapshot being received, installing it after the
+    /// last one. A chunk that does not follow on from what has arrived is
+    /// ignored, and one of a different snapshot starts over; either way the
+    /// leader is told where to continue. Returns `(offset, done)`.
+    pub fn receive_snapshot_chunk(
+        &mut self,
+        snapshot: LogSnapshot,
+        offset: usize,
+        done: bool,
+        s: &mut State,
+    ) -> (usize, bool) {
+        // Everything up to a committed index is already here; installing
+        // an older snapshot would roll back committed state. Checked on
+        // every chunk, so a transfer overtaken by commits is dropped too.
+        if snapshot.last_index <= s.commit_index {
+            self.incoming_snapshot = None;
+            return (0, true);
+        }
+        let mut incoming = match self.incoming_snapshot.take() {
+            Some(incoming)
+                if incoming.last_index == snapshot.last_index
+                    && incoming.last_term == snapshot.last_term =>
+            {
+                incoming
+            }
+            _ => LogSnapshot {
+                data: vec![],
+                ..snapshot.clone()
+            },
+        };
+        if offset != incoming.data.len() {
+            let held = incoming.data.len();
+            self.incoming_snapshot = Some(incoming);
+            return (held, false);
+        }
+        incoming.data.extend(snapshot.data);
+        if !done {
+            let held = incoming.data.len();
+            self.incoming_snapshot = Some(incoming);
+            return (held, false);
+        }
+        let held = incoming.data.len();
+        self.leader_match = self.leader_match.max(incoming.last_index);
+        s.install_snapshot(incoming);
+        (held, true)
+    }
+}
+
+impl Role for Follower {
+    fn transition(&mut self, msg: &Message, at: Instant, s: &mut State) -> Option<Box<dyn Role>> {
+        if s.adopt_term(msg.term()) {
+            self.leader_id = None;
+            self.leader_match = 0;
+        }
+        match *msg {
+            Message::TimeoutNow { term, leader_id }
+                if term == s.current_term && self.leader_id == Some(leader_id) && s.is_voter() =>
+            {
+                Some(Candidate::start_forced(at, s))
+            }
+            _ => None,
+        }
+    }
+
+    fn handle(&mut self, msg: Message, at: Instant, s: &mut State) -> Outbox {
+        match msg {
+            Message::RequestVoteRequest { .. } => {
+                let reply = answer_vote(&msg, s);
+                if matches!(
+                    reply,
+                    Some((
+                        _,
+                        Message::RequestVoteResponse {
+                            vote_granted: true,
+                            ..
+                        }
+                    ))
+                ) {
+                    self.election_deadline = at + s.random_election_timeout();
+                }
+                reply.into_iter().collect()
+            }
+            Message::AppendEntryRequest {
+                term,
+                leader_id,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+                read_round,
+                read_lease,
+            } => {
+                let mut success = false;
+                let mut match_index = prev_log_index;
+                let mut term_bounds = vec![];
+                if term == s.current_term {
+                    self.leader_id = Some(leader_id);
+                    s.elections_without_leader = 0;
+                    self.election_deadline = at + s.random_election_timeout();
+                    if let Some(last_new) = s.merge_entries(prev_log_index, prev_log_term, entries)
+                    {
+                        // A heartbeat appends nothing but still vouches for
+                        // the log up to `prev_log_index`, so a caught-up
+                        // follower learns the leader's commit index from it.
+                        self.leader_match = self.leader_match.max(last_new);
+                        self.advance_commit(leader_commit, s);
+                        if s.commit_index >= leader_commit {
+                            self.caught_up_at = Some(at);
+                            self.lease_until = read_lease.map(|lease| at + lease);
+                        } else {
+                            self.lease_until = None;
+                        }
+                        success = true;
+                        match_index = last_new;
+                    } else {
+                        let reason = if prev_log_index > s.last_log_index() {
+                            AppendRejectionReason::LogGap
+                        } else {
+                            AppendRejectionReason::TermConflict
+                        };
+                        s.reject_append(term, leader_id, prev_log_index, reason);
+                        term_bounds = s.term_bounds(prev_log_index);
+                    }
+                    if s.options.fast_election {
+                        let up_to_date = success && s.commit_index >= leader_commit;
+                        self.election_deadline = at + s.biased_election_timeout(up_to_date);
+                    }
+                } else {
+                    s.reject_append(
+                        term,
+                        leader_id,
+                        prev_log_index,
+                        AppendRejectionReason::StaleTerm,
+                    );
+                }
+                vec![(
+                    leader_id,
+                    Message::AppendEntryResponse {
+                        term: s.current_term,
+                        success,
+                        from: s.id,
+                        match_index,
+                        read_round,
+                        term_bounds,
+                    },
+                )]
+            }
+            Message::InstallSnapshotRequest {
+                term,
+                leader_id,
+                last_index,
+                last_term,
+                config,
+                applied,
+                sessions,
+                offset,
+                data,
+                done,
+            } => {
+                let mut reply = (0, false);
+                if term == s.current_term {
+                    self.leader_id = Some(leader_id);
+                    s.elections_without_leader = 0;
+                    self.election_deadline = at + s.random_election_timeout();
+                    let chunk = LogSnapshot {
+                        last_index,
+                        last_term,
+                        config,
+                        applied,
+                        data,
+                        sessions,
+                    };
+                    reply = self.receive_snapshot_chunk(chunk, offset, done, s);
+                }
+                vec![(
+                    leader_id,
+                    Message::InstallSnapshotResponse {
+                        term: s.current_term,
+                        from: s.id,
+                        last_index,
+                        offset: reply.0,
+                        done: reply.1,
+                    },
+                )]
+            }
+            Message::CommitNotify {
+                term,
+                leader_id,
+                commit_index,
+            } => {
+                if term == s.current_term && self.leader_id == Some(leader_id) {
+                    self.advance_commit(commit_index, s);
+                }
+                vec![]
+            }
+            _ => vec![],
+        }
+    }
+
+    fn tick(&mut self, at: Instant, s: &mut State) -> Option<Box<dyn Role>> {
+        let grace = if self.leader_id.is_some() {
+            s.options.heartbeat_interval * s.options.election_grace
+        } else {
+            Duration::ZERO
+        };
+        // A learner, or a node outside the configuration, must not disrupt
+        // it with elections however long it goes without a leader.
+        if at >= self.election_deadline + grace && s.is_voter() {
+            if s.options.pre_vote_enabled {
+                return Some(PreCandidate::start(at, s));
+            }
+            return Some(Candidate::start(at, s));
+        }
+        None
+    }
+
+    fn tick_msg(&mut self, _at: Instant, _s: &mut State) -> Outbox {
+        vec![]
+    }
+
+    fn name(&self) -> &'static str {
+        "Follower"
+    }
+
+    /// Nothing happens before the election deadline; any grace only makes
+    /// the wait longer.
+    fn next_tick(&self, _now: Instant) -> Instant {
+        self.election_deadline
+    }
+
+    fn debug_state(&self) -> RoleDebug {
+        RoleDebug::Follower {
+            election_deadline: self.election_deadline,
+            leader_id: self.leader_id,
+            leader_match: self.leader_match,
+        }
+    }
+
+    fn to_json(&self, base: Instant) -> Json {
+        Json::obj(vec![
+            ("name", self.name().into()),
+            ("election_deadline", (self.election_deadline - base).into()),
+            ("leader_id", self.leader_id.into()),
+            ("leader_match", self.leader_match.into()),
+            ("caught_up_at", self.caught_up_at.map(|t| t - base).into()),
+            ("lease_until", self.lease_until.map(|t| t - base).into()),
+            (
+                "incoming_snapshot",
+                self.incoming_snapshot
+                    .as_ref()
+                    .map_or(Json::Null, snapshot::log_snapshot_json),
+            ),
+        ])
+    }
+
+    fn leader_hint(&self, _s: &State) -> Option<u32> {
+        self.leader_id
+    }
+
+    fn staleness(&self, at: Instant) -> Option<Duration> {
+        self.caught_up_at.map(|t| at - t)
+    }
+
+    fn read_lease(&self) -> Option<Instant> {
+        self.lease_until
+    }
+
+    fn leader_alive(&self, at: Instant) -> bool {
+        self.leader_id.is_some() && at < self.election_deadline
+    }
+}
+
+/// Follows the log like a Follower on a node listed in `observers`, but
+/// never campaigns and refuses every vote, whatever the configuration says.
+pub struct Observer {
+    pub follower: Follower,
+}
+
+impl Role for Observer {
+    fn transition(&mut self, msg: &Message, at: Instant, s: &mut State) -> Option<Box<dyn Role>> {
+        // Only the term and leader it learns matter; it never changes role.
+        self.follower.transition(msg, at, s);
+        None
+    }
+
+    fn handle(&mut self, msg: Message, at: Instant, s: &mut State) -> Outbox {
+        match msg {
+            Message::RequestVoteRequest { candidate_id, .. } => vec![(
+                candidate_id,
+                Message::RequestVoteResponse {
+                    term: s.current_term,
+                    vote_granted: false,
+                    from: s.id,
+                },
+            )],
+            msg => self.follower.handle(msg, at, s),
+        }
+    }
+
+    fn tick(&mut self, _at: Instant, _s: &mut State) -> Option<Box<dyn Role>> {
+        None
+    }
+
+    fn tick_msg(&mut self, at: Instant, s: &mut State) -> Outbox {
+        self.follower.tick_msg(at, s)
+    }
+
+    fn name(&self) -> &'static str {
+        "Observer"
+    }
+
+    fn next_tick(&self, now: Instant) -> Instant {
+        self.follower.next_tick(now)
+    }
+
+    fn debug_state(&self) -> RoleDebug {
+        RoleDebug::Observer {
+            leader_id: self.follower.leader_id,
+            leader_match: self.follower.leader_match,
+        }
+    }
+
+    fn to_json(&self, base: Instant) -> Json {
+        Json::obj(vec![
+            ("name", self.name().into()),
+            ("follower", self.follower.to_json(base)),
+        ])
+    }
+
+    fn leader_hint(&self, s: &State) -> Option<u32> {
+        self.follower.leader_hint(s)
+    }
+
+    fn staleness(&self, at: Instant) -> Option<Duration> {
+        self.follower.staleness(at)
+    }
+
+    fn read_lease(&self) -> Option<Instant> {
+        self.follower.read_lease()
+    }
+
+    fn leader_alive(&self, at: Instant) -> bool {
+        self.follower.leader_alive(at)
+    }
+}
+
+/// Polls peers with pre-votes and only becomes a Candidate once a majority
+/// would vote for it; the term stays as it is until then.
+pub struct PreCandidate {
+    pub votes_received: HashSet<u32>,
+    pub election_deadline: Instant,
+    pub votes_requested: bool,
+}
+
+impl PreCandidate {
+    pub fn start(at: Instant, s: &mut State) -> Box<dyn Role> {
+        if s.pre_vote_won(&[s.id]) {
+            return Candidate::start(at, s);
+        }
+        Box::new(PreCandidate {
+            votes_received: HashSet::from([s.id]),
+            election_deadline: at + s.random_election_timeout(),
+            votes_requested: false,
+        })
+    }
+}
+
+impl Role for PreCandidate {
+    fn transition(&mut self, msg: &Message, at: Instant, s: &mut State) -> Option<Box<dyn Role>> {
+        if s.adopt_term(msg.term()) {
+            return Some(Box::new(Follower::new(at, s)));
+        }
+        match *msg {
+            Message::AppendEntryRequest { term, .. }
+            | Message::InstallSnapshotRequest { term, .. }
+                if term == s.current_term =>
+            {
+                Some(Box::new(Follower::new(at, s)))
+            }
+            Message::PreVoteResponse {
+                term,
+                vote_granted: true,
+                from,
+            } if term == s.current_term + 1 => {
+                self.votes_received.insert(from);
+                s.pre_vote_won(&self.votes_received)
+                    .then(|| Candidate::start(at, s))
+            }
+            _ => None,
+        }
+    }
+
+    fn handle(&mut self, msg: Message, _at: Instant, s: &mut State) -> Outbox {
+        answer_vote(&msg, s).into_iter().collect()
+    }
+
+    fn tick(&mut self, at: Instant, s: &mut State) -> Option<Box<dyn Role>> {
+        if at >= self.election_deadline {
+            return Some(PreCandidate::start(at, s));
+        }
+        None
+    }
+
+    fn tick_msg(&mut self, _at: Instant, s: &mut State) -> Outbox {
+        if self.votes_requested {
+            return vec![];
+        }
+        self.votes_requested = true;
+        s.voting_peers()
+            .map(|peer| {
+                (
+                    peer,
+                    Message::PreVoteRequest {
+                        term: s.current_term + 1,
+                        candidate_id: s.id,
+                        last_log_index: s.last_log_index(),
+                        last_log_term: s.last_log_term(),
+                    },
+                )
+            })
+            .collect()
+    }
+
+    fn name(&self) -> &'static str {
+        "PreCandidate"
+    }
+
+    fn next_tick(&self, now: Instant) -> Instant {
+        if self.votes_requested {
+            self.election_deadline
+        } else {
+            now
+        }
+    }
+
+    fn debug_state(&self) -> RoleDebug {
+        RoleDebug::PreCandidate {
+            votes_received: self.votes_received.iter().copied().collect(),
+            election_deadline: self.election_deadline,
+        }
+    }
+
+    fn to_json(&self, base: Instant) -> Json {
+        let mut votes: Vec<u32> = self.votes_received.iter().copied().collect();
+        votes.sort_unstable();
+        Json::obj(vec![
+            ("name", self.name().into()),
+            ("votes_received", votes.into()),
+            ("election_deadline", (self.election_deadline - base).into()),
+            ("votes_requested", self.votes_requested.into()),
+        ])
+    }
+}
+
+pub struct Candidate {
+    /// Voters that granted this term's vote, each counted once; grants
+    /// from earlier elections are dropped as stale before they get here.
+    pub votes_received: HashSet<u32>,
+    /// Peers that refused; once too many have, the election is lost.
+    pub votes_denied: HashSet<u32>,
+    pub election_started: Instant,
+    pub election_deadline: Instant,
+    pub votes_requested: bool,
+    /// Voters not asked yet, with `vote_fan_out`; the next waves go to them.
+    pub unasked: Vec<u32>,
+    /// When to ask voters that have not answered again, if retrying.
+    pub votes_resend_at: Option<Instant>,
+    /// Started by TimeoutNow; its vote requests say so.
+    pub forced: bool,
+}
+
+impl Candidate {
+    /// Starts a new election: bumps the term and votes for itself.
+    pub fn new(at: Instant, s: &mut State) -> Self {
+        s.advance_term(s.current_term + 1);
+        s.set_voted_for(Some(s.id));
+        s.elections_without_leader += 1;
+        Candidate {
+            votes_received: HashSet::from([s.id]),
+            votes_denied: HashSet::new(),
+            election_started: at,
+            election_deadline: at + s.random_election_timeout(),
+            votes_requested: false,
+            unasked: vec![],
+            votes_resend_at: None,
+            forced: false,
+        }
+    }
+
+    /// Starts an election; a lone voter wins it on its own vote.
+    pub fn start(at: Instant, s: &mut State) -> Box<dyn Role> {
+        Candidate::new(at, s).into_role(at, s)
+    }
+
+    /// Starts the election a leader handed over with TimeoutNow.
+    pub fn start_forced(at: Instant, s: &mut State) -> Box<dyn Role> {
+        Candidate {
+            forced: true,
+            ..Candidate::new(at, s)
+        }
+        .into_role(at, s)
+    }
+
+    pub fn into_role(self, at: Instant, s: &mut State) -> Box<dyn Role> {
+        if s.config.is_quorum(&self.votes_received) {
+            return Box::new(Leader::new(at, s));
+        }
+        Box::new(self)
+    }
+
+    /// Peers asked for their vote this round that have not answered yet.
+    pub fn outstanding(&self, s: &State) -> Vec<u32> {
+        s.voting_peers()
+            .filter(|p| !self.votes_received.contains(p) && !self.votes_denied.contains(p))
+            .filter(|p| !self.unasked.contains(p))
+            .collect()
+    }
+}
+
+impl Role for Candidate {
+    fn transition(&mut self, msg: &Message, at: Instant, s: &mut State) -> Option<Box<dyn Role>> {
+        // Step down on a newer term; the message is then handled as a Follower.
+        if s.adopt_term(msg.term()) {
+            return Some(Box::new(Follower::new(at, s)));
+        }
+        match msg {
+            // A newer term was adopted above, so this is the current one:
+            // someone else already won this election.
+            Message::AppendEntryRequest { term, .. }
+            | Message::InstallSnapshotRequest { term, .. } => {
+                if *term >= s.current_term {
+                    return Some(Box::new(Follower::new(at, s)));
+                }
+                None
+            }
+            Message::RequestVoteRequest {
+                candidate_id,
+                last_log_index,
+                last_log_term,
+                ..
+            } if (s.options.concede_to_lower_id
+                && *candidate_id < s.id
+                // A lower id with an older log could never get our vote as
+                // a Follower either; conceding would only waste the term.
+                && log_is_up_to_date(
+                    *last_log_term,
+                    *last_log_index,
+                    s.last_log_term(),
+                    s.last_log_index(),
+                ))
+                || (s.options.concede_to_better_log
+                    && !log_is_up_to_date(
+                        s.last_log_term(),
+                        s.last_log_index(),
+                        *last_log_term,
+                        *last_log_index,
+                    )) =>
+            {
+                // Withdraw the self-vote so the Follower can grant this request.
+                s.set_voted_for(None);
+                Some(Box::new(Follower::new(at, s)))
+            }
+            Message::RequestVoteResponse {
+                vote_granted, from, ..
+            } => {
+                if *vote_granted {
+                    self.votes_received.insert(*from);
+                    if s.config.is_quorum(&self.votes_received) {
+                        return Some(Box::new(Leader::new(at, s)));
+                    }
+                } else {
+                    self.votes_denied.insert(*from);
+                    let undecided = s
+                        .config
+                        .voters
+                        .iter()
+                        .filter(|v| !self.votes_denied.contains(v));
+                    if !s.config.is_quorum(undecided) {
+                        // Lost even if every outstanding vote were granted: stop
+                        // campaigning in this term, keeping the self-vote.
+                        return Some(Box::new(Follower::new(at, s)));
+                    }
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn handle(&mut self, msg: Message, _at: Instant, s: &mut State) -> Outbox {
+        answer_vote(&msg, s).into_iter().collect()
+    }
+
+    fn tick(&mut self, at: Instant, s: &mut State) -> Option<Box<dyn Role>> {
+        // Votes still outstanding at the deadline count as denials; a
+        // partitioned peer never holds up the next round.
+        if at >= self.election_deadline {
+            return Some(Candidate::start(at, s));
+        }
+        None
+    }
+
+    fn tick_msg(&mut self, at: Instant, s: &mut State) -> Outbox {
+        if !self.votes_requested {
+            self.votes_requested = true;
+            self.unasked = s.voting_peers().collect();
+            self.votes_resend_at = s.options.vote_retry_interval.map(|d| at + d);
+        }
+        let peers: Vec<u32> = if !self.unasked.is_empty() {
+            let wave = s.options.vote_fan_out.unwrap_or(usize::MAX).max(1);
+            self.unasked.drain(..wave.min(self.unasked.len())).collect()
+        } else if self.votes_resend_at.is_some_and(|t| at >= t) {
+            self.votes_resend_at = s.options.vote_retry_interval.map(|d| at + d);
+            self.outstanding(s)
+        } else {
+            return vec![];
+        };
+        peers
+            .into_iter()
+            .map(|peer| {
+                (
+                    peer,
+                    Message::RequestVoteRequest {
+                        term: s.current_term,
+                        candidate_id: s.id,
+                        last_log_index: s.last_log_index(),
+                        last_log_term: s.last_log_term(),
+                        forced: self.forced,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    fn name(&self) -> &'static str {
+        "Candidate"
+    }
+
+    fn next_tick(&self, now: Instant) -> Instant {
+        if !self.votes_requested || !self.unasked.is_empty() {
+            return now;
+        }
+        self.votes_resend_at
+            .map_or(self.election_deadline, |t| t.min(self.election_deadline))
+    }
+
+    fn debug_state(&self) -> RoleDebug {
+        RoleDebug::Candidate {
+            votes_received: self.votes_received.iter().copied().collect(),
+            votes_denied: self.votes_denied.iter().copied().collect(),
+            election_deadline: self.election_deadline,
+        }
+    }
+
+    fn to_json(&self, base: Instant) -> Json {
+        let mut votes: Vec<u32> = self.votes_received.iter().copied().collect();
+        votes.sort_unstable();
+        let mut denied: Vec<u32> = self.votes_denied.iter().copied().collect();
+        denied.sort_unstable();
+        Json::obj(vec![
+            ("name", self.name().into()),
+            ("votes_received", votes.into()),
+            ("votes_denied", denied.into()),
+            ("election_started", (self.election_started - base).into()),
+            ("election_deadline", (self.election_deadline - base).into()),
+            ("votes_requested", self.votes_requested.into()),
+            ("unasked", self.unasked.clone().into()),
+            (
+                "votes_resend_at",
+                self.votes_resend_at.map(|t| t - base).into(),
+            ),
+            ("forced", self.forced.into()),
+        ])
+    }
+}
+
+pub struct Leader {
+    pub next_heartbeat: Instant,
+    pub next_index: HashMap<u32, usize>,
+    pub match_index: HashMap<u32, usize>,
+    /// When each peer last answered an AppendEntries, starting at election.
+    pub last_ack: HashMap<u32, Instant>,
+    /// Last read confirmation round sent; each heartbeat carries it.
+    pub read_round: usize,
+    /// Reads are waiting for a round that has not been sent yet.
+    pub round_wanted: bool,
+    /// Highest round each peer has echoed back.
+    pub round_acks: HashMap<u32, usize>,
+    /// Refusing writes while handing leadership over.
+    pub draining: bool,
+    /// When the last TimeoutNow went out, to retry if the handover stalls.
+    pub transfer_sent: Option<Instant>,
+    /// Bytes of the snapshot each peer being sent one has acknowledged.
+    pub snapshot_offset: HashMap<u32, usize>,
+    /// Round each learner is in, with `max_catch_up_rounds` set.
+    pub catch_up: HashMap<u32, CatchUpRound>,
+    /// Who stored each entry of this term when, the leader included, in
+    /// order; entries are forgotten once compacted.
+    pub ack_times: BTreeMap<usize, Vec<(u32, Instant)>>,
+}
+
+/// One round of a learner catching up with the leader's log.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CatchUpRound {
+    /// Counting from 1.
+    pub number: u32,
+    /// The leader's last log index when the round began.
+    pub target: usize,
+    pub started: Instant,
+}
+
+/// Where a learner stands in catching up.
+pub enum CatchUp {
+    Done,
+    Behind,
+    GaveUp,
+}
+
+impl Leader {
+    pub fn new(at: Instant, s: &mut State) -> Self {
+        let next = s.last_log_index() + 1;
+        s.elections_without_leader = 0;
+        let mut leader = Leader {
+            next_heartbeat: at,
+            next_index: s.peers.iter().map(|&p| (p, next)).collect(),
+            match_index: s.peers.iter().map(|&p| (p, 0)).collect(),
+            last_ack: s.peers.iter().map(|&p| (p, at)).collect(),
+            read_round: 0,
+            round_wanted: false,
+            round_acks: s.peers.iter().map(|&p| (p, 0)).collect(),
+            draining: false,
+            transfer_sent: None,
+            snapshot_offset: HashMap::new(),
+            catch_up: HashMap::new(),
+            ack_times: BTreeMap::new(),
+        };
+        if s.options.snapshot_on_election && s.last_log_term() != s.current_term {
+            let index = s.append(vec![]);
+            leader.note_acks(s.id, index - 1, index, at, s);
+            leader.maybe_advance_commit(at, s);
+        }
+        leader
+    }
+
+    /// Notes that `node` stored the entries after `from` up to `to` at `at`.
+    pub fn note_acks(&mut self, node: u32, from: usize, to: usize, at: Instant, s: &State) {
+        if self
+            .ack_times
+            .first_key_value()
+            .is_some_and(|(&index, _)| index <= s.snapshot.last_index)
+        {
+            self.ack_times = self.ack_times.split_off(&(s.snapshot.last_index + 1));
+        }
+        for index in from + 1..=to {
+            self.ack_times.entry(index).or_default().push((node, at));
+        }
+    }
+
+    /// Once everything is committed, asks the voter with the most recent ack
+    /// among those holding the whole log to take over; again after an
+    /// election timeout if nobody has. Learners are passed over however
+    /// caught up they are, until their promotion is in the configuration.
+    pub fn transfer(&mut self, at: Instant, s: &State) -> Outbox {
+        if s.commit_index < s.last_log_index()
+            || self
+                .transfer_sent
+                .is_some_and(|t| at < t + s.options.base_election_timeout())
+        {
+            return vec![];
+        }
+        let Some(target) = s
+            .voting_peers()
+            .filter(|p| self.match_index.get(p) == Some(&s.last_log_index()))
+            .max_by_key(|p| self.last_ack.get(p).copied())
+        else {
+            return vec![];
+        };
+        self.transfer_sent = Some(at);
+        vec![(
+            target,
+            Message::TimeoutNow {
+                term: s.current_term,
+                leader_id: s.id,
+            },
+        )]
+    }
+
+    /// Nothing to replicate: every peer holds the whole log, all of it
+    /// committed, and no snapshot or handover is under way.
+    pub fn is_idle(&self, s: &State) -> bool {
+        let last = s.last_log_index();
+        s.commit_index == last
+            && s.peers
+                .iter()
+                .all(|p| self.match_index.get(p) == Some(&last))
+            && self.snapshot_offset.is_empty()
+            && !self.draining
+    }
+
+    pub fn heartbeat_interval(&self, s: &State) -> Duration {
+        match s.options.idle_heartbeat_interval {
+            Some(interval) if self.is_idle(s) => interval,
+            _ => s.options.heartbeat_interval,
+        }
+    }
+
+    /// Ends an idle stretch, so the commit index of new entries goes out
+    /// with the next regular heartbeat rather than the next keepalive.
+    pub fn wake(&mut self, at: Instant, s: &State) {
+        self.next_heartbeat = self.next_heartbeat.min(at + s.options.heartbeat_interval);
+    }
+
+    /// Makes the first learner that has caught up a voter, or removes the
+    /// first that never will, once the configuration that added it has
+    /// committed; one at a time, since each is a membership change of its own.
+    pub fn promote_learners(&mut self, at: Instant, s: &mut State) -> Outbox {
+        self.catch_up.retain(|p, _| s.config.learners.contains(p));
+        if s.commit_index < s.config_index() {
+            return vec![];
+        }
+        let learners: Vec<u32> = s.config.learners.iter().copied().collect();
+        for learner in learners {
+            let change = match self.catch_up_progress(learner, at, s) {
+                CatchUp::Done => ConfigChange::add(learner),
+                CatchUp::GaveUp => ConfigChange::remove(learner),
+                CatchUp::Behind => continue,
+            };
+            return self
+                .change_config(&change, at, s)
+                .map_or(vec![], |(_, out)| out);
+        }
+        vec![]
+    }
+
+    /// Without a round limit a learner has caught up once it holds
+    /// everything committed, however long that takes.
+    pub fn catch_up_progress(&mut self, learner: u32, at: Instant, s: &State) -> CatchUp {
+        let matched = self.match_index.get(&learner).copied().unwrap_or(0);
+        let Some(max_rounds) = s.options.max_catch_up_rounds else {
+            return if matched >= s.commit_index {
+                CatchUp::Done
+            } else {
+                CatchUp::Behind
+            };
+        };
+        let round = self.catch_up.entry(learner).or_insert(CatchUpRound {
+            number: 1,
+            target: s.last_log_index(),
+            started: at,
+        });
+        if matched >= round.target {
+            return CatchUp::Done;
+        }
+        if at < round.started + s.options.base_election_timeout() {
+            return CatchUp::Behind;
+        }
+        if round.number >= max_rounds {
+            return CatchUp::GaveUp;
+        }
+        *round = CatchUpRound {
+            number: round.number + 1,
+            target: s.last_log_index(),
+            started: at,
+        };
+        CatchUp::Behind
+    }
+
+    /// Starts tracking peers added by a membership change. Removed peers keep
+    /// being replicated to until they hold the entry that removes them, so
+    /// they learn not to start elections.
+    pub fn sync_peers(&mut self, at: Instant, s: &State) {
+        let next = s.last_log_index() + 1;
+        for &peer in &s.peers {
+            self.next_index.entry(peer).or_insert(next);
+            self.match_index.entry(peer).or_insert(0);
+            self.last_ack.entry(peer).or_insert(at);
+            self.round_acks.entry(peer).or_insert(0);
+        }
+        let config_index = s.config_index();
+        let retired: Vec<u32> = self
+            .match_index
+            .iter()
+            .filter(|(p, &m)| !s.peers.contains(p) && m >= config_index)
+            .map(|(&p, _)| p)
+            .collect();
+        for peer in retired {
+            self.next_index.remove(&peer);
+            self.match_index.remove(&peer);
+            self.last_ack.remove(&peer);
+            self.round_acks.remove(&peer);
+            self.snapshot_offset.remove(&peer);
+        }
+    }
+
+    /// What `peer` needs next: AppendEntries from its `next_index`, or, once
+    /// those entries are compacted away, the snapshot that stands in for them.
+    pub fn message_for(&self, peer: u32, s: &State) -> Message {
+        let next = self.next_index[&peer];
+        if next <= s.snapshot.last_index {
+            self.snapshot_chunk_for(peer, s)
+        } else {
+            self.append_entries_from(next, s)
+        }
+    }
+
+    /// AppendEntries carrying the log from `next` on, which must not be
+    /// compacted: `entries_from` would skip to the snapshot's end instead.
+    pub fn append_entries_from(&self, next: usize, s: &State) -> Message {
+        debug_assert!(
+            next > s.snapshot.last_index,
+            "entries from {} are compacted into the snapshot at {}",
+            next,
+            s.snapshot.last_index
+        );
+        let entries = s.entries_from(next);
+        Message::AppendEntryRequest {
+            term: s.current_term,
+            leader_id: s.id,
+            prev_log_index: next - 1,
+            prev_log_term: s.term_at(next - 1).unwrap_or(0),
+            entries,
+            leader_commit: s.commit_index,
+            read_round: self.read_round,
+            read_lease: s.options.follower_read_lease.filter(|_| !self.draining),
+        }
+    }
+
+    /// The piece of the snapshot after what `peer` last acknowledged. Only
+    /// one is in flight at a time; a lost one is sent again with the next
+    /// heartbeat.
+    ///
+    /// The snapshot goes out as ending at `applied`, which its data is as of:
+    /// the entries after `last_index` up to there are committed and still in
+    /// the log, and a follower installing it must commit them as well, or it
+    /// would have applied past its commit index.
+    pub fn snapshot_chunk_for(&self, peer: u32, s: &State) -> Message {
+        let snapshot = &s.snapshot;
+        let offset = self.snapshot_offset.get(&peer).copied().unwrap_or(0);
+        let offset = offset.min(snapshot.data.len());
+        let end = (offset + s.options.snapshot_chunk_size.max(1)).min(snapshot.data.len());
+        Message::InstallSnapshotRequest {
+            term: s.current_term,
+            leader_id: s.id,
+            last_index: snapshot.applied,
+            last_term: s.term_at(snapshot.applied).unwrap(),
+            config: s.config_at(snapshot.applied),
+            applied: snapshot.applied,
+            sessions: snapshot.sessions.clone(),
+            offset,
+            data: snapshot.data[offset..end].to_vec(),
+            done: end == snapshot.data.len(),
+        }
+    }
+
+    /// One message per peer in `peers`, each built for that peer by `make`.
+    pub fn broadcast<F: Fn(u32) -> Message>(&self, peers: &[u32], make: F) -> Outbox {
+        peers.iter().map(|&peer| (peer, make(peer))).collect()
+    }
+
+    /// An AppendEntries, or a snapshot chunk, to every tracked peer, each
+    /// starting at that peer's own `next_index`.
+    pub fn replicate(&self, s: &State) -> Outbox {
+        let mut targets: Vec<u32> = self.next_index.keys().copied().collect();
+        targets.sort_unstable();
+        self.broadcast(&targets, |peer| self.message_for(peer, s))
+    }
+
+    /// Highest read round a majority, counting this node, has echoed: every
+    /// read waiting on it is known to have arrived while we still led.
+    pub fn confirmed_round(&self, s: &State) -> usize {
+        let rounds = s.peers.iter().map(|&p| (p, self.round_acks[&p]));
+        s.config.agreed(rounds.chain([(s.id, self.read_round)]))
+    }
+
+    /// When each node is known to have stored entry `index`, earliest first,
+    /// for an entry this leader appended. The entry committed once the acks
+    /// made up a quorum, so the node completing it was the bottleneck.
+    pub fn commit_timeline(&self, index: usize) -> Vec<(u32, Instant)> {
+        let mut timeline = self.ack_times.get(&index).cloned().unwrap_or_default();
+        timeline.sort_by_key(|&(_, at)| at);
+        timeline
+    }
+
+    /// Whether a majority, counting this node, answered within the last
+    /// election timeout.
+    pub fn has_lease(&self, at: Instant, s: &State) -> bool {
+        let fresh = s.peers.iter().filter(|p| {
+            at.saturating_duration_since(self.last_ack[*p]) <= s.options.base_election_timeout()
+        });
+        s.config.is_quorum(fresh.chain([&s.id]))
+    }
+
+    /// Commits the highest index stored on a majority, as long as it belongs
+    /// to the current term; older entries only commit along with it.
+    pub fn maybe_advance_commit(&self, at: Instant, s: &mut State) -> bool {
+        // Only voters of the current configuration count, the leader included.
+        let fresh = |p: &u32| {
+            !s.options.require_fresh_acks
+                || at.saturating_duration_since(self.last_ack[p])
+                    <= s.options.base_election_timeout()
+        };
+        let matched = s
+            .peers
+            .iter()
+            .map(|&p| (p, if fresh(&p) { self.match_index[&p] } else { 0 }));
+        let candidate = s.config.agreed(matched.chain([(s.id, s.last_log_index())]));
+        // An entry from an earlier term may sit on a majority and still be
+        // overwritten by another leader (Figure 8 of the Raft paper); it only
+        // commits along with a later entry of our own term.
+        if candidate > s.commit_index && s.term_at(candidate) == Some(s.current_term) {
+            debug_assert!(candidate <= s.last_log_index());
+            s.commit_index = candidate;
+            return true;
+        }
+        false
+    }
+}
+
+impl Role for Leader {
+    fn transition(&mut self, msg: &Message, at: Instant, s: &mut State) -> Option<Box<dyn Role>> {
+        let lease = self.has_lease(at, s);
+        if !s.adopt_term(msg.term()) {
+            return None;
+        }
+        if let Message::RequestVoteRequest {
+            last_log_index,
+            last_log_term,
+            ..
+        } = *msg
+        {
+            // That candidate cannot win against our log, so take leadership
+            // back in the next term; the request is then refused.
+            let behind = !log_is_up_to_date(
+                last_log_term,
+                last_log_index,
+                s.last_log_term(),
+                s.last_log_index(),
+            );
+            if s.options.leader_affinity && lease && behind {
+                return Some(Candidate::start(at, s));
+            }
+        }
+        Some(Box::new(Follower::new(at, s)))
+    }
+
+    fn handle(&mut self, msg: Message, at: Instant, s: &mut State) -> Outbox {
+        match msg {
+            Message::AppendEntryResponse {
+                term,
+                success,
+                from,
+                match_index: matched,
+                read_round,
+                term_bounds,
+            } if term == s.current_term && self.next_index.contains_key(&from) => {
+                self.last_ack.insert(from, at);
+                let acked = self.round_acks.entry(from).or_insert(0);
+                *acked = (*acked).max(read_round);
+                if !success {
+                    // Only back off for a rejection of the probe we last sent;
+                    // an older rejection would walk next_index back twice.
+                    let next = self.next_index.get_mut(&from).unwrap();
+                    if matched + 1 != *next {
+                        return vec![];
+                    }
+                    // Below the snapshot, the follower gets the snapshot instead.
+                    let agreed = s.last_agreeing(&term_bounds);
+                    *next = agreed.min(matched.saturating_sub(1)) + 1;
+                    return vec![(from, self.message_for(from, s))];
+                }
+                // A delayed ack for something already known to be replicated
+                // only matters if it makes a stale follower count again.
+                let known = matched <= self.match_index[&from];
+                if known && !s.options.require_fresh_acks {
+                    return vec![];
+                }
+                if !known {
+                    let previous = self.match_index.insert(from, matched).unwrap_or(0);
+                    self.note_acks(from, previous, matched, at, s);
+                    let next = self.next_index.get_mut(&from).unwrap();
+                    *next = (*next).max(matched + 1);
+                    if !s.peers.contains(&from) {
+                        self.sync_peers(at, s);
+                        return vec![];
+                    }
+                }
+                let mut out = vec![];
+                let advanced = self.maybe_advance_commit(at, s);
+                if advanced && !s.is_voter() {
+                    // Announce the commit before stepping down at the next tick.
+                    return self.replicate(s);
+                }
+                if advanced && s.options.commit_notify {
+                    out.extend(s.peers.iter().map(|&peer| {
+                        (
+                            peer,
+                            Message::CommitNotify {
+                                term: s.current_term,
+                                leader_id: s.id,
+                                commit_index: s.commit_index,
+                            },
+                        )
+                    }));
+                }
+                if !known && matched < s.last_log_index() {
+                    out.push((from, self.message_for(from, s)));
+                }
+                out
+            }
+            Message::InstallSnapshotResponse {
+                term,
+                from,
+                last_index,
+                offset,
+                done,
+            } if term == s.current_term && self.next_index.contains_key(&from) => {
+                self.last_ack.insert(from, at);
+                if last_index != s.snapshot.applied {
+                    // About a snapshot compaction has since replaced.
+                    return vec![];
+                }
+                if done {
+                    self.snapshot_offset.remove(&from);
+                    let matched = self.match_index.get_mut(&from).unwrap();
+                    *matched = (*matched).max(last_index);
+                    let next = self.next_index.get_mut(&from).unwrap();
+                    *next = (*next).max(last_index + 1);
+                    return vec![(from, self.message_for(from, s))];
+                }
+                if self.snapshot_offset.insert(from, offset) == Some(offset) {
+                    // A duplicate answer; the chunk it asks for is already out.
+                    return vec![];
+                }
+                vec![(from, self.snapshot_chunk_for(from, s))]
+            }
+            _ => answer_vote(&msg, s).into_iter().collect(),
+        }
+    }
+
+    fn tick(&mut self, at: Instant, s: &mut State) -> Option<Box<dyn Role>> {
+        // A leader that removed itself steps down once the removal commits.
+        if !s.is_voter() && s.commit_index >= s.config_index() {
+            return Some(Box::new(Follower::new(at, s)));
+        }
+        None
+    }
+
+    fn tick_msg(&mut self, at: Instant, s: &mut State) -> Outbox {
+        // All reads that arrived since the last round share the next one.
+        if self.round_wanted {
+            self.round_wanted = false;
+            self.read_round += 1;
+        } else if at < self.next_heartbeat {
+            return vec![];
+        }
+        self.next_heartbeat = at + self.heartbeat_interval(s);
+        let mut out = self.replicate(s);
+        if self.draining {
+            out.extend(self.transfer(at, s));
+        }
+        out.extend(self.promote_learners(at, s));
+        out
+    }
+
+    fn name(&self) -> &'static str {
+        "Leader"
+    }
+
+    fn debug_state(&self) -> RoleDebug {
+        RoleDebug::Leader {
+            next_index: self.next_index.iter().map(|(&p, &i)| (p, i)).collect(),
+            match_index: self.match_index.iter().map(|(&p, &i)| (p, i)).collect(),
+        }
+    }
+
+    fn to_json(&self, base: Instant) -> Json {
+        Json::obj(vec![
+            ("name", self.name().into()),
+            ("next_heartbeat", (self.next_heartbeat - base).into()),
+            ("next_index", snapshot::index_map_json(&self.next_index)),
+            ("match_index", snapshot::index_map_json(&self.match_index)),
+            ("last_ack", snapshot::instant_map_json(&self.last_ack, base)),
+            ("read_round", self.read_round.into()),
+            ("round_wanted", self.round_wanted.into()),
+            ("round_acks", snapshot::index_map_json(&self.round_acks)),
+            ("draining", self.draining.into()),
+            ("transfer_sent", self.transfer_sent.map(|t| t - base).into()),
+            (
+                "snapshot_offset",
+                snapshot::index_map_json(&self.snapshot_offset),
+            ),
+            ("catch_up", snapshot::catch_up_json(&self.catch_up, base)),
+            ("ack_times", snapshot::ack_times_json(&self.ack_times, base)),
+        ])
+    }
+
+    fn leader_hint(&self, s: &State) -> Option<u32> {
+        Some(s.id)
+    }
+
+    fn propose(
+        &mut self,
+        command: Vec<u8>,
+        session: Option<(u32, u64)>,
+        at: Instant,
+        s: &mut State,
+    ) -> Option<(usize, Outbox)> {
+        if self.draining {
+            return None;
+        }
+        let index = s.push_entry(command, None, session);
+        self.note_acks(s.id, index - 1, index, at, s);
+        self.wake(at, s);
+        // A lone leader is its own majority.
+        self.maybe_advance_commit(at, s);
+        if s.options.batch_appends {
+            return Some((index, vec![]));
+        }
+        Some((index, self.replicate(s)))
+    }
+
+    fn read_index(&mut self, at: Instant, s: &mut State) -> Option<(usize, usize)> {
+        // Until an entry of this term commits we may not know the full commit
+        // index, so read at the end of the log, adding a no-op if needed.
+        let index = if s.term_at(s.commit_index) == Some(s.current_term) {
+            s.commit_index
+        } else {
+            if s.last_log_term() != s.current_term {
+                let index = s.append(vec![]);
+                self.note_acks(s.id, index - 1, index, at, s);
+                self.maybe_advance_commit(at, s);
+            }
+            s.last_log_index()
+        };
+        self.round_wanted = true;
+        Some((index, self.read_round + 1))
+    }
+
+    fn confirmed_round(&self, s: &State) -> usize {
+        Leader::confirmed_round(self, s)
+    }
+
+    fn replicas(&self, index: usize, s: &State) -> Option<usize> {
+        let followers = self.match_index.values().filter(|&&m| m >= index).count();
+        Some(usize::from(s.last_log_index() >= index) + followers)
+    }
+
+    fn agreed_index(&self, s: &State) -> Option<usize> {
+        let matched = s.peers.iter().map(|&p| (p, self.match_index[&p]));
+        Some(s.config.agreed(matched.chain([(s.id, s.last_log_index())])))
+    }
+
+    fn commit_timeline(&self, index: usize) -> Option<Vec<(u32, Instant)>> {
+        Some(Leader::commit_timeline(self, index))
+    }
+
+    fn replication_progress(&self) -> Option<Vec<(u32, usize, usize)>> {
+        let mut progress: Vec<_> = self
+            .next_index
+            .iter()
+            .map(|(&p, &next)| (p, next, self.match_index[&p]))
+            .collect();
+        progress.sort_unstable();
+        Some(progress)
+    }
+
+    fn change_config(
+        &mut self,
+        change: &ConfigChange,
+        at: Instant,
+        s: &mut State,
+    ) -> Result<(usize, Outbox), ConfigError> {
+        if s.config_index() > s.commit_index {
+            return Err(ConfigError::ChangeInProgress(s.config_index()));
+        }
+        let change = if s.options.learner_catch_up {
+            change.learners_first(&s.config)
+        } else {
+            change.clone()
+        };
+        validate_config_change(&s.config, &change)?;
+        let index = s.append_config(s.config.apply(&change))?;
+        self.note_acks(s.id, index - 1, index, at, s);
+        self.wake(at, s);
+        self.sync_peers(at, s);
+        self.maybe_advance_commit(at, s);
+        Ok((index, self.replicate(s)))
+    }
+
+    fn leader_alive(&self, _at: Instant) -> bool {
+        true
+    }
+
+    fn drain(&mut self, _at: Instant, s: &mut State) -> Option<DrainHandle> {
+        if s.peers.is_empty() {
+            return None;
+        }
+        self.draining = true;
+        Some(DrainHandle {
+            node: s.id,
+            term: s.current_term,
+        })
+    }
+}
+
+pub struct Machine {
+    pub role: Box<dyn Role>,
+    pub last_tick: Instant,
+    /// What role changes are timed from in storage; in a simulation, its start.
+    pub epoch: Instant,
+    pub state: State,
+    pub state_machine: Box<dyn StateMachine>,
+    /// Client requests waiting for their entry to be applied, by log index.
+    pub pending_clients: HashMap<usize, PendingClient>,
+    /// Reads waiting for their round to be confirmed and index applied.
+    pub pending_reads: Vec<PendingRead>,
+    /// Successful writes not yet answered because too few nodes store them
+    /// for `min_ack_replicas`.
+    pub pending_acks: Vec<PendingAck>,
+    /// Filled in as entries proposed with `dedup_writes` are applied, so
+    /// every node holds the same table for the same `last_applied`.
+    pub sessions: Sessions,
+    pub storage: Box<dyn Storage>,
+    /// Output that may only leave once the write it depends on is durable,
+    /// in the order it was produced; `None` waits for the pending batch.
+    pub held: Vec<(Option<Instant>, Outbox)>,
+    /// Records buffered for the next group commit.
+    pub batch: Vec<WalRecord>,
+    /// A write failed and its records went back into `batch`; later records
+    /// queue behind them there until `flush` gets them all written.
+    pub write_failed: bool,
+    /// Role changes not yet written; nothing waits for them.
+    pub audit: Vec<WalRecord>,
+    /// Called with `(old_role, new_role, term)` on every role change.
+    pub observers: Vec<RoleObserver>,
+    /// Called with newly applied entries, one at a time or in batches as
+    /// `batch_commit_notifications` says.
+    pub commit_observers: Vec<CommitObserver>,
+    /// When each not yet committed index was appended while leading.
+    pub append_times: BTreeMap<usize, Instant>,
+    /// Append-to-commit delay of every entry committed while leading.
+    pub commit_latencies: Vec<Duration>,
+    /// Messages this node has released to the network, held ones once
+    /// durable.
+    pub messages_sent: u64,
+    /// Set once the state machine panics; nothing is applied after that.
+    pub apply_error: Option<ApplyError>,
+    /// Entries applied since the last tick, against the state machine's
+    /// `apply_budget`.
+    pub applied_since_tick: usize,
+    /// Last `last_applied` written to storage.
+    pub saved_applied: usize,
+    /// Last `commit_index` written to storage, with `persist_commit_index`.
+    pub saved_commit: usize,
+    /// When `last_applied` last advanced or was caught up with the commit
+    /// index, for `max_apply_stall`.
+    pub apply_progress_at: Instant,
+    /// Every alert raised, oldest first.
+    pub alerts: Vec<Alert>,
+    /// Last term in which this node, as a new leader, compacted for
+    /// `snapshot_on_election`.
+    pub election_snapshot_term: usize,
+}
+
+pub type RoleObserver = Box<dyn FnMut(&str, &str, usize)>;
+
+pub type CommitObserver = Box<dyn FnMut(&[LogEntry])>;
+
+/// Something an operator should look at; nothing changes in behaviour.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Alert {
+    /// `failed` elections in a row ended without a leader; raised once per
+    /// streak, as the next one starts in `term`.
+    StuckElecting { term: usize, failed: u32 },
+}
+
+pub struct PendingRead {
+    pub client_id: u32,
+    pub request_id: u64,
+    pub queries: Vec<Vec<u8>>,
+    /// Answer with every query's result packed together, as a read
+    /// transaction, rather than with the single query's result.
+    pub txn: bool,
+    pub read_index: usize,
+    pub round: usize,
+    /// Term the read arrived in; serving it in a later term is unsafe.
+    pub term: usize,
+}
+
+pub struct PendingAck {
+    pub index: usize,
+    pub client_id: u32,
+    pub request_id: u64,
+    pub result: Vec<u8>,
+}
+
+pub struct PendingClient {
+    pub client_id: u32,
+    pub request_id: u64,
+    /// Term the entry was appended in; if a different entry ends up at the
+    /// same index the request was lost with a leadership change.
+    pub term: usize,
+    /// Answer once the entry is applied, with its result, rather than as
+    /// soon as it commits.
+    pub wait_for_apply: bool,
+}
+
+/// Tracks a leader handing over leadership; see `Machine::drain`.
+#[derive(Debug, Clone, Copy)]
+pub struct DrainHandle {
+    pub node: u32,
+    pub term: usize,
+}
+
+impl DrainHandle {
+    /// True once `machine`, the drained node, follows a different leader.
+    pub fn is_complete(&self, machine: &Machine) -> bool {
+        machine.state.current_term > self.term
+            && machine
+                .role
+                .leader_hint(&machine.state)
+                .is_some_and(|leader| leader != self.node)
+    }
+}
+
+impl Machine {
+    pub fn new(
+        id: u32,
+        peers: Vec<u32>,
+        options: Options,
+        seed: u64,
+        at: Instant,
+    ) -> Result<Self, OptionsError> {
+        let state_machine = Box::new(KvStateMachine::default());
+        Self::with_state_machine(id, peers, options, seed, at, state_machine)
+    }
+
+    /// Fails, instead of building a node, if `options` do not pass
+    /// `Options::validate`.
+    pub fn with_state_machine(
+        id: u32,
+        peers: Vec<u32>,
+        options: Options,
+        seed: u64,
+        at: Instant,
+        state_machine: Box<dyn StateMachine>,
+    ) -> Result<Self, OptionsError> {
+        options.validate()?;
+        let mut state = State::new(id, peers, options, seed);
+        Ok(Machine {
+            role: state.initial_role(at),
+            last_tick: at,
+            epoch: at,
+            state,
+            state_machine,
+            pending_clients: HashMap::new(),
+            pending_reads: vec![],
+            pending_acks: vec![],
+            sessions: Sessions::new(),
+            storage: Box::new(MemStorage::default()),
+            held: vec![],
+            batch: vec![],
+            write_failed: false,
+            audit: vec![],
+            observers: vec![],
+            commit_observers: vec![],
+            append_times: BTreeMap::new(),
+            commit_latencies: vec![],
+            messages_sent: 0,
+            apply_error: None,
+            applied_since_tick: 0,
+            saved_applied: 0,
+            saved_commit: 0,
+            apply_progress_at: at,
+            alerts: vec![],
+            election_snapshot_term: 0,
+        })
+    }
+
+    /// Node `id` holding `log`, following in the term of its last entry;
+    /// for testing one role on its own without running an election.
+    pub fn as_follower(id: u32, peers: Vec<u32>, log: Vec<LogEntry>, at: Instant) -> Self {
+        let mut m = Machine::new(id, peers, Options::default(), 0, at).unwrap();
+        m.state
+            .advance_term(log.last().map_or(1, |e| e.term.max(1)));
+        m.state.log = log;
+        m.state.refresh_config();
+        m
+    }
+
+    /// Like `as_follower`, but campaigning in the next term, with no votes
+    /// asked for yet.
+    pub fn as_candidate(id: u32, peers: Vec<u32>, log: Vec<LogEntry>, at: Instant) -> Self {
+        let mut m = Self::as_follower(id, peers, log, at);
+        m.role = Box::new(Candidate::new(at, &mut m.state));
+        m
+    }
+
+    /// Like `as_follower`, but leading that term, with every peer's next
+    /// index just past the log as after an election.
+    pub fn as_leader(id: u32, peers: Vec<u32>, log: Vec<LogEntry>, at: Instant) -> Self {
+        let mut m = Self::as_follower(id, peers, log, at);
+        m.state.set_voted_for(Some(id));
+        m.role = Box::new(Leader::new(at, &mut m.state));
+        m
+    }
+
+    /// Rebuilds a crashed node from its write-ahead log at `path`: term,
+    /// vote and log as they were, and the state machine as of the last
+    /// recorded `last_applied`. The recovered node keeps logging to `path`;
+    /// with no log there yet, it starts out fresh.
+    pub fn recover_from_wal(
+        path: impl AsRef<Path>,
+        id: u32,
+        peers: Vec<u32>,
+        options: Options,
+        seed: u64,
+        at: Instant,
+    ) -> io::Result<Self> {
+        let wal = FileStorage::open(&path)?;
+        let records = storage::read_wal(&path)?;
+        let mut machine = Machine::new(id, peers, options, seed, at)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+        machine.replay(&records);
+        machine.storage = Box::new(wal);
+        Ok(machine)
+    }
+
+    /// Applies `records` to a fresh machine, as if they had been loaded from its storage.
+    ///
+    /// `last_applied` is volatile, so the state machine is rebuilt from the
+    /// latest snapshot by applying each entry after it exactly once, up to
+    /// the last `Applied` or `Committed` record, whichever is further:
+    /// either was committed, and nothing later is known to be. Entries at or
+    /// below `last_applied` are never applied again, so replaying into a
+    /// machine that has already recovered leaves its state machine as it was.
+    pub fn replay(&mut self, records: &[WalRecord]) {
+        let s = &mut self.state;
+        let mut applied = 0;
+        let mut committed = 0;
+        for record in records {
+            let base = s.snapshot.last_index;
+            match record {
+                WalRecord::HardState { term, voted_for } => {
+                    if *term > s.current_term {
+                        s.advance_term(*term);
+                    }
+                    if *voted_for != s.voted_for {
+                        s.set_voted_for(*voted_for);
+                    }
+                }
+                WalRecord::Append(entry) if entry.index > base => {
+                    s.log.truncate(entry.index - base - 1);
+                    s.log.push(entry.clone());
+                }
+                WalRecord::Append(_) => {}
+                WalRecord::TruncateFrom(index) => s.log.truncate(index.saturating_sub(base + 1)),
+                WalRecord::Applied(index) => applied = *index,
+                WalRecord::Committed(index) => committed = *index,
+                WalRecord::RoleChanged { .. } => {}
+                WalRecord::Snapshot(snapshot) => {
+                    s.log.drain(..(snapshot.last_index - base).min(s.log.len()));
+                    s.snapshot = snapshot.clone();
+                    self.state_machine.restore(&snapshot.data);
+                    self.sessions = snapshot.sessions.clone();
+                    s.last_applied = snapshot.applied;
+                    applied = applied.max(snapshot.applied);
+                }
+            }
+        }
+        debug_assert_eq!(storage::validate_log(&s.log), Ok(()));
+        s.refresh_config();
+        // Applied entries were committed; a lost tail of the log cannot be.
+        s.commit_index = applied.max(committed).min(s.last_log_index());
+        self.apply_committed(self.last_tick);
+        self.saved_applied = self.state.last_applied;
+        self.saved_commit = self.state.commit_index;
+        self.role = self.state.initial_role(self.last_tick);
+    }
+
+    /// Why applying stopped, if the state machine panicked on an entry.
+    pub fn apply_error(&self) -> Option<&ApplyError> {
+        self.apply_error.as_ref()
+    }
+
+    /// Panics in debug builds if this node's own state is inconsistent:
+    /// commits past the log, applies past the commit index, campaigns or
+    /// leads without having voted for itself, or campaigns without a vote in
+    /// the configuration. Cheap enough for every step.
+    pub fn debug_check_invariants(&self) {
+        let s = &self.state;
+        debug_assert!(
+            s.commit_index <= s.last_log_index(),
+            "node {}: commit index {} past the end of the log at {}",
+            s.id,
+            s.commit_index,
+            s.last_log_index()
+        );
+        debug_assert!(
+            s.last_applied <= s.commit_index,
+            "node {}: applied {} past commit index {}",
+            s.id,
+            s.last_applied,
+            s.commit_index
+        );
+        debug_assert!(
+            s.snapshot.last_index <= s.last_applied,
+            "node {}: snapshot at {} past applied {}",
+            s.id,
+            s.snapshot.last_index,
+            s.last_applied
+        );
+        debug_assert_eq!(s.last_log_index(), s.snapshot.last_index + s.log.len());
+        if matches!(self.role_name(), "Candidate" | "Leader") {
+            debug_assert_eq!(
+                s.voted_for,
+                Some(s.id),
+                "node {}: {} in term {} without its own vote",
+                s.id,
+                self.role_name(),
+                s.current_term
+            );
+        }
+        debug_assert!(
+            s.is_voter() || !matches!(self.role_name(), "PreCandidate" | "Candidate"),
+            "node {}: {} in term {} without a vote in {:?}",
+            s.id,
+            self.role_name(),
+            s.current_term,
+            s.config
+        );
+    }
+
+    /// Checks, in debug builds, that `entry` is the next one to apply and
+    /// is committed.
+    pub fn debug_check_apply(&self, entry: &LogEntry) {
+        let s = &self.state;
+        debug_assert_eq!(
+            entry.index,
+            s.last_applied + 1,
+            "node {}: applying entry {} out of order after {}",
+            s.id,
+            entry.index,
+            s.last_applied
+        );
+        debug_assert!(
+            entry.index <= s.commit_index,
+            "node {}: applying entry {} past commit index {}",
+            s.id,
+            entry.index,
+            s.commit_index
+        );
+    }
+
+    /// The entry at `index` for callers outside Raft, saying why there is
+    /// none; index 0, before the first entry, counts as compacted.
+    pub fn entry_at(&self, index: usize) -> EntryLookup<'_> {
+        if index <= self.state.snapshot.last_index {
+            return EntryLookup::Compacted;
+        }
+        self.state
+            .entry(index)
+            .map_or(EntryLookup::NotYetExists, EntryLookup::Present)
+    }
+
+    /// Whether `index` is known to be committed here; compacted entries are.
+    pub fn is_committed(&self, index: usize) -> bool {
+        index <= self.state.commit_index
+    }
+
+    pub fn is_applied(&self, index: usize) -> bool {
+        index <= self.state.last_applied
+    }
+
+    /// Entries accepted into the log but not yet known to be committed.
+    pub fn uncommitted(&self) -> &[LogEntry] {
+        let s = &self.state;
+        &s.log[(s.commit_index - s.snapshot.last_index).min(s.log.len())..]
+    }
+
+    /// Commands of the committed entries still in the log, in commit order;
+    /// the ones before them are summarized by `state.snapshot.data`, so a
+    /// consumer replaying the sequence starts from that. Membership entries
+    /// and a new leader's no-ops are not commands and are skipped.
+    pub fn committed_commands(&self) -> impl Iterator<Item = &[u8]> {
+        self.commands_through(self.state.commit_index)
+    }
+
+    /// Like `committed_commands`, but only up to `index`. Entries the
+    /// snapshot data already reflects, which the log may still hold when it
+    /// was compacted short of `last_applied`, are skipped too.
+    pub fn commands_through(&self, index: usize) -> impl Iterator<Item = &[u8]> {
+        let s = &self.state;
+        s.log[..(index - s.snapshot.last_index).min(s.log.len())]
+            .iter()
+            .filter(move |e| e.index > s.snapshot.applied)
+            .filter(|e| e.config.is_none() && !e.command.is_empty())
+            .map(|e| e.command.as_slice())
+    }
+
+    /// Replays the applied commands against a fresh state machine, starting
+    /// from the snapshot if there is one, and checks it ends up in the same
+    /// state as the live one: false means applying is not deterministic, and
+    /// replicas applying the same log could diverge. Panics if the state
+    /// machine cannot make a fresh instance.
+    pub fn verify_determinism(&self) -> bool {
+        let mut replay = self
+            .state_machine
+            .fresh()
+            .expect("state machine cannot make a fresh instance to replay against");
+        let s = &self.state;
+        if s.snapshot.applied > 0 {
+            replay.restore(&s.snapshot.data);
+        }
+        for command in self.commands_through(s.last_applied) {
+            replay.apply(command);
+        }
+        replay.snapshot() == self.state_machine.snapshot()
+    }
+
+    /// The entries `entries_from(index)` would return, borrowed and marked
+    /// committed or applied as of now, for display and export.
+    pub fn log_view(&self, index: usize) -> impl Iterator<Item = LogView<'_>> {
+        let base = self.state.snapshot.last_index;
+        self.state
+            .log
+            .get(index.max(base + 1) - base - 1..)
+            .unwrap_or_default()
+            .iter()
+            .map(|entry| LogView {
+                entry,
+                committed: self.is_committed(entry.index),
+                applied: self.is_applied(entry.index),
+            })
+    }
+
+    /// Discards the log up to and including `up_to` in favour of a snapshot
+    /// of the state machine. Only applied entries may go: in debug builds a
+    /// boundary past `last_applied` panics, otherwise it is cut back to it.
+    pub fn force_compact(&mut self, up_to: usize) {
+        debug_assert!(
+            up_to <= self.state.last_applied,
+            "node {}: compacting to {} past last_applied {}",
+            self.state.id,
+            up_to,
+            self.state.last_applied
+        );
+        let up_to = up_to.min(self.state.last_applied);
+        if up_to > self.state.snapshot.last_index {
+            let data = self.state_machine.snapshot();
+            self.state.compact(up_to, data, self.sessions.clone());
+        }
+        self.state.debug_check_offsets();
+    }
+
+    /// The leader's view of how far each follower has got; `None` unless
+    /// this node leads.
+    pub fn replication_progress(&self) -> Option<Vec<(u32, usize, usize)>> {
+        self.role.replication_progress()
+    }
+
+    /// When each node stored entry `index`, earliest first; `None` unless
+    /// this node leads.
+    pub fn commit_timeline(&self, index: usize) -> Option<Vec<(u32, Instant)>> {
+        self.role.commit_timeline(index)
+    }
+
+    pub fn debug_state(&self) -> RoleDebug {
+        self.role.debug_state()
+    }
+
+    pub fn commit_latencies(&self) -> &[Duration] {
+        &self.commit_latencies
+    }
+
+    /// Notes when new entries were appended and how long committed ones took.
+    /// Only a leader tracks this; entries it did not see commit are forgotten.
+    pub fn track_commits(&mut self, at: Instant) {
+        if self.role.name() != "Leader" {
+            self.append_times.clear();
+            return;
+        }
+        let s = &self.state;
+        let first_uncommitted = s.commit_index - s.snapshot.last_index;
+        for entry in &s.log[first_uncommitted.min(s.log.len())..] {
+            if entry.term == s.current_term {
+                self.append_times.entry(entry.index).or_insert(at);
+            }
+        }
+        let pending = self.append_times.split_off(&(s.commit_index + 1));
+        for appended in std::mem::replace(&mut self.append_times, pending).into_values() {
+            self.commit_latencies.push(at - appended);
+        }
+    }
+
+    /// Registers `f` to be told about every role change of this node.
+    pub fn observe(&mut self, f: RoleObserver) {
+        self.observers.push(f);
+    }
+
+    /// Registers `f` to be given entries as this node applies them.
+    pub fn on_commit(&mut self, f: CommitObserver) {
+        self.commit_observers.push(f);
+    }
+
+    pub fn notify_commit(&mut self, entries: &[LogEntry]) {
+        for f in &mut self.commit_observers {
+            f(entries);
+        }
+    }
+
+    pub fn set_role(&mut self, new_role: Box<dyn Role>, at: Instant) {
+        let old = self.role.name();
+        self.role = new_role;
+        self.audit.push(WalRecord::RoleChanged {
+            at: at.saturating_duration_since(self.epoch),
+            term: self.state.current_term,
+            from: old.to_string(),
+            to: self.role.name().to_string(),
+        });
+        for f in &mut self.observers {
+            f(old, self.role.name(), self.state.current_term);
+        }
+        let failed = self.state.elections_without_leader.saturating_sub(1);
+        if self.role.name() == "Candidate" && Some(failed) == self.state.options.max_election_rounds
+        {
+            let alert = Alert::StuckElecting {
+                term: self.state.current_term,
+                failed,
+            };
+            eprintln!("warning: node {}: {:?}", self.state.id, alert);
+            self.alerts.push(alert);
+        }
+    }
+
+    pub fn hard_state(&self) -> (usize, Option<u32>) {
+        (self.state.current_term, self.state.voted_for)
+    }
+
+    /// Hands this step's changes to storage. `out` answers requests, so it is
+    /// held back until those changes are durable.
+    pub fn persist(&mut self, at: Instant, before: (usize, Option<u32>), out: Outbox) -> Outbox {
+        let mut records = vec![];
+        if self.hard_state() != before {
+            records.push(WalRecord::HardState {
+                term: self.state.current_term,
+                voted_for: self.state.voted_for,
+            });
+        }
+        records.append(&mut self.state.unsaved);
+        // Apply and commit progress only speed up recovery, and role changes
+        // are only there for post-mortems, so nothing waits for them.
+        let mut unawaited = std::mem::take(&mut self.audit);
+        unawaited.extend(
+            (self.state.options.persist_commit_index
+                && self.state.commit_index > self.saved_commit)
+                .then_some(WalRecord::Committed(self.state.commit_index)),
+        );
+        self.saved_commit = self.state.commit_index;
+        unawaited.extend(
+            (self.state.last_applied > self.saved_applied)
+                .then_some(WalRecord::Applied(self.state.last_applied)),
+        );
+        self.saved_applied = self.state.last_applied;
+        let durable_at = if self.state.options.batch_writes {
+            self.batch.extend(records);
+            let durable_at = (self.batch.is_empty()).then_some(at);
+            self.batch.extend(unawaited);
+            durable_at
+        } else {
+            let batch_votes = self.state.options.vote_persistence == VotePersistence::Batched;
+            let mut durable_at = Some(at);
+            for record in records {
+                if self.write_failed
+                    || (batch_votes && matches!(record, WalRecord::HardState { .. }))
+                {
+                    self.batch.push(record);
+                    continue;
+                }
+                match self.storage.save(std::slice::from_ref(&record), at) {
+                    Ok(t) => durable_at = Some(t),
+                    Err(error) => {
+                        eprintln!("warning: node {} failed to write: {}", self.state.id, error);
+                        self.write_failed = true;
+                        self.batch.push(record);
+                    }
+                }
+            }
+            if self.write_failed {
+                self.batch.extend(unawaited);
+            } else if !unawaited.is_empty() {
+                if let Err(error) = self.storage.save(&unawaited, at) {
+                    eprintln!("warning: node {} failed to write: {}", self.state.id, error);
+                }
+            }
+            // Held until `flush` writes the vote, or what failed to be
+            // written, and says when it is durable.
+            durable_at.filter(|_| self.batch.is_empty())
+        };
+        if durable_at.is_some_and(|t| t <= at) && self.held.is_empty() {
+            self.messages_sent += out.len() as u64;
+            return out;
+        }
+        if !out.is_empty() {
+            self.held.push((durable_at, out));
+        }
+        vec![]
+    }
+
+    /// Writes the buffered batch in one go and releases whatever is durable.
+    pub fn flush(&mut self, at: Instant) -> Outbox {
+        if !self.batch.is_empty() {
+            match self.storage.save(&self.batch, at) {
+                Ok(durable_at) => {
+                    self.batch.clear();
+                    self.write_failed = false;
+                    for (t, _) in &mut self.held {
+                        t.get_or_insert(durable_at);
+                    }
+                }
+                // Nothing waiting on the batch leaves until a retry works.
+                Err(error) => {
+                    eprintln!("warning: node {} failed to write: {}", self.state.id, error)
+                }
+            }
+        }
+        let due = self
+            .held
+            .iter()
+            .take_while(|(t, _)| t.is_some_and(|t| t <= at))
+            .count();
+        let out: Outbox = self.held.drain(..due).flat_map(|(_, out)| out).collect();
+        self.messages_sent += out.len() as u64;
+        out
+    }
+
+    pub fn tick(&mut self, at: Instant) -> Outbox {
+        let before = self.hard_state();
+        if let Some(new_role) = self.role.tick(at, &mut self.state) {
+            self.set_role(new_role, at);
+        }
+        self.last_tick = at;
+        self.applied_since_tick = 0;
+        let mut out = self.role.tick_msg(at, &mut self.state);
+        out.extend(self.apply_committed(at));
+        let mut out = self.persist(at, before, out);
+        out.extend(self.flush(at));
+        self.check_apply_stall(at);
+        self.debug_check_invariants();
+        out
+    }
+
+    /// When this node next needs a tick: at once while it holds output for
+    /// a write, has records waiting for a group commit or committed entries
+    /// left to apply; otherwise when its role asks.
+    pub fn next_tick(&self, now: Instant) -> Instant {
+        if !self.held.is_empty()
+            || !self.batch.is_empty()
+            || self.state.last_applied < self.state.commit_index
+        {
+            return now;
+        }
+        self.role.next_tick(now)
+    }
+
+    /// With `max_apply_stall`, starts handing leadership over once applying
+    /// has made no progress for that long.
+    pub fn check_apply_stall(&mut self, at: Instant) {
+        let Some(window) = self.state.options.max_apply_stall else {
+            return;
+        };
+        if self.role_name() == "Leader"
+            && at.saturating_duration_since(self.apply_progress_at) >= window
+        {
+            self.role.drain(at, &mut self.state);
+        }
+    }
+
+    /// Appends `command` if this node leads and can take it, on the same
+    /// terms as a client's write; returns its log index.
+    pub fn propose(&mut self, command: Vec<u8>, at: Instant) -> Result<(usize, Outbox), RaftError> {
+        self.check_write(&command)?;
+        let before = self.hard_state();
+        // Only a draining leader refuses past `check_write`.
+        let (index, mut out) = self
+            .role
+            .propose(command, None, at, &mut self.state)
+            .ok_or(RaftError::Busy)?;
+        out.extend(self.apply_committed(at));
+        Ok((index, self.persist(at, before, out)))
+    }
+
+    /// Applies newly committed entries, answering any client waiting on them.
+    pub fn apply_committed(&mut self, at: Instant) -> Outbox {
+        self.track_commits(at);
+        let applied_before = self.state.last_applied;
+        let mut out = vec![];
+        if self.state.snapshot.applied > self.state.last_applied {
+            // A snapshot from the leader replaces everything applied here.
+            self.state_machine.restore(&self.state.snapshot.data);
+            self.sessions = self.state.snapshot.sessions.clone();
+            self.state.last_applied = self.state.snapshot.applied;
+        }
+        out.extend(self.answer_committed());
+        let batch = self.state.options.batch_commit_notifications;
+        let mut applied = vec![];
+        let budget = self.state_machine.apply_budget().unwrap_or(usize::MAX);
+        while self.apply_error.is_none()
+            && self.state.last_applied < self.state.commit_index
+            && self.applied_since_tick < budget
+        {
+            self.applied_since_tick += 1;
+            let index = self.state.last_applied + 1;
+            let entry = self.state.entry(index).expect("applying a compacted entry");
+            self.debug_check_apply(entry);
+            let applied_before = entry.session.and_then(|(client_id, request_id)| {
+                let (id, result) = self.sessions.get(&client_id)?;
+                (*id == request_id).then(|| result.clone())
+            });
+            let result = if let Some(result) = applied_before {
+                // A retry committed again; the first copy already took effect.
+                result
+            } else if entry.config.is_none() {
+                let state_machine = &mut self.state_machine;
+                match panic::catch_unwind(AssertUnwindSafe(|| state_machine.apply(&entry.command)))
+                {
+                    Ok(result) => result,
+                    Err(payload) => {
+                        let message = payload
+                            .downcast_ref::<&str>()
+                            .map(|m| m.to_string())
+                            .or_else(|| payload.downcast_ref::<String>().cloned())
+                            .unwrap_or_else(|| "unknown panic".into());
+                        let error = ApplyError { index, message };
+                        eprintln!("error: node {} stopped applying: {}", self.state.id, error);
+                        self.apply_error = Some(error);
+                        break;
+                    }
+                }
+            } else {
+                vec![]
+            };
+            if let Some((client_id, request_id)) = entry.session {
+                self.sessions
+                    .insert(client_id, (request_id, result.clone()));
+            }
+            if let Some(pending) = self.pending_clients.remove(&index) {
+                let success = pending.term == entry.term;
+                out.extend(self.answer_write(index, pending, success, result));
+            }
+            self.state.last_applied = index;
+            if !self.commit_observers.is_empty() {
+                let entry = self.state.entry(index).unwrap().clone();
+                if batch {
+                    applied.push(entry);
+                } else {
+                    self.notify_commit(&[entry]);
+                }
+            }
+        }
+        if !applied.is_empty() {
+            self.notify_commit(&applied);
+        }
+        if self.state.last_applied > applied_before
+            || self.state.last_applied == self.state.commit_index
+        {
+            self.apply_progress_at = at;
+        }
+        self.snapshot_on_election();
+        out.extend(self.release_acks());
+        out.extend(self.serve_reads());
+        out
+    }
+
+    /// With `snapshot_on_election`, compacts once per term as soon as the
+    /// leader has applied an entry of its own term, and so knows its commit
+    /// index is the cluster's.
+    pub fn snapshot_on_election(&mut self) {
+        let s = &self.state;
+        if !s.options.snapshot_on_election
+            || self.election_snapshot_term == s.current_term
+            || self.role_name() != "Leader"
+            || s.term_at(s.last_applied) != Some(s.current_term)
+        {
+            return;
+        }
+        self.election_snapshot_term = s.current_term;
+        self.force_compact(self.state.last_applied);
+    }
+
+    /// Answers the writes that committed but are not applied yet, where the
+    /// client did not ask to wait for the result.
+    pub fn answer_committed(&mut self) -> Outbox {
+        let (applied, committed) = (self.state.last_applied, self.state.commit_index);
+        let mut ready: Vec<usize> = self
+            .pending_clients
+            .iter()
+            .filter(|&(&i, p)| !p.wait_for_apply && i > applied && i <= committed)
+            .map(|(&i, _)| i)
+            .collect();
+        ready.sort_unstable();
+        let mut out = vec![];
+        for index in ready {
+            let pending = self.pending_clients.remove(&index).unwrap();
+            let success = self.state.term_at(index) == Some(pending.term);
+            out.extend(self.answer_write(index, pending, success, vec![]));
+        }
+        out
+    }
+
+    /// Answers the client that proposed the entry at `index`, unless it is
+    /// held back for `min_ack_replicas`.
+    pub fn answer_write(
+        &mut self,
+        index: usize,
+        pending: PendingClient,
+        success: bool,
+        result: Vec<u8>,
+    ) -> Outbox {
+        if success && self.state.options.min_ack_replicas.is_some() {
+            self.pending_acks.push(PendingAck {
+                index,
+                client_id: pending.client_id,
+                request_id: pending.request_id,
+                result,
+            });
+            return vec![];
+        }
+        vec![(
+            pending.client_id,
+            Message::ClientResponse {
+                request_id: pending.request_id,
+                success,
+                leader_hint: self.role.leader_hint(&self.state),
+                result: if success { result } else { vec![] },
+                busy: false,
+                reconfiguring: false,
+                too_large: None,
+                staleness: None,
+            },
+        )]
+    }
+
+    /// The answer for a write the client already sent, if `dedup_writes`
+    /// knows it: nothing while this node still owes the first copy an
+    /// answer, else the result in the session table once the write has been
+    /// applied, whichever leader committed it.
+    pub fn answer_duplicate(&self, client_id: u32, request_id: u64) -> Option<Outbox> {
+        if !self.state.options.dedup_writes {
+            return None;
+        }
+        let in_log = self
+            .pending_clients
+            .values()
+            .map(|p| (p.client_id, p.request_id))
+            .chain(
+                self.pending_acks
+                    .iter()
+                    .map(|a| (a.client_id, a.request_id)),
+            )
+            .any(|ids| ids == (client_id, request_id));
+        if in_log {
+            return Some(vec![]);
+        }
+        let (_, result) = self
+            .sessions
+            .get(&client_id)
+            .filter(|(id, _)| *id == request_id)?;
+        Some(vec![(
+            client_id,
+            Message::ClientResponse {
+                request_id,
+                success: true,
+                leader_hint: self.role.leader_hint(&self.state),
+                result: result.clone(),
+                busy: false,
+                reconfiguring: false,
+                too_large: None,
+                staleness: None,
+            },
+        )])
+    }
+
+    /// Answers the writes held for `min_ack_replicas` that are now stored
+    /// widely enough. A node that stopped leading can no longer tell, so it
+    /// refuses them instead; the entries stay committed regardless.
+    pub fn release_acks(&mut self) -> Outbox {
+        let min = self.state.options.min_ack_replicas.unwrap_or(0);
+        let mut out = vec![];
+        let mut waiting = vec![];
+        for ack in std::mem::take(&mut self.pending_acks) {
+            match self.role.replicas(ack.index, &self.state) {
+                Some(n) if n < min => waiting.push(ack),
+                Some(_) => {
+                    out.push((
+                        ack.client_id,
+                        Message::ClientResponse {
+                            request_id: ack.request_id,
+                            success: true,
+                            leader_hint: Some(self.state.id),
+                            result: ack.result,
+                            busy: false,
+                            reconfiguring: false,
+                            too_large: None,
+                            staleness: None,
+                        },
+                    ));
+                }
+                None => out.extend(self.reject_client(ack.client_id, ack.request_id, false)),
+            }
+        }
+        self.pending_acks = waiting;
+        out
+    }
+
+    /// Answers the reads that are safe to serve, and refuses those that never
+    /// will be because leadership moved on.
+    pub fn serve_reads(&mut self) -> Outbox {
+        let leading = self.role.leader_hint(&self.state) == Some(self.state.id);
+        let confirmed = self.role.confirmed_round(&self.state);
+        let mut out = vec![];
+        let mut waiting = vec![];
+        for read in std::mem::take(&mut self.pending_reads) {
+            if !leading || read.term != self.state.current_term {
+                out.extend(self.reject_client(read.client_id, read.request_id, false));
+            } else if read.round <= confirmed && read.read_index <= self.state.last_applied {
+                out.push((
+                    read.client_id,
+                    Message::ClientResponse {
+                        request_id: read.request_id,
+                        success: true,
+                        leader_hint: Some(self.state.id),
+                        result: self.answer_read(&read.queries, read.txn),
+                        busy: false,
+                        reconfiguring: false,
+                        too_large: None,
+                        staleness: None,
+                    },
+                ));
+            } else {
+                waiting.push(read);
+            }
+        }
+        self.pending_reads = waiting;
+        out
+    }
+
+    /// Answers `queries` once a ReadIndex confirms this node still leads,
+    /// or at once under a lease or within `max_staleness`; all of them from
+    /// the same state, packed together if `txn`. With `local_only`, only
+    /// the latter: the read is refused rather than sent through a ReadIndex.
+    pub fn handle_client_read(
+        &mut self,
+        client_id: u32,
+        request_id: u64,
+        queries: Vec<Vec<u8>>,
+        txn: bool,
+        local_only: bool,
+        at: Instant,
+    ) -> Outbox {
+        let staleness = self.role.staleness(at);
+        let leased = self.role.read_lease().is_some_and(|until| at < until);
+        if leased
+            || staleness.is_some_and(|st| {
+                self.state
+                    .options
+                    .max_staleness
+                    .is_some_and(|max| st <= max)
+            })
+        {
+            return vec![(
+                client_id,
+                Message::ClientResponse {
+                    request_id,
+                    success: true,
+                    leader_hint: self.role.leader_hint(&self.state),
+                    result: self.answer_read(&queries, txn),
+                    busy: false,
+                    reconfiguring: false,
+                    too_large: None,
+                    staleness,
+                },
+            )];
+        }
+        if local_only {
+            return self.reject_client(client_id, request_id, false);
+        }
+        let Some((read_index, round)) = self.role.read_index(at, &mut self.state) else {
+            return self.reject_client(client_id, request_id, false);
+        };
+        self.pending_reads.push(PendingRead {
+            client_id,
+            request_id,
+            queries,
+            txn,
+            read_index,
+            round,
+            term: self.state.current_term,
+        });
+        self.apply_committed(at)
+    }
+
+    /// The result of a read: the single query's, or every query's packed
+    /// together for a transaction.
+    pub fn answer_read(&self, queries: &[Vec<u8>], txn: bool) -> Vec<u8> {
+        let mut results = queries.iter().map(|q| self.state_machine.query(q));
+        if txn {
+            wire::encode_results(&results.collect::<Vec<_>>())
+        } else {
+            results.next().unwrap_or_default()
+        }
+    }
+
+    /// Appends a membership change if this node leads; returns its log index.
+    pub fn change_config(
+        &mut self,
+        change: &ConfigChange,
+        at: Instant,
+    ) -> Result<(usize, Outbox), ConfigError> {
+        let before = self.hard_state();
+        let (index, mut out) = self.role.change_config(change, at, &mut self.state)?;
+        out.extend(self.apply_committed(at));
+        Ok((index, self.persist(at, before, out)))
+    }
+
+    /// Prepares this leader for removal: new writes are refused as busy,
+    /// and once every entry is committed leadership moves to a caught-up
+    /// peer. Clients then follow the new leader's hint.
+    pub fn drain(&mut self, at: Instant) -> Option<DrainHandle> {
+        self.role.drain(at, &mut self.state)
+    }
+
+    pub fn status(&self) -> Message {
+        Message::StatusResponse {
+            from: self.state.id,
+            role: self.role.name().to_string(),
+            term: self.state.current_term,
+            commit_index: self.state.commit_index,
+            leader_id: self.role.leader_hint(&self.state),
+            log_len: self.state.last_log_index(),
+        }
+    }
+
+    /// Answers `query` from this node's state machine, however far behind
+    /// it is; the client compares `last_applied` across nodes.
+    pub fn local_read(&self, request_id: u64, query: &[u8]) -> Message {
+        Message::LocalReadResponse {
+            from: self.state.id,
+            request_id,
+            last_applied: self.state.last_applied,
+            result: self.state_machine.query(query),
+        }
+    }
+
+    /// The membership as of the commit index, if leading; a configuration
+    /// still being replicated may yet be lost, so it is not reported.
+    pub fn membership(&self) -> Message {
+        let leader_hint = self.role.leader_hint(&self.state);
+        let (voters, learners) = if leader_hint == Some(self.state.id) {
+            let config = self.state.config_at(self.state.commit_index);
+            (
+                config.voters.into_iter().collect(),
+                config.learners.into_iter().collect(),
+            )
+        } else {
+            (vec![], vec![])
+        };
+        Message::MembershipResponse {
+            from: self.state.id,
+            term: self.state.current_term,
+            voters,
+            learners,
+            leader_hint,
+        }
+    }
+
+    /// Grants a pre-vote if a real vote in the proposed term could be
+    /// granted and no leader is known to be alive.
+    pub fn answer_pre_vote(&self, msg: &Message, at: Instant) -> Outbox {
+        let Message::PreVoteRequest {
+            term,
+            candidate_id,
+            last_log_index,
+            last_log_term,
+        } = *msg
+        else {
+            return vec![];
+        };
+        let s = &self.state;
+        let vote_granted = term > s.current_term
+            && !self.role.leader_alive(at)
+            && log_is_up_to_date(
+                last_log_term,
+                last_log_index,
+                s.last_log_term(),
+                s.last_log_index(),
+            );
+        vec![(
+            candidate_id,
+            Message::PreVoteResponse {
+                term,
+                vote_granted,
+                from: s.id,
+            },
+        )]
+    }
+
+    pub fn reject_client(&self, client_id: u32, request_id: u64, busy: bool) -> Outbox {
+        vec![(
+            client_id,
+            Message::ClientResponse {
+                request_id,
+                success: false,
+                leader_hint: self.role.leader_hint(&self.state),
+                result: vec![],
+                busy,
+                reconfiguring: false,
+                too_large: None,
+                staleness: None,
+            },
+        )]
+    }
+
+    /// Why this node cannot take `command` right now, if it cannot: it does
+    /// not lead, the command exceeds `max_entry_size`, the leader has too
+    /// much uncommitted, or is changing membership with
+    /// `reject_writes_during_reconfig`.
+    pub fn check_write(&self, command: &[u8]) -> Result<(), RaftError> {
+        match self.role.leader_hint(&self.state) {
+            None => return Err(RaftError::NoLeader),
+            Some(leader) if leader != self.state.id => {
+                return Err(RaftError::LeadershipLost {
+                    leader_hint: Some(leader),
+                })
+            }
+            Some(_) => {}
+        }
+        if let Some(max) = self.state.options.max_entry_size {
+            if command.len() > max {
+                return Err(RaftError::EntryTooLarge { max });
+            }
+        }
+        let uncommitted = self.state.last_log_index() - self.state.commit_index;
+        if self
+            .state
+            .options
+            .max_uncommitted
+            .is_some_and(|max| uncommitted >= max)
+        {
+            return Err(RaftError::Busy);
+        }
+        if self.state.options.reject_writes_during_reconfig
+            && self.state.config_index() > self.state.commit_index
+        {
+            return Err(RaftError::Reconfiguring);
+        }
+        Ok(())
+    }
+
+    /// Tells a client its write was refused, and why.
+    pub fn refuse_client(&self, client_id: u32, request_id: u64, error: &RaftError) -> Outbox {
+        vec![(
+            client_id,
+            Message::ClientResponse {
+                request_id,
+                success: false,
+                leader_hint: self.role.leader_hint(&self.state),
+                result: vec![],
+                busy: *error == RaftError::Busy,
+                reconfiguring: *error == RaftError::Reconfiguring,
+                too_large: match *error {
+                    RaftError::EntryTooLarge { max } => Some(max),
+                    _ => None,
+                },
+                staleness: None,
+            },
+        )]
+    }
+
+    pub fn handle_client_request(
+        &mut self,
+        client_id: u32,
+        request_id: u64,
+        command: Vec<u8>,
+        wait_for_apply: bool,
+        at: Instant,
+    ) -> Outbox {
+        if let Some(out) = self.answer_duplicate(client_id, request_id) {
+            return out;
+        }
+        if let Err(error) = self.check_write(&command) {
+            return self.refuse_client(client_id, request_id, &error);
+        }
+        let session = self
+            .state
+            .options
+            .dedup_writes
+            .then_some((client_id, request_id));
+        let Some((index, mut out)) = self.role.propose(command, session, at, &mut self.state)
+        else {
+            // A leader that refuses is draining; leadership moves shortly.
+            return self.refuse_client(client_id, request_id, &RaftError::Busy);
+        };
+        self.pending_clients.insert(
+            index,
+            PendingClient {
+                client_id,
+                request_id,
+                term: self.state.current_term,
+                wait_for_apply,
+            },
+        );
+        out.extend(self.apply_committed(at));
+        out
+    }
+
+    pub fn handle(&mut self, msg: Message, at: Instant) -> Outbox {
+        // Only a network loop or a misconfiguration sends a node its own
+        // messages, and acting on them could corrupt its state.
+        if msg.sender() == Some(self.state.id) {
+            eprintln!("warning: node {} ignored its own {:?}", self.state.id, msg);
+            return vec![];
+        }
+        let before = self.hard_state();
+        let out = self.process(msg, at);
+        let out = self.persist(at, before, out);
+        self.debug_check_invariants();
+        out
+    }
+
+    pub fn process(&mut self, msg: Message, at: Instant) -> Outbox {
+        if let Message::ClientRequest {
+            client_id,
+            request_id,
+            command,
+            wait_for_apply,
+        } = msg
+        {
+            return self.handle_client_request(client_id, request_id, command, wait_for_apply, at);
+        }
+        if let Message::ClientRead {
+            client_id,
+            request_id,
+            query,
+            local_only,
+        } = msg
+        {
+            let queries = vec![query];
+            return self.handle_client_read(client_id, request_id, queries, false, local_only, at);
+        }
+        if let Message::ClientReadTxn {
+            client_id,
+            request_id,
+            queries,
+        } = msg
+        {
+            return self.handle_client_read(client_id, request_id, queries, true, false, at);
+        }
+        if let Message::StatusRequest { from } = msg {
+            return vec![(from, self.status())];
+        }
+        if let Message::LocalRead {
+            client_id,
+            request_id,
+            query,
+        } = msg
+        {
+            return vec![(client_id, self.local_read(request_id, &query))];
+        }
+        if let Message::MembershipRequest { from } = msg {
+            return vec![(from, self.membership())];
+        }
+        if let Message::PreVoteRequest { .. } = msg {
+            return self.answer_pre_vote(&msg, at);
+        }
+        if let Message::PreVoteResponse { .. } = msg {
+            // Only a PreCandidate reacts, and never by adopting the term.
+            if let Some(new_role) = self.role.transition(&msg, at, &mut self.state) {
+                self.set_role(new_role, at);
+            }
+            return vec![];
+        }
+        if self.state.current_term > msg.term() {
+            if let Message::AppendEntryRequest {
+                term,
+                leader_id,
+                prev_log_index,
+                ..
+            } = msg
+            {
+                self.state.reject_append(
+                    term,
+                    leader_id,
+                    prev_log_index,
+                    AppendRejectionReason::StaleTerm,
+                );
+            }
+            // A stale candidate learns the newer term from the refusal and
+            // steps down; other stale messages are dropped.
+            return answer_vote(&msg, &mut self.state).into_iter().collect();
+        }
+        // Neither the term nor the vote changes while the leader is alive.
+        if let Message::RequestVoteRequest {
+            term,
+            candidate_id,
+            forced: false,
+            ..
+        } = msg
+        {
+            if self.state.options.leader_stickiness && self.role.leader_alive(at) {
+                self.state
+                    .deny_vote(term, candidate_id, VoteDenialReason::LeaderContact);
+                return vec![];
+            }
+        }
+        if let Some(max) = self.state.options.max_term_jump {
+            if msg.term() - self.state.current_term > max {
+                eprintln!(
+                    "warning: node {} in term {} dropped {:?}",
+                    self.state.id, self.state.current_term, msg
+                );
+                return vec![];
+            }
+        }
+        // First check if message triggers a role transition
+        if let Some(new_role) = self.role.transition(&msg, at, &mut self.state) {
+            self.set_role(new_role, at);
+        }
+        // Then handle the message with current role
+        let mut out = self.role.handle(msg, at, &mut self.state);
+        out.extend(self.apply_committed(at));
+        out
+    }
+
+    /// Demotes this node if it leads in `term`, so that a new election is
+    /// held; a stale request is ignored. Only the operator asks, through
+    /// `Simulation::step_down`: no message from a peer can do this.
+    pub fn step_down(&mut self, term: usize, at: Instant) -> Outbox {
+        if self.role_name() != "Leader" || term != self.state.current_term {
+            return vec![];
+        }
+        let before = self.hard_state();
+        self.state.advance_term(term + 1);
+        let follower = Follower::new(at, &mut self.state);
+        self.set_role(Box::new(follower), at);
+        let out = self.persist(at, before, vec![]);
+        self.debug_check_invariants();
+        out
+    }
+
+    pub fn id(&self) -> u32 {
+        self.state.id
+    }
+
+    pub fn role_name(&self) -> &'static str {
+        self.role.name()
+    }
+
+    /// `(last_log_index, last_log_term)`, which decides whose vote this node
+    /// can get: candidates need a log at least this up to date.
+    pub fn last_log(&self) -> (usize, usize) {
+        (self.state.last_log_index(), self.state.last_log_term())
+    }
+
+    /// Digest of the term, role, log, commit and apply indexes and state
+    /// machine contents; equal across runs that left this node the same.
+    pub fn state_hash(&self) -> u64 {
+        let s = &self.state;
+        let mut hasher = Fnv1a::default();
+        s.current_term.hash(&mut hasher);
+        self.role_name().hash(&mut hasher);
+        (s.snapshot.last_index, s.snapshot.last_term).hash(&mut hasher);
+        s.log.hash(&mut hasher);
+        (s.commit_index, s.last_applied).hash(&mut hasher);
+        self.state_machine.snapshot().hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+#[derive(Debug)]
+pub struct TimedMessage {
+    pub delivery_time: Instant,
+    /// When it was handed to the network, for its age.
+    pub sent_at: Instant,
+    /// Enqueue order, for the delivery policy to break ties at one instant with.
+    pub seq: u64,
+    pub from: u32,
+    pub to: u32,
+    pub message: Message,
+}
+
+impl PartialEq for TimedMessage {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for TimedMessage {}
+
+impl PartialOrd for TimedMessage {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for TimedMessage {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Reverse ordering for min-heap (earliest messages first)
+        other
+            .delivery_time
+            .cmp(&self.delivery_time)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+/// Decides in which order messages due at the same instant are delivered.
+pub trait DeliveryPolicy {
+    /// `Less` if `a` should be delivered before `b`.
+    fn order(&self, a: &TimedMessage, b: &TimedMessage) -> Ordering;
+
+    /// Order everything due within a step as one batch, instead of instant
+    /// by instant, so messages due at different instants swap places too.
+    fn whole_step(&self) -> bool {
+        false
+    }
+}
+
+/// Oldest first, the order messages were sent in.
+pub struct Fifo;
+
+impl DeliveryPolicy for Fifo {
+    fn order(&self, a: &TimedMessage, b: &TimedMessage) -> Ordering {
+        a.seq.cmp(&b.seq)
+    }
+}
+
+/// Newest first.
+pub struct Lifo;
+
+impl DeliveryPolicy for Lifo {
+    fn order(&self, a: &TimedMessage, b: &TimedMessage) -> Ordering {
+        b.seq.cmp(&a.seq)
+    }
+}
+
+/// Newest first among everything due within a step, whichever instant in it
+/// each message was due at: as out of order as delivery gets without
+/// holding messages back.
+pub struct MaxReorder;
+
+impl DeliveryPolicy for MaxReorder {
+    fn order(&self, a: &TimedMessage, b: &TimedMessage) -> Ordering {
+        b.seq.cmp(&a.seq)
+    }
+
+    fn whole_step(&self) -> bool {
+        true
+    }
+}
+
+/// Lets elections interfere before the heartbeats that would have headed
+/// them off, and delivers replication newest first so followers see gaps
+/// and stale rejections.
+pub struct Adversarial;
+
+impl Adversarial {
+    pub fn rank(msg: &Message) -> u8 {
+        match msg {
+            Message::RequestVoteRequest { .. }
+            | Message::PreVoteRequest { .. }
+            | Message::TimeoutNow { .. } => 0,
+            Message::AppendEntryRequest { .. } | Message::InstallSnapshotRequest { .. } => 2,
+            _ => 1,
+        }
+    }
+}
+
+impl DeliveryPolicy for Adversarial {
+    fn order(&self, a: &TimedMessage, b: &TimedMessage) -> Ordering {
+        Self::rank(&a.message)
+            .cmp(&Self::rank(&b.message))
+            .then_with(|| b.seq.cmp(&a.seq))
+    }
+}
+
+/// Clients get ids from here on so they never collide with nodes.
+pub const CLIENT_ID_BASE: u32 = 1000;
+
+/// Deterministic cluster simulation driven by a logical clock.
+pub struct Simulation {
+    pub machines: BTreeMap<u32, Machine>,
+    pub clients: BTreeMap<u32, Client>,
+    pub queue: BinaryHeap<TimedMessage>,
+    pub start_time: Instant,
+    pub current_time: Instant,
+    pub tick_interval: Duration,
+    pub network_latency: Duration,
+    /// Each message is delayed by a further uniform `[0, network_jitter)`.
+    pub network_jitter: Duration,
+    /// Directed links whose delay is instead normally distributed, as
+    /// `(mean, std_dev)`.
+    pub normal_links: BTreeMap<(u32, u32), (Duration, Duration)>,
+    /// Directed links with limited bandwidth, in bytes per second: each
+    /// message on one takes its encoded length over that to go out, after
+    /// the ones sent before it, and only then starts its latency.
+    pub link_bandwidth: BTreeMap<(u32, u32), u64>,
+    /// When each bandwidth-limited link is done sending what it has queued.
+    pub link_busy_until: BTreeMap<(u32, u32), Instant>,
+    /// Chance that a message is lost on its way, whatever its link.
+    pub loss_rate: f64,
+    /// Drives network randomness; nodes have their own.
+    pub rng: Rng,
+    pub next_seq: u64,
+    /// Directed links currently unable to carry messages.
+    pub cut_links: HashSet<(u32, u32)>,
+    /// `(elapsed, leader, term)`, sampled whenever either changes.
+    pub timeline: Vec<(Duration, Option<u32>, usize)>,
+    /// `(node, term)` of every node in the Leader role at the last step, by
+    /// id, while there was more than one.
+    pub split_brain: Vec<(u32, usize)>,
+    /// Scripted drops still waiting for their message.
+    pub drop_rules: Vec<DropRule>,
+    /// Scripted delivery order; messages it holds back wait in the queue.
+    pub scenario: Scenario,
+    /// Frozen nodes: they are not ticked and their messages wait in the queue.
+    pub paused: HashSet<u32>,
+    /// Nodes ticking slower than every step, by how long they wait between ticks.
+    pub node_tick_intervals: BTreeMap<u32, Duration>,
+    /// Logical time handling one message takes on each node that has a cost.
+    pub processing_costs: BTreeMap<u32, Duration>,
+    /// When each node with a processing cost is done with what it has taken
+    /// on; messages that arrive before then wait in the queue.
+    pub busy_until: BTreeMap<u32, Instant>,
+    /// Every index any node has seen committed, as `(term, command, seen_in)`
+    /// where `seen_in` is the term of the first node seen to commit it.
+    pub committed: BTreeMap<usize, (usize, Vec<u8>, usize)>,
+    /// The first node seen leading each term; no other may lead it later.
+    pub term_leaders: BTreeMap<usize, u32>,
+    /// Orders messages due at the same instant.
+    pub delivery_policy: Box<dyn DeliveryPolicy>,
+    /// Scripted crashes, restarts and clock jumps, with the elapsed time
+    /// each is due at.
+    pub faults: Vec<(Duration, Fault)>,
+    /// How far each node's clock has been jumped forward and backward in
+    /// all, by id; the node sees the simulation's time shifted by the
+    /// difference.
+    pub clock_offsets: BTreeMap<u32, (Duration, Duration)>,
+    /// Nodes that are down; only what they wrote to storage survives.
+    pub crashed: BTreeMap<u32, CrashedNode>,
+    /// Nodes restarted since `run_and_check` last looked, whose volatile
+    /// commit and applied indices may have gone back.
+    pub restarted: HashSet<u32>,
+    /// Commands from `submit` still waiting for an answer, by request id.
+    pub submitted: HashMap<u64, Submitted>,
+    pub next_submit_id: u64,
+    /// Tick each node only once `Machine::next_tick` says it needs one,
+    /// instead of at every step.
+    pub paced_ticks: bool,
+    /// Most messages the queue may hold, and what happens beyond that.
+    pub queue_limit: Option<(usize, Overflow)>,
+    /// Longest plausible time from sending a message to delivering it, and
+    /// what happens to one due later than that.
+    pub max_delivery_delay: Option<(Duration, FarFuture)>,
+    /// How long a message may spend in the network, deferred or not, before
+    /// it is dropped instead of delivered.
+    pub message_ttl: Option<Duration>,
+    /// Most messages one node handles per step; the rest wait for the next,
+    /// so a flooded node cannot hog a step while the others wait on it.
+    pub messages_per_step: Option<usize>,
+    /// How many messages of each kind were sent, dropped ones included.
+    pub message_counts: HashMap<MessageKind, usize>,
+    /// Messages dropped because the queue was full.
+    pub overflow_drops: usize,
+    /// The first time the queue went past its limit under `Overflow::Error`.
+    pub queue_overflow: Option<QueueOverflow>,
+    /// What happened to messages besides being delivered, once `record_trace`
+    /// has been called.
+    pub trace: Option<Vec<TraceEvent>>,
+    /// Called with every `Event`, in registration order, as it happens.
+    pub subscribers: Vec<Subscriber>,
+}
+
+pub type Subscriber = Box<dyn Fn(&Event)>;
+
+/// Something that happened in the cluster, at `time` since the start.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    RoleChanged {
+        time: Duration,
+        node: u32,
+        from: &'static str,
+        to: &'static str,
+        term: usize,
+    },
+    /// `node` learned that everything up to `index` is committed.
+    Committed {
+        time: Duration,
+        node: u32,
+        index: usize,
+    },
+    LeaderChanged {
+        time: Duration,
+        leader: Option<u32>,
+        term: usize,
+    },
+    Dropped {
+        time: Duration,
+        from: u32,
+        to: u32,
+        reason: DropReason,
+    },
+    /// The queue went past its limit under `Overflow::Error`.
+    QueueOverflow(QueueOverflow),
+    /// More than one node is in the Leader role, as `(node, term)` by id.
+    /// Safe as long as their terms differ, but worth a look.
+    PotentialSplitBrain {
+        time: Duration,
+        leaders: Vec<(u32, usize)>,
+    },
+}
+
+/// Something the simulation did to a message, or saw in the cluster, at
+/// `time` since the start.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceEvent {
+    Dropped {
+        time: Duration,
+        from: u32,
+        to: u32,
+        reason: DropReason,
+    },
+    PotentialSplitBrain {
+        time: Duration,
+        leaders: Vec<(u32, usize)>,
+    },
+}
+
+/// Why the simulation discarded a message instead of delivering it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DropReason {
+    /// The link from sender to receiver was cut.
+    Partition,
+    /// Lost at random, at the simulation's `loss_rate`.
+    Loss,
+    /// Matched a `drop_nth` rule.
+    Scripted,
+    /// The queue was at its limit.
+    QueueFull,
+    /// The receiver had crashed.
+    Crashed,
+    /// Older than the simulation's `message_ttl` when it came up for delivery.
+    Expired,
+    /// Due further ahead than the simulation's `max_delivery_delay`.
+    FarFuture,
+}
+
+/// What to do with a message due implausibly far in the future, as a clock
+/// bug could make it, which would otherwise keep the run from settling.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FarFuture {
+    /// Drop it.
+    Drop,
+    /// Warn about it and deliver it at the limit instead.
+    Clamp,
+}
+
+/// What to do when a message would take the queue past its limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overflow {
+    /// Make room by dropping the message sent longest ago.
+    DropOldest,
+    /// Drop the message being sent.
+    DropNewest,
+    /// Refuse the message being sent and report the overflow, for runs
+    /// that are expected never to reach the limit: see
+    /// `Simulation::check_queue_limit`.
+    Error,
+}
+
+/// The message queue reached its limit under `Overflow::Error`, first at
+/// `time` since the start.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueOverflow {
+    pub time: Duration,
+    pub limit: usize,
+}
+
+impl fmt::Display for QueueOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "message queue went past its limit of {} at {:?}",
+            self.limit, self.time
+        )
+    }
+}
+
+impl std::error::Error for QueueOverflow {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    Crash(u32),
+    Restart(u32),
+    ClockJump(u32, ClockJump),
+}
+
+/// A sudden correction of one node's clock, as NTP might make.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClockJump {
+    Forward(Duration),
+    Backward(Duration),
+}
+
+/// What outlives a crash: storage, plus what the node was started with.
+pub struct CrashedNode {
+    pub storage: Box<dyn Storage>,
+    pub initial_config: Config,
+    pub options: Options,
+}
+
+/// Drops the `nth` delivered message for which `matches` holds.
+pub struct DropRule {
+    pub matches: Box<dyn Fn(&TimedMessage) -> bool>,
+    pub nth: usize,
+    pub seen: usize,
+}
+
+impl Simulation {
+    pub fn new(n_nodes: u32, seed: u64) -> Self {
+        ClusterBuilder::new(n_nodes).seed(seed).build()
+    }
+
+    pub fn with_options(n_nodes: u32, seed: u64, options: Options) -> Self {
+        ClusterBuilder::new(n_nodes)
+            .seed(seed)
+            .options(options)
+            .build()
+    }
+
+    /// A simulation of `machines`, all started at `start_time`, on a
+    /// network with the default latency and no faults.
+    fn with_machines(machines: BTreeMap<u32, Machine>, seed: u64, start_time: Instant) -> Self {
+        Simulation {
+            machines,
+            clients: BTreeMap::new(),
+            queue: BinaryHeap::new(),
+            start_time,
+            current_time: start_time,
+            tick_interval: Duration::from_millis(10),
+            network_latency: Duration::from_millis(20),
+            network_jitter: Duration::ZERO,
+            normal_links: BTreeMap::new(),
+            link_bandwidth: BTreeMap::new(),
+            link_busy_until: BTreeMap::new(),
+            loss_rate: 0.0,
+            rng: Rng::new(seed),
+            next_seq: 0,
+            cut_links: HashSet::new(),
+            timeline: vec![],
+            split_brain: vec![],
+            drop_rules: vec![],
+            scenario: Scenario::new(),
+            paused: HashSet::new(),
+            node_tick_intervals: BTreeMap::new(),
+            processing_costs: BTreeMap::new(),
+            busy_until: BTreeMap::new(),
+            committed: BTreeMap::new(),
+            term_leaders: BTreeMap::new(),
+            delivery_policy: Box::new(Fifo),
+            faults: vec![],
+            clock_offsets: BTreeMap::new(),
+            crashed: BTreeMap::new(),
+            restarted: HashSet::new(),
+            submitted: HashMap::new(),
+            next_submit_id: 1,
+            paced_ticks: false,
+            queue_limit: None,
+            max_delivery_delay: None,
+            message_ttl: None,
+            messages_per_step: None,
+            message_counts: HashMap::new(),
+            overflow_drops: 0,
+            queue_overflow: None,
+            trace: None,
+            subscribers: vec![],
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.current_time - self.start_time
+    }
+
+    pub fn send(&mut self, from: u32, outbox: Outbox) {
+        for (to, message) in outbox {
+            if message.is_heartbeat() {
+                if let Some(message) = self.coalesce_heartbeat(from, to, message) {
+                    self.enqueue(from, to, message);
+                }
+            } else {
+                self.enqueue(from, to, message);
+            }
+        }
+    }
+
+    pub fn enqueue(&mut self, from: u32, to: u32, message: Message) {
+        *self.message_counts.entry(message.kind()).or_default() += 1;
+        if self.loss_rate > 0.0 && self.rng.next_f64() < self.loss_rate {
+            self.record_drop(from, to, DropReason::Loss);
+            return;
+        }
+        let delay = match self.normal_links.get(&(from, to)) {
+            Some(&(mean, std_dev)) => self.rng.normal_duration(mean, std_dev),
+            None => {
+                self.network_latency
+                    + self
+                        .rng
+                        .duration_between(Duration::ZERO, self.network_jitter)
+            }
+        };
+        let sent = match self.link_bandwidth.get(&(from, to)) {
+            Some(&bandwidth) => {
+                let bytes = wire::encode(&message).len() as u64;
+                let transmit = Duration::from_nanos(bytes * 1_000_000_000 / bandwidth.max(1));
+                let now = self.current_time;
+                let busy = self.link_busy_until.entry((from, to)).or_insert(now);
+                *busy = (*busy).max(now) + transmit;
+                *busy
+            }
+            None => self.current_time,
+        };
+        let mut delivery_time = sent + delay;
+        if let Some((max, policy)) = self.max_delivery_delay {
+            if delivery_time > self.current_time + max {
+                eprintln!(
+                    "warning: {:?} from {} to {} is due in {:?}, past the {:?} limit",
+                    message.kind(),
+                    from,
+                    to,
+                    delivery_time - self.current_time,
+                    max
+                );
+                match policy {
+                    FarFuture::Drop => {
+                        self.record_drop(from, to, DropReason::FarFuture);
+                        return;
+                    }
+                    FarFuture::Clamp => delivery_time = self.current_time + max,
+                }
+            }
+        }
+        self.queue.push(TimedMessage {
+            delivery_time,
+            sent_at: self.current_time,
+            seq: self.next_seq,
+            from,
+            to,
+            message,
+        });
+        self.next_seq += 1;
+        self.enforce_queue_limit();
+    }
+
+    /// Brings the queue back within its limit, if it has one; messages go in
+    /// the order they were sent, so the newest is the one just enqueued.
+    pub fn enforce_queue_limit(&mut self) {
+        let Some((max, overflow)) = self.queue_limit else {
+            return;
+        };
+        let excess = self.queue.len().saturating_sub(max);
+        if excess == 0 {
+            return;
+        }
+        if overflow == Overflow::Error && self.queue_overflow.is_none() {
+            let overflow = QueueOverflow {
+                time: self.elapsed(),
+                limit: max,
+            };
+            self.queue_overflow = Some(overflow);
+            self.publish(Event::QueueOverflow(overflow));
+        }
+        let mut queued = std::mem::take(&mut self.queue).into_vec();
+        queued.sort_unstable_by_key(|m| m.seq);
+        let dropped: Vec<TimedMessage> = match overflow {
+            Overflow::DropOldest => queued.drain(..excess).collect(),
+            _ => queued.split_off(max),
+        };
+        self.queue = queued.into();
+        self.overflow_drops += excess;
+        for msg in dropped {
+            self.record_drop(msg.from, msg.to, DropReason::QueueFull);
+        }
+    }
+
+    /// Starts keeping a trace of what happens to messages, from now on.
+    pub fn record_trace(&mut self) {
+        self.trace.get_or_insert_with(Vec::new);
+    }
+
+    /// Events recorded since `record_trace`, oldest first.
+    pub fn trace(&self) -> &[TraceEvent] {
+        self.trace.as_deref().unwrap_or_default()
+    }
+
+    /// Registers `f` to be called with every event from now on, after the
+    /// subscribers already registered.
+    pub fn subscribe(&mut self, f: Subscriber) {
+        self.subscribers.push(f);
+    }
+
+    pub fn publish(&self, event: Event) {
+        for f in &self.subscribers {
+            f(&event);
+        }
+    }
+
+    /// Role and commit index of node `id`, to publish what changes across a
+    /// message or tick.
+    pub fn node_status(&self, id: u32) -> Option<(&'static str, usize)> {
+        let m = self.machines.get(&id)?;
+        Some((m.role_name(), m.state.commit_index))
+    }
+
+    pub fn publish_node_changes(&self, id: u32, before: Option<(&'static str, usize)>) {
+        let (Some((old_role, old_commit)), Some((role, commit))) = (before, self.node_status(id))
+        else {
+            return;
+        };
+        let time = self.elapsed();
+        if role != old_role {
+            self.publish(Event::RoleChanged {
+                time,
+                node: id,
+                from: old_role,
+                to: role,
+                term: self.machines[&id].state.current_term,
+            });
+        }
+        if commit > old_commit {
+            self.publish(Event::Committed {
+                time,
+                node: id,
+                index: commit,
+            });
+        }
+    }
+
+    pub fn record_drop(&mut self, from: u32, to: u32, reason: DropReason) {
+        let time = self.elapsed();
+        self.publish(Event::Dropped {
+            time,
+            from,
+            to,
+            reason,
+        });
+        if let Some(trace) = &mut self.trace {
+            trace.push(TraceEvent::Dropped {
+                time,
+                from,
+                to,
+                reason,
+            });
+        }
+    }
+
+    /// Puts `heartbeat` in place of an undelivered one on the same link, as
+    /// it says everything the older one did; hands it back if there is none.
+    /// The older one's delivery time is kept, so a steady stream of
+    /// heartbeats cannot keep pushing delivery back.
+    pub fn coalesce_heartbeat(
+        &mut self,
+        from: u32,
+        to: u32,
+        heartbeat: Message,
+    ) -> Option<Message> {
+        let term = heartbeat.term();
+        let stale = |m: &TimedMessage| {
+            m.from == from && m.to == to && m.message.is_heartbeat() && m.message.term() == term
+        };
+        if !self.queue.iter().any(stale) {
+            return Some(heartbeat);
+        }
+        // Only the message changes, not its place in the heap.
+        let mut queued = std::mem::take(&mut self.queue).into_vec();
+        queued.iter_mut().find(|m| stale(m)).unwrap().message = heartbeat;
+        self.queue = queued.into();
+        None
+    }
+
+    /// Delivers every due message, ticks every machine, then advances the clock.
+    pub fn step(&mut self) {
+        self.apply_faults();
+        let mut deferred = vec![];
+        let mut handled: HashMap<u32, usize> = HashMap::new();
+        while let Some(due) = self
+            .queue
+            .peek()
+            .map(|m| m.delivery_time)
+            .filter(|&t| t <= self.current_time)
+        {
+            // Everything due at this instant, or within this step if the
+            // policy says so, in the order the policy picks.
+            let until = if self.delivery_policy.whole_step() {
+                self.current_time
+            } else {
+                due
+            };
+            let mut batch = vec![];
+            while self.queue.peek().is_some_and(|m| m.delivery_time <= until) {
+                batch.push(self.queue.pop().unwrap());
+            }
+            batch.sort_by(|a, b| self.delivery_policy.order(a, b));
+            for msg in batch {
+                if self
+                    .message_ttl
+                    .is_some_and(|ttl| self.current_time - msg.sent_at > ttl)
+                {
+                    self.record_drop(msg.from, msg.to, DropReason::Expired);
+                    continue;
+                }
+                let over_share = self
+                    .messages_per_step
+                    .is_some_and(|max| handled.get(&msg.to).is_some_and(|&n| n >= max));
+                if self.paused.contains(&msg.to)
+                    || self.is_busy(msg.to)
+                    || over_share
+                    || self.scenario_holds(&msg)
+                {
+                    deferred.push(msg);
+                    continue;
+                }
+                if self.cut_links.contains(&(msg.from, msg.to)) {
+                    self.record_drop(msg.from, msg.to, DropReason::Partition);
+                    continue;
+                }
+                if self.scripted_drop(&msg) {
+                    self.record_drop(msg.from, msg.to, DropReason::Scripted);
+                    continue;
+                }
+                self.scenario_delivered(&msg);
+                let before = self.node_status(msg.to);
+                let outbox = if self.machines.contains_key(&msg.to) {
+                    *handled.entry(msg.to).or_default() += 1;
+                    if let Some(&cost) = self.processing_costs.get(&msg.to) {
+                        let start = self
+                            .busy_until
+                            .get(&msg.to)
+                            .map_or(self.current_time, |&t| t.max(self.current_time));
+                        self.busy_until.insert(msg.to, start + cost);
+                    }
+                    let at = self.node_time(msg.to);
+                    let machine = self.machines.get_mut(&msg.to).unwrap();
+                    let outbox = machine.handle(msg.message, at);
+                    self.publish_node_changes(msg.to, before);
+                    outbox
+                } else if let Some(client) = self.clients.get_mut(&msg.to) {
+                    client.handle(msg.message, self.current_time);
+                    vec![]
+                } else if msg.to == DRIVER_ID {
+                    self.resolve_submitted(msg.message);
+                    vec![]
+                } else {
+                    // Crashed nodes just miss their messages; any other id
+                    // is unknown, e.g. a node removed from the cluster.
+                    if self.crashed.contains_key(&msg.to) {
+                        self.record_drop(msg.from, msg.to, DropReason::Crashed);
+                    } else {
+                        eprintln!(
+                            "warning: dropped {:?} from {} to unknown node {}",
+                            msg.message, msg.from, msg.to
+                        );
+                    }
+                    continue;
+                };
+                self.send(msg.to, outbox);
+            }
+        }
+        self.queue.extend(deferred);
+        // Messages to paused nodes were out of the queue while being deferred.
+        self.enforce_queue_limit();
+        let ids: Vec<u32> = self
+            .machines
+            .keys()
+            .copied()
+            .filter(|id| !self.paused.contains(id) && self.tick_due(*id))
+            .collect();
+        for id in ids {
+            let before = self.node_status(id);
+            let at = self.node_time(id);
+            let outbox = self.machines.get_mut(&id).unwrap().tick(at);
+            self.publish_node_changes(id, before);
+            self.send(id, outbox);
+        }
+        let ids: Vec<u32> = self.clients.keys().copied().collect();
+        for id in ids {
+            let outbox = self.clients.get_mut(&id).unwrap().tick(self.current_time);
+            self.send(id, outbox);
+        }
+        self.fail_deposed_submits();
+        self.sample_leadership();
+        self.assert_one_leader_per_term();
+        self.current_time += self.tick_interval;
+    }
+
+    /// Splits the cluster into `groups`; messages only flow within a group.
+    /// Nodes not listed in any group are isolated.
+    pub fn partition(&mut self, groups: &[&[u32]]) {
+        let group_of = |id: u32| groups.iter().position(|g| g.contains(&id));
+        self.cut_links.clear();
+        for &from in self.machines.keys() {
+            for &to in self.machines.keys() {
+                if from != to && (group_of(from).is_none() || group_of(from) != group_of(to)) {
+                    self.cut_links.insert((from, to));
+                }
+            }
+        }
+    }
+
+    /// Makes every node's writes take `latency` to become durable.
+    pub fn set_write_latency(&mut self, latency: Duration) {
+        for m in self.machines.values_mut() {
+            let inner = std::mem::replace(&mut m.storage, Box::new(MemStorage::default()));
+            m.storage = Box::new(SlowStorage::new(inner, latency));
+        }
+    }
+
+    /// Crashes node `id` once `at` has elapsed: its role, volatile state and
+    /// unwritten records are lost, and messages to it are dropped.
+    pub fn crash(&mut self, id: u32, at: Duration) {
+        self.faults.push((at, Fault::Crash(id)));
+    }
+
+    /// Brings crashed node `id` back once `at` has elapsed, as a Follower
+    /// rebuilt from its storage.
+    pub fn restart(&mut self, id: u32, at: Duration) {
+        self.faults.push((at, Fault::Restart(id)));
+    }
+
+    /// Stops node `id` at once, keeping only its storage.
+    pub fn take_down(&mut self, id: u32) {
+        let Some(machine) = self.machines.remove(&id) else {
+            return;
+        };
+        self.paused.remove(&id);
+        let crashed = CrashedNode {
+            storage: machine.storage,
+            initial_config: machine.state.initial_config,
+            options: machine.state.options,
+        };
+        self.crashed.insert(id, crashed);
+    }
+
+    /// Applies every fault that is due, earliest first.
+    pub fn apply_faults(&mut self) {
+        let now = self.elapsed();
+        let (mut due, later): (Vec<_>, Vec<_>) =
+            self.faults.drain(..).partition(|&(at, _)| at <= now);
+        self.faults = later;
+        due.sort_by_key(|&(at, _)| at);
+        for (_, fault) in due {
+            match fault {
+                Fault::Crash(id) => self.take_down(id),
+                Fault::Restart(id) => {
+                    let Some(crashed) = self.crashed.remove(&id) else {
+                        continue;
+                    };
+                    let seed = self.rng.next_u64();
+                    // The options were validated when the node first started.
+                    let mut machine =
+                        Machine::new(id, vec![], crashed.options, seed, self.node_time(id))
+                            .unwrap();
+                    machine.epoch = self.start_time;
+                    machine.state.initial_config = crashed.initial_config;
+                    let records = crashed.storage.load();
+                    machine.replay(&records);
+                    machine.storage = crashed.storage;
+                    self.machines.insert(id, machine);
+                    self.restarted.insert(id);
+                }
+                Fault::ClockJump(id, jump) => {
+                    let (forward, backward) = self.clock_offsets.entry(id).or_default();
+                    match jump {
+                        ClockJump::Forward(by) => *forward += by,
+                        ClockJump::Backward(by) => *backward += by,
+                    }
+                }
+            }
+        }
+    }
+
+    /// Jumps node `id`'s clock by `jump` once `at` has elapsed; it keeps
+    /// running at the same rate from there, across crashes too.
+    pub fn jump_clock(&mut self, id: u32, at: Duration, jump: ClockJump) {
+        self.faults.push((at, Fault::ClockJump(id, jump)));
+    }
+
+    /// The time as node `id`'s clock shows it, jumps included.
+    pub fn node_time(&self, id: u32) -> Instant {
+        let Some(&(forward, backward)) = self.clock_offsets.get(&id) else {
+            return self.current_time;
+        };
+        (self.current_time + forward)
+            .checked_sub(backward)
+            .unwrap_or(self.start_time)
+    }
+
+    /// Caps the queue at `max` messages, handling any beyond with `overflow`.
+    pub fn set_queue_limit(&mut self, max: usize, overflow: Overflow) {
+        self.queue_limit = Some((max, overflow));
+        self.enforce_queue_limit();
+    }
+
+    /// Fails if the queue ever went past its limit under `Overflow::Error`.
+    pub fn check_queue_limit(&self) -> Result<(), QueueOverflow> {
+        self.queue_overflow.map_or(Ok(()), Err)
+    }
+
+    /// Handles any message sent from now on that would be delivered more
+    /// than `max` after it is sent with `policy`, instead of leaving it in
+    /// the queue until then.
+    pub fn set_max_delivery_delay(&mut self, max: Duration, policy: FarFuture) {
+        self.max_delivery_delay = Some((max, policy));
+    }
+
+    /// Drops messages sent more than `ttl` ago when they come up for
+    /// delivery, as a transport that expires stale messages would; `None`
+    /// delivers them however old.
+    pub fn set_message_ttl(&mut self, ttl: Option<Duration>) {
+        self.message_ttl = ttl;
+    }
+
+    /// How many messages of each kind have been sent so far, whether or not
+    /// they arrived.
+    pub fn message_counts(&self) -> HashMap<MessageKind, usize> {
+        self.message_counts.clone()
+    }
+
+    /// Lets each node handle at most `max` messages per step, leaving the
+    /// rest queued in order for later steps; `None` handles everything due.
+    pub fn set_messages_per_step(&mut self, max: Option<usize>) {
+        self.messages_per_step = max;
+    }
+
+    /// Picks how messages due at the same instant are ordered; `Fifo` by default.
+    pub fn set_delivery_policy(&mut self, policy: impl DeliveryPolicy + 'static) {
+        self.delivery_policy = Box::new(policy);
+    }
+
+    /// Starts a fresh node `id` with an empty log and no configuration, so it
+    /// stays out of elections until a leader adds it with `change_config`.
+    pub fn add_node(&mut self, id: u32) {
+        assert!(
+            !self.machines.contains_key(&id),
+            "node {} already exists",
+            id
+        );
+        let peers = self.machines.keys().copied().collect();
+        let options = self.machines.values().next().unwrap().state.options.clone();
+        let seed = self.rng.next_u64();
+        let mut machine = Machine::new(id, peers, options, seed, self.current_time).unwrap();
+        machine.epoch = self.start_time;
+        machine.state.clear_initial_config();
+        self.machines.insert(id, machine);
+    }
+
+    /// Adds a client that talks to every node of the cluster.
+    pub fn add_client(&mut self) -> u32 {
+        let id = CLIENT_ID_BASE + self.clients.len() as u32;
+        let cluster = self.machines.keys().copied().collect();
+        self.clients.insert(id, Client::new(id, cluster));
+        id
+    }
+
+    /// Drops the `n`th message (counting from 1) matching `predicate` among
+    /// those delivered from now on, e.g. the third AppendEntries to node 2.
+    /// Messages already lost to a partition do not count.
+    pub fn drop_nth(&mut self, n: usize, predicate: impl Fn(&TimedMessage) -> bool + 'static) {
+        assert!(n > 0, "messages are counted from 1");
+        self.drop_rules.push(DropRule {
+            matches: Box::new(predicate),
+            nth: n,
+            seen: 0,
+        });
+    }
+
+    /// Advances every rule matching `msg`; true if one of them drops it.
+    pub fn scripted_drop(&mut self, msg: &TimedMessage) -> bool {
+        let mut dropped = false;
+        self.drop_rules.retain_mut(|rule| {
+            if !(rule.matches)(msg) {
+                return true;
+            }
+            rule.seen += 1;
+            if rule.seen == rule.nth {
+                dropped = true;
+                return false;
+            }
+            true
+        });
+        dropped
+    }
+
+    /// Ticks node `id` only every `interval` instead of every step, to model
+    /// a slower machine. `interval` is rounded up to whole steps.
+    pub fn set_tick_interval(&mut self, id: u32, interval: Duration) {
+        self.node_tick_intervals.insert(id, interval);
+    }
+
+    /// Delays messages from `from` to `to` by a normally distributed time
+    /// with the given mean and deviation, never less than zero, instead of
+    /// the cluster-wide latency and jitter.
+    pub fn set_link_latency_normal(
+        &mut self,
+        from: u32,
+        to: u32,
+        mean: Duration,
+        std_dev: Duration,
+    ) {
+        self.normal_links.insert((from, to), (mean, std_dev));
+    }
+
+    /// Limits the link from `from` to `to` to `bytes_per_sec`, so a large
+    /// message, such as a snapshot chunk, holds up everything sent after it.
+    pub fn set_link_bandwidth(&mut self, from: u32, to: u32, bytes_per_sec: u64) {
+        self.link_bandwidth.insert((from, to), bytes_per_sec);
+    }
+
+    /// Makes every message node `id` handles take `cost` of logical time;
+    /// it handles the next only once done, so messages queue up behind a
+    /// node given more than it can keep up with, as behind a saturated CPU.
+    pub fn set_processing_cost(&mut self, id: u32, cost: Duration) {
+        self.processing_costs.insert(id, cost);
+    }
+
+    /// Node `id` is still working through messages it took on earlier and
+    /// cannot start another within this step.
+    pub fn is_busy(&self, id: u32) -> bool {
+        self.busy_until
+            .get(&id)
+            .is_some_and(|&t| t >= self.current_time + self.tick_interval)
+    }
+
+    pub fn tick_due(&self, id: u32) -> bool {
+        let m = &self.machines[&id];
+        let now = self.node_time(id);
+        self.node_tick_intervals
+            .get(&id)
+            .is_none_or(|&interval| now >= m.last_tick + interval)
+            && (!self.paced_ticks || now >= m.next_tick(now))
+    }
+
+    /// Ticks nodes only when their role or pending work needs it, e.g. a
+    /// follower at its election deadline, rather than every node every step.
+    pub fn set_paced_ticks(&mut self, paced: bool) {
+        self.paced_ticks = paced;
+    }
+
+    /// Freezes node `id`, as in a long GC pause: it stops ticking and
+    /// messages to it stay queued until `resume`.
+    pub fn pause(&mut self, id: u32) {
+        self.paused.insert(id);
+    }
+
+    /// Unfreezes node `id`; everything queued for it is delivered on the next step.
+    pub fn resume(&mut self, id: u32) {
+        self.paused.remove(&id);
+    }
+
+    pub fn heal(&mut self) {
+        self.cut_links.clear();
+    }
+
+    pub fn sample_leadership(&mut self) {
+        let leader = self.leader();
+        let term = match leader {
+            Some(id) => self.machines[&id].state.current_term,
+            None => self
+                .machines
+                .values()
+                .map(|m| m.state.current_term)
+                .max()
+                .unwrap_or(0),
+        };
+        if self
+            .timeline
+            .last()
+            .is_none_or(|&(_, l, t)| (l, t) != (leader, term))
+        {
+            let elapsed = self.elapsed();
+            self.timeline.push((elapsed, leader, term));
+            self.publish(Event::LeaderChanged {
+                time: elapsed,
+                leader,
+                term,
+            });
+        }
+        self.sample_split_brain();
+    }
+
+    /// Flags each new set of nodes that are in the Leader role at once.
+    pub fn sample_split_brain(&mut self) {
+        let leaders: Vec<(u32, usize)> = self
+            .machines
+            .iter()
+            .filter(|(_, m)| m.role_name() == "Leader")
+            .map(|(&id, m)| (id, m.state.current_term))
+            .collect();
+        let leaders = if leaders.len() > 1 { leaders } else { vec![] };
+        if leaders == self.split_brain {
+            return;
+        }
+        self.split_brain = leaders.clone();
+        if leaders.is_empty() {
+            return;
+        }
+        let time = self.elapsed();
+        self.publish(Event::PotentialSplitBrain {
+            time,
+            leaders: leaders.clone(),
+        });
+        if let Some(trace) = &mut self.trace {
+            trace.push(TraceEvent::PotentialSplitBrain { time, leaders });
+        }
+    }
+
+    /// How leadership and the cluster's term evolved over the run.
+    pub fn leadership_timeline(&self) -> Vec<(Duration, Option<u32>, usize)> {
+        self.timeline.clone()
+    }
+
+    /// Messages queued for `to` but not yet delivered, in delivery order.
+    pub fn pending_for(&self, to: u32) -> Vec<&TimedMessage> {
+        let mut pending: Vec<&TimedMessage> = self.queue.iter().filter(|m| m.to == to).collect();
+        pending.sort_by(|a, b| {
+            a.delivery_time
+                .cmp(&b.delivery_time)
+                .then_with(|| self.delivery_policy.order(a, b))
+        });
+        pending
+    }
+
+    /// Whether the cluster has settled: exactly one leader, every log equal
+    /// to it and fully applied, idle clients, and nothing in flight but
+    /// heartbeats and their successful answers.
+    pub fn is_quiescent(&self) -> bool {
+        let mut leaders = self.machines.values().filter(|m| m.role_name() == "Leader");
+        let (Some(leader), None) = (leaders.next(), leaders.next()) else {
+            return false;
+        };
+        let converged = self.machines.values().all(|m| {
+            m.state.last_log_index() == leader.state.last_log_index()
+                && m.state.last_log_term() == leader.state.last_log_term()
+                && m.state.commit_index == leader.state.commit_index
+                && m.state.last_applied == m.state.commit_index
+        });
+        let idle = self.queue.iter().all(|m| {
+            m.message.is_heartbeat()
+                || matches!(
+                    m.message,
+                    Message::AppendEntryResponse { success: true, .. }
+                )
+        });
+        converged && idle && self.clients.values().all(Client::is_idle)
+    }
+
+    /// Steps until the cluster is quiescent, for at most `max`; true if it
+    /// got there.
+    pub fn run_until_quiescent(&mut self, max: Duration) -> bool {
+        let until = self.current_time + max;
+        while !self.is_quiescent() {
+            if self.current_time >= until {
+                return false;
+            }
+            self.step();
+        }
+        true
+    }
+
+    /// Steps until every live node has applied `index`, for at most `max`;
+    /// unlike a commit, which a majority suffices for, this waits for the
+    /// slowest node too.
+    pub fn wait_for_applied_all(
+        &mut self,
+        index: usize,
+        max: Duration,
+    ) -> Result<(), TimeoutError> {
+        let until = self.current_time + max;
+        loop {
+            let lagging: Vec<u32> = self
+                .machines
+                .values()
+                .filter(|m| m.state.last_applied < index)
+                .map(Machine::id)
+                .collect();
+            if lagging.is_empty() {
+                return Ok(());
+            }
+            if self.current_time >= until {
+                return Err(TimeoutError {
+                    waited: max,
+                    lagging,
+                });
+            }
+            self.step();
+        }
+    }
+
+    pub fn run_for(&mut self, duration: Duration) {
+        let until = self.current_time + duration;
+        while self.current_time < until {
+            self.step();
+        }
+    }
+
+    /// Like `run_for`, but checks safety invariants after every step: no
+    /// node's commit index or applied index ever moves backwards, other than
+    /// across a restart, no node votes twice in one term, and leaders hold
+    /// every committed entry. Panics on the first violation.
+    pub fn run_and_check(&mut self, duration: Duration) {
+        let mut seen: BTreeMap<u32, (usize, usize)> = self
+            .machines
+            .values()
+            .map(|m| (m.id(), (m.state.commit_index, m.state.last_applied)))
+            .collect();
+        let until = self.current_time + duration;
+        while self.current_time < until {
+            self.step();
+            for id in self.restarted.drain() {
+                seen.remove(&id);
+            }
+            for m in self.machines.values() {
+                let now = (m.state.commit_index, m.state.last_applied);
+                let (commit, applied) = seen.insert(m.id(), now).unwrap_or_default();
+                assert!(
+                    now.0 >= commit && now.1 >= applied,
+                    "node {} went from commit {} / applied {} to {} / {} at {:?}",
+                    m.id(),
+                    commit,
+                    applied,
+                    now.0,
+                    now.1,
+                    self.elapsed()
+                );
+            }
+            self.assert_single_vote_per_term();
+            self.assert_leader_completeness();
+        }
+    }
+
+    /// Steps at most `max_steps` times until exactly one node leads and every
+    /// node is in its term and knows it, returning the leader. Panics with
+    /// every node's role, term and pending messages if that never happens,
+    /// so a convergence test fails the same way on every run of a seed.
+    pub fn assert_converges(&mut self, max_steps: usize) -> u32 {
+        for _ in 0..=max_steps {
+            if let Some(leader) = self.converged_leader() {
+                return leader;
+            }
+            self.step();
+        }
+        panic!(
+            "no single leader after {} steps ({:?}):\n{}",
+            max_steps,
+            self.elapsed(),
+            self.convergence_dump()
+        );
+    }
+
+    /// The one leader every node is in the term of and follows, if any.
+    pub fn converged_leader(&self) -> Option<u32> {
+        let mut leaders = self.machines.values().filter(|m| m.role_name() == "Leader");
+        let (Some(leader), None) = (leaders.next(), leaders.next()) else {
+            return None;
+        };
+        let (id, term) = (leader.id(), leader.state.current_term);
+        self.machines
+            .values()
+            .all(|m| m.state.current_term == term && m.role.leader_hint(&m.state) == Some(id))
+            .then_some(id)
+    }
+
+    /// One line per node: role, term, vote, log and the kinds of the
+    /// messages queued for it.
+    pub fn convergence_dump(&self) -> String {
+        let mut out = String::new();
+        for (id, m) in &self.machines {
+            let mut queued: BTreeMap<MessageKind, usize> = BTreeMap::new();
+            for msg in self.pending_for(*id) {
+                *queued.entry(msg.message.kind()).or_default() += 1;
+            }
+            out += &format!(
+                "  node {}: {} term {} voted {:?} leader {:?} last log {}/{} commit {} queued {:?}\n",
+                id,
+                m.role_name(),
+                m.state.current_term,
+                m.state.voted_for,
+                m.role.leader_hint(&m.state),
+                m.state.last_log_index(),
+                m.state.last_log_term(),
+                m.state.commit_index,
+                queued
+            );
+        }
+        out
+    }
+
+    /// Proposes `command` to the current leader; returns its log index, or
+    /// why no leader took it.
+    pub fn propose(&mut self, command: &[u8]) -> Result<usize, RaftError> {
+        let leader = self.leader().ok_or(RaftError::NoLeader)?;
+        let at = self.node_time(leader);
+        let (index, outbox) = self
+            .machines
+            .get_mut(&leader)
+            .unwrap()
+            .propose(command.to_vec(), at)?;
+        self.send(leader, outbox);
+        Ok(index)
+    }
+
+    /// Asks the current leader to change the membership.
+    pub fn change_config(&mut self, change: &ConfigChange) -> Result<usize, ConfigError> {
+        let leader = self.leader().ok_or(ConfigError::NotLeader)?;
+        let at = self.node_time(leader);
+        let (index, outbox) = self
+            .machines
+            .get_mut(&leader)
+            .unwrap()
+            .change_config(change, at)?;
+        self.send(leader, outbox);
+        Ok(index)
+    }
+
+    /// Tells node `id` to step down if it leads in `term`, as the operator.
+    pub fn step_down(&mut self, id: u32, term: usize) {
+        if !self.machines.contains_key(&id) {
+            return;
+        }
+        let before = self.node_status(id);
+        let at = self.node_time(id);
+        let outbox = self.machines.get_mut(&id).unwrap().step_down(term, at);
+        self.publish_node_changes(id, before);
+        self.send(id, outbox);
+    }
+
+    /// Starts draining the current leader ahead of its removal.
+    pub fn drain_leader(&mut self) -> Option<DrainHandle> {
+        let leader = self.leader()?;
+        let at = self.node_time(leader);
+        self.machines.get_mut(&leader).unwrap().drain(at)
+    }
+
+    /// Compacts every node's log up to `up_to`, as if each had taken a
+    /// snapshot there, so that lagging or new nodes can only catch up by
+    /// InstallSnapshot. Nodes that have not applied that far, or already
+    /// compacted past it, are left alone; returns the nodes compacted.
+    pub fn compact_all(&mut self, up_to: usize) -> Vec<u32> {
+        let mut compacted = vec![];
+        for (&id, m) in &mut self.machines {
+            if m.state.last_applied >= up_to && m.state.snapshot.last_index < up_to {
+                m.force_compact(up_to);
+                compacted.push(id);
+            }
+        }
+        compacted
+    }
+
+    /// Every node's `last_log`, by id, to see why an election went the way
+    /// it did.
+    pub fn last_logs(&self) -> BTreeMap<u32, (usize, usize)> {
+        self.machines
+            .iter()
+            .map(|(&id, m)| (id, m.last_log()))
+            .collect()
+    }
+
+    /// The leader with the highest term, if any node currently believes it leads.
+    pub fn leader(&self) -> Option<u32> {
+        self.machines
+            .values()
+            .filter(|m| m.role_name() == "Leader")
+            .max_by_key(|m| m.state.current_term)
+            .map(|m| m.id())
+    }
+
+    /// Combines every node's `state_hash`, by id, so that two runs can be
+    /// compared in one go.
+    pub fn cluster_hash(&self) -> u64 {
+        let mut hasher = Fnv1a::default();
+        for (id, m) in &self.machines {
+            (id, m.state_hash()).hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    /// The index a quorum has stored according to the current leader's
+    /// match indexes, or `None` without a leader.
+    pub fn agreed_commit_index(&self) -> Option<usize> {
+        let m = &self.machines[&self.leader()?];
+        m.role.agreed_index(&m.state)
+    }
+
+    /// Records what every node has committed and panics if two nodes commit
+    /// different entries at one index, or if a leader's log lacks an entry
+    /// committed before, so no committed entry is lost across leader changes.
+    pub fn assert_leader_completeness(&mut self) {
+        for m in self.machines.values() {
+            let committed = m.state.commit_index - m.state.snapshot.last_index;
+            for entry in &m.state.log[..committed] {
+                let seen = self
+                    .committed
+                    .entry(entry.index)
+                    .or_insert_with(|| (entry.term, entry.command.clone(), m.state.current_term));
+                assert!(
+                    (seen.0, &seen.1) == (entry.term, &entry.command),
+                    "node {} committed term {} at index {}, but term {} was committed there before",
+                    m.id(),
+                    entry.term,
+                    entry.index,
+                    seen.0
+                );
+            }
+        }
+        for m in self.machines.values() {
+            if m.role_name() != "Leader" {
+                continue;
+            }
+            // A leader deposed without knowing it may rightly lack entries
+            // committed after its term; later leaders may not.
+            let committed = self
+                .committed
+                .iter()
+                .filter(|(_, (_, _, seen_in))| m.state.current_term >= *seen_in);
+            for (&index, (term, command, _)) in committed {
+                // Compacted entries were committed here, so agreed with these.
+                if index <= m.state.snapshot.last_index {
+                    continue;
+                }
+                let held = m.state.entry(index);
+                assert!(
+                    held.is_some_and(|e| e.term == *term && e.command == *command),
+                    "leader {} in term {} lacks committed entry {} from term {}",
+                    m.id(),
+                    m.state.current_term,
+                    index,
+                    term
+                );
+            }
+        }
+    }
+
+    /// First index at which the logs of nodes `a` and `b` hold different
+    /// entries, by term or command; `None` if one is a prefix of the other.
+    /// Entries either node compacted were committed, so they agree.
+    pub fn diff_logs(&self, a: u32, b: u32) -> Option<usize> {
+        let (a, b) = (&self.machines[&a].state, &self.machines[&b].state);
+        let first = a.snapshot.last_index.max(b.snapshot.last_index) + 1;
+        let last = a.last_log_index().min(b.last_log_index());
+        (first..=last).find(|&index| {
+            let (x, y) = (a.entry(index).unwrap(), b.entry(index).unwrap());
+            (x.term, &x.command) != (y.term, &y.command)
+        })
+    }
+
+    /// Halts the run, in release builds too, the moment a second node leads
+    /// a term, whether alongside the first or after it: election safety is
+    /// gone and nothing after it is worth simulating. The message dumps
+    /// every node's state and log terms.
+    pub fn assert_one_leader_per_term(&mut self) {
+        for (&id, m) in &self.machines {
+            if m.role_name() != "Leader" {
+                continue;
+            }
+            let term = m.state.current_term;
+            let first = *self.term_leaders.entry(term).or_insert(id);
+            if first != id {
+                let mut dump = self.convergence_dump();
+                for (id, m) in &self.machines {
+                    let terms: Vec<usize> = m.state.log.iter().map(|e| e.term).collect();
+                    dump += &format!(
+                        "  node {} log terms {:?} (snapshot {}/{})\n",
+                        id, terms, m.state.snapshot.last_index, m.state.snapshot.last_term
+                    );
+                }
+                panic!(
+                    "election safety violated at {:?}: nodes {} and {} both lead term {}\n{}",
+                    self.elapsed(),
+                    first,
+                    id,
+                    term,
+                    dump
+                );
+            }
+        }
+    }
+
+    /// Panics if any node ever voted for two different candidates in one term.
+    pub fn assert_single_vote_per_term(&self) {
+        for m in self.machines.values() {
+            if let Some((term, first, second)) = m.state.conflicting_vote() {
+                panic!(
+                    "node {} voted for both {} and {} in term {}",
+                    m.id(),
+                    first,
+                    second,
+                    term
+                );
+            }
+        }
+    }
+}
+
+/// Sets up a `Simulation`. By default every node starts out knowing the full
+/// membership; `bootstrap` instead has it come from a single committed entry.
+pub struct ClusterBuilder {
+    pub n_nodes: u32,
+    pub seed: u64,
+    pub options: Options,
+    pub bootstrap: bool,
+    /// Vote weight of node `i` at index `i`; nodes past the end weigh 1.
+    pub weights: Vec<u32>,
+    /// Where each node keeps its write-ahead log, if not in memory.
+    pub wal_dir: Option<PathBuf>,
+}
+
+impl ClusterBuilder {
+    pub fn new(n_nodes: u32) -> Self {
+        ClusterBuilder {
+            n_nodes,
+            seed: 0,
+            options: Options::default(),
+            bootstrap: false,
+            weights: vec![],
+            wal_dir: None,
+        }
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn options(mut self, options: Options) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn vote_persistence(mut self, policy: VotePersistence) -> Self {
+        self.options.vote_persistence = policy;
+        self
+    }
+
+    /// Node 0 starts with a committed entry naming every node as a founding
+    /// voter; the others start with no configuration and learn it from the
+    /// first leader, which can only be node 0.
+    pub fn bootstrap(mut self) -> Self {
+        self.bootstrap = true;
+        self
+    }
+
+    /// Weighs node `i`'s vote `weights[i]` in elections and commits, so a
+    /// quorum is any set of voters carrying more than half the total weight.
+    pub fn weights(mut self, weights: Vec<u32>) -> Self {
+        self.weights = weights;
+        self
+    }
+
+    /// Keeps node `i`'s write-ahead log in `dir/node-i.wal`. Each node starts
+    /// out as `Machine::recover_from_wal` rebuilds it from there, so building
+    /// over the directory of an earlier run picks up where that run stopped.
+    pub fn wal_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.wal_dir = Some(dir.into());
+        self
+    }
+
+    pub fn build(self) -> Simulation {
+        assert!(self.n_nodes > 0, "a cluster needs at least one node");
+        let start_time = Instant::now();
+        let ids: Vec<u32> = (0..self.n_nodes).collect();
+        let weights: Vec<(u32, u32)> = (0..).zip(self.weights.iter().copied()).collect();
+        let machines = ids
+            .iter()
+            .map(|&id| {
+                let peers = ids.iter().copied().filter(|&p| p != id).collect();
+                let node_seed = self.seed ^ (u64::from(id) + 1).wrapping_mul(0x2545_f491_4f6c_dd1d);
+                let options = self.options.clone();
+                let mut m = match &self.wal_dir {
+                    Some(dir) => {
+                        let path = dir.join(format!("node-{}.wal", id));
+                        Machine::recover_from_wal(path, id, peers, options, node_seed, start_time)
+                            .unwrap_or_else(|error| panic!("node {}: {}", id, error))
+                    }
+                    None => Machine::new(id, peers, options, node_seed, start_time)
+                        .unwrap_or_else(|error| panic!("node {}: {}", id, error)),
+                };
+                let config = m.state.initial_config.clone();
+                m.state.initial_config = config.with_weights(weights.iter().copied());
+                m.state.refresh_config();
+                if self.bootstrap {
+                    m.state.clear_initial_config();
+                    if id == 0 && m.state.last_log_index() == 0 {
+                        let founders =
+                            Config::new(ids.iter().copied()).with_weights(weights.iter().copied());
+                        m.state.bootstrap(founders);
+                    }
+                }
+                (id, m)
+            })
+            .collect();
+        Simulation::with_machines(machines, self.seed, start_time)
+    }
+}
@@ -0,0 +1,266 @@
+//! Cluster membership: which nodes vote, and which changes to that set are safe.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct Config {
+    pub voters: BTreeSet<u32>,
+    /// Vote weight of each voter that does not weigh 1; only such voters
+    /// are listed.
+    pub weights: BTreeMap<u32, u32>,
+    /// Nodes that are replicated to but neither vote nor count towards a
+    /// quorum, e.g. while they catch up before becoming voters.
+    pub learners: BTreeSet<u32>,
+}
+
+impl Config {
+    pub fn new(voters: impl IntoIterator<Item = u32>) -> Self {
+        Config {
+            voters: voters.into_iter().collect(),
+            ..Default::default()
+        }
+    }
+
+    pub fn is_member(&self, id: u32) -> bool {
+        self.voters.contains(&id) || self.learners.contains(&id)
+    }
+
+    /// Gives voters other than weight 1; ids that are not voters are ignored.
+    pub fn with_weights(mut self, weights: impl IntoIterator<Item = (u32, u32)>) -> Self {
+        for (id, weight) in weights {
+            if self.voters.contains(&id) && weight != 1 {
+                self.weights.insert(id, weight);
+            } else {
+                self.weights.remove(&id);
+            }
+        }
+        self
+    }
+
+    pub fn weight(&self, id: u32) -> u32 {
+        self.weights.get(&id).copied().unwrap_or(1)
+    }
+
+    fn total_weight(&self, ids: impl IntoIterator<Item = u32>) -> usize {
+        ids.into_iter()
+            .filter(|id| self.voters.contains(id))
+            .map(|id| self.weight(id) as usize)
+            .sum()
+    }
+
+    /// Least vote weight that makes a quorum: more than half of all of it.
+    pub fn majority(&self) -> usize {
+        self.total_weight(self.voters.iter().copied()) / 2 + 1
+    }
+
+    /// Whether the voters among `ids` together carry a quorum.
+    pub fn is_quorum<'a>(&self, ids: impl IntoIterator<Item = &'a u32>) -> bool {
+        self.carries(ids, self.majority())
+    }
+
+    /// Whether the voters among `ids` together carry at least `weight`.
+    pub fn carries<'a>(&self, ids: impl IntoIterator<Item = &'a u32>, weight: usize) -> bool {
+        self.total_weight(ids.into_iter().copied()) >= weight
+    }
+
+    /// The highest value that voters carrying a quorum have all reached,
+    /// given each voter's value; 0 if no quorum has reported one.
+    pub fn agreed(&self, values: impl IntoIterator<Item = (u32, usize)>) -> usize {
+        let mut values: Vec<(u32, usize)> = values
+            .into_iter()
+            .filter(|(id, _)| self.voters.contains(id))
+            .collect();
+        values.sort_unstable_by_key(|&(_, value)| std::cmp::Reverse(value));
+        let mut weight = 0;
+        for (id, value) in values {
+            weight += self.weight(id) as usize;
+            if weight >= self.majority() {
+                return value;
+            }
+        }
+        0
+    }
+
+    pub fn apply(&self, change: &ConfigChange) -> Config {
+        let mut voters = self.voters.clone();
+        voters.extend(&change.add);
+        voters.retain(|id| !change.remove.contains(id));
+        let mut weights = self.weights.clone();
+        weights.retain(|id, _| voters.contains(id));
+        let mut learners = self.learners.clone();
+        learners.extend(&change.learners);
+        learners.retain(|id| !voters.contains(id) && !change.remove.contains(id));
+        Config {
+            voters,
+            weights,
+            learners,
+        }
+    }
+}
+
+/// Voters to add and remove in one configuration entry. Adding a learner
+/// as a voter promotes it; removing a node drops it whether it votes or not.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ConfigChange {
+    pub add: BTreeSet<u32>,
+    pub remove: BTreeSet<u32>,
+    /// Nodes to start replicating to without giving them a vote.
+    pub learners: BTreeSet<u32>,
+}
+
+impl ConfigChange {
+    pub fn add(id: u32) -> Self {
+        ConfigChange {
+            add: BTreeSet::from([id]),
+            ..Default::default()
+        }
+    }
+
+    pub fn remove(id: u32) -> Self {
+        ConfigChange {
+            remove: BTreeSet::from([id]),
+            ..Default::default()
+        }
+    }
+
+    /// The same change, except that voters new to `current` join as
+    /// learners first; adding them again later promotes them.
+    pub fn learners_first(&self, current: &Config) -> Self {
+        let (add, fresh): (BTreeSet<u32>, BTreeSet<u32>) = self
+            .add
+            .iter()
+            .partition(|id| current.learners.contains(id));
+        ConfigChange {
+            add,
+            remove: self.remove.clone(),
+            learners: self.learners.union(&fresh).copied().collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    NotLeader,
+    AlreadyMember(u32),
+    NotAMember(u32),
+    EmptyConfig,
+    /// The previous change, appended at this index, has not committed yet;
+    /// only one change may be in flight at a time.
+    ChangeInProgress(usize),
+    /// Some majority of the old voters and some majority of the new ones are
+    /// disjoint, so they could elect two leaders in the same term.
+    NoQuorumOverlap {
+        old: Box<Config>,
+        new: Box<Config>,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::NotLeader => write!(f, "only the leader can change the configuration"),
+            ConfigError::AlreadyMember(id) => write!(f, "node {} is already a member", id),
+            ConfigError::NotAMember(id) => write!(f, "node {} is not a member", id),
+            ConfigError::EmptyConfig => write!(f, "the new configuration has no voters"),
+            ConfigError::ChangeInProgress(index) => {
+                write!(f, "the change at index {} has not committed yet", index)
+            }
+            ConfigError::NoQuorumOverlap { old, new } => write!(
+                f,
+                "majorities of {:?} and {:?} need not overlap",
+                old.voters, new.voters
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Checks that `change` can be applied to `current` in a single step without
+/// risking two disjoint quorums.
+pub fn validate_config_change(current: &Config, change: &ConfigChange) -> Result<(), ConfigError> {
+    if let Some(&id) = change.add.iter().find(|id| current.voters.contains(id)) {
+        return Err(ConfigError::AlreadyMember(id));
+    }
+    if let Some(&id) = change.learners.iter().find(|&&id| current.is_member(id)) {
+        return Err(ConfigError::AlreadyMember(id));
+    }
+    if let Some(&id) = change.remove.iter().find(|&&id| !current.is_member(id)) {
+        return Err(ConfigError::NotAMember(id));
+    }
+    let new = current.apply(change);
+    if new.voters.is_empty() {
+        return Err(ConfigError::EmptyConfig);
+    }
+    // Two quorums overlap exactly when together they outweigh all nodes involved.
+    let involved: usize = current
+        .voters
+        .union(&new.voters)
+        .map(|&id| current.weight(id).max(new.weight(id)) as usize)
+        .sum();
+    if current.majority() + new.majority() <= involved {
+        return Err(ConfigError::NoQuorumOverlap {
+            old: Box::new(current.clone()),
+            new: Box::new(new),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    use super::super::{Machine, Simulation};
+
+    #[test]
+    fn removing_most_voters_at_once_refused() {
+        let mut sim = Simulation::new(5, 42);
+        sim.run_for(Duration::from_secs(1));
+        // Dropping three of five voters at once leaves no overlapping majority.
+        let change = ConfigChange {
+            remove: [0, 1, 2].into(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            sim.change_config(&change),
+            Err(ConfigError::NoQuorumOverlap { .. })
+        ));
+    }
+
+    #[test]
+    fn second_membership_change_waits_for_first() {
+        // A second membership change waits for the first one to commit.
+        let mut sim = Simulation::new(5, 23);
+        sim.run_for(Duration::from_secs(1));
+        let leader = sim.leader().unwrap();
+        let mut others = (0..5).filter(|&id| id != leader);
+        let (a, b) = (others.next().unwrap(), others.next().unwrap());
+        let first = sim.change_config(&ConfigChange::remove(a)).unwrap();
+        assert_eq!(
+            sim.change_config(&ConfigChange::remove(b)),
+            Err(ConfigError::ChangeInProgress(first))
+        );
+        sim.run_for(Duration::from_millis(200));
+        let second = sim.change_config(&ConfigChange::remove(b)).unwrap();
+        assert_eq!(second, first + 1);
+    }
+
+    #[test]
+    fn append_config_refuses_second_uncommitted_change() {
+        // Appending membership entries straight to the log, bypassing the
+        // leader's checks, still stops at one uncommitted change.
+        let start = Instant::now();
+        let mut m = Machine::as_follower(0, vec![1, 2], vec![], start);
+        let config = m.state.config.apply(&ConfigChange::remove(2));
+        let first = m.state.append_config(config.clone()).unwrap();
+        assert_eq!(
+            m.state.append_config(config.clone()),
+            Err(ConfigError::ChangeInProgress(first))
+        );
+        m.state.commit_index = first;
+        assert_eq!(m.state.append_config(config), Ok(first + 1));
+    }
+}
@@ -0,0 +1,133 @@
+//! Many independent Raft groups on one node, sharing its transport and tick
+//! loop. Every message is tagged with the group it belongs to.
+
+use std::collections::HashMap;
+use std::time::Instant;
+
+use super::{Machine, Message, Options, OptionsError, Outbox};
+
+pub type GroupId = u32;
+
+#[derive(Debug, Clone)]
+pub struct GroupMessage {
+    pub group_id: GroupId,
+    pub message: Message,
+}
+
+/// Messages to send, each addressed to a node and tagged with its group.
+pub type GroupOutbox = Vec<(u32, GroupMessage)>;
+
+/// One node's replicas of every group it takes part in.
+pub struct MultiRaft {
+    pub id: u32,
+    pub groups: HashMap<GroupId, Machine>,
+}
+
+impl MultiRaft {
+    pub fn new(id: u32) -> Self {
+        MultiRaft {
+            id,
+            groups: HashMap::new(),
+        }
+    }
+
+    /// Joins `group_id` alongside `peers`. Groups need not share members.
+    pub fn add_group(
+        &mut self,
+        group_id: GroupId,
+        peers: Vec<u32>,
+        options: Options,
+        seed: u64,
+        at: Instant,
+    ) -> Result<(), OptionsError> {
+        let machine = Machine::new(self.id, peers, options, seed, at)?;
+        self.groups.insert(group_id, machine);
+        Ok(())
+    }
+
+    /// Ticks every group, in group id order so runs stay deterministic.
+    pub fn tick(&mut self, at: Instant) -> GroupOutbox {
+        let mut ids: Vec<GroupId> = self.groups.keys().copied().collect();
+        ids.sort_unstable();
+        ids.into_iter()
+            .flat_map(|group_id| tag(group_id, self.groups.get_mut(&group_id).unwrap().tick(at)))
+            .collect()
+    }
+
+    /// Routes `msg` to its group; messages for groups this node is not in are dropped.
+    pub fn handle(&mut self, msg: GroupMessage, at: Instant) -> GroupOutbox {
+        match self.groups.get_mut(&msg.group_id) {
+            Some(machine) => tag(msg.group_id, machine.handle(msg.message, at)),
+            None => vec![],
+        }
+    }
+
+    /// The node this one believes leads `group_id`.
+    pub fn leader_of(&self, group_id: GroupId) -> Option<u32> {
+        let machine = self.groups.get(&group_id)?;
+        machine.role.leader_hint(&machine.state)
+    }
+}
+
+fn tag(group_id: GroupId, outbox: Outbox) -> GroupOutbox {
+    outbox
+        .into_iter()
+        .map(|(to, message)| (to, GroupMessage { group_id, message }))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    #[test]
+    fn multiraft_groups_elect_independently() {
+        // Two groups over the same three nodes elect and keep terms on their own.
+        let start = Instant::now();
+        let mut nodes: BTreeMap<u32, MultiRaft> =
+            (0..3).map(|id| (id, MultiRaft::new(id))).collect();
+        for (&id, node) in &mut nodes {
+            let peers: Vec<u32> = (0..3).filter(|&p| p != id).collect();
+            for group_id in [1, 2] {
+                let seed = u64::from(group_id) << 32 | u64::from(id);
+                node.add_group(group_id, peers.clone(), Options::default(), seed, start)
+                    .unwrap();
+            }
+        }
+        let latency = Duration::from_millis(20);
+        let mut in_transit: Vec<(Instant, u32, GroupMessage)> = vec![];
+        let mut now = start;
+        while now < start + Duration::from_secs(2) {
+            let (due, later): (Vec<_>, Vec<_>) =
+                in_transit.into_iter().partition(|(t, _, _)| *t <= now);
+            in_transit = later;
+            for (_, to, msg) in due {
+                let out = nodes.get_mut(&to).unwrap().handle(msg, now);
+                in_transit.extend(out.into_iter().map(|(to, m)| (now + latency, to, m)));
+            }
+            for node in nodes.values_mut() {
+                let out = node.tick(now);
+                in_transit.extend(out.into_iter().map(|(to, m)| (now + latency, to, m)));
+            }
+            now += Duration::from_millis(10);
+        }
+        let mut leaders = vec![];
+        for group_id in [1, 2] {
+            let views: Vec<(Option<u32>, usize)> = nodes
+                .values()
+                .map(|n| {
+                    (
+                        n.leader_of(group_id),
+                        n.groups[&group_id].state.current_term,
+                    )
+                })
+                .collect();
+            assert!(views[0].0.is_some(), "group {} has no leader", group_id);
+            assert!(views.iter().all(|v| *v == views[0]), "{:?}", views);
+            leaders.push(views[0].0);
+        }
+        assert_ne!(leaders[0], leaders[1]);
+    }
+}
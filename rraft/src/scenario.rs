@@ -0,0 +1,157 @@
+//! Scripted delivery orders, for reproducing the interleavings safety
+//! arguments worry about: "this message arrives before that one, even though
+//! that one was sent first".
+
+use super::{Simulation, TimedMessage};
+
+/// Named messages, each the first one delivered that matches its
+/// predicate, and the order some of them must be delivered in.
+#[derive(Default)]
+pub struct Scenario {
+    messages: Vec<ScriptedMessage>,
+    /// `(first, then)` by position in `messages`.
+    order: Vec<(usize, usize)>,
+}
+
+struct ScriptedMessage {
+    name: &'static str,
+    matches: Box<dyn Fn(&TimedMessage) -> bool>,
+    delivered: bool,
+}
+
+impl Scenario {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Names the first message delivered from now on for which `matches`
+    /// holds.
+    pub fn message(
+        mut self,
+        name: &'static str,
+        matches: impl Fn(&TimedMessage) -> bool + 'static,
+    ) -> Self {
+        assert!(self.position(name).is_none(), "{name} is named twice");
+        self.messages.push(ScriptedMessage {
+            name,
+            matches: Box::new(matches),
+            delivered: false,
+        });
+        self
+    }
+
+    /// Holds messages matching `then` in the queue until `first` has been
+    /// delivered, however early they were sent.
+    pub fn before(mut self, first: &'static str, then: &'static str) -> Self {
+        let position = |name| {
+            self.position(name)
+                .unwrap_or_else(|| panic!("{name} is not a named message"))
+        };
+        let edge = (position(first), position(then));
+        self.order.push(edge);
+        self
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.messages.iter().position(|m| m.name == name)
+    }
+}
+
+impl Simulation {
+    /// Enforces `scenario`'s order on delivery from now on, replacing any
+    /// scenario scripted before.
+    pub fn script(&mut self, scenario: Scenario) {
+        self.scenario = scenario;
+    }
+
+    /// Named messages of the scripted scenario not delivered yet, in the
+    /// order they were named.
+    pub fn scenario_pending(&self) -> Vec<&'static str> {
+        let messages = &self.scenario.messages;
+        messages
+            .iter()
+            .filter(|m| !m.delivered)
+            .map(|m| m.name)
+            .collect()
+    }
+
+    /// Whether `msg` has to wait for a named message not delivered yet.
+    pub(super) fn scenario_holds(&self, msg: &TimedMessage) -> bool {
+        let messages = &self.scenario.messages;
+        self.scenario
+            .order
+            .iter()
+            .any(|&(first, then)| !messages[first].delivered && (messages[then].matches)(msg))
+    }
+
+    /// Marks the named messages `msg` is the first delivery of.
+    pub(super) fn scenario_delivered(&mut self, msg: &TimedMessage) {
+        for m in &mut self.scenario.messages {
+            if !m.delivered && (m.matches)(msg) {
+                m.delivered = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use super::super::{Leader, LogBuilder, Machine, Message};
+
+    #[test]
+    fn figure_8_uncommitted_entry_overwritten() {
+        // Figure 8 of the Raft paper: node 0 leads term 4 and gets its entry
+        // from term 2 onto a majority, yet node 4, holding a term 3 entry at
+        // the same index, is still elected and overwrites it. Scripted so node
+        // 2's ack reaches the leader before node 4 asks node 2 for its vote,
+        // and the leader reaches node 4 only after that, the entry is never
+        // committed, so nothing committed is lost.
+        let mut sim = Simulation::new(5, 31);
+        let at = sim.current_time;
+        for (id, terms) in [(0, &[2][..]), (1, &[2]), (2, &[]), (3, &[]), (4, &[3])] {
+            let peers = (0..5).filter(|&p| p != id).collect();
+            let mut m = Machine::as_follower(id, peers, LogBuilder::new().terms(terms).build(), at);
+            m.state.advance_term(4);
+            if id == 0 {
+                m.state.set_voted_for(Some(0));
+                m.role = Box::new(Leader::new(at, &mut m.state));
+            }
+            sim.machines.insert(id, m);
+        }
+        sim.script(
+            Scenario::new()
+                .message("node 2 acks the term 2 entry", |m| {
+                    m.from == 2
+                        && m.to == 0
+                        && matches!(
+                            m.message,
+                            Message::AppendEntryResponse {
+                                success: true,
+                                match_index: 1,
+                                ..
+                            }
+                        )
+                })
+                .message("node 4 asks node 2 for its vote", |m| {
+                    m.from == 4
+                        && m.to == 2
+                        && matches!(m.message, Message::RequestVoteRequest { .. })
+                })
+                .message("node 0 reaches node 4", |m| m.from == 0 && m.to == 4)
+                .before(
+                    "node 2 acks the term 2 entry",
+                    "node 4 asks node 2 for its vote",
+                )
+                .before("node 4 asks node 2 for its vote", "node 0 reaches node 4"),
+        );
+        sim.run_and_check(Duration::from_secs(1));
+        assert_eq!(sim.scenario_pending(), Vec::<&str>::new());
+        assert_eq!(sim.leader(), Some(4));
+        for m in sim.machines.values() {
+            assert_eq!(m.state.term_at(1), Some(3), "node {}", m.id());
+        }
+    }
+}
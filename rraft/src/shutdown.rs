@@ -0,0 +1,129 @@
+//! Orderly shutdown of the whole cluster: every node finishes applying what
+//! it knows is committed and gets everything it buffered onto disk first.
+
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+use super::{Machine, Simulation};
+
+/// What a node left in storage at shutdown, and so comes back with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShutdownReport {
+    pub term: usize,
+    pub voted_for: Option<u32>,
+    pub last_log_index: usize,
+    /// Entries a restart applies again before hearing from anyone.
+    pub last_applied: usize,
+}
+
+impl Machine {
+    /// Applies everything committed, a tick's budget at a time for as long
+    /// as that makes progress, and writes out whatever is still buffered.
+    /// Output is dropped: there is no one left to send it to.
+    fn shut_down(&mut self, at: Instant) {
+        let before = self.hard_state();
+        loop {
+            let applied = self.state.last_applied;
+            self.applied_since_tick = 0;
+            self.apply_committed(at);
+            if self.state.last_applied == applied {
+                break;
+            }
+        }
+        self.persist(at, before, vec![]);
+        self.flush(at);
+    }
+}
+
+impl Simulation {
+    /// Shuts every running node down in an orderly way, leaving each as if
+    /// crashed, so `restart` brings it back. Reports, by id, what every node
+    /// has in storage, nodes that had already crashed included.
+    pub fn shutdown_all(&mut self) -> BTreeMap<u32, ShutdownReport> {
+        let ids: Vec<u32> = self.machines.keys().copied().collect();
+        for id in ids {
+            let at = self.node_time(id);
+            self.machines.get_mut(&id).unwrap().shut_down(at);
+            self.take_down(id);
+        }
+        self.crashed
+            .iter()
+            .map(|(&id, crashed)| {
+                let mut m = Machine::new(id, vec![], crashed.options.clone(), 0, self.current_time)
+                    .unwrap();
+                m.state.initial_config = crashed.initial_config.clone();
+                m.replay(&crashed.storage.load());
+                let report = ShutdownReport {
+                    term: m.state.current_term,
+                    voted_for: m.state.voted_for,
+                    last_log_index: m.state.last_log_index(),
+                    last_applied: m.state.last_applied,
+                };
+                (id, report)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use super::super::{KvStateMachine, Options, Throttled};
+
+    #[test]
+    fn shutdown_keeps_everything_committed() {
+        // An orderly shutdown right after writes commit, with records still
+        // buffered for group commit and a leader applying one entry a tick,
+        // leaves every node to come back with all it knew was committed.
+        let options = Options {
+            batch_writes: true,
+            ..Options::default()
+        };
+        let mut sim = Simulation::with_options(3, 13, options);
+        let leader = sim.assert_converges(200);
+        let m = sim.machines.get_mut(&leader).unwrap();
+        m.state_machine = Box::new(Throttled {
+            inner: KvStateMachine::default(),
+            per_tick: 1,
+        });
+        let mut last = 0;
+        for i in 0..5 {
+            last = sim
+                .propose(format!("set k{} v{}", i, i).as_bytes())
+                .unwrap();
+        }
+        let deadline = sim.current_time + Duration::from_secs(1);
+        while sim.machines[&leader].state.commit_index < last {
+            assert!(sim.current_time < deadline, "writes never committed");
+            sim.step();
+        }
+        assert!(sim.machines[&leader].state.last_applied < last);
+        let commit_index: BTreeMap<u32, usize> = sim
+            .machines
+            .iter()
+            .map(|(&id, m)| (id, m.state.commit_index))
+            .collect();
+        let reports = sim.shutdown_all();
+        assert!(sim.machines.is_empty());
+        for (&id, report) in &reports {
+            assert_eq!(report.last_applied, commit_index[&id], "node {}", id);
+            sim.restart(id, sim.elapsed());
+        }
+        let stored = reports
+            .values()
+            .filter(|r| r.last_log_index >= last)
+            .count();
+        assert!(stored >= 2, "committed entries on too few disks");
+        sim.step();
+        let recovered = &sim.machines[&leader];
+        assert_eq!(recovered.state.last_applied, last);
+        for i in 0..5 {
+            let value = recovered
+                .state_machine
+                .query(format!("get k{}", i).as_bytes());
+            assert_eq!(value, format!("v{}", i).as_bytes());
+        }
+    }
+}
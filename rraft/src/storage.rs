@@ -0,0 +1,349 @@
+//! Durable node state, written as an append-only sequence of records.
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use super::json::Json;
+use super::snapshot::{wal_record, wal_record_json_with};
+use super::{LogEntry, LogSnapshot};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WalRecord {
+    HardState {
+        term: usize,
+        voted_for: Option<u32>,
+    },
+    Append(LogEntry),
+    /// Drops every entry at or after this index.
+    TruncateFrom(usize),
+    /// The state machine has applied every entry up to this index.
+    Applied(usize),
+    /// Every entry up to this index is committed; only written with
+    /// `persist_commit_index`, and only trusted as far as the log goes.
+    Committed(usize),
+    /// Entries up to the snapshot's last index are compacted into it.
+    Snapshot(LogSnapshot),
+    /// The node went from role `from` to `to` in `term`, `at` after its
+    /// epoch. Only there for post-mortems; replay skips it.
+    RoleChanged {
+        at: Duration,
+        term: usize,
+        from: String,
+        to: String,
+    },
+}
+
+/// What is wrong with a log, found by `validate_log`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogError {
+    /// The entry that should have index `expected` has index `index`.
+    Gap { index: usize, expected: usize },
+    /// Entry `index` has a lower term than the entry before it.
+    TermRegression {
+        index: usize,
+        term: usize,
+        previous: usize,
+    },
+}
+
+impl fmt::Display for LogError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogError::Gap { index, expected } => {
+                write!(f, "entry {} found where entry {} belongs", index, expected)
+            }
+            LogError::TermRegression {
+                index,
+                term,
+                previous,
+            } => write!(
+                f,
+                "entry {} has term {}, below the term {} before it",
+                index, term, previous
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LogError {}
+
+/// Checks that `log` is a run of consecutive indices, starting wherever its
+/// first entry does, with terms that never go down; reports the first entry
+/// that breaks either rule.
+pub fn validate_log(log: &[LogEntry]) -> Result<(), LogError> {
+    for pair in log.windows(2) {
+        let (prev, entry) = (&pair[0], &pair[1]);
+        if entry.index != prev.index + 1 {
+            return Err(LogError::Gap {
+                index: entry.index,
+                expected: prev.index + 1,
+            });
+        }
+        if entry.term < prev.term {
+            return Err(LogError::TermRegression {
+                index: entry.index,
+                term: entry.term,
+                previous: prev.term,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Every role change recorded in `records`, oldest first, as
+/// `(at, term, from, to)`.
+pub fn role_changes(records: &[WalRecord]) -> Vec<(Duration, usize, &str, &str)> {
+    records
+        .iter()
+        .filter_map(|record| match record {
+            WalRecord::RoleChanged { at, term, from, to } => {
+                Some((*at, *term, from.as_str(), to.as_str()))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+pub trait Storage {
+    /// Starts writing `records` at `at` and returns when they are durable;
+    /// on an error, none of them counts as durable.
+    fn save(&mut self, records: &[WalRecord], at: Instant) -> io::Result<Instant>;
+    fn load(&self) -> Vec<WalRecord>;
+}
+
+impl<S: Storage + ?Sized> Storage for Box<S> {
+    fn save(&mut self, records: &[WalRecord], at: Instant) -> io::Result<Instant> {
+        (**self).save(records, at)
+    }
+
+    fn load(&self) -> Vec<WalRecord> {
+        (**self).load()
+    }
+}
+
+/// Keeps records in memory; writes are durable immediately.
+#[derive(Debug, Default)]
+pub struct MemStorage {
+    records: Vec<WalRecord>,
+}
+
+impl Storage for MemStorage {
+    fn save(&mut self, records: &[WalRecord], at: Instant) -> io::Result<Instant> {
+        self.records.extend_from_slice(records);
+        Ok(at)
+    }
+
+    fn load(&self) -> Vec<WalRecord> {
+        self.records.clone()
+    }
+}
+
+/// Appends records to a file, one JSON object per line, syncing each write.
+pub struct FileStorage {
+    path: PathBuf,
+    file: File,
+    /// Commands at least this long are written compressed, where that
+    /// makes them smaller.
+    compress_over: Option<usize>,
+}
+
+impl FileStorage {
+    /// Opens the log at `path`, creating it if needed; new records go after
+    /// any already there.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(FileStorage {
+            path,
+            file,
+            compress_over: None,
+        })
+    }
+
+    /// Compresses appended commands of at least `threshold` bytes from now
+    /// on; reading decompresses them whatever the setting.
+    pub fn compress_over(mut self, threshold: usize) -> Self {
+        self.compress_over = Some(threshold);
+        self
+    }
+}
+
+/// Reads back every record written to the log at `path`.
+pub fn read_wal(path: impl AsRef<Path>) -> io::Result<Vec<WalRecord>> {
+    let invalid = |e: super::json::JsonError| io::Error::new(io::ErrorKind::InvalidData, e.0);
+    BufReader::new(File::open(path)?)
+        .lines()
+        .map(|line| wal_record(&Json::parse(&line?).map_err(invalid)?).map_err(invalid))
+        .collect()
+}
+
+impl Storage for FileStorage {
+    fn save(&mut self, records: &[WalRecord], at: Instant) -> io::Result<Instant> {
+        let mut buf = String::new();
+        for record in records {
+            buf.push_str(&wal_record_json_with(record, self.compress_over).to_string());
+            buf.push('\n');
+        }
+        self.file
+            .write_all(buf.as_bytes())
+            .and_then(|()| self.file.sync_data())
+            .map_err(|e| {
+                io::Error::new(e.kind(), format!("writing {}: {}", self.path.display(), e))
+            })?;
+        Ok(at)
+    }
+
+    fn load(&self) -> Vec<WalRecord> {
+        read_wal(&self.path).unwrap_or_else(|e| panic!("reading {}: {}", self.path.display(), e))
+    }
+}
+
+/// Models fsync cost: every write takes `latency` of logical time, and writes
+/// queue behind each other like they would on a single disk.
+pub struct SlowStorage<S> {
+    inner: S,
+    latency: Duration,
+    busy_until: Option<Instant>,
+}
+
+impl<S: Storage> SlowStorage<S> {
+    pub fn new(inner: S, latency: Duration) -> Self {
+        SlowStorage {
+            inner,
+            latency,
+            busy_until: None,
+        }
+    }
+}
+
+impl<S: Storage> Storage for SlowStorage<S> {
+    fn save(&mut self, records: &[WalRecord], at: Instant) -> io::Result<Instant> {
+        self.inner.save(records, at)?;
+        let start = self.busy_until.map_or(at, |busy| busy.max(at));
+        let done = start + self.latency;
+        self.busy_until = Some(done);
+        Ok(done)
+    }
+
+    fn load(&self) -> Vec<WalRecord> {
+        self.inner.load()
+    }
+}
+
+/// Fails the `nth` write (counting from 1) as a full or broken disk would,
+/// writing nothing of it; every other write goes to `inner`.
+pub struct FailingStorage<S> {
+    inner: S,
+    nth: usize,
+    writes: usize,
+}
+
+impl<S: Storage> FailingStorage<S> {
+    pub fn new(inner: S, nth: usize) -> Self {
+        assert!(nth > 0, "writes are counted from 1");
+        FailingStorage {
+            inner,
+            nth,
+            writes: 0,
+        }
+    }
+}
+
+impl<S: Storage> Storage for FailingStorage<S> {
+    fn save(&mut self, records: &[WalRecord], at: Instant) -> io::Result<Instant> {
+        self.writes += 1;
+        if self.writes == self.nth {
+            return Err(io::Error::other(format!("write {} failed", self.nth)));
+        }
+        self.inner.save(records, at)
+    }
+
+    fn load(&self) -> Vec<WalRecord> {
+        self.inner.load()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{LogBuilder, Machine, Message, Options};
+    use super::*;
+
+    #[test]
+    fn role_changes_recorded_for_audit() {
+        // Role changes go to storage with the rest of the node's state, so a
+        // post-mortem can read back what the node was doing.
+        let start = Instant::now();
+        let path = std::env::temp_dir().join(format!("rraft-{}-audit.wal", std::process::id()));
+        let mut m = Machine::new(0, vec![1, 2], Options::default(), 0, start).unwrap();
+        m.storage = Box::new(FileStorage::open(&path).unwrap());
+        let ms = Duration::from_millis;
+        m.tick(start + ms(1000));
+        m.handle(
+            Message::RequestVoteResponse {
+                term: 1,
+                vote_granted: true,
+                from: 1,
+            },
+            start + ms(1020),
+        );
+        m.handle(
+            Message::RequestVoteRequest {
+                term: 3,
+                candidate_id: 2,
+                last_log_index: 5,
+                last_log_term: 3,
+                forced: true,
+            },
+            start + ms(1050),
+        );
+        let records = read_wal(&path).unwrap();
+        let recovered =
+            Machine::recover_from_wal(&path, 0, vec![1, 2], Options::default(), 0, start);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            role_changes(&records),
+            vec![
+                (ms(1000), 1, "Follower", "Candidate"),
+                (ms(1020), 1, "Candidate", "Leader"),
+                (ms(1050), 3, "Leader", "Follower"),
+            ]
+        );
+        assert_eq!(recovered.unwrap().state.current_term, 3);
+    }
+
+    #[test]
+    fn validate_log_reports_first_bad_entry() {
+        // A log with a missing entry or a term going backwards is reported at
+        // the first entry that is out of place.
+        let good = LogBuilder::new().terms(&[1, 1, 2, 2]).build();
+        assert_eq!(validate_log(&good), Ok(()));
+        let mut gap = good.clone();
+        gap.remove(2);
+        let regressed = LogBuilder::new().terms(&[1, 2, 1, 2]).build();
+        for (name, log, expected) in [
+            (
+                "gap",
+                gap,
+                LogError::Gap {
+                    index: 4,
+                    expected: 3,
+                },
+            ),
+            (
+                "regression",
+                regressed,
+                LogError::TermRegression {
+                    index: 3,
+                    term: 1,
+                    previous: 2,
+                },
+            ),
+        ] {
+            let error = validate_log(&log).unwrap_err();
+            assert_eq!(error, expected, "log with a {}", name);
+        }
+    }
+}
@@ -0,0 +1,430 @@
+//! Client writes and reads: refusals, answers, retries and read paths.
+
+use super::super::*;
+
+#[test]
+fn follower_serves_reads_only_within_max_staleness() {
+    // A follower last caught up with the leader's commit index at `start`
+    // answers a read 50ms later by itself, saying how stale it may be; at
+    // 200ms it is past `max_staleness` and points the client to the leader.
+    let start = Instant::now();
+    let mut m = Machine::as_follower(1, vec![0, 2], LogBuilder::new().terms(&[1]).build(), start);
+    m.state.options.max_staleness = Some(Duration::from_millis(100));
+    m.process(
+        Message::AppendEntryRequest {
+            term: 1,
+            leader_id: 0,
+            prev_log_index: 1,
+            prev_log_term: 1,
+            entries: vec![],
+            leader_commit: 1,
+            read_round: 0,
+            read_lease: None,
+        },
+        start,
+    );
+    let read = |m: &mut Machine, after: u64| {
+        let out = m.process(
+            Message::ClientRead {
+                client_id: 99,
+                request_id: after,
+                query: b"get e1".to_vec(),
+                local_only: false,
+            },
+            start + Duration::from_millis(after),
+        );
+        match &out[..] {
+            [(
+                99,
+                Message::ClientResponse {
+                    success,
+                    leader_hint,
+                    result,
+                    staleness,
+                    ..
+                },
+            )] => (*success, *leader_hint, result.clone(), *staleness),
+            _ => panic!("unexpected reply {:?}", out),
+        }
+    };
+    assert_eq!(
+        read(&mut m, 50),
+        (
+            true,
+            Some(0),
+            b"t1".to_vec(),
+            Some(Duration::from_millis(50))
+        )
+    );
+    let (success, leader_hint, _, _) = read(&mut m, 200);
+    assert!(!success);
+    assert_eq!(leader_hint, Some(0));
+}
+
+#[test]
+fn min_ack_replicas_holds_back_acknowledgement() {
+    // Writes commit on three of five nodes but are only acknowledged on four.
+    let options = Options {
+        min_ack_replicas: Some(4),
+        ..Options::default()
+    };
+    let mut sim = Simulation::with_options(5, 13, options);
+    sim.run_for(Duration::from_secs(1));
+    let leader = sim.leader().unwrap();
+    let lagging: Vec<u32> = (0..5).filter(|&id| id != leader).take(2).collect();
+    for &id in &lagging {
+        sim.pause(id);
+    }
+    let client = sim.add_client();
+    sim.clients
+        .get_mut(&client)
+        .unwrap()
+        .submit(b"set durable yes");
+    sim.run_for(Duration::from_millis(300));
+    let committed = sim.machines[&leader].is_committed(1);
+    let acked_early = sim.clients[&client].completed.len();
+    sim.resume(lagging[0]);
+    sim.run_for(Duration::from_millis(300));
+    assert!(committed);
+    assert_eq!(acked_early, 0);
+    assert_eq!(sim.clients[&client].result(1), Some(Ok(&b"yes"[..])));
+}
+
+#[test]
+fn wait_for_apply_answers_with_the_result() {
+    // A write answered on apply carries the state machine's result; one
+    // answered on commit comes back before the slow state machine gets to it.
+    let mut sim = Simulation::new(3, 37);
+    for m in sim.machines.values_mut() {
+        m.state_machine = Box::new(Throttled {
+            inner: KvStateMachine::default(),
+            per_tick: 1,
+        });
+    }
+    sim.run_for(Duration::from_secs(1));
+    let leader = sim.leader().unwrap();
+    for i in 0..20 {
+        sim.propose(format!("set backlog{} yes", i).as_bytes())
+            .unwrap();
+    }
+    let client = sim.add_client();
+    let c = sim.clients.get_mut(&client).unwrap();
+    c.submit_committed(b"set farewell bye");
+    c.submit(b"set greeting hello");
+    let mut answered_unapplied = false;
+    while sim.clients[&client].completed.len() < 2 {
+        sim.step();
+        let applied = sim.machines[&leader].state_machine.query(b"get farewell");
+        answered_unapplied |= sim.clients[&client].completed.len() == 1 && applied.is_empty();
+    }
+    let results = &sim.clients[&client].completed;
+    assert!(results[0].1.is_empty() && answered_unapplied);
+    assert_eq!(results[1].1, b"hello");
+}
+
+#[test]
+fn dedup_writes_answers_retry_from_session() {
+    // With write dedup a retried write gets the answer the first copy got,
+    // straight away, instead of being committed and applied again.
+    let start = Instant::now();
+    let options = Options {
+        dedup_writes: true,
+        ..Options::default()
+    };
+    let mut m = Machine::new(0, vec![], options, 0, start).unwrap();
+    let mut at = start;
+    while m.role_name() != "Leader" {
+        at += Duration::from_millis(10);
+        m.tick(at);
+    }
+    let write = |request_id, command: &str| Message::ClientRequest {
+        client_id: CLIENT_ID_BASE,
+        request_id,
+        command: command.as_bytes().to_vec(),
+        wait_for_apply: true,
+    };
+    m.handle(write(1, "set k v"), at);
+    let first = m.handle(write(2, "del k"), at);
+    let applied = m.state.last_applied;
+    let retry = m.handle(write(2, "del k"), at);
+    for out in [&first, &retry] {
+        assert!(
+            matches!(
+                &out[..],
+                [(_, Message::ClientResponse { request_id: 2, success: true, result, .. })]
+                    if result == b"v"
+            ),
+            "write not answered with the deleted value: {:?}",
+            out
+        );
+    }
+    assert_eq!(m.state.last_applied, applied, "retried write applied twice");
+    assert_eq!(m.state.last_log_index(), applied);
+}
+
+#[test]
+fn dedup_writes_answers_retry_at_new_leader() {
+    // The session table is filled in on apply, so a retry that reaches the
+    // next leader is answered from it rather than deleting the key again.
+    let options = Options {
+        dedup_writes: true,
+        ..Options::default()
+    };
+    let mut sim = Simulation::with_options(3, 7, options);
+    let leader = sim.assert_converges(500);
+    let write = |request_id, command: &str| Message::ClientRequest {
+        client_id: DRIVER_ID,
+        request_id,
+        command: command.as_bytes().to_vec(),
+        wait_for_apply: true,
+    };
+    sim.send(
+        DRIVER_ID,
+        vec![(leader, write(1, "set k v")), (leader, write(2, "del k"))],
+    );
+    sim.run_and_check(Duration::from_millis(500));
+    let others: Vec<u32> = (0..3).filter(|&id| id != leader).collect();
+    sim.partition(&[&[leader], &others]);
+    sim.run_and_check(Duration::from_secs(2));
+    let new_leader = others
+        .iter()
+        .copied()
+        .find(|id| sim.machines[id].role_name() == "Leader")
+        .expect("no new leader behind the partition");
+    let at = sim.node_time(new_leader);
+    let m = sim.machines.get_mut(&new_leader).unwrap();
+    let applied = m.state.last_applied;
+    let last_index = m.state.last_log_index();
+    let retry = m.handle(write(2, "del k"), at);
+    assert!(
+        matches!(
+            &retry[..],
+            [(_, Message::ClientResponse { request_id: 2, success: true, result, .. })]
+                if result == b"v"
+        ),
+        "retry not answered with the deleted value: {:?}",
+        retry
+    );
+    assert_eq!(m.state.last_applied, applied, "retried write applied again");
+    assert_eq!(
+        m.state.last_log_index(),
+        last_index,
+        "retried write appended again"
+    );
+}
+
+#[test]
+fn follower_read_lease_serves_until_expiry() {
+    // A follower holding a read lease from the leader answers reads itself
+    // until the lease runs out, then sends the client to the leader.
+    let options = Options {
+        follower_read_lease: Some(Duration::from_millis(100)),
+        ..Options::default()
+    };
+    let mut sim = Simulation::with_options(3, 12, options);
+    sim.run_for(Duration::from_secs(1));
+    sim.propose(b"set k v").unwrap();
+    sim.run_for(Duration::from_millis(200));
+    let leader = sim.leader().unwrap();
+    let follower = (0..3).find(|&id| id != leader).unwrap();
+    let at = sim.current_time;
+    let m = sim.machines.get_mut(&follower).unwrap();
+    let lease = m.role.read_lease().expect("leader granted no lease");
+    let read = |request_id| Message::ClientRead {
+        client_id: CLIENT_ID_BASE,
+        request_id,
+        query: b"get k".to_vec(),
+        local_only: false,
+    };
+    let answer = |out: Outbox| match out.as_slice() {
+        [(
+            _,
+            Message::ClientResponse {
+                success,
+                result,
+                leader_hint,
+                ..
+            },
+        )] => (*success, result.clone(), *leader_hint),
+        other => panic!("expected one answer, got {:?}", other),
+    };
+    assert_eq!(
+        answer(m.handle(read(1), at)),
+        (true, b"v".to_vec(), Some(leader))
+    );
+    assert_eq!(
+        answer(m.handle(read(2), lease)),
+        (false, vec![], Some(leader))
+    );
+}
+
+#[test]
+fn propose_says_why_write_refused() {
+    // Proposing directly to a machine says why a write was refused instead
+    // of just returning nothing.
+    let start = Instant::now();
+    let mut follower = Machine::as_follower(1, vec![0, 2], vec![], start);
+    let new_leader = |options: Options| {
+        let mut m = Machine::as_leader(0, vec![1, 2], vec![], start);
+        m.state.options = options;
+        m
+    };
+    let mut full = new_leader(Options {
+        max_uncommitted: Some(1),
+        ..Options::default()
+    });
+    full.propose(b"set a 1".to_vec(), start).unwrap();
+    let mut reconfiguring = new_leader(Options {
+        reject_writes_during_reconfig: true,
+        ..Options::default()
+    });
+    reconfiguring
+        .change_config(&ConfigChange::remove(2), start)
+        .unwrap();
+    let mut draining = new_leader(Options::default());
+    draining.drain(start).unwrap();
+    let mut bounded = new_leader(Options {
+        max_entry_size: Some(4),
+        ..Options::default()
+    });
+    for (to, m, expected) in [
+        ("leaderless follower", &mut follower, RaftError::NoLeader),
+        ("full leader", &mut full, RaftError::Busy),
+        (
+            "reconfiguring leader",
+            &mut reconfiguring,
+            RaftError::Reconfiguring,
+        ),
+        ("draining leader", &mut draining, RaftError::Busy),
+        (
+            "bounded leader",
+            &mut bounded,
+            RaftError::EntryTooLarge { max: 4 },
+        ),
+    ] {
+        let error = m.propose(b"set b 2".to_vec(), start).unwrap_err();
+        assert_eq!(error, expected, "propose to {}", to);
+    }
+    assert_eq!(
+        follower
+            .change_config(&ConfigChange::remove(2), start)
+            .unwrap_err(),
+        ConfigError::NotLeader
+    );
+    assert_eq!(
+        new_leader(Options::default())
+            .change_config(&ConfigChange::remove(7), start)
+            .unwrap_err(),
+        ConfigError::NotAMember(7)
+    );
+}
+
+#[test]
+fn follower_points_refused_write_at_leader() {
+    // A follower that has heard from a leader refuses a write with a hint at
+    // it, while the simulation refuses one outright until someone leads.
+    let mut sim = Simulation::new(3, 8);
+    assert_eq!(sim.propose(b"set a 1"), Err(RaftError::NoLeader));
+    sim.run_for(Duration::from_secs(1));
+    let leader = sim.leader().unwrap();
+    let follower = (0..3).find(|&id| id != leader).unwrap();
+    let at = sim.current_time;
+    let error = sim
+        .machines
+        .get_mut(&follower)
+        .unwrap()
+        .propose(b"set a 1".to_vec(), at)
+        .unwrap_err();
+    assert_eq!(
+        error,
+        RaftError::LeadershipLost {
+            leader_hint: Some(leader)
+        }
+    );
+    let index = sim.propose(b"set a 1").unwrap();
+    assert_eq!(index, sim.machines[&leader].state.last_log_index());
+}
+
+#[test]
+fn max_entry_size_refuses_oversized_command() {
+    // A leader with an entry size limit refuses a command one byte over it
+    // before appending, and the client gives up on it instead of retrying;
+    // a command exactly at the limit still commits.
+    let mut sim = Simulation::with_options(
+        3,
+        5,
+        Options {
+            max_entry_size: Some(16),
+            ..Options::default()
+        },
+    );
+    sim.assert_converges(200);
+    let leader = sim.leader().unwrap();
+    let oversized = b"set k 01234567890";
+    let borderline = b"set k 0123456789";
+    assert_eq!((oversized.len(), borderline.len()), (17, 16));
+    let client = sim.add_client();
+    let c = sim.clients.get_mut(&client).unwrap();
+    c.submit(oversized);
+    c.submit(borderline);
+    sim.run_for(Duration::from_secs(1));
+    let c = &sim.clients[&client];
+    assert_eq!(c.result(1), Some(Err(RaftError::EntryTooLarge { max: 16 })));
+    assert_eq!(c.result(2), Some(Ok(&b"0123456789"[..])));
+    let commit = sim.submit(oversized);
+    let refused = sim.block_on(Duration::from_secs(1), commit);
+    assert_eq!(refused, Some(Err(RaftError::EntryTooLarge { max: 16 })));
+    let log = &sim.machines[&leader].state.log;
+    assert!(log.iter().all(|e| e.command != oversized));
+    assert!(log.iter().any(|e| e.command == borderline));
+}
+
+#[test]
+fn leader_with_clock_jumped_back_serves_no_stale_read() {
+    // A leader whose clock jumps a second back still believes every ack is
+    // fresh, but that belief serves no reads: cut off while the others elect
+    // a leader and overwrite a key, it cannot confirm a read with a quorum,
+    // so its client only gets an answer, the new value, once it is deposed.
+    let mut sim = Simulation::with_options(
+        3,
+        13,
+        Options {
+            follower_read_lease: Some(Duration::from_millis(100)),
+            ..Options::default()
+        },
+    );
+    let old = sim.assert_converges(200);
+    sim.propose(b"set x 1").unwrap();
+    sim.run_for(Duration::from_millis(200));
+    sim.jump_clock(
+        old,
+        sim.elapsed(),
+        ClockJump::Backward(Duration::from_secs(1)),
+    );
+    sim.step();
+    assert_eq!(
+        sim.node_time(old) + Duration::from_secs(1),
+        sim.current_time
+    );
+    let rest: Vec<u32> = (0..3).filter(|&id| id != old).collect();
+    sim.partition(&[&[old], &rest]);
+    sim.run_and_check(Duration::from_secs(1));
+    let new = sim.leader().unwrap();
+    assert_ne!(new, old);
+    assert_eq!(sim.machines[&old].role_name(), "Leader");
+    sim.propose(b"set x 2").unwrap();
+    sim.run_and_check(Duration::from_millis(300));
+    let client = sim.add_client();
+    let c = sim.clients.get_mut(&client).unwrap();
+    c.leader_hint = Some(old);
+    c.read(b"get x");
+    sim.run_and_check(Duration::from_secs(1));
+    assert_eq!(
+        sim.clients[&client].result(1),
+        None,
+        "stale leader answered"
+    );
+    sim.heal();
+    sim.run_and_check(Duration::from_secs(1));
+    assert_eq!(sim.clients[&client].result(1), Some(Ok(&b"2"[..])));
+}
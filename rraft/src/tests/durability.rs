@@ -0,0 +1,223 @@
+//! Durability: the write-ahead log, recovery and persisted votes.
+
+use super::super::storage::FailingStorage;
+use super::super::*;
+
+#[test]
+fn cluster_over_wal_dir_resumes_earlier_run() {
+    // Every node of a cluster built over the logs an earlier run left
+    // behind starts where that node stopped, committed writes applied.
+    let dir = std::env::temp_dir().join(format!("rraft-{}-cluster", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut sim = ClusterBuilder::new(3).seed(4).wal_dir(&dir).build();
+    sim.run_for(Duration::from_secs(1));
+    sim.propose(b"set key value").unwrap();
+    sim.run_for(Duration::from_secs(1));
+    let before: Vec<_> = sim
+        .machines
+        .values()
+        .map(|m| (m.state.current_term, m.state.log.clone()))
+        .collect();
+    drop(sim);
+    let sim = ClusterBuilder::new(3).seed(4).wal_dir(&dir).build();
+    std::fs::remove_dir_all(&dir).unwrap();
+    let after: Vec<_> = sim
+        .machines
+        .values()
+        .map(|m| (m.state.current_term, m.state.log.clone()))
+        .collect();
+    assert_eq!(after, before);
+    for m in sim.machines.values() {
+        assert_eq!(m.state_machine.query(b"get key"), b"value");
+    }
+}
+
+#[test]
+fn follower_recovers_from_wal_after_crash() {
+    // A follower crashes mid-replication and comes back from its WAL.
+    let mut sim = Simulation::new(3, 2);
+    sim.run_for(Duration::from_secs(1));
+    let follower = (0..3).find(|&id| Some(id) != sim.leader()).unwrap();
+    for i in 0..10 {
+        sim.propose(format!("set key{} value{}", i, i).as_bytes())
+            .unwrap();
+    }
+    let now = sim.elapsed();
+    sim.crash(follower, now + Duration::from_millis(30));
+    sim.restart(follower, now + Duration::from_millis(500));
+    sim.run_for(Duration::from_millis(510));
+    let recovered = sim.machines[&follower].state.last_log_index();
+    assert_eq!(recovered, 10, "entries logged before the crash were lost");
+    sim.run_and_check(Duration::from_secs(1));
+    let leader = sim.leader().unwrap();
+    assert_eq!(
+        sim.machines[&follower].state.commit_index,
+        sim.machines[&leader].state.commit_index
+    );
+}
+
+#[test]
+fn batched_vote_persistence_holds_vote_until_durable() {
+    // With batched vote persistence a vote waits for the group commit, and
+    // is only sent once that write is durable.
+    let mut sim = ClusterBuilder::new(3)
+        .seed(5)
+        .vote_persistence(VotePersistence::Batched)
+        .build();
+    let latency = Duration::from_millis(20);
+    sim.set_write_latency(latency);
+    let at = sim.current_time;
+    let m = sim.machines.get_mut(&1).unwrap();
+    let ask = Message::RequestVoteRequest {
+        term: m.state.current_term + 1,
+        candidate_id: 2,
+        last_log_index: m.state.last_log_index(),
+        last_log_term: m.state.last_log_term(),
+        forced: false,
+    };
+    let is_vote = |out: &Outbox| {
+        out.iter().any(|(to, msg)| {
+            *to == 2
+                && matches!(
+                    msg,
+                    Message::RequestVoteResponse {
+                        vote_granted: true,
+                        ..
+                    }
+                )
+        })
+    };
+    assert!(m.handle(ask, at).is_empty(), "vote sent before any write");
+    assert_eq!(m.state.voted_for, Some(2));
+    assert!(!is_vote(&m.tick(at)), "vote sent as its write started");
+    assert!(!is_vote(&m.tick(at + latency / 2)), "vote sent mid-write");
+    assert!(
+        is_vote(&m.tick(at + latency)),
+        "vote still held once durable"
+    );
+}
+
+#[test]
+fn wal_replay_applies_each_entry_once() {
+    // Recovering from the same log twice, or replaying it into a machine
+    // that already recovered, applies every entry exactly once.
+    let start = Instant::now();
+    let path = std::env::temp_dir().join(format!("rraft-{}-replay.wal", std::process::id()));
+    let mut leader = Machine::as_leader(0, vec![], vec![], start);
+    leader.storage = Box::new(FileStorage::open(&path).unwrap());
+    for command in [&b"set a 1"[..], b"set b 2", b"del a"] {
+        leader.propose(command.to_vec(), start).unwrap();
+    }
+    leader.tick(start);
+    let recover = || Machine::recover_from_wal(&path, 0, vec![], Options::default(), 1, start);
+    let (first, second) = (recover().unwrap(), recover().unwrap());
+    assert_eq!(first.state.last_applied, 3);
+    assert_eq!(first.state.last_applied, second.state.last_applied);
+    assert_eq!(
+        first.state_machine.snapshot(),
+        second.state_machine.snapshot()
+    );
+    let applies = Rc::new(Cell::new(0));
+    let counted = Box::new(Counted {
+        inner: KvStateMachine::default(),
+        applies: applies.clone(),
+    });
+    let mut m =
+        Machine::with_state_machine(0, vec![], Options::default(), 1, start, counted).unwrap();
+    let records = storage::read_wal(&path).unwrap();
+    m.replay(&records);
+    m.replay(&records);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(applies.get(), 3);
+    assert_eq!(m.state_machine.snapshot(), first.state_machine.snapshot());
+}
+
+#[test]
+fn persisted_commit_index_resumes_apply() {
+    // A node that committed more than it had applied before crashing picks
+    // up from its persisted commit index on recovery, if it wrote one;
+    // otherwise from what it had applied. A commit index past the end of
+    // the recovered log is cut back to it.
+    let start = Instant::now();
+    for persist_commit_index in [false, true] {
+        let options = Options {
+            persist_commit_index,
+            ..Options::default()
+        };
+        let path = std::env::temp_dir().join(format!(
+            "rraft-{}-commit-{}.wal",
+            std::process::id(),
+            persist_commit_index
+        ));
+        let mut leader = Machine::as_leader(0, vec![], vec![], start);
+        leader.state.options = options.clone();
+        leader.storage = Box::new(FileStorage::open(&path).unwrap());
+        leader.state_machine = Box::new(Throttled {
+            inner: KvStateMachine::default(),
+            per_tick: 0,
+        });
+        for command in [&b"set a 1"[..], b"set b 2", b"set c 3"] {
+            leader.propose(command.to_vec(), start).unwrap();
+        }
+        leader.tick(start);
+        assert_eq!(leader.state.commit_index, 3);
+        assert_eq!(leader.state.last_applied, 0);
+        let recovered =
+            Machine::recover_from_wal(&path, 0, vec![], options.clone(), 1, start).unwrap();
+        let resumed = if persist_commit_index { 3 } else { 0 };
+        assert_eq!(recovered.state.commit_index, resumed);
+        assert_eq!(recovered.state.last_applied, resumed);
+        let mut records = storage::read_wal(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        records.push(WalRecord::Committed(10));
+        let mut m = Machine::new(0, vec![], options, 1, start).unwrap();
+        m.replay(&records);
+        assert_eq!(m.state.commit_index, 3);
+    }
+}
+
+#[test]
+fn failed_vote_write_withholds_grant() {
+    // A vote whose write fails is not granted, not even to the candidate
+    // asking again; the node answers once a later write gets it to disk.
+    let start = Instant::now();
+    let mut m = Machine::as_follower(0, vec![1, 2], vec![], start);
+    m.storage = Box::new(FailingStorage::new(MemStorage::default(), 1));
+    let request = Message::RequestVoteRequest {
+        term: 2,
+        candidate_id: 1,
+        last_log_index: 0,
+        last_log_term: 0,
+        forced: false,
+    };
+    let voted = |m: &Machine| {
+        m.storage.load().iter().any(|r| {
+            matches!(
+                r,
+                WalRecord::HardState {
+                    voted_for: Some(1),
+                    ..
+                }
+            )
+        })
+    };
+    let granted = |out: &Outbox| {
+        out.iter().any(|(to, msg)| {
+            *to == 1
+                && matches!(
+                    msg,
+                    Message::RequestVoteResponse {
+                        vote_granted: true,
+                        ..
+                    }
+                )
+        })
+    };
+    let at = start + Duration::from_millis(10);
+    assert!(!granted(&m.handle(request.clone(), at)));
+    assert!(!granted(&m.handle(request, at)));
+    assert!(!voted(&m));
+    let out = m.tick(at + Duration::from_millis(10));
+    assert!(voted(&m));
+    assert!(granted(&out));
+}
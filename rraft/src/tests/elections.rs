@@ -0,0 +1,1055 @@
+//! Elections: who votes for whom, when nodes campaign, and how leadership
+//! changes hands.
+
+use std::cell::RefCell;
+
+use super::super::*;
+
+#[test]
+fn partitioned_leader_steps_down_once_healed() {
+    let mut sim = Simulation::new(5, 42);
+    sim.run_for(Duration::from_secs(1));
+    let leader = sim.leader().unwrap();
+    let transitions = Rc::new(RefCell::new(vec![]));
+    let seen = transitions.clone();
+    sim.machines
+        .get_mut(&leader)
+        .unwrap()
+        .observe(Box::new(move |old, new, term| {
+            seen.borrow_mut()
+                .push((old.to_string(), new.to_string(), term))
+        }));
+    let rest: Vec<u32> = (0..5).filter(|&id| id != leader).collect();
+    sim.partition(&[&[leader], &rest]);
+    sim.run_for(Duration::from_secs(1));
+    sim.heal();
+    sim.run_and_check(Duration::from_secs(1));
+    let new = sim.leader().unwrap();
+    assert_ne!(new, leader);
+    let term = sim.machines[&new].state.current_term;
+    assert_eq!(
+        *transitions.borrow(),
+        [("Leader".to_string(), "Follower".to_string(), term)]
+    );
+    let timeline = sim.leadership_timeline();
+    assert_eq!(timeline.first().map(|&(_, l, _)| l), Some(None));
+    assert!(timeline.iter().any(|&(_, l, _)| l == Some(leader)));
+    assert_eq!(
+        timeline.last().map(|&(_, l, t)| (l, t)),
+        Some((Some(new), term))
+    );
+    sim.assert_single_vote_per_term();
+}
+
+#[test]
+fn election_timeouts_stay_within_range_for_each_distribution() {
+    // Both distributions draw from `[base, 2 * base)`; the normal one
+    // bunches up around `1.5 * base` while the uniform one spreads out.
+    let mut spreads = vec![];
+    for distribution in [TimeoutDistribution::Uniform, TimeoutDistribution::Normal] {
+        let options = Options {
+            timeout_distribution: distribution,
+            ..Options::default()
+        };
+        let mut s = State::new(0, vec![1, 2], options, 7);
+        let base = s.options.base_election_timeout();
+        let timeouts: Vec<Duration> = (0..10_000).map(|_| s.random_election_timeout()).collect();
+        for &t in &timeouts {
+            assert!(
+                base <= t && t < base * 2,
+                "{:?} timeout {:?} outside [{:?}, {:?})",
+                distribution,
+                t,
+                base,
+                base * 2
+            );
+        }
+        let middle = timeouts
+            .iter()
+            .filter(|&&t| base * 5 / 4 <= t && t < base * 7 / 4)
+            .count();
+        spreads.push(middle);
+    }
+    // Half of a uniform draw lands in the middle half of the range; about
+    // two thirds of a normal one with deviation `base / 4` does.
+    assert!((4_500..5_500).contains(&spreads[0]), "{:?}", spreads);
+    assert!(spreads[1] > 6_500, "{:?}", spreads);
+}
+
+#[test]
+fn candidate_concedes_to_lower_id_only_if_its_log_would_win() {
+    // Nodes 1 and 2 campaign in the same term. Node 2 concedes to node 1
+    // when their logs are alike, but not when node 1's is behind: as a
+    // Follower it would refuse node 1 its vote anyway.
+    let options = Options {
+        concede_to_lower_id: true,
+        ..Options::default()
+    };
+    let start = Instant::now();
+    for longer in [false, true] {
+        let mut low = Machine::new(1, vec![2, 3], options.clone(), 1, start).unwrap();
+        let mut high = Machine::new(2, vec![1, 3], options.clone(), 2, start).unwrap();
+        if longer {
+            high.state.append(b"set x 1".to_vec());
+        }
+        let at = start + Duration::from_secs(1);
+        high.tick(at);
+        let request = low
+            .tick(at)
+            .into_iter()
+            .find(|(to, _)| *to == 2)
+            .map(|(_, m)| m)
+            .unwrap();
+        assert_eq!(high.state.current_term, low.state.current_term);
+        let reply = high.handle(request, at);
+        let granted = matches!(
+            reply[..],
+            [(
+                1,
+                Message::RequestVoteResponse {
+                    vote_granted: true,
+                    ..
+                }
+            )]
+        );
+        if longer {
+            assert_eq!(high.role_name(), "Candidate");
+            assert_eq!(high.state.voted_for, Some(2));
+            assert!(!granted);
+        } else {
+            assert_eq!(high.role_name(), "Follower");
+            assert!(granted);
+        }
+    }
+}
+
+#[test]
+fn withdrawn_self_vote_excused_only_when_conceding() {
+    // Voting for itself and then for another candidate in one term breaks
+    // the single-vote rule, unless candidates are allowed to concede.
+    for concede_to_lower_id in [false, true] {
+        let options = Options {
+            concede_to_lower_id,
+            ..Options::default()
+        };
+        let mut s = State::new(2, vec![1, 3], options, 2);
+        s.advance_term(1);
+        s.set_voted_for(Some(2));
+        s.set_voted_for(None);
+        s.set_voted_for(Some(1));
+        let expected = if concede_to_lower_id {
+            None
+        } else {
+            Some((1, 2, 1))
+        };
+        assert_eq!(s.conflicting_vote(), expected);
+    }
+}
+
+#[test]
+fn deposed_leader_with_lease_and_longer_log_campaigns_at_once() {
+    // A leader still holding its lease is asked for a vote by a candidate
+    // whose log is behind. With `leader_affinity` it campaigns in the next
+    // term right away instead of following and waiting out an election
+    // that candidate cannot win.
+    let start = Instant::now();
+    for leader_affinity in [false, true] {
+        let mut m = Machine::as_leader(
+            0,
+            vec![1, 2],
+            LogBuilder::new().terms(&[1, 1]).build(),
+            start,
+        );
+        m.state.options.leader_affinity = leader_affinity;
+        let at = start + Duration::from_millis(10);
+        m.process(
+            Message::AppendEntryResponse {
+                term: 1,
+                success: true,
+                from: 1,
+                match_index: 2,
+                read_round: 0,
+                term_bounds: vec![],
+            },
+            at,
+        );
+        m.process(
+            Message::RequestVoteRequest {
+                term: 2,
+                candidate_id: 2,
+                last_log_index: 1,
+                last_log_term: 1,
+                forced: false,
+            },
+            at,
+        );
+        if leader_affinity {
+            assert_eq!(m.role_name(), "Candidate");
+            assert_eq!(m.state.current_term, 3);
+            let out = m.tick(at);
+            assert!(out
+                .iter()
+                .any(|(to, msg)| *to == 1
+                    && matches!(msg, Message::RequestVoteRequest { term: 3, .. })));
+        } else {
+            assert_eq!(m.role_name(), "Follower");
+            assert_eq!(m.state.current_term, 2);
+        }
+    }
+}
+
+#[test]
+fn absurd_term_jump_rejected_not_adopted() {
+    // A message claiming term `usize::MAX` would pin the cluster's term for
+    // good; with `max_term_jump` the follower drops it and keeps its term.
+    let start = Instant::now();
+    for max_term_jump in [None, Some(10)] {
+        let mut m =
+            Machine::as_follower(1, vec![0, 2], LogBuilder::new().terms(&[1]).build(), start);
+        m.state.options.max_term_jump = max_term_jump;
+        let out = m.process(
+            Message::AppendEntryRequest {
+                term: usize::MAX,
+                leader_id: 0,
+                prev_log_index: 1,
+                prev_log_term: 1,
+                entries: vec![],
+                leader_commit: 1,
+                read_round: 0,
+                read_lease: None,
+            },
+            start,
+        );
+        if max_term_jump.is_some() {
+            assert!(out.is_empty());
+            assert_eq!(m.state.current_term, 1);
+        } else {
+            assert_eq!(m.state.current_term, usize::MAX);
+        }
+    }
+}
+
+#[test]
+fn election_grace_absorbs_late_heartbeat() {
+    // Past any election deadline, a follower that heard from a leader
+    // still waits out `election_grace` heartbeat intervals before it
+    // campaigns; without grace it campaigns at once.
+    let start = Instant::now();
+    for election_grace in [0, 4] {
+        let mut m =
+            Machine::as_follower(1, vec![0, 2], LogBuilder::new().terms(&[1]).build(), start);
+        m.state.options.election_grace = election_grace;
+        m.process(
+            Message::AppendEntryRequest {
+                term: 1,
+                leader_id: 0,
+                prev_log_index: 1,
+                prev_log_term: 1,
+                entries: vec![],
+                leader_commit: 1,
+                read_round: 0,
+                read_lease: None,
+            },
+            start,
+        );
+        let late = start + m.state.options.base_election_timeout() * 2;
+        m.tick(late);
+        if election_grace == 0 {
+            assert_ne!(m.role_name(), "Follower");
+            continue;
+        }
+        assert_eq!(m.role_name(), "Follower");
+        m.tick(late + m.state.options.heartbeat_interval * election_grace);
+        assert_ne!(m.role_name(), "Follower");
+    }
+}
+
+#[test]
+fn up_to_date_compares_last_term_then_index() {
+    // (candidate term, candidate index, own term, own index, up to date)
+    let cases = [
+        (1, 1, 1, 1, true),
+        (1, 2, 1, 1, true),
+        (1, 1, 1, 2, false),
+        (2, 1, 1, 5, true),
+        (1, 5, 2, 1, false),
+        (0, 0, 0, 0, true),
+        (0, 0, 1, 1, false),
+        (3, 0, 2, 9, true),
+    ];
+    for (cand_term, cand_index, my_term, my_index, expected) in cases {
+        assert_eq!(
+            log_is_up_to_date(cand_term, cand_index, my_term, my_index),
+            expected,
+            "candidate ({}, {}) against ({}, {})",
+            cand_term,
+            cand_index,
+            my_term,
+            my_index
+        );
+    }
+}
+
+#[test]
+fn stuck_election_alert_raised_once_past_threshold() {
+    // Every node is cut off from the others, so no election can ever be
+    // won. Each node raises `StuckElecting` as its fourth election in a
+    // row starts, after three failed, and not again for the same streak.
+    let options = Options {
+        max_election_rounds: Some(3),
+        ..Options::default()
+    };
+    let mut sim = Simulation::with_options(3, 6, options);
+    sim.partition(&[&[0], &[1], &[2]]);
+    sim.run_for(Duration::from_secs(5));
+    for m in sim.machines.values() {
+        assert!(
+            m.state.current_term > 5,
+            "node {} barely campaigned",
+            m.id()
+        );
+        match m.alerts[..] {
+            [Alert::StuckElecting { term, failed: 3 }] => assert!(term < m.state.current_term),
+            _ => panic!("node {} raised {:?}", m.id(), m.alerts),
+        }
+    }
+    sim.heal();
+    sim.assert_converges(1_000);
+    assert!(sim.machines.values().all(|m| m.alerts.len() == 1));
+}
+
+#[test]
+fn pre_vote_stops_isolated_node_inflating_term() {
+    // A follower cut off for three seconds keeps timing out. Without
+    // pre-vote each timeout raises its term, and once back it deposes the
+    // leader; with pre-vote it never gets a majority to campaign, so its
+    // term stays put and the leader keeps leading.
+    for pre_vote_enabled in [false, true] {
+        let options = Options {
+            pre_vote_enabled,
+            ..Options::default()
+        };
+        let mut sim = Simulation::with_options(5, 9, options);
+        let leader = sim.assert_converges(500);
+        let term = sim.machines[&leader].state.current_term;
+        let isolated = (0..5).find(|&id| id != leader).unwrap();
+        let rest: Vec<u32> = (0..5).filter(|&id| id != isolated).collect();
+        sim.partition(&[&[isolated], &rest]);
+        sim.run_for(Duration::from_secs(3));
+        let isolated_term = sim.machines[&isolated].state.current_term;
+        sim.heal();
+        sim.run_and_check(Duration::from_secs(2));
+        let after = sim.leader().unwrap();
+        if pre_vote_enabled {
+            assert_eq!(isolated_term, term);
+            assert_eq!(after, leader);
+            assert_eq!(sim.machines[&after].state.current_term, term);
+        } else {
+            assert!(isolated_term > term + 5, "term {}", isolated_term);
+            assert!(sim.machines[&after].state.current_term > term);
+        }
+    }
+}
+
+#[test]
+fn weighted_votes_decide_the_quorum() {
+    // Node 0 weighs as much as two others: with it, three nodes are a quorum;
+    // without it, three are not.
+    let mut sim = ClusterBuilder::new(5)
+        .seed(3)
+        .weights(vec![2, 1, 1, 1, 1])
+        .build();
+    sim.partition(&[&[0, 1, 2], &[3, 4]]);
+    sim.run_for(Duration::from_secs(1));
+    sim.propose(b"set weighted yes").unwrap();
+    sim.run_for(Duration::from_millis(200));
+    let committed = sim.machines[&0].state.commit_index;
+    sim.partition(&[&[0, 1], &[2, 3, 4]]);
+    sim.run_for(Duration::from_secs(1));
+    sim.propose(b"set weighted no").unwrap();
+    sim.run_for(Duration::from_secs(1));
+    assert_eq!(committed, 1);
+    assert!([2, 3, 4]
+        .iter()
+        .all(|id| sim.machines[id].state.commit_index <= 1));
+    let views: Vec<(usize, bool, bool)> = sim.machines[&0]
+        .log_view(1)
+        .map(|view| (view.entry.index, view.committed, view.applied))
+        .collect();
+    assert_eq!(views, [(1, true, true), (2, false, false)]);
+}
+
+#[test]
+fn shorter_log_candidate_concedes() {
+    // Two candidates in one term: the one with the shorter log concedes.
+    let options = Options {
+        concede_to_better_log: true,
+        ..Options::default()
+    };
+    let start = Instant::now();
+    let mut short = Machine::new(1, vec![2, 3], options.clone(), 1, start).unwrap();
+    let mut long = Machine::new(2, vec![1, 3], options, 2, start).unwrap();
+    long.state.append(b"set x 1".to_vec());
+    let at = start + Duration::from_secs(1);
+    short.tick(at);
+    let request = long
+        .tick(at)
+        .into_iter()
+        .find(|(to, _)| *to == 1)
+        .map(|(_, m)| m)
+        .unwrap();
+    let reply = short.handle(request, at);
+    assert_eq!(short.role_name(), "Follower");
+    assert_eq!(short.state.current_term, long.state.current_term);
+    assert!(matches!(
+        reply[..],
+        [(
+            2,
+            Message::RequestVoteResponse {
+                vote_granted: true,
+                ..
+            }
+        )]
+    ));
+}
+
+#[test]
+fn leader_stickiness_ignores_unforced_candidate() {
+    // With leader stickiness, followers ignore a candidate while the leader
+    // is alive, unless that leader handed over to it.
+    let options = Options {
+        leader_stickiness: true,
+        ..Options::default()
+    };
+    let mut sim = Simulation::with_options(3, 23, options.clone());
+    sim.run_for(Duration::from_secs(1));
+    let leader = sim.leader().unwrap();
+    let (candidate, voter) = {
+        let mut followers = (0..3).filter(|&id| id != leader);
+        (followers.next().unwrap(), followers.next().unwrap())
+    };
+    let now = sim.current_time;
+    let term = sim.machines[&voter].state.current_term;
+    let request = |forced| Message::RequestVoteRequest {
+        term: term + 1,
+        candidate_id: candidate,
+        last_log_index: sim.machines[&candidate].state.last_log_index(),
+        last_log_term: sim.machines[&candidate].state.last_log_term(),
+        forced,
+    };
+    let (normal, forced) = (request(false), request(true));
+    let voter = sim.machines.get_mut(&voter).unwrap();
+    assert!(voter.handle(normal, now).is_empty());
+    assert_eq!(voter.state.current_term, term);
+    let granted = voter.handle(forced, now).into_iter().any(|(_, m)| {
+        matches!(
+            m,
+            Message::RequestVoteResponse {
+                vote_granted: true,
+                ..
+            }
+        )
+    });
+    assert!(granted, "a leader's handover was refused");
+}
+
+#[test]
+fn leader_stickiness_lets_drained_leader_hand_over() {
+    let options = Options {
+        leader_stickiness: true,
+        ..Options::default()
+    };
+    let mut sim = Simulation::with_options(3, 23, options);
+    sim.run_for(Duration::from_secs(1));
+    let before = sim.leader().unwrap();
+    sim.drain_leader();
+    sim.run_and_check(Duration::from_secs(1));
+    let after = sim.leader().expect("no leader after the handover");
+    assert_ne!(after, before);
+}
+
+#[test]
+fn vote_retries_speed_up_lossy_elections() {
+    // With 30% of messages lost, resending unanswered vote requests wins
+    // elections sooner than waiting for the next one.
+    let mut totals = vec![];
+    for vote_retry_interval in [None, Some(Duration::from_millis(50))] {
+        let options = Options {
+            vote_retry_interval,
+            ..Options::default()
+        };
+        let mut total = Duration::ZERO;
+        for seed in 0..20 {
+            let mut sim = Simulation::with_options(5, seed, options.clone());
+            sim.loss_rate = 0.3;
+            while sim.leader().is_none() {
+                sim.step();
+            }
+            total += sim.elapsed();
+        }
+        totals.push(total / 20);
+    }
+    assert!(totals[1] < totals[0], "vote retries slowed elections down");
+}
+
+#[test]
+fn operator_step_down_demotes_leader() {
+    // The operator demotes the leader; a stale request later changes nothing.
+    let mut sim = Simulation::new(3, 31);
+    sim.run_for(Duration::from_secs(1));
+    let old_leader = sim.leader().unwrap();
+    let old_term = sim.machines[&old_leader].state.current_term;
+    sim.step_down(old_leader, old_term);
+    sim.run_for(Duration::from_millis(30));
+    assert_eq!(sim.machines[&old_leader].role_name(), "Follower");
+    sim.run_and_check(Duration::from_secs(1));
+    let new_leader = sim.leader().expect("no leader after stepping down");
+    let new_term = sim.machines[&new_leader].state.current_term;
+    assert!(new_term > old_term);
+    sim.step_down(new_leader, old_term);
+    sim.run_for(Duration::from_millis(100));
+    assert_eq!(sim.leader(), Some(new_leader));
+}
+
+#[test]
+fn suggested_election_timeout_settles_nine_nodes() {
+    // Nine nodes 40ms apart keep splitting the vote with a timeout that
+    // ignores the round trip; the suggested one settles every time.
+    let rtt = Duration::from_millis(40);
+    let short = Options {
+        heartbeat_interval: Duration::from_millis(10),
+        election_timeout: Duration::from_millis(15),
+        ..Options::default()
+    };
+    let scaled = Options {
+        election_timeout: Options::suggested_election_timeout(9, rtt),
+        ..Options::default()
+    };
+    let mut settled = vec![];
+    for options in [short, scaled] {
+        let count = (0..10)
+            .filter(|&seed| {
+                let mut sim = Simulation::with_options(9, seed, options.clone());
+                sim.network_latency = rtt / 2;
+                sim.run_until_quiescent(Duration::from_secs(5))
+            })
+            .count();
+        settled.push(count);
+    }
+    assert_eq!(settled[0], 0, "a timeout below the round trip settled");
+    assert_eq!(settled[1], 10, "the suggested timeout failed to settle");
+}
+
+#[test]
+fn vote_denials_record_their_reason() {
+    // Every refused vote records why it was refused.
+    let start = Instant::now();
+    let ask = |term, candidate_id, last_log_term| Message::RequestVoteRequest {
+        term,
+        candidate_id,
+        last_log_index: 1,
+        last_log_term,
+        forced: false,
+    };
+    let options = Options {
+        leader_stickiness: true,
+        ..Options::default()
+    };
+    let mut voter = Machine::new(0, vec![1, 2], options, 0, start).unwrap();
+    voter.state.advance_term(2);
+    voter.state.append(b"set x 1".to_vec());
+    voter.handle(ask(1, 1, 2), start);
+    assert_eq!(
+        voter.state.last_vote_denial.map(|d| d.reason),
+        Some(VoteDenialReason::StaleTerm)
+    );
+    voter.handle(ask(3, 1, 1), start);
+    assert_eq!(
+        voter.state.last_vote_denial.map(|d| d.reason),
+        Some(VoteDenialReason::LogNotUpToDate)
+    );
+    voter.handle(ask(3, 2, 3), start);
+    assert_eq!(voter.state.voted_for, Some(2));
+    voter.handle(ask(3, 1, 3), start);
+    assert_eq!(
+        voter.state.last_vote_denial.map(|d| d.reason),
+        Some(VoteDenialReason::AlreadyVoted)
+    );
+    voter.handle(
+        Message::AppendEntryRequest {
+            term: 3,
+            leader_id: 2,
+            prev_log_index: 1,
+            prev_log_term: 2,
+            entries: vec![],
+            leader_commit: 0,
+            read_round: 0,
+            read_lease: None,
+        },
+        start,
+    );
+    voter.handle(ask(4, 1, 3), start);
+    assert_eq!(
+        voter.state.last_vote_denial,
+        Some(VoteDenial {
+            term: 4,
+            candidate: 1,
+            reason: VoteDenialReason::LeaderContact
+        })
+    );
+}
+
+#[test]
+fn candidate_follows_leader_of_same_term() {
+    // A candidate hearing from a leader of its own term gives up and
+    // follows it, taking the entries it sent.
+    let start = Instant::now();
+    let log = LogBuilder::new().terms(&[1, 1]).build();
+    let mut candidate = Machine::as_candidate(1, vec![0, 2], log, start);
+    let term = candidate.state.current_term;
+    candidate.handle(
+        Message::AppendEntryRequest {
+            term,
+            leader_id: 2,
+            prev_log_index: 2,
+            prev_log_term: 1,
+            entries: vec![],
+            leader_commit: 2,
+            read_round: 0,
+            read_lease: None,
+        },
+        start,
+    );
+    assert_eq!(candidate.role_name(), "Follower");
+    assert_eq!(candidate.state.current_term, term);
+    assert_eq!(candidate.role.leader_hint(&candidate.state), Some(2));
+}
+
+#[test]
+fn only_log_from_latest_term_wins() {
+    // With most logs ending in a later term, only one of those can win,
+    // however long the others' logs are.
+    let mut sim = Simulation::new(5, 37);
+    let logs = [&[1, 1, 1, 1][..], &[1, 3], &[1, 3], &[1, 3], &[1, 2, 2]];
+    for (id, terms) in (0..).zip(logs) {
+        let m = sim.machines.get_mut(&id).unwrap();
+        m.state.advance_term(3);
+        m.state.log = LogBuilder::new().terms(terms).build();
+    }
+    let last_logs = sim.last_logs();
+    sim.run_for(Duration::from_secs(1));
+    let winner = sim.leader().unwrap();
+    assert_eq!(last_logs[&winner].1, 3);
+}
+
+#[test]
+fn fast_election_holds_back_lagging_follower() {
+    // Right after an election, a follower whose log is behind refuses the
+    // new leader's first AppendEntries. If the leader dies then, that
+    // follower waits longer, so an up-to-date one campaigns first and wins
+    // in the next term, instead of the lagging one wasting a term it
+    // cannot win.
+    let mut next_term_wins = [0, 0];
+    for (i, fast_election) in [false, true].into_iter().enumerate() {
+        for seed in 0..20 {
+            let options = Options {
+                fast_election,
+                ..Options::default()
+            };
+            let mut sim = Simulation::with_options(5, seed, options);
+            for id in 0..5 {
+                let terms = if id == 4 { &[1][..] } else { &[1, 1] };
+                let m = sim.machines.get_mut(&id).unwrap();
+                m.state.log = LogBuilder::new().terms(terms).build();
+            }
+            while sim.leader().is_none() {
+                sim.step();
+            }
+            let leader = sim.leader().unwrap();
+            let term = sim.machines[&leader].state.current_term;
+            sim.run_for(Duration::from_millis(30));
+            let first_candidate = Rc::new(Cell::new(None));
+            let first = first_candidate.clone();
+            sim.subscribe(Box::new(move |event| {
+                if let Event::RoleChanged {
+                    node,
+                    to: "Candidate",
+                    ..
+                } = *event
+                {
+                    first.set(first.get().or(Some(node)));
+                }
+            }));
+            sim.crash(leader, sim.elapsed());
+            sim.run_for(Duration::from_secs(1));
+            let winner = sim.leader().unwrap();
+            assert_ne!(winner, 4);
+            if fast_election {
+                assert_ne!(first_candidate.get(), Some(4), "seed {}", seed);
+            }
+            if sim.machines[&winner].state.current_term == term + 1 {
+                next_term_wins[i] += 1;
+            }
+        }
+    }
+    assert!(
+        next_term_wins[1] > next_term_wins[0],
+        "{:?}",
+        next_term_wins
+    );
+}
+
+#[test]
+fn candidate_tally_visible_through_debug_state() {
+    // A candidate's tally is visible without knowing its concrete type.
+    let start = Instant::now();
+    let mut candidate = Machine::as_candidate(1, vec![0, 2, 3, 4], vec![], start);
+    let term = candidate.state.current_term;
+    for (from, vote_granted) in [(2, true), (3, false)] {
+        candidate.handle(
+            Message::RequestVoteResponse {
+                term,
+                vote_granted,
+                from,
+            },
+            start,
+        );
+    }
+    let RoleDebug::Candidate {
+        votes_received,
+        votes_denied,
+        ..
+    } = candidate.debug_state()
+    else {
+        panic!("expected a candidate, got {:?}", candidate.debug_state());
+    };
+    assert_eq!(votes_received, BTreeSet::from([1, 2]));
+    assert_eq!(votes_denied, BTreeSet::from([3]));
+}
+
+#[test]
+fn full_partition_commits_nothing_and_elects_nobody() {
+    // With every node cut off from every other, nothing commits and nobody
+    // wins an election; once healed, the cluster settles on one leader.
+    let mut sim = Simulation::new(5, 53);
+    sim.run_for(Duration::from_secs(1));
+    let old_leader = sim.leader().unwrap();
+    let commits: BTreeMap<u32, usize> = sim
+        .machines
+        .iter()
+        .map(|(&id, m)| (id, m.state.commit_index))
+        .collect();
+    let elected = Rc::new(Cell::new(0));
+    let counter = elected.clone();
+    sim.subscribe(Box::new(move |event| {
+        if let Event::RoleChanged { to: "Leader", .. } = event {
+            counter.set(counter.get() + 1);
+        }
+    }));
+    sim.partition(&[]);
+    sim.propose(b"set lost 1").unwrap();
+    sim.run_and_check(Duration::from_secs(5));
+    for (id, m) in &sim.machines {
+        assert_eq!(m.state.commit_index, commits[id], "node {} committed", id);
+    }
+    assert_eq!(elected.get(), 0);
+    for (&id, m) in &sim.machines {
+        assert!(id == old_leader || m.role_name() != "Leader");
+    }
+    sim.heal();
+    sim.run_and_check(Duration::from_secs(2));
+    let leader = sim.leader().unwrap();
+    let term = sim.machines[&leader].state.current_term;
+    let leaders = sim
+        .machines
+        .values()
+        .filter(|m| m.role_name() == "Leader")
+        .count();
+    assert_eq!(leaders, 1);
+    assert!(sim
+        .machines
+        .values()
+        .all(|m| m.state.current_term == term && m.role.leader_hint(&m.state) == Some(leader)));
+}
+
+#[test]
+fn election_timeout_floor_stops_thrashing() {
+    // A floor on the election timeout overrides a pathologically small
+    // one: instead of campaigning nonstop, the cluster elects one leader.
+    let mut terms = vec![];
+    for floor in [None, Some(Duration::from_millis(100))] {
+        let options = Options {
+            heartbeat_interval: Duration::from_millis(10),
+            election_timeout: Duration::from_millis(1),
+            min_election_timeout: floor,
+            ..Options::default()
+        };
+        assert_eq!(
+            options.base_election_timeout(),
+            floor.unwrap_or(Duration::from_millis(1))
+        );
+        let mut sim = Simulation::with_options(3, 59, options);
+        sim.run_for(Duration::from_secs(3));
+        let term = sim.machines.values().map(|m| m.state.current_term).max();
+        terms.push(term.unwrap());
+    }
+    assert!(terms[0] > 20, "no thrashing without a floor: {:?}", terms);
+    assert!(terms[1] <= 3, "thrashing despite the floor: {:?}", terms);
+    let too_low = Options {
+        heartbeat_interval: Duration::from_millis(10),
+        min_election_timeout: Some(Duration::from_millis(5)),
+        ..Options::default()
+    };
+    assert!(matches!(
+        too_low.validate(),
+        Err(OptionsError::TimeoutFloorTooLow { .. })
+    ));
+}
+
+#[test]
+fn node_refuses_options_that_cannot_work() {
+    // Building a node with an election timeout floor at or below the
+    // heartbeat interval fails instead of panicking.
+    let options = Options {
+        heartbeat_interval: Duration::from_millis(50),
+        min_election_timeout: Some(Duration::from_millis(50)),
+        ..Options::default()
+    };
+    let error = Machine::new(0, vec![1, 2], options, 8, Instant::now())
+        .err()
+        .unwrap();
+    assert_eq!(
+        error,
+        OptionsError::TimeoutFloorTooLow {
+            floor: Duration::from_millis(50),
+            heartbeat_interval: Duration::from_millis(50),
+        }
+    );
+}
+
+#[test]
+fn max_apply_stall_hands_leadership_over() {
+    // A leader whose state machine stops applying hands leadership over
+    // once max_apply_stall passes; without the option it leads on, stuck.
+    for max_apply_stall in [None, Some(Duration::from_millis(300))] {
+        let mut sim = Simulation::with_options(
+            3,
+            1,
+            Options {
+                max_apply_stall,
+                ..Options::default()
+            },
+        );
+        let stuck = sim.assert_converges(200);
+        let m = sim.machines.get_mut(&stuck).unwrap();
+        let mut inner = KvStateMachine::default();
+        inner.restore(&m.state_machine.snapshot());
+        m.state_machine = Box::new(Throttled { inner, per_tick: 0 });
+        sim.propose(b"set stuck yes").unwrap();
+        sim.run_and_check(Duration::from_secs(1));
+        let leader = sim.leader().unwrap();
+        let applied = sim.machines[&leader].state_machine.query(b"get stuck");
+        if max_apply_stall.is_some() {
+            assert_ne!(leader, stuck);
+            assert_eq!(sim.machines[&stuck].role_name(), "Follower");
+            assert_eq!(applied, b"yes");
+        } else {
+            assert_eq!(leader, stuck);
+            assert!(applied.is_empty());
+        }
+    }
+}
+
+#[test]
+fn startup_stagger_avoids_split_first_vote() {
+    // Booted all at once, five nodes often split their first vote; with
+    // their first timeouts staggered by id one candidate runs alone.
+    let mut split = [0, 0];
+    for (i, startup_stagger) in [None, Some(Duration::from_millis(150))]
+        .into_iter()
+        .enumerate()
+    {
+        for seed in 0..20 {
+            let mut sim = Simulation::with_options(
+                5,
+                seed,
+                Options {
+                    startup_stagger,
+                    ..Options::default()
+                },
+            );
+            let candidates = Rc::new(Cell::new(0));
+            let counter = candidates.clone();
+            sim.subscribe(Box::new(move |event| {
+                if let Event::RoleChanged {
+                    to: "Candidate", ..
+                } = event
+                {
+                    counter.set(counter.get() + 1);
+                }
+            }));
+            let leader = sim.assert_converges(200);
+            let term = sim.machines[&leader].state.current_term;
+            if candidates.get() > 1 || term > 1 {
+                split[i] += 1;
+            }
+        }
+    }
+    assert!(split[0] > 0, "no split votes to avoid");
+    assert_eq!(split[1], 0);
+}
+
+#[test]
+fn paced_ticks_only_at_election_deadline() {
+    // With paced ticks a follower is only ticked when its election deadline
+    // comes up, far less often than heartbeats arrive, yet still campaigns
+    // in time once the leader is gone.
+    let mut sim = Simulation::new(5, 1);
+    sim.set_paced_ticks(true);
+    let leader = sim.assert_converges(200);
+    let term = sim.machines[&leader].state.current_term;
+    let mut ticks: BTreeMap<u32, usize> = sim.machines.keys().map(|&id| (id, 0)).collect();
+    let second = sim.current_time + Duration::from_secs(1);
+    while sim.current_time < second {
+        let before: Vec<(u32, Instant)> = sim
+            .machines
+            .iter()
+            .map(|(&id, m)| (id, m.last_tick))
+            .collect();
+        sim.step();
+        for (id, last_tick) in before {
+            if sim.machines[&id].last_tick != last_tick {
+                *ticks.get_mut(&id).unwrap() += 1;
+            }
+        }
+    }
+    let heartbeats = (Duration::from_secs(1).as_millis()
+        / Options::default().heartbeat_interval.as_millis()) as usize;
+    assert_eq!(sim.leader(), Some(leader));
+    assert_eq!(sim.machines[&leader].state.current_term, term);
+    assert_eq!(ticks[&leader], 100);
+    for (&id, &n) in &ticks {
+        if id != leader {
+            assert!(n < heartbeats / 4, "follower {} ticked {} times", id, n);
+        }
+    }
+    let crashed_at = sim.elapsed();
+    sim.crash(leader, crashed_at);
+    sim.step();
+    let next = sim.assert_converges(100);
+    assert_ne!(next, leader);
+}
+
+#[test]
+fn pre_vote_quorum_sets_grants_needed() {
+    // Out of five voters, three pre-votes usually start a real election;
+    // with a pre-vote quorum of four, the third leaves the node waiting
+    // for a fourth, and only that one sends it campaigning.
+    let start = Instant::now();
+    for (pre_vote_quorum, needed) in [(None, 3), (Some(4), 4)] {
+        let mut m = Machine::as_follower(0, vec![1, 2, 3, 4], vec![], start);
+        m.state.options = Options {
+            pre_vote_enabled: true,
+            pre_vote_quorum,
+            ..Options::default()
+        };
+        let at = start + Duration::from_secs(1);
+        m.tick(at);
+        assert_eq!(m.role_name(), "PreCandidate");
+        let term = m.state.current_term;
+        let mut granted = 1;
+        for from in 1..=4 {
+            if m.role_name() != "PreCandidate" {
+                break;
+            }
+            m.process(
+                Message::PreVoteResponse {
+                    term: term + 1,
+                    vote_granted: true,
+                    from,
+                },
+                at,
+            );
+            granted += 1;
+        }
+        assert_eq!(m.role_name(), "Candidate");
+        assert_eq!(granted, needed);
+    }
+}
+
+#[test]
+fn stale_vote_grants_not_counted() {
+    // A candidate cut off mid-election moves on to the next term; once the
+    // partition heals, the grants of its earlier election arrive late, and
+    // even with the voters' fresh grants they count once each.
+    let start = Instant::now();
+    let mut m = Machine::as_follower(0, vec![1, 2, 3, 4], vec![], start);
+    m.tick(start + Duration::from_secs(1));
+    assert_eq!(m.role_name(), "Candidate");
+    let old_term = m.state.current_term;
+    let at = start + Duration::from_secs(2);
+    m.tick(at);
+    assert_eq!(m.role_name(), "Candidate");
+    let term = m.state.current_term;
+    assert!(term > old_term);
+    let grant = |term, from| Message::RequestVoteResponse {
+        term,
+        vote_granted: true,
+        from,
+    };
+    for from in [1, 2] {
+        m.process(grant(old_term, from), at);
+    }
+    assert_eq!(m.role_name(), "Candidate", "stale votes made a leader");
+    for _ in 0..2 {
+        m.process(grant(term, 1), at);
+    }
+    assert_eq!(m.role_name(), "Candidate", "a vote counted twice");
+    m.process(grant(term, 2), at);
+    assert_eq!(m.role_name(), "Leader");
+}
+
+#[test]
+fn vote_fan_out_asks_in_waves() {
+    // A candidate in a cluster of fifteen asking three voters a tick wins
+    // with the grants of its first three waves, never asking the rest.
+    let start = Instant::now();
+    let mut m = Machine::as_follower(0, (1..15).collect(), vec![], start);
+    m.state.options = Options {
+        vote_fan_out: Some(3),
+        ..Options::default()
+    };
+    let mut at = start + Duration::from_secs(1);
+    let mut asked = vec![];
+    while m.role_name() != "Leader" {
+        assert!(asked.len() < 14, "asked every voter");
+        let out = m.tick(at);
+        let wave: Vec<(u32, usize)> = out
+            .iter()
+            .filter_map(|(to, msg)| match msg {
+                Message::RequestVoteRequest { term, .. } => Some((*to, *term)),
+                _ => None,
+            })
+            .collect();
+        assert!(wave.len() <= 3);
+        for (from, term) in wave {
+            asked.push(from);
+            m.process(
+                Message::RequestVoteResponse {
+                    term,
+                    vote_granted: true,
+                    from,
+                },
+                at,
+            );
+        }
+        at += Duration::from_millis(10);
+    }
+    assert_eq!(asked.len(), 9);
+    let options = Options {
+        vote_fan_out: Some(3),
+        ..Options::default()
+    };
+    let mut sim = Simulation::with_options(15, 5, options);
+    sim.assert_converges(500);
+}
@@ -0,0 +1,300 @@
+//! Joining and leaving the cluster, learners and observers.
+
+use super::super::*;
+
+#[test]
+fn bootstrap_cluster_learns_config_from_node_0() {
+    let mut sim = ClusterBuilder::new(3).seed(7).bootstrap().build();
+    assert!(sim.run_until_quiescent(Duration::from_secs(5)));
+    assert_eq!(sim.leader(), Some(0));
+    for m in sim.machines.values() {
+        assert_eq!(m.state.config.voters, BTreeSet::from([0, 1, 2]));
+    }
+}
+
+#[test]
+fn drained_leader_removed_once_handover_completes() {
+    // Writes are not yet committed when the leader starts draining. The
+    // drain completes once it follows another leader; only then is it
+    // removed, and every write submitted before the drain committed.
+    let mut sim = Simulation::new(5, 14);
+    let old = sim.assert_converges(500);
+    let writes: Vec<_> = (0..5)
+        .map(|i| sim.submit(format!("set key{} value{}", i, i).as_bytes()))
+        .collect();
+    // Appended by the leader, not yet committed.
+    sim.run_for(Duration::from_millis(30));
+    let appended = sim.machines[&old].state.last_log_index();
+    assert!(sim.machines[&old].state.commit_index < appended);
+    let drain = sim.drain_leader().unwrap();
+    let deadline = sim.current_time + Duration::from_secs(2);
+    while !drain.is_complete(&sim.machines[&old]) {
+        assert!(sim.current_time < deadline, "drain never completed");
+        sim.step();
+    }
+    let new = sim.leader().unwrap();
+    assert_ne!(new, old);
+    sim.change_config(&ConfigChange::remove(old)).unwrap();
+    sim.run_and_check(Duration::from_secs(1));
+    assert!(!sim.machines[&new].state.config.voters.contains(&old));
+    for write in writes {
+        let result = sim.block_on(Duration::from_secs(1), write);
+        assert!(matches!(result, Some(Ok(_))), "{:?}", result);
+    }
+    assert_eq!(
+        sim.machines[&new].state_machine.query(b"get key4"),
+        b"value4"
+    );
+}
+
+#[test]
+fn learner_catches_up_before_it_votes() {
+    // A node joining a busy cluster learns the log before it gets a vote.
+    let options = Options {
+        learner_catch_up: true,
+        ..Options::default()
+    };
+    let mut sim = ClusterBuilder::new(3).seed(5).options(options).build();
+    sim.run_for(Duration::from_secs(1));
+    for i in 0..50 {
+        sim.propose(format!("set key{} value{}", i, i).as_bytes())
+            .unwrap();
+    }
+    sim.add_node(3);
+    let index = sim.change_config(&ConfigChange::add(3)).unwrap();
+    let mut became_learner = false;
+    let deadline = sim.current_time + Duration::from_secs(2);
+    while !sim.machines[&3].state.is_voter() {
+        assert!(sim.current_time < deadline, "node 3 never got a vote");
+        became_learner |= sim.machines[&3].state.config.learners.contains(&3);
+        sim.step();
+    }
+    assert!(became_learner);
+    assert!(sim.machines[&3].state.log.len() > index);
+}
+
+#[test]
+fn membership_request_reports_voters_after_change() {
+    // After a membership change the leader reports the new voters once the
+    // change commits; a follower points at the leader instead.
+    let mut sim = Simulation::new(3, 23);
+    sim.run_for(Duration::from_secs(1));
+    sim.add_node(3);
+    sim.change_config(&ConfigChange::add(3)).unwrap();
+    sim.run_for(Duration::from_secs(1));
+    let leader = sim.leader().unwrap();
+    let at = sim.current_time;
+    let ask = Message::MembershipRequest {
+        from: CLIENT_ID_BASE,
+    };
+    let out = sim
+        .machines
+        .get_mut(&leader)
+        .unwrap()
+        .handle(ask.clone(), at);
+    let [(
+        to,
+        Message::MembershipResponse {
+            voters, learners, ..
+        },
+    )] = out.as_slice()
+    else {
+        panic!("leader gave no membership: {:?}", out);
+    };
+    assert_eq!(*to, CLIENT_ID_BASE);
+    assert_eq!(*voters, vec![0, 1, 2, 3]);
+    assert!(learners.is_empty());
+    let follower = (0..4).find(|&id| id != leader).unwrap();
+    let out = sim.machines.get_mut(&follower).unwrap().handle(ask, at);
+    let [(
+        _,
+        Message::MembershipResponse {
+            voters,
+            leader_hint,
+            ..
+        },
+    )] = out.as_slice()
+    else {
+        panic!("follower gave no answer: {:?}", out);
+    };
+    assert!(voters.is_empty());
+    assert_eq!(*leader_hint, Some(leader));
+}
+
+#[test]
+fn lagging_learner_removed_after_catch_up_rounds() {
+    // A learner whose disk cannot keep up with the write load is removed
+    // after its catch-up rounds run out, and the cluster carries on; with
+    // no load the same learner catches up and gets its vote.
+    let options = Options {
+        learner_catch_up: true,
+        max_catch_up_rounds: Some(3),
+        ..Options::default()
+    };
+    for load in [0, 5] {
+        let mut sim = ClusterBuilder::new(3)
+            .seed(5)
+            .options(options.clone())
+            .build();
+        sim.run_for(Duration::from_secs(1));
+        sim.add_node(3);
+        sim.machines.get_mut(&3).unwrap().storage = Box::new(SlowStorage::new(
+            MemStorage::default(),
+            Duration::from_millis(5),
+        ));
+        sim.change_config(&ConfigChange::add(3)).unwrap();
+        let until = sim.current_time + Duration::from_secs(2);
+        while sim.current_time < until {
+            for i in 0..load {
+                sim.propose(format!("set key{} value{}", i, i).as_bytes())
+                    .unwrap();
+            }
+            sim.step();
+        }
+        let leader = sim.leader().unwrap();
+        let config = &sim.machines[&leader].state.config;
+        if load == 0 {
+            assert!(config.voters.contains(&3), "idle learner not promoted");
+        } else {
+            assert!(!config.is_member(3), "lagging learner still a member");
+            assert!(
+                sim.machines[&leader].state.commit_index
+                    >= sim.machines[&leader].state.config_index(),
+                "removal of the learner not committed"
+            );
+        }
+    }
+}
+
+#[test]
+fn partitioned_learner_never_campaigns() {
+    // A learner cut off from the leader for many election timeouts keeps
+    // following and never campaigns.
+    let mut sim = Simulation::new(3, 31);
+    sim.run_for(Duration::from_secs(1));
+    sim.add_node(3);
+    let change = ConfigChange {
+        learners: [3].into(),
+        ..Default::default()
+    };
+    sim.change_config(&change).unwrap();
+    // Cut it off as soon as it knows it is a learner, before the leader
+    // hears it caught up and promotes it.
+    while !sim.machines[&3].state.config.learners.contains(&3) {
+        sim.step();
+    }
+    let term = sim.machines[&3].state.current_term;
+    sim.partition(&[&[0, 1, 2], &[3]]);
+    sim.run_for(Options::default().election_timeout * 20);
+    let learner = &sim.machines[&3];
+    assert!(learner.state.config.learners.contains(&3));
+    assert_eq!(learner.role_name(), "Follower");
+    assert_eq!(learner.state.current_term, term);
+    sim.heal();
+}
+
+#[test]
+fn reconfig_refuses_writes_until_committed() {
+    // With writes paused during reconfiguration, the leader refuses them
+    // until the membership change commits, then takes them again.
+    let options = Options {
+        reject_writes_during_reconfig: true,
+        ..Options::default()
+    };
+    let mut sim = Simulation::with_options(5, 8, options);
+    let max = Duration::from_secs(1);
+    sim.run_for(Duration::from_secs(1));
+    let leader = sim.leader().unwrap();
+    let removed = (0..5).find(|&id| id != leader).unwrap();
+    let index = sim.change_config(&ConfigChange::remove(removed)).unwrap();
+    let during = sim.submit(b"set answer 42");
+    let during = sim.block_on(max, during);
+    assert_eq!(during, Some(Err(RaftError::Reconfiguring)));
+    assert!(sim.machines[&leader].state.commit_index >= index);
+    let after = sim.submit(b"set answer 42");
+    let after = sim.block_on(max, after);
+    assert!(matches!(after, Some(Ok(_))), "{:?}", after);
+}
+
+#[test]
+fn observers_follow_without_voting() {
+    // Observers follow the log but are left out of the quorum: three
+    // voters commit with two of them, and the observers keep up, never
+    // campaigning even when cut off from everyone.
+    let options = Options {
+        observers: vec![3, 4],
+        ..Options::default()
+    };
+    let mut sim = Simulation::with_options(5, 43, options);
+    sim.run_for(Duration::from_secs(1));
+    let leader = sim.leader().unwrap();
+    assert!(leader < 3);
+    for m in sim.machines.values() {
+        assert_eq!(m.state.config.voters, BTreeSet::from([0, 1, 2]));
+        assert_eq!(m.state.config.majority(), 2);
+    }
+    let voter = (0..3).find(|&id| id != leader).unwrap();
+    sim.partition(&[&[leader, voter]]);
+    let index = sim.propose(b"set key value").unwrap();
+    sim.run_for(Duration::from_secs(1));
+    assert!(sim.machines[&leader].state.commit_index >= index);
+    let term = sim.machines[&3].state.current_term;
+    for id in [3, 4] {
+        assert_eq!(sim.machines[&id].role_name(), "Observer");
+        assert_eq!(sim.machines[&id].state.current_term, term);
+    }
+    sim.heal();
+    sim.run_for(Duration::from_secs(1));
+    let applied = sim.machines[&leader].state.last_applied;
+    for id in [3, 4] {
+        assert_eq!(sim.machines[&id].state.last_applied, applied);
+    }
+}
+
+#[test]
+fn drain_hands_over_only_to_voter() {
+    // A drained leader hands over only to a voter: a learner that holds the
+    // whole log and acked it last gets no TimeoutNow until the entry
+    // promoting it is in the configuration.
+    let start = Instant::now();
+    let mut m = Machine::as_leader(
+        0,
+        vec![1, 2, 3],
+        LogBuilder::new().terms(&[1]).build(),
+        start,
+    );
+    let mut config = Config::new([0, 1, 2]);
+    config.learners.insert(3);
+    m.state.set_config(config);
+    let acked_by = |m: &mut Machine, order: [u32; 3], at: Instant| {
+        let (term, match_index) = (m.state.current_term, m.state.last_log_index());
+        for (i, from) in order.into_iter().enumerate() {
+            m.process(
+                Message::AppendEntryResponse {
+                    term,
+                    success: true,
+                    from,
+                    match_index,
+                    read_round: 0,
+                    term_bounds: vec![],
+                },
+                at + Duration::from_millis(i as u64),
+            );
+        }
+    };
+    let handed_to = |out: &Outbox| {
+        out.iter()
+            .find(|(_, msg)| matches!(msg, Message::TimeoutNow { .. }))
+            .map(|&(to, _)| to)
+    };
+    acked_by(&mut m, [1, 2, 3], start);
+    m.drain(start).unwrap();
+    let at = start + Duration::from_millis(50);
+    let out = m.tick(at);
+    assert_eq!(handed_to(&out), Some(2));
+    assert!(m.state.config.voters.contains(&3), "learner not promoted");
+    acked_by(&mut m, [1, 2, 3], at);
+    let at = at + m.state.options.base_election_timeout();
+    let out = m.tick(at);
+    assert_eq!(handed_to(&out), Some(3));
+}
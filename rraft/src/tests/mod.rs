@@ -0,0 +1,11 @@
+//! Scenarios against single machines and simulated clusters, one behaviour
+//! each, grouped by area in the submodules. Tests of a feature that lives in
+//! its own module sit with it.
+
+mod clients;
+mod durability;
+mod elections;
+mod membership;
+mod replication;
+mod simulation;
+mod snapshots;
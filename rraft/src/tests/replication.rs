@@ -0,0 +1,618 @@
+//! Getting entries onto followers and deciding when they commit.
+
+use std::cell::RefCell;
+
+use super::super::*;
+
+#[test]
+fn commit_latency_is_one_round_trip() {
+    // With a fixed 20ms network delay a write commits once the followers'
+    // acks come back: one round trip, give or take a simulation step.
+    let mut sim = Simulation::new(3, 5);
+    sim.network_jitter = Duration::ZERO;
+    sim.assert_converges(500);
+    let leader = sim.leader().unwrap();
+    for i in 0..5 {
+        sim.propose(format!("set key{} value{}", i, i).as_bytes())
+            .unwrap();
+        sim.run_for(Duration::from_millis(200));
+    }
+    let latencies = sim.machines[&leader].commit_latencies();
+    assert_eq!(latencies.len(), 5);
+    let round_trip = sim.network_latency * 2;
+    for &latency in latencies {
+        assert!(
+            round_trip <= latency && latency <= round_trip + sim.tick_interval,
+            "latency {:?}, round trip {:?}",
+            latency,
+            round_trip
+        );
+    }
+}
+
+#[test]
+fn stale_ack_left_out_of_commit_quorum() {
+    // Node 1 acked the new entry long ago and has been silent since; with
+    // `require_fresh_acks` the leader does not count it, so node 2's ack
+    // alone makes no majority of five until node 1 answers again, even
+    // with nothing new to ack.
+    let start = Instant::now();
+    for require_fresh_acks in [false, true] {
+        let mut m = Machine::as_leader(
+            0,
+            vec![1, 2, 3, 4],
+            LogBuilder::new().terms(&[1]).build(),
+            start,
+        );
+        m.state.options.require_fresh_acks = require_fresh_acks;
+        let committed = m.state.commit_index;
+        let (index, _) = m.propose(b"set x 1".to_vec(), start).unwrap();
+        let ack = |m: &mut Machine, from: u32, at: Instant| {
+            m.process(
+                Message::AppendEntryResponse {
+                    term: m.state.current_term,
+                    success: true,
+                    from,
+                    match_index: index,
+                    read_round: 0,
+                    term_bounds: vec![],
+                },
+                at,
+            );
+        };
+        ack(&mut m, 1, start);
+        let later = start + m.state.options.base_election_timeout() * 2;
+        ack(&mut m, 2, later);
+        if require_fresh_acks {
+            assert_eq!(m.state.commit_index, committed, "stale ack counted");
+            ack(&mut m, 1, later);
+        }
+        assert_eq!(m.state.commit_index, index);
+    }
+}
+
+#[test]
+fn leader_repairs_follower_log_after_lost_append() {
+    let mut sim = Simulation::new(5, 42);
+    sim.run_for(Duration::from_secs(1));
+    // Lose the third AppendEntries to node 2; the leader has to repair its log.
+    sim.drop_nth(3, |m| {
+        m.to == 2 && matches!(m.message, Message::AppendEntryRequest { .. })
+    });
+    for i in 0..10 {
+        sim.propose(format!("set key{} value{}", i, i).as_bytes())
+            .unwrap();
+        sim.run_for(Duration::from_millis(100));
+    }
+    sim.run_and_check(Duration::from_secs(1));
+    let leader = sim.leader().unwrap();
+    assert_ne!(leader, 2);
+    assert_eq!(sim.machines[&2].state.log, sim.machines[&leader].state.log);
+    assert_eq!(sim.machines[&2].state_machine.query(b"get key9"), b"value9");
+}
+
+#[test]
+fn old_term_entry_commits_only_with_current_term_entry() {
+    // Figure 8 of the Raft paper: leading term 4, node 0 gets its term 2
+    // entry onto a majority, but that alone does not commit it; another
+    // leader could still overwrite it. It commits along with the first
+    // entry of term 4 once that is on a majority too.
+    let start = Instant::now();
+    let mut m = Machine::as_follower(
+        0,
+        vec![1, 2, 3, 4],
+        LogBuilder::new().terms(&[1, 2]).build(),
+        start,
+    );
+    m.state.advance_term(4);
+    m.state.set_voted_for(Some(0));
+    m.role = Box::new(Leader::new(start, &mut m.state));
+    let ack = |m: &mut Machine, from: u32, match_index: usize| {
+        m.process(
+            Message::AppendEntryResponse {
+                term: 4,
+                success: true,
+                from,
+                match_index,
+                read_round: 0,
+                term_bounds: vec![],
+            },
+            start,
+        );
+    };
+    for from in 1..=4 {
+        ack(&mut m, from, 2);
+    }
+    assert_eq!(m.state.commit_index, 0, "term 2 entry committed on its own");
+    let (index, _) = m.propose(b"set x 4".to_vec(), start).unwrap();
+    assert_eq!(m.state.term_at(index), Some(4));
+    ack(&mut m, 1, index);
+    assert_eq!(m.state.commit_index, 0);
+    ack(&mut m, 2, index);
+    assert_eq!(m.state.commit_index, index);
+}
+
+#[test]
+fn status_reports_one_leader_and_full_log_length() {
+    // Every node of a converged cluster answers a StatusRequest at once;
+    // exactly one says it leads, and all report the same log length, even
+    // with part of the log compacted away.
+    let mut sim = Simulation::new(3, 5);
+    sim.run_for(Duration::from_secs(1));
+    for i in 0..5 {
+        sim.propose(format!("set key{} value{}", i, i).as_bytes())
+            .unwrap();
+    }
+    sim.run_and_check(Duration::from_secs(1));
+    assert_eq!(sim.compact_all(3).len(), 3);
+    let at = sim.current_time;
+    let mut statuses = vec![];
+    for m in sim.machines.values_mut() {
+        let out = m.handle(Message::StatusRequest { from: 9 }, at);
+        let [(9, Message::StatusResponse { role, log_len, .. })] = &out[..] else {
+            panic!("node {} answered {:?}", m.id(), out);
+        };
+        statuses.push((role.clone(), *log_len));
+    }
+    let leaders = statuses.iter().filter(|(role, _)| role == "Leader").count();
+    assert_eq!(leaders, 1, "{:?}", statuses);
+    let last = sim.machines.values().next().unwrap().state.last_log_index();
+    assert!(
+        statuses.iter().all(|&(_, len)| len == last),
+        "{:?}",
+        statuses
+    );
+}
+
+#[test]
+fn batched_commit_notifications() {
+    // A follower catching up on 500 entries hears about them in one call.
+    let options = Options {
+        batch_commit_notifications: true,
+        ..Options::default()
+    };
+    let mut sim = Simulation::with_options(3, 6, options);
+    sim.run_for(Duration::from_secs(1));
+    let leader = sim.leader().unwrap();
+    let follower = (0..3).find(|&id| id != leader).unwrap();
+    let batches = Rc::new(RefCell::new(vec![]));
+    let seen = batches.clone();
+    sim.machines
+        .get_mut(&follower)
+        .unwrap()
+        .on_commit(Box::new(move |entries| {
+            seen.borrow_mut().push(entries.len())
+        }));
+    sim.pause(follower);
+    for i in 0..500 {
+        sim.propose(format!("set key{} value{}", i, i).as_bytes())
+            .unwrap();
+    }
+    sim.run_for(Duration::from_millis(200));
+    sim.resume(follower);
+    sim.run_for(Duration::from_millis(200));
+    assert_eq!(*batches.borrow(), [500]);
+}
+
+#[test]
+fn cut_off_leader_holds_uncommitted_writes() {
+    // A leader cut off from its followers accepts writes it cannot commit.
+    let mut sim = Simulation::new(3, 12);
+    sim.run_for(Duration::from_secs(1));
+    let leader = sim.leader().unwrap();
+    let rest: Vec<u32> = (0..3).filter(|&id| id != leader).collect();
+    sim.partition(&[&[leader], &rest]);
+    for i in 0..3 {
+        sim.propose(format!("set stranded{} yes", i).as_bytes())
+            .unwrap();
+    }
+    sim.run_for(Duration::from_millis(100));
+    let stranded: Vec<_> = sim.machines[&leader]
+        .uncommitted()
+        .iter()
+        .map(|e| (e.index, String::from_utf8_lossy(&e.command).into_owned()))
+        .collect();
+    assert_eq!(
+        stranded,
+        (0..3)
+            .map(|i| (i + 1, format!("set stranded{} yes", i)))
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn quorum_index_is_what_commits() {
+    // Three of five nodes keep up; the quorum's index is what commits.
+    let mut sim = Simulation::new(5, 17);
+    sim.run_for(Duration::from_secs(1));
+    let leader = sim.leader().unwrap();
+    for id in (0..5).filter(|&id| id != leader).take(2) {
+        sim.pause(id);
+    }
+    for i in 0..4 {
+        sim.propose(format!("set key{} value{}", i, i).as_bytes())
+            .unwrap();
+    }
+    sim.run_for(Duration::from_millis(300));
+    assert_eq!(sim.agreed_commit_index(), Some(4));
+    assert_eq!(sim.machines[&leader].state.commit_index, 4);
+}
+
+#[test]
+fn committed_commands_in_commit_order() {
+    // The committed commands come back in the order they were committed, the
+    // ones compacted away standing behind the snapshot.
+    let mut sim = Simulation::new(3, 12);
+    sim.run_for(Duration::from_secs(1));
+    let leader = sim.leader().unwrap();
+    let commands: Vec<String> = (0..6).map(|i| format!("set history{} {}", i, i)).collect();
+    for command in &commands[..3] {
+        sim.propose(command.as_bytes()).unwrap();
+    }
+    sim.run_for(Duration::from_millis(300));
+    let leader_machine = sim.machines.get_mut(&leader).unwrap();
+    leader_machine.force_compact(leader_machine.state.last_applied);
+    for command in &commands[3..] {
+        sim.propose(command.as_bytes()).unwrap();
+    }
+    sim.run_for(Duration::from_millis(300));
+    let history: Vec<_> = sim.machines[&leader]
+        .committed_commands()
+        .map(|c| String::from_utf8_lossy(c).into_owned())
+        .collect();
+    assert_eq!(history, commands[3..], "committed commands out of order");
+}
+
+#[test]
+fn replicate_from_each_followers_next_index() {
+    // Followers at different points in the log each get entries from their own.
+    let mut state = State::new(0, vec![1, 2, 3], Options::default(), 0);
+    state.advance_term(1);
+    let mut leader = Leader::new(Instant::now(), &mut state);
+    for i in 0..6 {
+        state.append(format!("set key{} value{}", i, i).into_bytes());
+    }
+    leader.next_index.extend([(1, 7), (2, 4), (3, 1)]);
+    let prev: Vec<(u32, usize, usize)> = leader
+        .replicate(&state)
+        .into_iter()
+        .map(|(peer, m)| match m {
+            Message::AppendEntryRequest {
+                prev_log_index,
+                entries,
+                ..
+            } => (peer, prev_log_index, entries.len()),
+            other => panic!("expected AppendEntries, got {:?}", other),
+        })
+        .collect();
+    assert_eq!(prev, [(1, 6, 0), (2, 3, 3), (3, 0, 6)]);
+}
+
+#[test]
+fn heartbeats_carry_commit_index() {
+    // A caught-up follower takes the commit index from bare heartbeats.
+    let start = Instant::now();
+    let mut follower = Machine::new(1, vec![0, 2], Options::default(), 0, start).unwrap();
+    follower.state.advance_term(1);
+    for i in 0..4 {
+        follower
+            .state
+            .append(format!("set key{} value{}", i, i).into_bytes());
+    }
+    for leader_commit in 1..=4 {
+        follower.handle(
+            Message::AppendEntryRequest {
+                term: 1,
+                leader_id: 0,
+                prev_log_index: 4,
+                prev_log_term: 1,
+                entries: vec![],
+                leader_commit,
+                read_round: 0,
+                read_lease: None,
+            },
+            start,
+        );
+        assert_eq!(follower.state.commit_index, leader_commit);
+        assert_eq!(follower.state.last_applied, leader_commit);
+    }
+}
+
+#[test]
+fn throttled_state_machines_catch_up() {
+    // Slow state machines fall behind the commit index, then catch up.
+    let mut sim = Simulation::new(3, 37);
+    for m in sim.machines.values_mut() {
+        m.state_machine = Box::new(Throttled {
+            inner: KvStateMachine::default(),
+            per_tick: 2,
+        });
+    }
+    sim.run_for(Duration::from_secs(1));
+    let leader = sim.leader().unwrap();
+    for i in 0..100 {
+        sim.propose(format!("set key{} value{}", i, i).as_bytes())
+            .unwrap();
+    }
+    let mut widest_gap = 0;
+    let first = sim.machines[&leader].state.last_applied;
+    let mut ticks = 0;
+    while sim
+        .machines
+        .values()
+        .any(|m| m.state.last_applied < first + 100)
+    {
+        sim.step();
+        ticks += 1;
+        let s = &sim.machines[&leader].state;
+        assert!(
+            s.last_applied - first <= 2 * ticks,
+            "applied past the budget"
+        );
+        widest_gap = widest_gap.max(s.commit_index - s.last_applied);
+    }
+    assert!(widest_gap > 50, "apply kept pace with commit");
+    for m in sim.machines.values() {
+        for i in 0..100 {
+            let value = m.state_machine.query(format!("get key{}", i).as_bytes());
+            assert_eq!(
+                value,
+                format!("value{}", i).as_bytes(),
+                "node {} lost key{}",
+                m.id(),
+                i
+            );
+        }
+    }
+}
+
+#[test]
+fn replication_progress_shows_paused_follower() {
+    // A paused follower shows up behind the others in the leader's view.
+    let mut sim = Simulation::new(3, 41);
+    sim.run_for(Duration::from_secs(1));
+    let leader = sim.leader().unwrap();
+    let lagging = (0..3).find(|&id| id != leader).unwrap();
+    sim.pause(lagging);
+    for i in 0..10 {
+        sim.propose(format!("set key{} value{}", i, i).as_bytes())
+            .unwrap();
+    }
+    sim.run_for(Duration::from_millis(300));
+    let progress = sim.machines[&leader].replication_progress().unwrap();
+    let last = sim.machines[&leader].state.last_log_index();
+    for &(follower, _, match_index) in &progress {
+        if follower == lagging {
+            assert!(match_index + 10 <= last, "paused follower kept up");
+        } else {
+            assert_eq!(match_index, last);
+        }
+    }
+}
+
+#[test]
+fn divergent_follower_takes_leader_entries() {
+    // A follower whose log parts ways with the leader's at index 4 takes the
+    // leader's entries from there on.
+    let leader_log = LogBuilder::new().terms(&[1, 1, 2, 3, 3]).build();
+    let mut follower = State::new(1, vec![0, 2], Options::default(), 0);
+    follower.advance_term(3);
+    follower.log = LogBuilder::new().terms(&[1, 1, 2, 2, 2, 2]).build();
+    let merged = follower.merge_entries(3, 2, leader_log[3..].to_vec());
+    assert_eq!(merged, Some(5));
+    assert_eq!(follower.log, leader_log);
+}
+
+#[test]
+fn built_leader_commits_through_built_follower() {
+    // A leader built straight from a log replicates a new entry to a
+    // follower built from the same log, and commits it on the answer.
+    let start = Instant::now();
+    let log = LogBuilder::new().terms(&[1, 1, 2]).build();
+    let mut leader = Machine::as_leader(0, vec![1, 2], log.clone(), start);
+    let mut follower = Machine::as_follower(1, vec![0, 2], log, start);
+    assert_eq!(
+        Machine::as_candidate(2, vec![0, 1], vec![], start).role_name(),
+        "Candidate"
+    );
+    let (index, out) = leader.propose(b"set x 1".to_vec(), start).unwrap();
+    assert_eq!(index, 4);
+    let to_follower = out.into_iter().find(|(to, _)| *to == 1).unwrap().1;
+    for (to, reply) in follower.handle(to_follower, start) {
+        assert_eq!(to, 0);
+        leader.handle(reply, start);
+    }
+    assert_eq!(leader.state.commit_index, 4);
+}
+
+#[test]
+fn idle_leader_drops_to_keepalives() {
+    // An idle leader drops to keepalives, which still hold off elections;
+    // a new write brings the regular heartbeat back.
+    let mut rates = vec![];
+    for idle_heartbeat_interval in [None, Some(Duration::from_millis(120))] {
+        let options = Options {
+            idle_heartbeat_interval,
+            ..Options::default()
+        };
+        let mut sim = Simulation::with_options(3, 9, options);
+        sim.run_for(Duration::from_secs(1));
+        sim.propose(b"set x 1").unwrap();
+        sim.run_for(Duration::from_millis(500));
+        let leader = sim.leader().unwrap();
+        let term = sim.machines[&leader].state.current_term;
+        let mut heartbeats = 0;
+        let until = sim.current_time + Duration::from_secs(3);
+        while sim.current_time < until {
+            let seq = sim.next_seq;
+            sim.step();
+            heartbeats += sim
+                .queue
+                .iter()
+                .filter(|m| m.seq >= seq && m.from == leader && m.message.is_heartbeat())
+                .count();
+        }
+        assert_eq!(sim.leader(), Some(leader), "an election started while idle");
+        assert_eq!(sim.machines[&leader].state.current_term, term);
+        let index = sim.propose(b"set y 2").unwrap();
+        sim.run_for(Duration::from_millis(200));
+        assert!(sim.machines.values().all(|m| m.state.commit_index >= index));
+        rates.push(heartbeats / 3 / 2);
+    }
+    assert!(
+        rates[1] * 2 < rates[0],
+        "keepalives as frequent as heartbeats"
+    );
+}
+
+#[test]
+fn diff_logs_finds_first_differing_entry() {
+    // Two logs that part ways are told apart at the first differing entry,
+    // whether it differs in term or only in command.
+    let mut sim = Simulation::new(3, 2);
+    sim.machines.get_mut(&0).unwrap().state.log = LogBuilder::new().terms(&[1, 1, 2, 2]).build();
+    sim.machines.get_mut(&1).unwrap().state.log = LogBuilder::new().terms(&[1, 1, 3]).build();
+    sim.machines.get_mut(&2).unwrap().state.log = LogBuilder::new().terms(&[1, 1]).build();
+    assert_eq!(sim.diff_logs(0, 1), Some(3));
+    assert_eq!(sim.diff_logs(0, 2), None);
+    sim.machines.get_mut(&2).unwrap().state.log[1].command = b"set other 1".to_vec();
+    assert_eq!(sim.diff_logs(2, 0), Some(2));
+}
+
+#[test]
+fn commit_waits_for_median_ack() {
+    // With a different round trip to each follower, an entry commits when
+    // the median of the five nodes has stored it; the follower completing
+    // that quorum is the bottleneck.
+    let start = Instant::now();
+    let log = LogBuilder::new().terms(&[1, 1, 2]).build();
+    let mut leader = Machine::as_leader(0, vec![1, 2, 3, 4], log.clone(), start);
+    let round_trips = [(1, 20), (3, 40), (2, 80), (4, 140)];
+    let (index, out) = leader.propose(b"set y 2".to_vec(), start).unwrap();
+    let mut committed_at = None;
+    for (peer, millis) in round_trips {
+        let mut follower = Machine::as_follower(peer, vec![0], log.clone(), start);
+        let request = out.iter().find(|(to, _)| *to == peer).unwrap().1.clone();
+        let at = start + Duration::from_millis(millis);
+        for (_, reply) in follower.handle(request, start) {
+            leader.handle(reply, at);
+        }
+        if committed_at.is_none() && leader.state.commit_index >= index {
+            committed_at = Some(at);
+        }
+    }
+    let timeline = leader.commit_timeline(index).unwrap();
+    let acked: Vec<u32> = timeline.iter().map(|&(node, _)| node).collect();
+    assert_eq!(acked, [0, 1, 3, 2, 4]);
+    assert_eq!(committed_at, Some(timeline[timeline.len() / 2].1));
+}
+
+#[test]
+fn wait_for_applied_all_covers_slow_follower() {
+    // Waiting for every node to apply an entry covers a slow follower that
+    // a commit, needing only a majority, would leave behind.
+    let mut sim = Simulation::new(3, 43);
+    sim.run_for(Duration::from_secs(1));
+    let leader = sim.leader().unwrap();
+    let slow = (0..3).find(|&id| id != leader).unwrap();
+    sim.set_link_latency_normal(leader, slow, Duration::from_millis(300), Duration::ZERO);
+    let index = sim.propose(b"set k v").unwrap();
+    let short = sim.wait_for_applied_all(index, Duration::from_millis(100));
+    assert_eq!(short.map_err(|e| e.lagging), Err(vec![slow]));
+    sim.wait_for_applied_all(index, Duration::from_secs(1))
+        .unwrap();
+    for m in sim.machines.values() {
+        assert_eq!(m.state_machine.query(b"get k"), b"v");
+    }
+}
+
+#[test]
+fn leader_commit_past_log_commits_only_log() {
+    // A leader_commit far past the follower's log commits only what it has.
+    let start = Instant::now();
+    let mut follower = Machine::new(1, vec![0, 2], Options::default(), 0, start).unwrap();
+    follower.state.advance_term(1);
+    for i in 0..4 {
+        follower
+            .state
+            .append(format!("set key{} value{}", i, i).into_bytes());
+    }
+    follower.handle(
+        Message::AppendEntryRequest {
+            term: 1,
+            leader_id: 0,
+            prev_log_index: 4,
+            prev_log_term: 1,
+            entries: vec![],
+            leader_commit: usize::MAX,
+            read_round: 0,
+            read_lease: None,
+        },
+        start,
+    );
+    assert_eq!(follower.state.commit_index, 4);
+    assert_eq!(follower.state.last_applied, 4);
+}
+
+#[test]
+fn batch_appends_sends_fewer_append_entries() {
+    // Replicating proposals with the next heartbeat sends fewer
+    // AppendEntries than replicating each one as it comes.
+    let mut appends = vec![];
+    for batch_appends in [false, true] {
+        let options = Options {
+            batch_appends,
+            ..Options::default()
+        };
+        let mut sim = Simulation::with_options(3, 47, options);
+        sim.run_for(Duration::from_secs(1));
+        let before = sim.message_counts();
+        let mut last = 0;
+        for i in 0..20 {
+            last = sim
+                .propose(format!("set key{} value{}", i, i).as_bytes())
+                .unwrap();
+        }
+        sim.run_for(Duration::from_secs(1));
+        assert!(sim.machines.values().all(|m| m.state.commit_index >= last));
+        let kind = MessageKind::AppendEntryRequest;
+        let counts = sim.message_counts();
+        appends.push(counts[&kind] - before.get(&kind).copied().unwrap_or(0));
+    }
+    assert!(appends[1] < appends[0], "{:?}", appends);
+}
+
+#[test]
+fn append_rejections_record_their_reason() {
+    // Every refused AppendEntries records why, one branch of the follower's
+    // consistency check each.
+    let start = Instant::now();
+    let append = |term, prev_log_index, prev_log_term| Message::AppendEntryRequest {
+        term,
+        leader_id: 1,
+        prev_log_index,
+        prev_log_term,
+        entries: vec![],
+        leader_commit: 0,
+        read_round: 0,
+        read_lease: None,
+    };
+    let mut follower = Machine::as_follower(
+        0,
+        vec![1, 2],
+        LogBuilder::new().terms(&[1, 2]).build(),
+        start,
+    );
+    for (msg, reason) in [
+        (append(1, 2, 2), AppendRejectionReason::StaleTerm),
+        (append(2, 5, 2), AppendRejectionReason::LogGap),
+        (append(2, 2, 1), AppendRejectionReason::TermConflict),
+    ] {
+        follower.handle(msg, start);
+        let rejection = follower.state.last_append_rejection.unwrap();
+        assert_eq!(rejection.reason, reason);
+    }
+    follower.state.last_append_rejection = None;
+    follower.handle(append(2, 2, 2), start);
+    assert_eq!(follower.state.last_append_rejection, None);
+}
@@ -0,0 +1,443 @@
+//! Compaction, and catching followers up with snapshots.
+
+use super::super::*;
+
+#[test]
+fn stale_snapshot_does_not_roll_back_committed_log() {
+    // A follower that committed index 4 gets a late snapshot ending at 2:
+    // it reports the snapshot done and keeps its log and state as they are.
+    let start = Instant::now();
+    let log = LogBuilder::new().terms(&[1, 1, 1, 1]).build();
+    let mut m = Machine::as_follower(1, vec![0, 2], log.clone(), start);
+    m.process(
+        Message::AppendEntryRequest {
+            term: 1,
+            leader_id: 0,
+            prev_log_index: 4,
+            prev_log_term: 1,
+            entries: vec![],
+            leader_commit: 4,
+            read_round: 0,
+            read_lease: None,
+        },
+        start,
+    );
+    assert_eq!(m.state.commit_index, 4);
+    let out = m.process(
+        Message::InstallSnapshotRequest {
+            term: 1,
+            leader_id: 0,
+            last_index: 2,
+            last_term: 1,
+            config: m.state.config.clone(),
+            applied: 2,
+            sessions: Sessions::new(),
+            offset: 0,
+            data: KvStateMachine::default().snapshot(),
+            done: true,
+        },
+        start,
+    );
+    assert!(matches!(
+        out[..],
+        [(0, Message::InstallSnapshotResponse { done: true, .. })]
+    ));
+    assert_eq!(m.state.snapshot.last_index, 0);
+    assert_eq!(m.state.log, log);
+    assert_eq!(m.state.commit_index, 4);
+    assert_eq!(m.state_machine.query(b"get e4"), b"t1");
+}
+
+#[test]
+fn snapshot_gone_stale_mid_transfer_is_dropped() {
+    // The first chunk of a snapshot ending at 3 arrives before the follower
+    // has committed anything; by the last chunk it has committed 4, so the
+    // snapshot is dropped rather than installed over the applied entries.
+    let start = Instant::now();
+    let log = LogBuilder::new().terms(&[1, 1, 1, 1]).build();
+    let mut m = Machine::as_follower(1, vec![0, 2], log.clone(), start);
+    let mut kv = KvStateMachine::default();
+    for entry in &log[..3] {
+        kv.apply(&entry.command);
+    }
+    let data = kv.snapshot();
+    let (head, tail) = data.split_at(data.len() / 2);
+    let chunk = |offset: usize, data: &[u8], done| Message::InstallSnapshotRequest {
+        term: 1,
+        leader_id: 0,
+        last_index: 3,
+        last_term: 1,
+        config: m.state.config.clone(),
+        applied: 3,
+        sessions: Sessions::new(),
+        offset,
+        data: data.to_vec(),
+        done,
+    };
+    let first = chunk(0, head, false);
+    let last = chunk(head.len(), tail, true);
+    m.process(first, start);
+    assert_eq!(m.state.commit_index, 0);
+    m.process(
+        Message::AppendEntryRequest {
+            term: 1,
+            leader_id: 0,
+            prev_log_index: 4,
+            prev_log_term: 1,
+            entries: vec![],
+            leader_commit: 4,
+            read_round: 0,
+            read_lease: None,
+        },
+        start,
+    );
+    assert_eq!(m.state.commit_index, 4);
+    let out = m.process(last, start);
+    assert!(matches!(
+        out[..],
+        [(0, Message::InstallSnapshotResponse { done: true, .. })]
+    ));
+    assert_eq!(m.state.snapshot.last_index, 0);
+    assert_eq!(m.state.log, log);
+    assert_eq!(m.state.last_applied, 4);
+    assert_eq!(m.state_machine.query(b"get e4"), b"t1");
+}
+
+#[test]
+fn compaction_keeps_later_appends_in_place() {
+    // Compacting every log midway leaves later appends where they belong.
+    let mut sim = Simulation::new(3, 5);
+    sim.run_for(Duration::from_secs(1));
+    for i in 0..50 {
+        sim.propose(format!("set key{} value{}", i, i).as_bytes())
+            .unwrap();
+    }
+    sim.run_for(Duration::from_millis(500));
+    for m in sim.machines.values_mut() {
+        m.force_compact(m.state.last_applied / 2);
+    }
+    let index = sim.propose(b"set after compaction").unwrap();
+    sim.run_for(Duration::from_millis(500));
+    for m in sim.machines.values() {
+        assert!(m.state.snapshot.last_index > 0);
+        assert_eq!(m.state.commit_index, index);
+        assert!(matches!(
+            m.entry_at(index),
+            EntryLookup::Present(e) if e.command == b"set after compaction"
+        ));
+    }
+}
+
+#[test]
+fn lost_snapshot_chunk_is_resent() {
+    // A follower back from a crash is behind the leader's compacted log, so
+    // it is sent the snapshot in 64-byte chunks; one of them gets lost.
+    let options = Options {
+        snapshot_chunk_size: 64,
+        ..Options::default()
+    };
+    let mut sim = Simulation::with_options(3, 9, options);
+    sim.run_for(Duration::from_secs(1));
+    let leader = sim.leader().unwrap();
+    let follower = (0..3).find(|&id| id != leader).unwrap();
+    sim.crash(follower, sim.elapsed());
+    for i in 0..30 {
+        sim.propose(format!("set key{} value{}", i, i).as_bytes())
+            .unwrap();
+    }
+    sim.run_for(Duration::from_millis(300));
+    let leader_machine = sim.machines.get_mut(&leader).unwrap();
+    leader_machine.force_compact(leader_machine.state.last_applied);
+    let snapshot_len = leader_machine.state.snapshot.data.len();
+    assert!(snapshot_len > 3 * 64, "too small to lose a third chunk");
+    sim.drop_nth(3, move |m| {
+        m.to == follower && matches!(m.message, Message::InstallSnapshotRequest { .. })
+    });
+    sim.restart(follower, sim.elapsed());
+    sim.run_and_check(Duration::from_secs(1));
+    let restored = &sim.machines[&follower];
+    assert!(restored.state.snapshot.last_index >= 30);
+    assert_eq!(restored.state_machine.query(b"get key29"), b"value29");
+}
+
+#[test]
+fn lagging_follower_commits_what_installed_snapshot_applied() {
+    // The leader compacted to 15 with its state machine as of 20. A
+    // follower that only has 5 installs that snapshot; it must not end up
+    // having applied 20 while its commit index still says 15.
+    let start = Instant::now();
+    let terms = [1; 20];
+    let mut leader = Machine::as_leader(
+        0,
+        vec![1, 2],
+        LogBuilder::new().terms(&terms).build(),
+        start,
+    );
+    leader.state.commit_index = 20;
+    leader.tick(start);
+    assert_eq!(leader.state.last_applied, 20);
+    leader.force_compact(15);
+    assert_eq!(
+        (
+            leader.state.snapshot.last_index,
+            leader.state.snapshot.applied
+        ),
+        (15, 20)
+    );
+    let mut follower = Machine::as_follower(
+        1,
+        vec![0, 2],
+        LogBuilder::new().terms(&terms[..5]).build(),
+        start,
+    );
+    let mut at = start + Duration::from_millis(50);
+    let mut to_follower: Outbox = leader.tick(at);
+    for _ in 0..20 {
+        let to_leader: Outbox = to_follower
+            .drain(..)
+            .filter(|&(to, _)| to == 1)
+            .flat_map(|(_, msg)| {
+                let out = follower.process(msg, at);
+                let s = &follower.state;
+                assert!(
+                    s.last_applied <= s.commit_index,
+                    "applied {} past commit index {}",
+                    s.last_applied,
+                    s.commit_index
+                );
+                out
+            })
+            .collect();
+        if to_leader.is_empty() {
+            break;
+        }
+        to_follower = to_leader
+            .into_iter()
+            .flat_map(|(_, msg)| leader.process(msg, at))
+            .collect();
+        at += Duration::from_millis(1);
+    }
+    let s = &follower.state;
+    assert!(s.snapshot.last_index >= 15);
+    assert_eq!(s.last_applied, 20);
+    assert_eq!(follower.state_machine.query(b"get e20"), b"t1");
+}
+
+#[test]
+fn entry_lookup_around_snapshot_and_log_end() {
+    // Looking up entries on either side of the snapshot and past the log.
+    let mut sim = Simulation::new(3, 43);
+    sim.run_for(Duration::from_secs(1));
+    let leader = sim.leader().unwrap();
+    for i in 0..6 {
+        sim.propose(format!("set key{} value{}", i, i).as_bytes())
+            .unwrap();
+    }
+    sim.run_for(Duration::from_millis(300));
+    let m = sim.machines.get_mut(&leader).unwrap();
+    m.force_compact(3);
+    assert_eq!(m.entry_at(3), EntryLookup::Compacted);
+    assert!(matches!(m.entry_at(4), EntryLookup::Present(e) if e.command == b"set key3 value3"));
+    assert_eq!(m.entry_at(7), EntryLookup::NotYetExists);
+}
+
+#[test]
+fn replicate_sends_snapshot_past_compaction() {
+    // Once the log up to 4 is compacted, the followers needing entries
+    // from there get the snapshot instead, as of 6 where it was taken; the
+    // one ahead of it does not.
+    let mut state = State::new(0, vec![1, 2, 3], Options::default(), 0);
+    state.advance_term(1);
+    let mut leader = Leader::new(Instant::now(), &mut state);
+    for i in 0..6 {
+        state.append(format!("set key{} value{}", i, i).into_bytes());
+    }
+    leader.next_index.extend([(1, 7), (2, 4), (3, 1)]);
+    state.commit_index = 6;
+    state.last_applied = 6;
+    state.compact(4, vec![], Sessions::new());
+    let kinds: Vec<(u32, &str)> = leader
+        .replicate(&state)
+        .into_iter()
+        .map(|(peer, m)| match m {
+            Message::AppendEntryRequest { .. } => (peer, "AppendEntries"),
+            Message::InstallSnapshotRequest { last_index: 6, .. } => (peer, "InstallSnapshot"),
+            other => panic!("unexpected {:?}", other),
+        })
+        .collect();
+    assert_eq!(
+        kinds,
+        [
+            (1, "AppendEntries"),
+            (2, "InstallSnapshot"),
+            (3, "InstallSnapshot")
+        ]
+    );
+}
+
+#[test]
+fn snapshot_on_election_compacts_to_new_term_entry() {
+    // With snapshot_on_election a new leader commits an entry of its term
+    // and then compacts up to it; without, nothing is compacted.
+    for snapshot_on_election in [false, true] {
+        let mut sim = Simulation::with_options(
+            3,
+            1,
+            Options {
+                snapshot_on_election,
+                ..Options::default()
+            },
+        );
+        sim.run_for(Duration::from_millis(500));
+        for i in 0..5 {
+            sim.propose(format!("set k{} {}", i, i).as_bytes()).unwrap();
+        }
+        sim.run_for(Duration::from_millis(500));
+        let old_leader = sim.leader().unwrap();
+        sim.crash(old_leader, sim.elapsed());
+        sim.run_and_check(Duration::from_secs(2));
+        let leader = sim.leader().unwrap();
+        assert_ne!(leader, old_leader);
+        let s = &sim.machines[&leader].state;
+        if snapshot_on_election {
+            assert_eq!(s.snapshot.last_index, s.commit_index);
+            assert_eq!(s.snapshot.last_term, s.current_term);
+        } else {
+            assert_eq!(s.snapshot.last_index, 0);
+        }
+    }
+}
+
+#[test]
+fn compact_all_skips_nodes_not_applied_that_far() {
+    // Compacting the whole cluster skips a node that has not applied that
+    // far; with no log left to replay, it and a node added afterwards both
+    // catch up through InstallSnapshot.
+    let mut sim = Simulation::new(3, 17);
+    let leader = sim.assert_converges(200);
+    let lagging = (leader + 1) % 3;
+    for i in 0..10 {
+        sim.propose(format!("set key{} value{}", i, i).as_bytes())
+            .unwrap();
+    }
+    sim.run_for(Duration::from_millis(200));
+    let rest: Vec<u32> = (0..3).filter(|&id| id != lagging).collect();
+    sim.partition(&[&[lagging], &rest]);
+    for i in 10..20 {
+        sim.propose(format!("set key{} value{}", i, i).as_bytes())
+            .unwrap();
+    }
+    sim.run_for(Duration::from_millis(200));
+    let up_to = sim.machines[&leader].state.last_applied;
+    let mut compacted = sim.compact_all(up_to);
+    compacted.sort_unstable();
+    assert_eq!(compacted, rest);
+    assert!(sim.compact_all(up_to).is_empty());
+    sim.heal();
+    sim.add_node(3);
+    sim.change_config(&ConfigChange::add(3)).unwrap();
+    let snapshots = |sim: &Simulation| {
+        let counts = sim.message_counts();
+        counts
+            .get(&MessageKind::InstallSnapshotRequest)
+            .copied()
+            .unwrap_or(0)
+    };
+    let before = snapshots(&sim);
+    sim.run_for(Duration::from_secs(1));
+    assert!(snapshots(&sim) >= before + 2);
+    for id in [lagging, 3] {
+        let m = &sim.machines[&id];
+        assert!(m.state.snapshot.last_index >= up_to);
+        assert!(m.state.log.iter().all(|e| e.index > up_to));
+        assert_eq!(m.state_machine.query(b"get key19"), b"value19");
+    }
+}
+
+#[test]
+fn compact_all_below_applied_then_lagging_follower_catches_up() {
+    // The healthy nodes are compacted five entries short of what they
+    // applied; the follower cut off meanwhile catches up by snapshot and
+    // never holds state past what it knows to be committed.
+    let mut sim = Simulation::new(3, 21);
+    let leader = sim.assert_converges(200);
+    let lagging = (leader + 1) % 3;
+    let rest: Vec<u32> = (0..3).filter(|&id| id != lagging).collect();
+    sim.partition(&[&[lagging], &rest]);
+    for i in 0..20 {
+        sim.propose(format!("set key{} value{}", i, i).as_bytes())
+            .unwrap();
+    }
+    sim.run_for(Duration::from_millis(200));
+    let applied = sim.machines[&leader].state.last_applied;
+    assert_eq!(sim.compact_all(applied - 5).len(), 2);
+    sim.heal();
+    let deadline = sim.current_time + Duration::from_secs(1);
+    while sim.machines[&lagging].state.last_applied < applied {
+        assert!(
+            sim.current_time < deadline,
+            "lagging follower never caught up"
+        );
+        sim.step();
+        let s = &sim.machines[&lagging].state;
+        assert!(
+            s.last_applied <= s.commit_index,
+            "applied {} past commit index {}",
+            s.last_applied,
+            s.commit_index
+        );
+    }
+    sim.run_and_check(Duration::from_millis(200));
+    assert_eq!(
+        sim.machines[&lagging].state_machine.query(b"get key19"),
+        b"value19"
+    );
+}
+
+#[test]
+fn snapshot_chunks_reset_election_timer() {
+    // A voter brought back by a snapshot sent in many small chunks hears
+    // nothing but InstallSnapshot for longer than any election timeout;
+    // each chunk resets its timer as an AppendEntries would, so it never
+    // campaigns in the middle of the transfer.
+    let mut sim = Simulation::with_options(
+        3,
+        19,
+        Options {
+            snapshot_chunk_size: 16,
+            ..Options::default()
+        },
+    );
+    let leader = sim.assert_converges(200);
+    let lagging = (leader + 1) % 3;
+    sim.crash(lagging, sim.elapsed());
+    for i in 0..20 {
+        sim.propose(format!("set key{} value{}", i, i).as_bytes())
+            .unwrap();
+    }
+    sim.run_for(Duration::from_millis(200));
+    let up_to = sim.machines[&leader].state.last_applied;
+    assert_eq!(sim.compact_all(up_to).len(), 2);
+    let campaigns = Rc::new(Cell::new(0));
+    let seen = campaigns.clone();
+    sim.subscribe(Box::new(move |event| {
+        if let Event::RoleChanged { node, to, .. } = *event {
+            if node == lagging && to != "Follower" {
+                seen.set(seen.get() + 1);
+            }
+        }
+    }));
+    sim.restart(lagging, sim.elapsed());
+    sim.step();
+    let term = sim.machines[&lagging].state.current_term;
+    let started = sim.elapsed();
+    let until = sim.current_time + Duration::from_secs(3);
+    while sim.machines[&lagging].state.snapshot.last_index < up_to {
+        assert!(sim.current_time < until, "node {} never caught up", lagging);
+        sim.step();
+    }
+    let took = sim.elapsed() - started;
+    assert!(took > sim.machines[&lagging].state.options.base_election_timeout() * 2);
+    assert_eq!(campaigns.get(), 0);
+    assert_eq!(sim.machines[&lagging].state.current_term, term);
+}
@@ -0,0 +1,255 @@
+//! Typed commands on top of the byte-oriented log. The log and the wire still
+//! carry bytes; this layer encodes on the way in and decodes on apply.
+
+use std::marker::PhantomData;
+use std::time::Instant;
+
+use super::driver::RaftError;
+use super::{Machine, Message, Options, OptionsError, Outbox, StateMachine};
+
+pub trait Command: Sized {
+    fn encode(&self) -> Vec<u8>;
+
+    /// `None` for bytes that are not a command, such as no-op entries.
+    fn decode(bytes: &[u8]) -> Option<Self>;
+}
+
+/// A state machine that works with commands of type `C` instead of bytes.
+pub trait TypedStateMachine<C: Command> {
+    fn apply(&mut self, command: C) -> Vec<u8>;
+
+    fn query(&self, query: &[u8]) -> Vec<u8>;
+
+    fn snapshot(&self) -> Vec<u8>;
+
+    fn restore(&mut self, data: &[u8]);
+}
+
+/// Adapts a typed state machine to the byte interface `Machine` expects.
+struct Typed<C, S> {
+    inner: S,
+    command: PhantomData<fn(C)>,
+}
+
+impl<C: Command, S: TypedStateMachine<C>> StateMachine for Typed<C, S> {
+    fn apply(&mut self, command: &[u8]) -> Vec<u8> {
+        C::decode(command).map_or_else(Vec::new, |c| self.inner.apply(c))
+    }
+
+    fn query(&self, query: &[u8]) -> Vec<u8> {
+        self.inner.query(query)
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        self.inner.snapshot()
+    }
+
+    fn restore(&mut self, data: &[u8]) {
+        self.inner.restore(data)
+    }
+}
+
+/// A node whose commands are typed. Everything but proposing goes straight
+/// to the underlying `Machine`.
+pub struct RaftNode<C: Command> {
+    pub machine: Machine,
+    command: PhantomData<fn(C)>,
+}
+
+impl<C: Command + 'static> RaftNode<C> {
+    pub fn new<S: TypedStateMachine<C> + 'static>(
+        id: u32,
+        peers: Vec<u32>,
+        options: Options,
+        seed: u64,
+        at: Instant,
+        state_machine: S,
+    ) -> Result<Self, OptionsError> {
+        let state_machine = Box::new(Typed {
+            inner: state_machine,
+            command: PhantomData,
+        });
+        Ok(RaftNode {
+            machine: Machine::with_state_machine(id, peers, options, seed, at, state_machine)?,
+            command: PhantomData,
+        })
+    }
+
+    /// Appends `command` if this node leads; returns its log index.
+    pub fn propose(&mut self, command: &C, at: Instant) -> Result<(usize, Outbox), RaftError> {
+        self.machine.propose(command.encode(), at)
+    }
+
+    pub fn tick(&mut self, at: Instant) -> Outbox {
+        self.machine.tick(at)
+    }
+
+    pub fn handle(&mut self, msg: Message, at: Instant) -> Outbox {
+        self.machine.handle(msg, at)
+    }
+
+    pub fn query(&self, query: &[u8]) -> Vec<u8> {
+        self.machine.state_machine.query(query)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum KvCommand {
+        Set(String, String),
+        Delete(String),
+    }
+
+    impl Command for KvCommand {
+        fn encode(&self) -> Vec<u8> {
+            match self {
+                KvCommand::Set(key, value) => format!("set\0{}\0{}", key, value),
+                KvCommand::Delete(key) => format!("del\0{}", key),
+            }
+            .into_bytes()
+        }
+
+        fn decode(bytes: &[u8]) -> Option<Self> {
+            let text = std::str::from_utf8(bytes).ok()?;
+            let mut parts = text.split('\0');
+            match (parts.next()?, parts.next()?, parts.next()) {
+                ("set", key, Some(value)) => Some(KvCommand::Set(key.into(), value.into())),
+                ("del", key, None) => Some(KvCommand::Delete(key.into())),
+                _ => None,
+            }
+        }
+    }
+
+    /// Keeps every command it applied, so the test sees them decoded.
+    #[derive(Default)]
+    struct KvStore {
+        data: BTreeMap<String, String>,
+        applied: Rc<RefCell<Vec<KvCommand>>>,
+    }
+
+    impl TypedStateMachine<KvCommand> for KvStore {
+        fn apply(&mut self, command: KvCommand) -> Vec<u8> {
+            self.applied.borrow_mut().push(command.clone());
+            let previous = match command {
+                KvCommand::Set(key, value) => self.data.insert(key, value),
+                KvCommand::Delete(key) => self.data.remove(&key),
+            };
+            previous.unwrap_or_default().into_bytes()
+        }
+
+        fn query(&self, query: &[u8]) -> Vec<u8> {
+            let key = String::from_utf8_lossy(query);
+            self.data
+                .get(&*key)
+                .cloned()
+                .unwrap_or_default()
+                .into_bytes()
+        }
+
+        fn snapshot(&self) -> Vec<u8> {
+            self.data
+                .iter()
+                .map(|(k, v)| format!("{}\0{}\n", k, v))
+                .collect::<String>()
+                .into_bytes()
+        }
+
+        fn restore(&mut self, data: &[u8]) {
+            self.data = String::from_utf8_lossy(data)
+                .lines()
+                .filter_map(|line| line.split_once('\0'))
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+        }
+    }
+
+    #[test]
+    fn typed_commands_applied_decoded() {
+        // A single node elects itself, then commits typed commands; the
+        // state machine sees them as `KvCommand`s, and the leader's no-op
+        // entry, which is no command, is skipped.
+        let start = Instant::now();
+        let store = KvStore::default();
+        let applied = store.applied.clone();
+        let mut node: RaftNode<KvCommand> =
+            RaftNode::new(0, vec![], Options::default(), 1, start, store).unwrap();
+        let mut at = start;
+        while node.machine.role_name() != "Leader" {
+            at += Duration::from_millis(10);
+            assert!(at < start + Duration::from_secs(1), "no leader");
+            node.tick(at);
+        }
+        let commands = [
+            KvCommand::Set("a".into(), "1".into()),
+            KvCommand::Set("b".into(), "2".into()),
+            KvCommand::Delete("a".into()),
+        ];
+        for command in &commands {
+            node.propose(command, at).unwrap();
+        }
+        node.tick(at + Duration::from_millis(10));
+        assert_eq!(*applied.borrow(), commands);
+        assert_eq!(node.query(b"a"), b"");
+        assert_eq!(node.query(b"b"), b"2");
+        assert_eq!(
+            KvCommand::decode(&KvCommand::Delete("x".into()).encode()),
+            Some(KvCommand::Delete("x".into()))
+        );
+        assert_eq!(KvCommand::decode(b""), None);
+    }
+
+    /// Hands every message to its receiver, and whatever that sends in turn,
+    /// until nothing is left in flight.
+    fn deliver(nodes: &mut BTreeMap<u32, RaftNode<KvCommand>>, mut outbox: Outbox, at: Instant) {
+        while let Some((to, msg)) = outbox.pop() {
+            outbox.extend(nodes.get_mut(&to).unwrap().handle(msg, at));
+        }
+    }
+
+    #[test]
+    fn typed_nodes_replicate_to_each_other() {
+        // Three typed nodes exchange messages through `handle` until the
+        // leader's command is applied, decoded, on every one of them.
+        let start = Instant::now();
+        let mut applied = Vec::new();
+        let mut nodes: BTreeMap<u32, RaftNode<KvCommand>> = BTreeMap::new();
+        for id in 0..3 {
+            let store = KvStore::default();
+            applied.push(store.applied.clone());
+            let peers = (0..3).filter(|&p| p != id).collect();
+            let node = RaftNode::new(id, peers, Options::default(), id as u64, start, store);
+            nodes.insert(id, node.unwrap());
+        }
+        let command = KvCommand::Set("k".into(), "v".into());
+        let mut proposed = false;
+        let mut at = start;
+        while applied.iter().any(|a| a.borrow().is_empty()) {
+            at += Duration::from_millis(10);
+            assert!(at < start + Duration::from_secs(5), "not replicated");
+            let outbox = nodes.values_mut().flat_map(|n| n.tick(at)).collect();
+            deliver(&mut nodes, outbox, at);
+            if !proposed {
+                if let Some(leader) = nodes
+                    .values_mut()
+                    .find(|n| n.machine.role_name() == "Leader")
+                {
+                    let (_, outbox) = leader.propose(&command, at).unwrap();
+                    deliver(&mut nodes, outbox, at);
+                    proposed = true;
+                }
+            }
+        }
+        for (node, applied) in nodes.values().zip(&applied) {
+            assert_eq!(*applied.borrow(), std::slice::from_ref(&command));
+            assert_eq!(node.query(b"k"), b"v");
+        }
+    }
+}
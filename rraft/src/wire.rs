@@ -0,0 +1,777 @@
+//! Compact binary encoding of messages, for when they leave the process.
+//!
+//! A message is a version byte, a variant tag, then its fields in declaration
+//! order: integers as big-endian `u64` (node ids as `u32`), durations as
+//! nanoseconds, bools as one byte, options as a presence byte, and sequences
+//! as a `u32` length. A log entry's command is preceded by a byte saying
+//! whether it is run-length encoded.
+
+use std::fmt;
+use std::time::Duration;
+
+use super::compress::{compress, decompress};
+use super::membership::Config;
+use super::{LogEntry, Message, Sessions};
+
+pub const VERSION: u8 = 16;
+
+const APPEND_ENTRY_REQUEST: u8 = 0;
+const APPEND_ENTRY_RESPONSE: u8 = 1;
+const REQUEST_VOTE_REQUEST: u8 = 2;
+const REQUEST_VOTE_RESPONSE: u8 = 3;
+const COMMIT_NOTIFY: u8 = 4;
+const CLIENT_REQUEST: u8 = 5;
+const CLIENT_RESPONSE: u8 = 6;
+const CLIENT_READ: u8 = 7;
+const TIMEOUT_NOW: u8 = 8;
+const PRE_VOTE_REQUEST: u8 = 9;
+const PRE_VOTE_RESPONSE: u8 = 10;
+const STATUS_REQUEST: u8 = 11;
+const STATUS_RESPONSE: u8 = 12;
+const INSTALL_SNAPSHOT_REQUEST: u8 = 13;
+const INSTALL_SNAPSHOT_RESPONSE: u8 = 14;
+const MEMBERSHIP_REQUEST: u8 = 16;
+const MEMBERSHIP_RESPONSE: u8 = 17;
+const LOCAL_READ: u8 = 18;
+const LOCAL_READ_RESPONSE: u8 = 19;
+const CLIENT_READ_TXN: u8 = 20;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The buffer ended in the middle of a message.
+    UnexpectedEof,
+    UnknownVariant(u8),
+    UnsupportedVersion(u8),
+    /// A string field is not UTF-8.
+    InvalidUtf8,
+    /// A whole message was decoded but bytes were left over.
+    TrailingBytes,
+    /// A compressed command does not decompress.
+    BadCompression,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnexpectedEof => write!(f, "unexpected end of message"),
+            DecodeError::UnknownVariant(tag) => write!(f, "unknown message variant {}", tag),
+            DecodeError::UnsupportedVersion(v) => {
+                write!(f, "unsupported wire version {} (expected {})", v, VERSION)
+            }
+            DecodeError::InvalidUtf8 => write!(f, "string field is not valid UTF-8"),
+            DecodeError::TrailingBytes => write!(f, "trailing bytes after message"),
+            DecodeError::BadCompression => write!(f, "compressed command is malformed"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+pub fn encode(msg: &Message) -> Vec<u8> {
+    encode_with(msg, None)
+}
+
+/// Like `encode`, but compresses the commands of entries at least
+/// `threshold` bytes long where that makes them smaller.
+pub fn encode_compressed(msg: &Message, threshold: usize) -> Vec<u8> {
+    encode_with(msg, Some(threshold))
+}
+
+fn encode_with(msg: &Message, compress_over: Option<usize>) -> Vec<u8> {
+    let mut w = Writer(vec![VERSION], compress_over);
+    match msg {
+        Message::AppendEntryRequest {
+            term,
+            leader_id,
+            prev_log_index,
+            prev_log_term,
+            entries,
+            leader_commit,
+            read_round,
+            read_lease,
+        } => {
+            w.u8(APPEND_ENTRY_REQUEST);
+            w.usize(*term);
+            w.u32(*leader_id);
+            w.usize(*prev_log_index);
+            w.usize(*prev_log_term);
+            w.len(entries.len());
+            for entry in entries {
+                w.entry(entry);
+            }
+            w.usize(*leader_commit);
+            w.usize(*read_round);
+            w.opt_duration(*read_lease);
+        }
+        Message::AppendEntryResponse {
+            term,
+            success,
+            from,
+            match_index,
+            read_round,
+            term_bounds,
+        } => {
+            w.u8(APPEND_ENTRY_RESPONSE);
+            w.usize(*term);
+            w.bool(*success);
+            w.u32(*from);
+            w.usize(*match_index);
+            w.usize(*read_round);
+            w.len(term_bounds.len());
+            for &(term, first, last) in term_bounds {
+                w.usize(term);
+                w.usize(first);
+                w.usize(last);
+            }
+        }
+        Message::RequestVoteRequest {
+            term,
+            candidate_id,
+            last_log_index,
+            last_log_term,
+            forced,
+        } => {
+            w.u8(REQUEST_VOTE_REQUEST);
+            w.usize(*term);
+            w.u32(*candidate_id);
+            w.usize(*last_log_index);
+            w.usize(*last_log_term);
+            w.bool(*forced);
+        }
+        Message::RequestVoteResponse {
+            term,
+            vote_granted,
+            from,
+        } => {
+            w.u8(REQUEST_VOTE_RESPONSE);
+            w.usize(*term);
+            w.bool(*vote_granted);
+            w.u32(*from);
+        }
+        Message::PreVoteRequest {
+            term,
+            candidate_id,
+            last_log_index,
+            last_log_term,
+        } => {
+            w.u8(PRE_VOTE_REQUEST);
+            w.usize(*term);
+            w.u32(*candidate_id);
+            w.usize(*last_log_index);
+            w.usize(*last_log_term);
+        }
+        Message::PreVoteResponse {
+            term,
+            vote_granted,
+            from,
+        } => {
+            w.u8(PRE_VOTE_RESPONSE);
+            w.usize(*term);
+            w.bool(*vote_granted);
+            w.u32(*from);
+        }
+        Message::InstallSnapshotRequest {
+            term,
+            leader_id,
+            last_index,
+            last_term,
+            config,
+            applied,
+            sessions,
+            offset,
+            data,
+            done,
+        } => {
+            w.u8(INSTALL_SNAPSHOT_REQUEST);
+            w.usize(*term);
+            w.u32(*leader_id);
+            w.usize(*last_index);
+            w.usize(*last_term);
+            w.config(config);
+            w.usize(*applied);
+            w.sessions(sessions);
+            w.usize(*offset);
+            w.bytes(data);
+            w.bool(*done);
+        }
+        Message::InstallSnapshotResponse {
+            term,
+            from,
+            last_index,
+            offset,
+            done,
+        } => {
+            w.u8(INSTALL_SNAPSHOT_RESPONSE);
+            w.usize(*term);
+            w.u32(*from);
+            w.usize(*last_index);
+            w.usize(*offset);
+            w.bool(*done);
+        }
+        Message::CommitNotify {
+            term,
+            leader_id,
+            commit_index,
+        } => {
+            w.u8(COMMIT_NOTIFY);
+            w.usize(*term);
+            w.u32(*leader_id);
+            w.usize(*commit_index);
+        }
+        Message::TimeoutNow { term, leader_id } => {
+            w.u8(TIMEOUT_NOW);
+            w.usize(*term);
+            w.u32(*leader_id);
+        }
+        Message::StatusRequest { from } => {
+            w.u8(STATUS_REQUEST);
+            w.u32(*from);
+        }
+        Message::LocalRead {
+            client_id,
+            request_id,
+            query,
+        } => {
+            w.u8(LOCAL_READ);
+            w.u32(*client_id);
+            w.u64(*request_id);
+            w.bytes(query);
+        }
+        Message::LocalReadResponse {
+            from,
+            request_id,
+            last_applied,
+            result,
+        } => {
+            w.u8(LOCAL_READ_RESPONSE);
+            w.u32(*from);
+            w.u64(*request_id);
+            w.usize(*last_applied);
+            w.bytes(result);
+        }
+        Message::MembershipRequest { from } => {
+            w.u8(MEMBERSHIP_REQUEST);
+            w.u32(*from);
+        }
+        Message::MembershipResponse {
+            from,
+            term,
+            voters,
+            learners,
+            leader_hint,
+        } => {
+            w.u8(MEMBERSHIP_RESPONSE);
+            w.u32(*from);
+            w.usize(*term);
+            w.ids(voters);
+            w.ids(learners);
+            w.opt_u32(*leader_hint);
+        }
+        Message::StatusResponse {
+            from,
+            role,
+            term,
+            commit_index,
+            leader_id,
+            log_len,
+        } => {
+            w.u8(STATUS_RESPONSE);
+            w.u32(*from);
+            w.bytes(role.as_bytes());
+            w.usize(*term);
+            w.usize(*commit_index);
+            w.opt_u32(*leader_id);
+            w.usize(*log_len);
+        }
+        Message::ClientRequest {
+            client_id,
+            request_id,
+            command,
+            wait_for_apply,
+        } => {
+            w.u8(CLIENT_REQUEST);
+            w.u32(*client_id);
+            w.u64(*request_id);
+            w.bytes(command);
+            w.bool(*wait_for_apply);
+        }
+        Message::ClientRead {
+            client_id,
+            request_id,
+            query,
+            local_only,
+        } => {
+            w.u8(CLIENT_READ);
+            w.u32(*client_id);
+            w.u64(*request_id);
+            w.bytes(query);
+            w.bool(*local_only);
+        }
+        Message::ClientReadTxn {
+            client_id,
+            request_id,
+            queries,
+        } => {
+            w.u8(CLIENT_READ_TXN);
+            w.u32(*client_id);
+            w.u64(*request_id);
+            w.len(queries.len());
+            for query in queries {
+                w.bytes(query);
+            }
+        }
+        Message::ClientResponse {
+            request_id,
+            success,
+            leader_hint,
+            result,
+            busy,
+            reconfiguring,
+            too_large,
+            staleness,
+        } => {
+            w.u8(CLIENT_RESPONSE);
+            w.u64(*request_id);
+            w.bool(*success);
+            w.opt_u32(*leader_hint);
+            w.bytes(result);
+            w.bool(*busy);
+            w.bool(*reconfiguring);
+            w.opt_usize(*too_large);
+            w.opt_duration(*staleness);
+        }
+    }
+    w.0
+}
+
+pub fn decode(buf: &[u8]) -> Result<Message, DecodeError> {
+    let mut r = Reader(buf);
+    let version = r.u8()?;
+    if version != VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+    let msg = match r.u8()? {
+        APPEND_ENTRY_REQUEST => Message::AppendEntryRequest {
+            term: r.usize()?,
+            leader_id: r.u32()?,
+            prev_log_index: r.usize()?,
+            prev_log_term: r.usize()?,
+            entries: (0..r.len()?).map(|_| r.entry()).collect::<Result<_, _>>()?,
+            leader_commit: r.usize()?,
+            read_round: r.usize()?,
+            read_lease: r.opt_duration()?,
+        },
+        APPEND_ENTRY_RESPONSE => Message::AppendEntryResponse {
+            term: r.usize()?,
+            success: r.bool()?,
+            from: r.u32()?,
+            match_index: r.usize()?,
+            read_round: r.usize()?,
+            term_bounds: (0..r.len()?)
+                .map(|_| Ok((r.usize()?, r.usize()?, r.usize()?)))
+                .collect::<Result<_, DecodeError>>()?,
+        },
+        REQUEST_VOTE_REQUEST => Message::RequestVoteRequest {
+            term: r.usize()?,
+            candidate_id: r.u32()?,
+            last_log_index: r.usize()?,
+            last_log_term: r.usize()?,
+            forced: r.bool()?,
+        },
+        REQUEST_VOTE_RESPONSE => Message::RequestVoteResponse {
+            term: r.usize()?,
+            vote_granted: r.bool()?,
+            from: r.u32()?,
+        },
+        PRE_VOTE_REQUEST => Message::PreVoteRequest {
+            term: r.usize()?,
+            candidate_id: r.u32()?,
+            last_log_index: r.usize()?,
+            last_log_term: r.usize()?,
+        },
+        PRE_VOTE_RESPONSE => Message::PreVoteResponse {
+            term: r.usize()?,
+            vote_granted: r.bool()?,
+            from: r.u32()?,
+        },
+        INSTALL_SNAPSHOT_REQUEST => Message::InstallSnapshotRequest {
+            term: r.usize()?,
+            leader_id: r.u32()?,
+            last_index: r.usize()?,
+            last_term: r.usize()?,
+            config: r.config()?,
+            applied: r.usize()?,
+            sessions: r.sessions()?,
+            offset: r.usize()?,
+            data: r.bytes()?,
+            done: r.bool()?,
+        },
+        INSTALL_SNAPSHOT_RESPONSE => Message::InstallSnapshotResponse {
+            term: r.usize()?,
+            from: r.u32()?,
+            last_index: r.usize()?,
+            offset: r.usize()?,
+            done: r.bool()?,
+        },
+        COMMIT_NOTIFY => Message::CommitNotify {
+            term: r.usize()?,
+            leader_id: r.u32()?,
+            commit_index: r.usize()?,
+        },
+        TIMEOUT_NOW => Message::TimeoutNow {
+            term: r.usize()?,
+            leader_id: r.u32()?,
+        },
+        STATUS_REQUEST => Message::StatusRequest { from: r.u32()? },
+        LOCAL_READ => Message::LocalRead {
+            client_id: r.u32()?,
+            request_id: r.u64()?,
+            query: r.bytes()?,
+        },
+        LOCAL_READ_RESPONSE => Message::LocalReadResponse {
+            from: r.u32()?,
+            request_id: r.u64()?,
+            last_applied: r.usize()?,
+            result: r.bytes()?,
+        },
+        MEMBERSHIP_REQUEST => Message::MembershipRequest { from: r.u32()? },
+        MEMBERSHIP_RESPONSE => Message::MembershipResponse {
+            from: r.u32()?,
+            term: r.usize()?,
+            voters: r.ids()?,
+            learners: r.ids()?,
+            leader_hint: r.opt_u32()?,
+        },
+        STATUS_RESPONSE => Message::StatusResponse {
+            from: r.u32()?,
+            role: String::from_utf8(r.bytes()?).map_err(|_| DecodeError::InvalidUtf8)?,
+            term: r.usize()?,
+            commit_index: r.usize()?,
+            leader_id: r.opt_u32()?,
+            log_len: r.usize()?,
+        },
+        CLIENT_REQUEST => Message::ClientRequest {
+            client_id: r.u32()?,
+            request_id: r.u64()?,
+            command: r.bytes()?,
+            wait_for_apply: r.bool()?,
+        },
+        CLIENT_READ => Message::ClientRead {
+            client_id: r.u32()?,
+            request_id: r.u64()?,
+            query: r.bytes()?,
+            local_only: r.bool()?,
+        },
+        CLIENT_READ_TXN => Message::ClientReadTxn {
+            client_id: r.u32()?,
+            request_id: r.u64()?,
+            queries: {
+                let n = r.len()?;
+                (0..n).map(|_| r.bytes()).collect::<Result<_, _>>()?
+            },
+        },
+        CLIENT_RESPONSE => Message::ClientResponse {
+            request_id: r.u64()?,
+            success: r.bool()?,
+            leader_hint: r.opt_u32()?,
+            result: r.bytes()?,
+            busy: r.bool()?,
+            reconfiguring: r.bool()?,
+            too_large: r.opt_usize()?,
+            staleness: r.opt_duration()?,
+        },
+        tag => return Err(DecodeError::UnknownVariant(tag)),
+    };
+    if !r.0.is_empty() {
+        return Err(DecodeError::TrailingBytes);
+    }
+    Ok(msg)
+}
+
+/// The bytes so far, and the size from which commands are compressed.
+/// The results of a read transaction as one byte string: a `u32` count,
+/// then each result as a `u32` length and its bytes.
+pub fn encode_results(results: &[Vec<u8>]) -> Vec<u8> {
+    let mut w = Writer(vec![], None);
+    w.len(results.len());
+    for result in results {
+        w.bytes(result);
+    }
+    w.0
+}
+
+/// Undoes `encode_results`.
+pub fn decode_results(data: &[u8]) -> Result<Vec<Vec<u8>>, DecodeError> {
+    let mut r = Reader(data);
+    let n = r.len()?;
+    let results = (0..n).map(|_| r.bytes()).collect::<Result<_, _>>()?;
+    if !r.0.is_empty() {
+        return Err(DecodeError::TrailingBytes);
+    }
+    Ok(results)
+}
+
+struct Writer(Vec<u8>, Option<usize>);
+
+impl Writer {
+    fn u8(&mut self, v: u8) {
+        self.0.push(v);
+    }
+
+    fn bool(&mut self, v: bool) {
+        self.u8(u8::from(v));
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.0.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.0.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn usize(&mut self, v: usize) {
+        self.u64(v as u64);
+    }
+
+    fn len(&mut self, n: usize) {
+        self.u32(n as u32);
+    }
+
+    fn opt_u32(&mut self, v: Option<u32>) {
+        self.bool(v.is_some());
+        if let Some(v) = v {
+            self.u32(v);
+        }
+    }
+
+    fn opt_usize(&mut self, v: Option<usize>) {
+        self.bool(v.is_some());
+        if let Some(v) = v {
+            self.usize(v);
+        }
+    }
+
+    fn opt_duration(&mut self, v: Option<Duration>) {
+        self.bool(v.is_some());
+        if let Some(d) = v {
+            self.u64(d.as_nanos() as u64);
+        }
+    }
+
+    fn bytes(&mut self, v: &[u8]) {
+        self.len(v.len());
+        self.0.extend_from_slice(v);
+    }
+
+    fn entry(&mut self, entry: &LogEntry) {
+        self.usize(entry.index);
+        self.usize(entry.term);
+        match self
+            .1
+            .and_then(|threshold| compress(&entry.command, threshold))
+        {
+            Some(compressed) => {
+                self.bool(true);
+                self.bytes(&compressed);
+            }
+            None => {
+                self.bool(false);
+                self.bytes(&entry.command);
+            }
+        }
+        self.bool(entry.config.is_some());
+        if let Some(config) = &entry.config {
+            self.config(config);
+        }
+        self.bool(entry.session.is_some());
+        if let Some((client_id, request_id)) = entry.session {
+            self.u32(client_id);
+            self.u64(request_id);
+        }
+    }
+
+    fn sessions(&mut self, sessions: &Sessions) {
+        self.len(sessions.len());
+        for (&client_id, (request_id, result)) in sessions {
+            self.u32(client_id);
+            self.u64(*request_id);
+            self.bytes(result);
+        }
+    }
+
+    fn ids(&mut self, ids: &[u32]) {
+        self.len(ids.len());
+        for &id in ids {
+            self.u32(id);
+        }
+    }
+
+    fn config(&mut self, config: &Config) {
+        self.len(config.voters.len());
+        for &id in &config.voters {
+            self.u32(id);
+            self.u32(config.weight(id));
+        }
+        self.len(config.learners.len());
+        for &id in &config.learners {
+            self.u32(id);
+        }
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        let (head, rest) = self
+            .0
+            .split_first_chunk::<N>()
+            .ok_or(DecodeError::UnexpectedEof)?;
+        self.0 = rest;
+        Ok(*head)
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    /// Any non-zero byte reads as `true`.
+    fn bool(&mut self) -> Result<bool, DecodeError> {
+        Ok(self.u8()? != 0)
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        Ok(u32::from_be_bytes(self.take()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, DecodeError> {
+        Ok(u64::from_be_bytes(self.take()?))
+    }
+
+    fn usize(&mut self) -> Result<usize, DecodeError> {
+        Ok(self.u64()? as usize)
+    }
+
+    fn len(&mut self) -> Result<usize, DecodeError> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn opt_u32(&mut self) -> Result<Option<u32>, DecodeError> {
+        Ok(if self.bool()? {
+            Some(self.u32()?)
+        } else {
+            None
+        })
+    }
+
+    fn opt_usize(&mut self) -> Result<Option<usize>, DecodeError> {
+        Ok(if self.bool()? {
+            Some(self.usize()?)
+        } else {
+            None
+        })
+    }
+
+    fn opt_duration(&mut self) -> Result<Option<Duration>, DecodeError> {
+        Ok(if self.bool()? {
+            Some(Duration::from_nanos(self.u64()?))
+        } else {
+            None
+        })
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, DecodeError> {
+        let n = self.len()?;
+        if self.0.len() < n {
+            return Err(DecodeError::UnexpectedEof);
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head.to_vec())
+    }
+
+    fn entry(&mut self) -> Result<LogEntry, DecodeError> {
+        Ok(LogEntry {
+            index: self.usize()?,
+            term: self.usize()?,
+            command: if self.bool()? {
+                decompress(&self.bytes()?).ok_or(DecodeError::BadCompression)?
+            } else {
+                self.bytes()?
+            },
+            config: if self.bool()? {
+                Some(self.config()?)
+            } else {
+                None
+            },
+            session: if self.bool()? {
+                Some((self.u32()?, self.u64()?))
+            } else {
+                None
+            },
+        })
+    }
+
+    fn sessions(&mut self) -> Result<Sessions, DecodeError> {
+        let n = self.len()?;
+        (0..n)
+            .map(|_| Ok((self.u32()?, (self.u64()?, self.bytes()?))))
+            .collect()
+    }
+
+    fn ids(&mut self) -> Result<Vec<u32>, DecodeError> {
+        let n = self.len()?;
+        (0..n).map(|_| self.u32()).collect()
+    }
+
+    fn config(&mut self) -> Result<Config, DecodeError> {
+        let n = self.len()?;
+        let voters = (0..n)
+            .map(|_| Ok((self.u32()?, self.u32()?)))
+            .collect::<Result<Vec<_>, DecodeError>>()?;
+        let mut config = Config::new(voters.iter().map(|&(id, _)| id)).with_weights(voters);
+        let n = self.len()?;
+        config.learners = (0..n).map(|_| self.u32()).collect::<Result<_, _>>()?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn malformed_buffers_name_what_is_wrong() {
+        let msg = Message::RequestVoteRequest {
+            term: 3,
+            candidate_id: 1,
+            last_log_index: 7,
+            last_log_term: 2,
+            forced: false,
+        };
+        let buf = encode(&msg);
+        assert!(matches!(
+            decode(&buf),
+            Ok(Message::RequestVoteRequest { term: 3, .. })
+        ));
+        let corrupt = |f: &dyn Fn(&mut Vec<u8>)| {
+            let mut buf = buf.clone();
+            f(&mut buf);
+            decode(&buf).unwrap_err()
+        };
+        assert_eq!(
+            corrupt(&|b| b.truncate(b.len() - 1)),
+            DecodeError::UnexpectedEof
+        );
+        assert_eq!(corrupt(&|b| b.clear()), DecodeError::UnexpectedEof);
+        assert_eq!(corrupt(&|b| b[1] = 200), DecodeError::UnknownVariant(200));
+        assert_eq!(
+            corrupt(&|b| b[0] = VERSION + 1),
+            DecodeError::UnsupportedVersion(VERSION + 1)
+        );
+        assert_eq!(corrupt(&|b| b.push(0)), DecodeError::TrailingBytes);
+        let err: Box<dyn std::error::Error> = Box::new(DecodeError::UnknownVariant(200));
+        assert_eq!(err.to_string(), "unknown message variant 200");
+    }
+}